@@ -0,0 +1,106 @@
+//! This example shows how to make one function reachable under several symbol names.
+//!
+//! ```
+//! fn add_one(x: i32) -> i32 {
+//!   return x + 1;
+//! }
+//!
+//! #[alias = "add_one"]
+//! fn increment(x: i32) -> i32;
+//!
+//! fn main() -> i32 {
+//!   return add_one(9);
+//! }
+//! ```
+//!
+//! Libraries sometimes export the same function under more than one name, for example libc's
+//! `memcpy` and `__memcpy`, or an old name which is kept around for compatibility after a rename.
+//!
+//! A symbol in an object file is only a name pointing at an offset in a section, so nothing stops
+//! two symbols from pointing at the same offset. Cranelift's `Module` doesn't know about aliases,
+//! so `declare_alias` only describes the alias. It's returned to `skip_boilerplate_with_aliases`,
+//! which adds the second symbol to the object file once the module has been finished.
+//!
+//! The alternative is to define `increment` as a real function which calls `add_one`, either with
+//! `call` or `return_call`. That works with every `Module` including the JIT, but it's separate
+//! code at a different address, which costs a jump on every call.
+//!
+//! Both names show up at the same address in the symbol table:
+//!
+//! `$ cargo run --example aliases -- -o aliases.o`
+//! `$ nm aliases.o`
+//!
+//! ```
+//! 0000000000000000 T add_one
+//! 0000000000000000 T increment
+//! 000000000000000c T main
+//! ```
+//!
+//! The main function returns `add_one(9)`, so the exit code will be `10`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ clang aliases.o -o aliases`
+//! `$ ./aliases; echo $?`
+//!
+//! There's no `--jit` mode for this example, since the alias only exists in the object file.
+
+use cranelift::prelude as cl;
+use cranelift::prelude::InstBuilder;
+use cranelift_examples::{
+    Codegen, declare_alias, declare_function_from_types, declare_main,
+    skip_boilerplate_with_aliases,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate_with_aliases(b"aliases", |ctx, fctx, module, _args| {
+        let mut codegen = Codegen::new(ctx, fctx, module);
+
+        let main_func_id = declare_main(codegen.module);
+
+        // fn add_one(x: i32) -> i32;
+        let add_one_func_id = declare_function_from_types(
+            codegen.module,
+            "add_one",
+            Linkage::Export,
+            &[cl::types::I32],
+            &[cl::types::I32],
+            None,
+        );
+
+        // #[alias = "add_one"]
+        // fn increment(x: i32) -> i32;
+        //
+        // The alias is only a name, so it isn't declared in the module and has no signature
+        let increment = declare_alias(codegen.module, "increment", add_one_func_id);
+
+        // fn add_one(x: i32) -> i32 {
+        //   return x + 1;
+        // }
+        codegen
+            .define(add_one_func_id, |_, fbuilder, entry| {
+                let x = fbuilder.block_params(entry)[0];
+                let result = fbuilder.ins().iadd_imm(x, 1);
+                fbuilder.ins().return_(&[result]);
+            })
+            .unwrap();
+
+        // fn main() -> i32 {
+        //   return add_one(9);
+        // }
+        codegen
+            .define(main_func_id, |module, fbuilder, _| {
+                let nine = fbuilder.ins().iconst(cl::types::I32, 9);
+
+                let fref = module.declare_func_in_func(add_one_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[nine]);
+                let result = fbuilder.inst_results(call)[0];
+
+                fbuilder.ins().return_(&[result]);
+            })
+            .unwrap();
+
+        vec![increment]
+    });
+}
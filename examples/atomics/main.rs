@@ -0,0 +1,279 @@
+//! This example shows how to use atomic instructions, and how to build a spinlock out of them.
+//!
+//! ```
+//! fn increment(counter: &AtomicI64) -> i64 {
+//!   return counter.fetch_add(1);
+//! }
+//!
+//! fn lock(l: &AtomicI8) {
+//!   while l.compare_exchange(0, 1) != 0 {}
+//! }
+//!
+//! fn unlock(l: &AtomicI8) {
+//!   l.store(0);
+//! }
+//!
+//! fn main() -> i32 {
+//!   let counter = AtomicI64::new(0);
+//!   let l = AtomicI8::new(0);
+//!   let shared = 0;
+//!
+//!   increment(&counter);
+//!   increment(&counter);
+//!   increment(&counter);
+//!
+//!   lock(&l);
+//!   shared += 10;
+//!   unlock(&l);
+//!
+//!   return counter.load() + shared;
+//! }
+//! ```
+//!
+//! Cranelift has four atomic instructions:
+//!
+//! * `atomic_load` and `atomic_store`, which read or write a value in a single step.
+//! * `atomic_rmw` (read-modify-write), which applies an `AtomicRmwOp` such as `Add`, `Xchg` or
+//!   `Umax` to the value in memory and returns the value from before the change.
+//! * `atomic_cas` (compare-and-swap), which only stores the new value if memory still holds the
+//!   expected value. It always returns the value it found, so comparing that with the expected
+//!   value tells us whether the swap happened.
+//!
+//! Unlike C or Rust, Cranelift doesn't let us pick a memory ordering. Every atomic instruction is
+//! sequentially consistent, the strongest ordering, and also orders the normal loads and stores
+//! around it. So the plain `load`s and `store`s in the critical section below can't be moved out
+//! past the `atomic_cas` of `lock` or the `atomic_store` of `unlock`. This means that a lock
+//! written with Cranelift atomics is always correct, but languages which expose weaker orderings
+//! such as `Relaxed` will end up with more fences than strictly necessary. For a standalone fence
+//! there's the `fence` instruction.
+//!
+//! The `MemFlags` of an atomic instruction need to say that the address is `aligned`. Atomics on
+//! misaligned addresses either trap or aren't atomic at all, depending on the CPU. `notrap` can be
+//! set as well if we know the address is valid, which `MemFlags::trusted()` does for us.
+//!
+//! This example only has a single thread, so the atomics never actually race. The emitted
+//! instructions are the same as they would be with many threads, such as `lock xadd` and
+//! `lock cmpxchg` on x86-64, or load-exclusive/store-exclusive loops on AArch64 without LSE.
+//!
+//! The main function returns `3 + 10`, so the exit code will be `13`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example atomics -- -o atomics.o`
+//! `$ clang atomics.o -o atomics`
+//! `$ ./atomics; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example atomics -- --jit`
+
+use cranelift::codegen::ir::AtomicRmwOp;
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"atomics", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn increment(counter: &AtomicI64) -> i64;
+    let increment_func_id = declare_function_from_types(
+        codegen.module,
+        "increment",
+        Linkage::Local,
+        &[size_t],
+        &[cl::types::I64],
+        None,
+    );
+
+    // fn lock(l: &AtomicI8);
+    // fn unlock(l: &AtomicI8);
+    let [lock_func_id, unlock_func_id] = ["lock", "unlock"].map(|name| {
+        declare_function_from_types(codegen.module, name, Linkage::Local, &[size_t], &[], None)
+    });
+
+    // fn main() -> i32 {
+    //   let counter = AtomicI64::new(0);
+    //   let l = AtomicI8::new(0);
+    //   let shared = 0;
+    //
+    //   increment(&counter);
+    //   increment(&counter);
+    //   increment(&counter);
+    //
+    //   lock(&l);
+    //   shared += 10;
+    //   unlock(&l);
+    //
+    //   return counter.load() + shared;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let flags = cl::MemFlags::trusted();
+
+            // Each slot is aligned to its own size, which atomics require
+            let [counter, l, shared] = [cl::types::I64, cl::types::I8, cl::types::I64].map(|ty| {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    ty.bytes(),
+                    ty.bytes().trailing_zeros() as u8,
+                ));
+
+                let zero = fbuilder.ins().iconst(ty, 0);
+                fbuilder.ins().stack_store(zero, slot, 0);
+
+                fbuilder.ins().stack_addr(size_t, slot, 0)
+            });
+
+            // increment(&counter);
+            // increment(&counter);
+            // increment(&counter);
+            for _ in 0..3 {
+                let fref = module.declare_func_in_func(increment_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[counter]);
+            }
+
+            // lock(&l);
+            {
+                let fref = module.declare_func_in_func(lock_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[l]);
+            }
+
+            // shared += 10;
+            //
+            // The critical section uses normal loads and stores, the lock is what keeps other
+            // threads from touching `shared` at the same time.
+            {
+                let n = fbuilder.ins().load(cl::types::I64, flags, shared, 0);
+                let n = fbuilder.ins().iadd_imm(n, 10);
+                fbuilder.ins().store(flags, n, shared, 0);
+            }
+
+            // unlock(&l);
+            {
+                let fref = module.declare_func_in_func(unlock_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[l]);
+            }
+
+            // return counter.load() + shared;
+            let result = {
+                let counter = fbuilder.ins().atomic_load(cl::types::I64, flags, counter);
+                let shared = fbuilder.ins().load(cl::types::I64, flags, shared, 0);
+                let sum = fbuilder.ins().iadd(counter, shared);
+
+                // The exit code is an `i32`
+                fbuilder.ins().ireduce(cl::types::I32, sum)
+            };
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn increment(counter: &AtomicI64) -> i64 {
+    //   return counter.fetch_add(1);
+    // }
+    codegen
+        .define(increment_func_id, |_, fbuilder, entry| {
+            let counter = fbuilder.block_params(entry)[0];
+
+            // A separate `load`, `iadd` and `store` could have another thread write to the counter
+            // in-between, losing its increment. `atomic_rmw` does all three as a single step.
+            let one = fbuilder.ins().iconst(cl::types::I64, 1);
+            let old = fbuilder.ins().atomic_rmw(
+                cl::types::I64,
+                cl::MemFlags::trusted(),
+                AtomicRmwOp::Add,
+                counter,
+                one,
+            );
+
+            fbuilder.ins().return_(&[old]);
+        })
+        .unwrap();
+
+    // fn lock(l: &AtomicI8) {
+    //   while l.compare_exchange(0, 1) != 0 {}
+    // }
+    //
+    // // -- Which we lower as -- //
+    //
+    // block0(l):
+    //   jump block1
+    //
+    // block1:
+    //   let old = atomic_cas(l, 0, 1);
+    //   brif old, block1, block2
+    //
+    // block2:
+    //   return
+    codegen
+        .define(lock_func_id, |_, fbuilder, entry| {
+            let l = fbuilder.block_params(entry)[0];
+
+            let spin = fbuilder.create_block();
+            let acquired = fbuilder.create_block();
+
+            fbuilder.ins().jump(spin, &[]);
+
+            // Try to swap the lock from unlocked (0) to locked (1). If it held anything other than
+            // 0, another thread owns the lock and we try again.
+            {
+                fbuilder.switch_to_block(spin);
+
+                let unlocked = fbuilder.ins().iconst(cl::types::I8, 0);
+                let locked = fbuilder.ins().iconst(cl::types::I8, 1);
+                let old = fbuilder
+                    .ins()
+                    .atomic_cas(cl::MemFlags::trusted(), l, unlocked, locked);
+
+                fbuilder.ins().brif(old, spin, &[], acquired, &[]);
+            }
+
+            // The only predecessors of `spin` are the entry block and itself
+            fbuilder.seal_block(spin);
+
+            {
+                fbuilder.seal_block(acquired);
+                fbuilder.switch_to_block(acquired);
+
+                fbuilder.ins().return_(&[]);
+            }
+        })
+        .unwrap();
+
+    // fn unlock(l: &AtomicI8) {
+    //   l.store(0);
+    // }
+    codegen
+        .define(unlock_func_id, |_, fbuilder, entry| {
+            let l = fbuilder.block_params(entry)[0];
+
+            // Since we own the lock, nobody else is writing to it, so a compare-and-swap isn't
+            // needed. The store still has to be atomic so that the writes of the critical section
+            // are visible to the next thread which takes the lock.
+            let unlocked = fbuilder.ins().iconst(cl::types::I8, 0);
+            fbuilder
+                .ins()
+                .atomic_store(cl::MemFlags::trusted(), unlocked, l);
+
+            fbuilder.ins().return_(&[]);
+        })
+        .unwrap();
+}
@@ -0,0 +1,219 @@
+//! This example shows the bit manipulation instructions, such as counting and reversing bits.
+//!
+//! ```
+//! fn count_ones(x: u32) -> u32 { x.count_ones() }
+//! fn leading_zeros(x: u32) -> u32 { x.leading_zeros() }
+//! fn trailing_zeros(x: u32) -> u32 { x.trailing_zeros() }
+//! fn swap_bytes(x: u32) -> u32 { x.swap_bytes() }
+//! fn reverse_bits(x: u32) -> u32 { x.reverse_bits() }
+//! fn rotate_left(x: u32, n: u32) -> u32 { x.rotate_left(n) }
+//! fn rotate_right(x: u32, n: u32) -> u32 { x.rotate_right(n) }
+//!
+//! fn main() -> i32 {
+//!   return count_ones(0xFF);
+//! }
+//! ```
+//!
+//! Each of these is a single Cranelift instruction. Whether they become a single machine
+//! instruction depends on the target.
+//!
+//! * `rotl`, `rotr` and `bswap` are single instructions on both x86-64 and aarch64.
+//! * `clz`, `ctz` and `bitrev` are single instructions on aarch64 (`clz` and `rbit`). On x86-64,
+//!   `clz`/`ctz` need the `lzcnt` and `bmi1` extensions, and `bitrev` has no instruction at all.
+//! * `popcnt` needs the `popcnt` extension on x86-64, and is done with vector registers on aarch64.
+//!
+//! When the instruction isn't available, Cranelift expands it into a short sequence of other
+//! instructions inline. None of these need a libcall, unlike for example `ceil` and `floor` on
+//! x86-64 without SSE 4.1, which is why `skip_boilerplate` gives the module the names of the
+//! runtime functions to call with `default_libcall_names`.
+//!
+//! The x86-64 extensions aren't enabled by default, since not every x86-64 CPU has them. Try
+//! comparing the disassembly of this example for `x86_64-unknown-linux` and `aarch64-unknown-linux`.
+//!
+//! The main function returns `popcnt(0xFF)`, so the exit code will be `8`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example bitops -- -o bitops.o`
+//! `$ clang bitops.o -o bitops`
+//! `$ ./bitops; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example bitops -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"bitops", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let int = cl::types::I32;
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // Each operation gets its own function taking its input as a parameter. If we applied them to
+    // constants inside of `main` instead, the unused results would be removed and wouldn't show
+    // up in the disassembly.
+    //
+    // fn count_ones(x: u32) -> u32;
+    // fn leading_zeros(x: u32) -> u32;
+    // ...
+    let [
+        count_ones,
+        leading_zeros,
+        trailing_zeros,
+        swap_bytes,
+        reverse_bits,
+    ] = [
+        "count_ones",
+        "leading_zeros",
+        "trailing_zeros",
+        "swap_bytes",
+        "reverse_bits",
+    ]
+    .map(|name| {
+        declare_function_from_types(codegen.module, name, Linkage::Local, &[int], &[int], None)
+    });
+
+    // fn rotate_left(x: u32, n: u32) -> u32;
+    // fn rotate_right(x: u32, n: u32) -> u32;
+    let [rotate_left, rotate_right] = ["rotate_left", "rotate_right"].map(|name| {
+        declare_function_from_types(
+            codegen.module,
+            name,
+            Linkage::Local,
+            &[int; 2],
+            &[int],
+            None,
+        )
+    });
+
+    // fn main() -> i32 {
+    //   return count_ones(0xFF);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let x = fbuilder.ins().iconst(int, 0xFF);
+
+            let fref = module.declare_func_in_func(count_ones, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &[x]);
+            let ones = fbuilder.inst_results(call)[0];
+
+            fbuilder.ins().return_(&[ones]);
+        })
+        .unwrap();
+
+    // fn count_ones(x: u32) -> u32 {
+    //   x.count_ones()
+    // }
+    //
+    // count_ones(0xFF) == 8
+    codegen
+        .define(count_ones, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+            let result = fbuilder.ins().popcnt(x);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn leading_zeros(x: u32) -> u32 {
+    //   x.leading_zeros()
+    // }
+    //
+    // leading_zeros(0xFF) == 24
+    codegen
+        .define(leading_zeros, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+            let result = fbuilder.ins().clz(x);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn trailing_zeros(x: u32) -> u32 {
+    //   x.trailing_zeros()
+    // }
+    //
+    // trailing_zeros(0x80) == 7
+    codegen
+        .define(trailing_zeros, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+            let result = fbuilder.ins().ctz(x);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn swap_bytes(x: u32) -> u32 {
+    //   x.swap_bytes()
+    // }
+    //
+    // swap_bytes(0x11223344) == 0x44332211
+    //
+    // Useful when reading or writing data in a different endianness than the target's.
+    codegen
+        .define(swap_bytes, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+            let result = fbuilder.ins().bswap(x);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn reverse_bits(x: u32) -> u32 {
+    //   x.reverse_bits()
+    // }
+    //
+    // reverse_bits(1) == 0x80000000
+    codegen
+        .define(reverse_bits, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+            let result = fbuilder.ins().bitrev(x);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn rotate_left(x: u32, n: u32) -> u32 {
+    //   x.rotate_left(n)
+    // }
+    //
+    // rotate_left(0x80000001, 4) == 0x00000018
+    //
+    // Bits shifted out of one end come back in on the other. The rotation amount is taken modulo
+    // the bit width. There's also `rotl_imm` for constant amounts.
+    codegen
+        .define(rotate_left, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+            let n = fbuilder.block_params(entry)[1];
+            let result = fbuilder.ins().rotl(x, n);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn rotate_right(x: u32, n: u32) -> u32 {
+    //   x.rotate_right(n)
+    // }
+    //
+    // rotate_right(0x80000001, 4) == 0x18000000
+    codegen
+        .define(rotate_right, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+            let n = fbuilder.block_params(entry)[1];
+            let result = fbuilder.ins().rotr(x, n);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
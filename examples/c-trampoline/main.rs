@@ -0,0 +1,194 @@
+//! This example shows how to let C code call an internal function which uses Cranelift's own
+//! calling convention, by going through a trampoline.
+//!
+//! ```
+//! extern "C" fn qsort(base: *mut i32, nmemb: usize, size: usize, compar: extern "C" fn(*const i32, *const i32) -> i32);
+//!
+//! fn compare(a: *const i32, b: *const i32) -> i32 {
+//!   return (*a > *b) as i32 - (*a < *b) as i32;
+//! }
+//!
+//! fn main() -> i32 {
+//!   let xs = [5, 3, 9, 1];
+//!   qsort(&xs, 4, 4, compare);
+//!   return xs[0] * 10 + xs[3];
+//! }
+//! ```
+//!
+//! Functions which are only called by our own code are declared with `CallConv::Fast`. That lets
+//! Cranelift pick whichever registers and stack layout it prefers, which is free to differ from what
+//! C expects. Some targets currently happen to use the same registers for both, but nothing
+//! guarantees it.
+//!
+//! `qsort` is C code, and calls the comparison function it's given using the default calling
+//! convention of the target, such as SystemV on Linux. So instead of giving it `compare` directly,
+//! `generate_c_trampoline` creates `compare_c`. It has the same parameters as `compare` but uses the
+//! default calling convention, and forwards them to `compare` with a regular `call`.
+//!
+//! The trampoline is exported, so the same approach works for exposing internal functions of a
+//! library to C programs linking against it.
+//!
+//! The main function sorts the array to `[1, 3, 5, 9]` and returns `1 * 10 + 9`, so the exit code
+//! will be `19`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example c-trampoline -- -o c-trampoline.o`
+//! `$ clang c-trampoline.o -o c-trampoline`
+//! `$ ./c-trampoline; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example c-trampoline -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::isa::CallConv;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, IntCC, MemFlags, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, generate_c_trampoline,
+    signature_from_decl, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"c-trampoline", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let main_func_id = declare_main(module);
+
+    // extern "C" fn qsort(base: *mut i32, nmemb: usize, size: usize, compar: extern "C" fn(..));
+    let qsort_func_id = declare_function_from_types(
+        module,
+        "qsort",
+        Linkage::Import,
+        &[size_t, size_t, size_t, size_t],
+        &[],
+        None,
+    );
+
+    // fn compare(a: *const i32, b: *const i32) -> i32;
+    //
+    // Only called by our own code, so it uses Cranelift's calling convention
+    let compare_func_id = declare_function_from_types(
+        module,
+        "compare",
+        Linkage::Local,
+        &[size_t, size_t],
+        &[cl::types::I32],
+        Some(CallConv::Fast),
+    );
+
+    // extern "C" fn compare_c(a: *const i32, b: *const i32) -> i32 {
+    //   return compare(a, b);
+    // }
+    let compare_c_func_id = generate_c_trampoline(module, compare_func_id);
+
+    // Only the calling convention differs between the two
+    {
+        let internal = signature_from_decl(module, compare_func_id);
+        let trampoline = signature_from_decl(module, compare_c_func_id);
+
+        assert_eq!(internal.params, trampoline.params);
+        assert_eq!(internal.returns, trampoline.returns);
+        assert_eq!(internal.call_conv, CallConv::Fast);
+        assert_eq!(trampoline.call_conv, module.isa().default_call_conv());
+    }
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    // fn compare(a: *const i32, b: *const i32) -> i32 {
+    //   return (*a > *b) as i32 - (*a < *b) as i32;
+    // }
+    codegen
+        .define(compare_func_id, |_, fbuilder, entry| {
+            let a = fbuilder.block_params(entry)[0];
+            let b = fbuilder.block_params(entry)[1];
+
+            let a = fbuilder
+                .ins()
+                .load(cl::types::I32, MemFlags::trusted(), a, 0);
+            let b = fbuilder
+                .ins()
+                .load(cl::types::I32, MemFlags::trusted(), b, 0);
+
+            // `icmp` gives an `i8` which is either 0 or 1
+            let greater = {
+                let v = fbuilder.ins().icmp(IntCC::SignedGreaterThan, a, b);
+                fbuilder.ins().uextend(cl::types::I32, v)
+            };
+            let less = {
+                let v = fbuilder.ins().icmp(IntCC::SignedLessThan, a, b);
+                fbuilder.ins().uextend(cl::types::I32, v)
+            };
+
+            let result = fbuilder.ins().isub(greater, less);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn main() -> i32 {
+    //   let xs = [5, 3, 9, 1];
+    //   qsort(&xs, 4, 4, compare);
+    //   return xs[0] * 10 + xs[3];
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let xs = [5, 3, 9, 1];
+            let xs = {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    4 * 4,
+                    2,
+                ));
+                let xs = fbuilder.ins().stack_addr(size_t, slot, 0);
+
+                for (i, n) in [5, 3, 9, 1].into_iter().enumerate() {
+                    let n = fbuilder.ins().iconst(cl::types::I32, n);
+                    fbuilder
+                        .ins()
+                        .store(MemFlags::trusted(), n, xs, i as i32 * 4);
+                }
+
+                xs
+            };
+
+            // qsort(&xs, 4, 4, compare);
+            //
+            // `qsort` is given the address of the trampoline rather than of `compare`, since
+            // it's going to call it the way C functions are called
+            {
+                let compar = {
+                    let fref = module.declare_func_in_func(compare_c_func_id, fbuilder.func);
+                    fbuilder.ins().func_addr(size_t, fref)
+                };
+                let nmemb = fbuilder.ins().iconst(size_t, 4);
+                let size = fbuilder.ins().iconst(size_t, 4);
+
+                let fref = module.declare_func_in_func(qsort_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[xs, nmemb, size, compar]);
+            }
+
+            // return xs[0] * 10 + xs[3];
+            let first = fbuilder
+                .ins()
+                .load(cl::types::I32, MemFlags::trusted(), xs, 0);
+            let last = fbuilder
+                .ins()
+                .load(cl::types::I32, MemFlags::trusted(), xs, 3 * 4);
+
+            let first = fbuilder.ins().imul_imm(first, 10);
+            let result = fbuilder.ins().iadd(first, last);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
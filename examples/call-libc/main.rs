@@ -0,0 +1,96 @@
+//! This example shows how to call functions from the C standard library, such as `puts`.
+//!
+//! ```
+//! extern "C" fn puts(s: *const u8) -> i32;
+//!
+//! fn main() -> i32 {
+//!   puts("Hello\0");
+//!   return 0;
+//! }
+//! ```
+//!
+//! Functions which are defined somewhere else are declared with `Linkage::Import`. We don't define
+//! a body for them, instead the linker will resolve the symbol when we link our object file.
+//!
+//! The string is stored as a data object in a read-only section of the object file, and we get a
+//! pointer to it from within `main`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`.
+//! No extra flags are needed since libc is linked by default.
+//!
+//! `$ cargo run --example call-libc -- -o call-libc.o`
+//! `$ clang call-libc.o -o call-libc`
+//! `$ ./call-libc`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example call-libc -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, data_addr_in_func, declare_data_string, declare_function_from_types, declare_main,
+    for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"call-libc", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // extern "C" fn puts(s: *const u8) -> i32;
+    //
+    // Cranelift doesn't have pointer types, so the pointer is declared as a `size_t` integer.
+    //
+    // Since `puts` is a C function, we use the default calling convention of the target, which is
+    // what C compilers use as well.
+    let puts_func_id = declare_function_from_types(
+        codegen.module,
+        "puts",
+        Linkage::Import,
+        &[size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    // Strings passed to C functions need to end with a nul byte, since that's how C knows where
+    // the string ends.
+    //
+    // The data is declared as non-writable, which puts it in a read-only section (`.rodata` on Linux).
+    let hello_data_id = declare_data_string(codegen.module, "hello", b"Hello", true);
+
+    // fn main() -> i32 {
+    //   puts("Hello\0");
+    //   return 0;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // Get a pointer to the string
+            let hello = data_addr_in_func(module, hello_data_id, fbuilder);
+
+            // puts("Hello\0");
+            {
+                let fref = module.declare_func_in_func(puts_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[hello]);
+            }
+
+            // return 0;
+            let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+            fbuilder.ins().return_(&[zero]);
+        })
+        .unwrap();
+}
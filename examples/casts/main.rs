@@ -0,0 +1,175 @@
+//! This example shows the instructions for casting between integers of different sizes, between
+//! integers and floats of the same size, and between pointers and integers.
+//!
+//! ```
+//! fn main() -> i32 {
+//!   let byte: i8 = -100;
+//!
+//!   // Changing the size of an integer
+//!   let sign_extended = byte as i32;
+//!   let zero_extended = byte as u8 as i32;
+//!   let truncated = 0x1_0000_0003_i64 as i32;
+//!
+//!   // Reinterpreting the bits of a float
+//!   let bits = 1.0_f32.to_bits();
+//!   let back = f32::from_bits(bits);
+//!
+//!   // Pointers and integers
+//!   let n = 5;
+//!   let addr = &n as *const i32 as u64;
+//!   let ptr = addr as *const i32;
+//!
+//!   return sign_extended + zero_extended + truncated + (bits >> 28) + back as i32 + *ptr;
+//! }
+//! ```
+//!
+//! Some casts preserve the value while changing its bits, and others preserve the bits while
+//! changing what they mean.
+//!
+//! * `sextend` widens an integer by copying its sign bit into the new upper bits, which preserves
+//!   the value of signed integers. `-100_i8` stays `-100`.
+//! * `uextend` widens an integer by filling the new upper bits with zeros, which preserves the value
+//!   of unsigned integers. The bits of `-100_i8` are `156` when read as unsigned, so that's what we get.
+//! * `ireduce` narrows an integer by dropping the upper bits, which preserves the value only if it
+//!   fits in the smaller type. `0x1_0000_0003` becomes `3`.
+//! * `bitcast` keeps every bit as-is and only changes the type, so both types must be the same
+//!   size. `1.0_f32` becomes `0x3f800000`. It takes `MemFlags` to say which byte order to use,
+//!   which only matters when casting between vectors with different lane sizes.
+//!
+//! Cranelift has no separate pointer type. A pointer is an integer of `isa.pointer_type()`, so
+//! casting between pointers and integers of that same size doesn't need any instruction at all.
+//! Casting to a different size, such as storing a pointer as a `u64` on a 32-bit target, uses
+//! `uextend` and `ireduce` like any other integer. Likewise `scalar_to_vector` isn't a cast but puts
+//! a scalar into the first lane of a vector, see the `simd` example for that.
+//!
+//! Casts between integers and floats which convert the value rather than the bits, such as `3.9 as
+//! i32`, are covered in the `conversions` example.
+//!
+//! The main function returns `-100 + 156 + 3 + 3 + 1 + 5`, so the exit code will be `68`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example casts -- -o casts.o`
+//! `$ clang casts.o -o casts`
+//! `$ ./casts; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example casts -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{Codegen, declare_main, for_any_module, skip_boilerplate};
+use cranelift_module::Module;
+
+fn main() {
+    skip_boilerplate(b"casts", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn main() -> i32 {
+    //   ...
+    //   return sign_extended + zero_extended + truncated + (bits >> 28) + back as i32 + *ptr;
+    // }
+    codegen
+        .define(main_func_id, |_, fbuilder, _| {
+            // let byte: i8 = -100;
+            let byte = fbuilder.ins().iconst(cl::types::I8, -100);
+
+            // let sign_extended = byte as i32;
+            //
+            // Preserves the value: -100
+            let sign_extended = fbuilder.ins().sextend(cl::types::I32, byte);
+
+            // let zero_extended = byte as u8 as i32;
+            //
+            // Cranelift integers don't have a signedness, the `i8` is the same bits as the `u8`.
+            // It's the choice of `uextend` which treats them as unsigned: 156
+            let zero_extended = fbuilder.ins().uextend(cl::types::I32, byte);
+
+            // let truncated = 0x1_0000_0003_i64 as i32;
+            //
+            // Only the lower 32 bits are kept: 3
+            let truncated = {
+                let wide = fbuilder.ins().iconst(cl::types::I64, 0x1_0000_0003);
+                fbuilder.ins().ireduce(cl::types::I32, wide)
+            };
+
+            // let bits = 1.0_f32.to_bits();
+            // let back = f32::from_bits(bits);
+            //
+            // Preserves the bits: 0x3f800000 and then 1.0 again
+            let (bits, back) = {
+                let one = fbuilder.ins().f32const(1.0);
+                let bits = fbuilder
+                    .ins()
+                    .bitcast(cl::types::I32, cl::MemFlags::new(), one);
+                let back = fbuilder
+                    .ins()
+                    .bitcast(cl::types::F32, cl::MemFlags::new(), bits);
+                (bits, back)
+            };
+
+            // bits >> 28
+            //
+            // The top four bits of `0x3f800000`: 3
+            let top_bits = fbuilder.ins().ushr_imm(bits, 28);
+
+            // back as i32
+            //
+            // Converts the value rather than the bits, see the `conversions` example: 1
+            let back = fbuilder.ins().fcvt_to_sint_sat(cl::types::I32, back);
+
+            // let n = 5;
+            // let addr = &n as *const i32 as u64;
+            // let ptr = addr as *const i32;
+            let ptr = {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    4,
+                    2,
+                ));
+
+                let n = fbuilder.ins().iconst(cl::types::I32, 5);
+                fbuilder.ins().stack_store(n, slot, 0);
+
+                let ptr = fbuilder.ins().stack_addr(size_t, slot, 0);
+
+                // On 64-bit targets both casts are no-ops since the pointer already is an `i64`
+                if size_t == cl::types::I64 {
+                    ptr
+                } else {
+                    let addr = fbuilder.ins().uextend(cl::types::I64, ptr);
+                    fbuilder.ins().ireduce(size_t, addr)
+                }
+            };
+
+            // *ptr
+            //
+            // Reading through the pointer we got back from the integer: 5
+            let loaded = fbuilder
+                .ins()
+                .load(cl::types::I32, cl::MemFlags::trusted(), ptr, 0);
+
+            // sign_extended + zero_extended + truncated + (bits >> 28) + back as i32 + *ptr
+            let result = [zero_extended, truncated, top_bits, back, loaded]
+                .into_iter()
+                .fold(sign_extended, |sum, v| fbuilder.ins().iadd(sum, v));
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
@@ -0,0 +1,229 @@
+//! This example shows how to make arithmetic crash the program on overflow instead of silently
+//! wrapping around.
+//!
+//! ```
+//! fn checked_add(a: i32, b: i32) -> i32 {
+//!   return a.checked_add(b).expect("overflow");
+//! }
+//!
+//! fn checked_add_unsigned(a: u32, b: u32) -> u32 {
+//!   return a.checked_add(b).expect("overflow");
+//! }
+//!
+//! fn checked_div(a: i32, b: i32) -> i32 {
+//!   return a / b;
+//! }
+//!
+//! fn main() -> i32 {
+//!   return checked_div(checked_add(40, 2), checked_add_unsigned(1, 1));
+//! }
+//! ```
+//!
+//! Integer instructions such as `iadd` wrap around on overflow. Languages which want overflow to be
+//! an error need to detect it themselves, and then trap.
+//!
+//! A trap stops the program, and comes with a `TrapCode` describing why. Cranelift has a couple of
+//! built-in trap codes such as `TrapCode::INTEGER_OVERFLOW` and `TrapCode::INTEGER_DIVISION_BY_ZERO`,
+//! and we can create our own with `TrapCode::user` as done in the `tagged-union-layouts` example.
+//!
+//! When compiled to an object file, a trap becomes an illegal instruction and the program is killed
+//! with `SIGILL`. A runtime such as wasmtime would instead look up which trap code the faulting
+//! instruction belongs to and report it as an error.
+//!
+//! The main function returns `42 / 2`, so the exit code will be `21`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example checked-arith -- -o checked-arith.o`
+//! `$ clang checked-arith.o -o checked-arith`
+//! `$ ./checked-arith; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example checked-arith -- --jit`
+
+use cranelift::codegen::ir::{Function, Opcode};
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"checked-arith", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn checked_add(a: i32, b: i32) -> i32;
+    // fn checked_add_unsigned(a: u32, b: u32) -> u32;
+    // fn checked_div(a: i32, b: i32) -> i32;
+    let [
+        checked_add_func_id,
+        checked_add_unsigned_func_id,
+        checked_div_func_id,
+    ] = ["checked_add", "checked_add_unsigned", "checked_div"].map(|name| {
+        declare_function_from_types(
+            codegen.module,
+            name,
+            Linkage::Local,
+            &[cl::types::I32; 2],
+            &[cl::types::I32],
+            None,
+        )
+    });
+
+    // fn main() -> i32 {
+    //   return checked_div(checked_add(40, 2), checked_add_unsigned(1, 1));
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let mut call = |func_id, a: i64, b: i64| {
+                let a = fbuilder.ins().iconst(cl::types::I32, a);
+                let b = fbuilder.ins().iconst(cl::types::I32, b);
+                let fref = module.declare_func_in_func(func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[a, b]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            let [sum, divisor] = [
+                call(checked_add_func_id, 40, 2),
+                call(checked_add_unsigned_func_id, 1, 1),
+            ];
+
+            let result = {
+                let fref = module.declare_func_in_func(checked_div_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[sum, divisor]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn checked_add(a: i32, b: i32) -> i32 {
+    //   let sum = a + b;
+    //   if overflowed { trap } else { return sum }
+    // }
+    //
+    // Signed overflow is detected by comparing the wrapped result. Adding a positive number should
+    // make the result larger, and adding a negative number should make it smaller. If the sum ends
+    // up on the wrong side of `a`, it wrapped around.
+    //
+    // Cranelift also has `sadd_overflow`, which returns the overflow flag alongside the sum.
+    codegen
+        .define(checked_add_func_id, |_, fbuilder, entry| {
+            let a = fbuilder.block_params(entry)[0];
+            let b = fbuilder.block_params(entry)[1];
+
+            let ok_block = fbuilder.create_block();
+            let overflow_block = fbuilder.create_block();
+
+            let sum = fbuilder.ins().iadd(a, b);
+
+            // overflowed = (sum < a) != (b < 0)
+            let overflowed = {
+                let sum_is_less = fbuilder.ins().icmp(cl::IntCC::SignedLessThan, sum, a);
+                let b_is_negative = fbuilder.ins().icmp_imm(cl::IntCC::SignedLessThan, b, 0);
+                fbuilder.ins().bxor(sum_is_less, b_is_negative)
+            };
+
+            fbuilder
+                .ins()
+                .brif(overflowed, overflow_block, &[], ok_block, &[]);
+
+            fbuilder.seal_block(ok_block);
+            fbuilder.seal_block(overflow_block);
+
+            // return sum;
+            {
+                fbuilder.switch_to_block(ok_block);
+                fbuilder.ins().return_(&[sum]);
+            }
+
+            // Like `return`, `trap` terminates the block.
+            //
+            // Keeping the trap in its own block moves it out of the way of the common path. We
+            // could also have used `trapnz(overflowed, ..)` which traps without needing a block.
+            {
+                fbuilder.switch_to_block(overflow_block);
+                fbuilder.ins().trap(cl::TrapCode::INTEGER_OVERFLOW);
+            }
+        })
+        .unwrap();
+
+    // After the function is defined, `ctx.func` holds the function as it was compiled. Cranelift
+    // noticed that `block2` does nothing but trap, and turned the branch into a `trapnz` in the
+    // entry block.
+    assert!(
+        contains_trap(&codegen.ctx.func),
+        "checked_add should branch to a trap on overflow"
+    );
+
+    // fn checked_add_unsigned(a: u32, b: u32) -> u32 {
+    //   return a.checked_add(b).expect("overflow");
+    // }
+    //
+    // For unsigned integers, Cranelift has a single instruction which adds and traps on overflow.
+    codegen
+        .define(checked_add_unsigned_func_id, |_, fbuilder, entry| {
+            let a = fbuilder.block_params(entry)[0];
+            let b = fbuilder.block_params(entry)[1];
+
+            let sum = fbuilder
+                .ins()
+                .uadd_overflow_trap(a, b, cl::TrapCode::INTEGER_OVERFLOW);
+
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+
+    assert!(
+        contains_trap(&codegen.ctx.func),
+        "checked_add_unsigned should trap on overflow"
+    );
+
+    // fn checked_div(a: i32, b: i32) -> i32 {
+    //   return a / b;
+    // }
+    //
+    // Division is already checked. `sdiv` traps with `TrapCode::INTEGER_DIVISION_BY_ZERO` if `b` is
+    // zero, and with `TrapCode::INTEGER_OVERFLOW` for `i32::MIN / -1` since the result doesn't fit.
+    //
+    // Languages which want to handle division by zero differently, such as returning `0`, need to
+    // check `b` before dividing.
+    codegen
+        .define(checked_div_func_id, |_, fbuilder, entry| {
+            let a = fbuilder.block_params(entry)[0];
+            let b = fbuilder.block_params(entry)[1];
+
+            let quotient = fbuilder.ins().sdiv(a, b);
+
+            fbuilder.ins().return_(&[quotient]);
+        })
+        .unwrap();
+}
+
+// Check whether any instruction in the function may trap.
+fn contains_trap(func: &Function) -> bool {
+    func.layout
+        .blocks()
+        .flat_map(|block| func.layout.block_insts(block))
+        .any(|inst| {
+            matches!(
+                func.dfg.insts[inst].opcode(),
+                Opcode::Trap | Opcode::Trapz | Opcode::Trapnz | Opcode::UaddOverflowTrap
+            )
+        })
+}
@@ -0,0 +1,282 @@
+//! This example shows how closures with different captures can be stored in the same array.
+//!
+//! ```
+//! fn main() -> i32 {
+//!   let a = 1;
+//!   let b = 2;
+//!
+//!   let f0 = |x| a + x + 1;
+//!   let f1 = |x| a + x + b;
+//!   let fs = [f0, f1];
+//!
+//!   let mut sum = 0;
+//!   for f in fs {
+//!     sum += f(3);
+//!   }
+//!   return sum;
+//! }
+//! ```
+//!
+//! The `closures` example represents every closure as a pair of an opaque capture pointer and a
+//! function pointer. `f0` captures one value and `f1` captures two, but since the captures are
+//! behind a pointer, both closures are the same size and have the same type `int -> int`.
+//!
+//! ```
+//! let f0 = { data: &(a)   , func: |data, x| (*data).a + x + 1 };
+//! let f1 = { data: &(a, b), func: |data, x| (*data).a + x + (*data).b };
+//! let fs = [f0, f1];
+//! ```
+//!
+//! Here we put that claim to use. The array is a stack slot holding two `{ data, func }` pairs, and
+//! the loop calls each of them with `call_indirect` without knowing which closure it's calling or
+//! what it captured.
+//!
+//! To keep this example self-contained, the underlying functions read their captures from `data`
+//! themselves instead of going through the forwarding functions used in the `closures` example.
+//!
+//! The main function returns `(1 + 3 + 1) + (1 + 3 + 2)`, so the exit code will be `11`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example closure-array -- -o closure-array.o`
+//! `$ clang closure-array.o -o closure-array`
+//! `$ ./closure-array; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example closure-array -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, MemFlags, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, signature_from_decl,
+    skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"closure-array", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let int = cl::types::I32;
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn f0(data: *(a), x: int) -> int;
+    // fn f1(data: *(a, b), x: int) -> int;
+    //
+    // The captures are type-erased into a pointer, so both functions have the same signature.
+    let [f0_func_id, f1_func_id] = ["f0", "f1"].map(|name| {
+        declare_function_from_types(
+            codegen.module,
+            name,
+            Linkage::Local,
+            &[size_t, int],
+            &[int],
+            None,
+        )
+    });
+
+    // fn main() -> i32 {
+    //   let a = 1;
+    //   let b = 2;
+    //
+    //   let fs = [f0, f1];
+    //
+    //   let mut sum = 0;
+    //   for f in fs {
+    //     sum += f(3);
+    //   }
+    //   return sum;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // A closure is two pointers, `data` followed by `func`
+            let closure_size = size_t.bytes() * 2;
+            let func_offset = size_t.bytes() as i32;
+
+            // let a = 1;
+            // let b = 2;
+            let [a, b] = [1, 2].map(|n| fbuilder.ins().iconst(int, n));
+
+            // Store the captures of each closure in their own stack slot, and get a pointer to it.
+            //
+            // All captures are `int`, so they're laid out one after another without padding.
+            let mut alloc_captures = |captures: &[cl::Value]| {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    int.bytes() * captures.len() as u32,
+                    int.bytes().trailing_zeros() as u8,
+                ));
+
+                for (i, &v) in captures.iter().enumerate() {
+                    let offset = i as i32 * int.bytes() as i32;
+                    fbuilder.ins().stack_store(v, slot, offset);
+                }
+
+                fbuilder.ins().stack_addr(size_t, slot, 0)
+            };
+
+            // let f0 = { data: &(a)   , func: f0 };
+            // let f1 = { data: &(a, b), func: f1 };
+            let f0_data = alloc_captures(&[a]);
+            let f1_data = alloc_captures(&[a, b]);
+
+            // let fs = [f0, f1];
+            //
+            // The array doesn't care what each closure captured, every element is the same size.
+            let (fs, len) = {
+                let closures = [(f0_data, f0_func_id), (f1_data, f1_func_id)];
+
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    closure_size * closures.len() as u32,
+                    size_t.bytes().trailing_zeros() as u8,
+                ));
+
+                for (i, (data, func_id)) in closures.into_iter().enumerate() {
+                    let offset = i as i32 * closure_size as i32;
+
+                    let fref = module.declare_func_in_func(func_id, fbuilder.func);
+                    let func = fbuilder.ins().func_addr(size_t, fref);
+
+                    fbuilder.ins().stack_store(data, slot, offset);
+                    fbuilder.ins().stack_store(func, slot, offset + func_offset);
+                }
+
+                let fs = fbuilder.ins().stack_addr(size_t, slot, 0);
+                (fs, closures.len() as i64)
+            };
+
+            // The signature shared by every closure in the array. Both `f0` and `f1` were declared
+            // with it, so we can take it from either of them.
+            let sig = {
+                let sig = signature_from_decl(module, f0_func_id);
+                fbuilder.import_signature(sig)
+            };
+
+            let header = fbuilder.create_block();
+            let body = fbuilder.create_block();
+            let exit = fbuilder.create_block();
+
+            // header(i: usize, sum: int)
+            fbuilder.append_block_param(header, size_t);
+            fbuilder.append_block_param(header, int);
+
+            // let mut sum = 0;
+            // for f in fs
+            {
+                let i = fbuilder.ins().iconst(size_t, 0);
+                let sum = fbuilder.ins().iconst(int, 0);
+                fbuilder.ins().jump(header, &[i.into(), sum.into()]);
+            }
+
+            // Check whether there are any closures left
+            {
+                fbuilder.switch_to_block(header);
+
+                let i = fbuilder.block_params(header)[0];
+                let in_range = fbuilder.ins().icmp_imm(cl::IntCC::UnsignedLessThan, i, len);
+                fbuilder.ins().brif(in_range, body, &[], exit, &[]);
+
+                fbuilder.seal_block(body);
+                fbuilder.seal_block(exit);
+            }
+
+            // sum += f(3);
+            //
+            // // -- Although the way we represent it in Cranelift looks like -- //
+            //
+            // sum += (fs[i].func)(fs[i].data, 3);
+            {
+                fbuilder.switch_to_block(body);
+
+                let i = fbuilder.block_params(header)[0];
+                let sum = fbuilder.block_params(header)[1];
+
+                // &fs[i]
+                let f = {
+                    let offset = fbuilder.ins().imul_imm(i, closure_size as i64);
+                    fbuilder.ins().iadd(fs, offset)
+                };
+
+                let data = fbuilder.ins().load(size_t, MemFlags::trusted(), f, 0);
+                let func = fbuilder
+                    .ins()
+                    .load(size_t, MemFlags::trusted(), f, func_offset);
+
+                // The same `call_indirect` calls `f0` in the first iteration and `f1` in the
+                // second. Only the signature is known here, which is all that's needed.
+                let result = {
+                    let x = fbuilder.ins().iconst(int, 3);
+                    let call = fbuilder.ins().call_indirect(sig, func, &[data, x]);
+                    fbuilder.inst_results(call)[0]
+                };
+
+                let sum = fbuilder.ins().iadd(sum, result);
+                let i = fbuilder.ins().iadd_imm(i, 1);
+
+                fbuilder.ins().jump(header, &[i.into(), sum.into()]);
+            }
+
+            // The back-edge from the body has been added, so the header has all its predecessors.
+            fbuilder.seal_block(header);
+
+            // return sum;
+            {
+                fbuilder.switch_to_block(exit);
+
+                let sum = fbuilder.block_params(header)[1];
+                fbuilder.ins().return_(&[sum]);
+            }
+        })
+        .unwrap();
+
+    // fn f0(data: *(a), x: int) -> int {
+    //   return (*data).a + x + 1;
+    // }
+    codegen
+        .define(f0_func_id, |_, fbuilder, entry| {
+            let data = fbuilder.block_params(entry)[0];
+            let x = fbuilder.block_params(entry)[1];
+
+            let a = fbuilder.ins().load(int, MemFlags::trusted(), data, 0);
+
+            let sum = fbuilder.ins().iadd(a, x);
+            let sum = fbuilder.ins().iadd_imm(sum, 1);
+
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+
+    // fn f1(data: *(a, b), x: int) -> int {
+    //   return (*data).a + x + (*data).b;
+    // }
+    codegen
+        .define(f1_func_id, |_, fbuilder, entry| {
+            let data = fbuilder.block_params(entry)[0];
+            let x = fbuilder.block_params(entry)[1];
+
+            let a = fbuilder.ins().load(int, MemFlags::trusted(), data, 0);
+            let b = fbuilder
+                .ins()
+                .load(int, MemFlags::trusted(), data, int.bytes() as i32);
+
+            let sum = fbuilder.ins().iadd(a, x);
+            let sum = fbuilder.ins().iadd(sum, b);
+
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+}
@@ -21,44 +21,107 @@
 //! let fs = [f0, f1];
 //! ```
 //!
+//! The captures can either be stored in the stack frame of the function creating the closure, or
+//! on the heap. Stack allocation is cheaper, but the captures are only valid until that function
+//! returns. A closure which escapes, such as by being returned, needs its captures on the heap.
+//!
+//! Captured structs are copied into the captures as a whole, and the forwarding function passes a
+//! pointer to its own copy of the struct to the real function.
+//!
+//! The main function returns `52`, which `tests/closures.rs` checks by running the functions behind
+//! the closures in Cranelift's interpreter.
+//!
 //! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
 //!
 //! `$ cargo run --example closures -- -o closures.o`
 //! `$ clang closures.o -o closures`
 //! `$ ./closures; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example closures -- --jit`
 
 use cranelift::prelude::isa::CallConv;
 use cranelift::prelude::{self as cl, InstBuilder, Type};
-use cranelift::prelude::{FunctionBuilder, MemFlags};
+use cranelift::prelude::{FunctionBuilder, FunctionBuilderContext, MemFlags, codegen::Context};
 use cranelift_examples::{
-    declare_main, function_builder_from_declaration, signature_from_decl, skip_boilerplate,
+    Codegen, InterpretedFunctions, declare_function_from_types, declare_main,
+    define_maybe_interpreted, for_any_module, signature_from_decl, skip_boilerplate,
 };
 use cranelift_module::{FuncId, Linkage, Module};
-use cranelift_object::ObjectModule;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 fn main() {
-    skip_boilerplate(b"closures", |ctx, fctx, module, _args| {
-        let main_func_id = declare_main(module);
-        let f0_funcid = declare_f0_real_function(module);
-        let f1_funcid = declare_f1_real_function(module);
-
-        // fn main() {
-        //   let a = 1;
-        //   let b = 2;
-        //   let x = 3;
-        //
-        //   let f0 = |x| a + x + 1;
-        //   let f1 = |x| a + x + b;
-        //
-        //   let t = f0(x);
-        //   let u = f1(x);
-        //
-        //   return t + u;
-        // }
-        {
-            let (mut fbuilder, _) =
-                function_builder_from_declaration(module, &mut ctx.func, fctx, main_func_id);
+    skip_boilerplate(b"closures", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    // `Codegen` takes care of setting up and defining each function for us.
+    let mut codegen = Codegen::new(ctx, fctx, module);
+    define_closures(&mut codegen);
+}
+
+// Define `main` and the functions behind its closures.
+//
+// If `codegen.interpreted` is set, the forwarding functions are kept for the interpreter as well,
+// which `tests/closures.rs` uses to call the closures through them.
+pub(crate) fn define_closures<M: Module>(codegen: &mut Codegen<'_, M>) -> ClosureFuncs {
+    // Forwarding functions are defined while `codegen` is busy defining the function creating the
+    // closure, so they're kept in the interpreter directly rather than through `codegen`
+    let interpreted = codegen.interpreted;
+
+    let mut closure_funcs = ClosureFuncs::default();
 
+    let main_func_id = declare_main(codegen.module);
+    let f0_funcid = declare_f0_real_function(codegen.module);
+    let f1_funcid = declare_f1_real_function(codegen.module);
+    let adder_funcid = declare_adder_real_function(codegen.module);
+    let make_adder_funcid = declare_make_adder(codegen.module);
+    let f2_funcid = declare_f2_real_function(codegen.module);
+    let f3_funcid = declare_f3_real_function(codegen.module);
+    let f4_funcid = declare_f4_real_function(codegen.module);
+
+    // fn main() {
+    //   let a = 1;
+    //   let b = 2;
+    //   let x = 3;
+    //
+    //   let f0 = |x| a + x + 1;
+    //   let f1 = |x| a + x + b;
+    //
+    //   let t = f0(x);
+    //   let u = f1(x);
+    //
+    //   let add10 = make_adder(10);
+    //   let v = add10(x);
+    //
+    //   let f0_again = |x| b + x + 1;
+    //   let w = f0_again(x);
+    //
+    //   let f2 = |x| x + x;
+    //   let y = f2(x);
+    //
+    //   let small: i8 = 1;
+    //   let big: i64 = 4;
+    //   let f3 = |x| small as int + big as int + x;
+    //   let z = f3(x);
+    //
+    //   let point = Point { x: 5, y: 6 };
+    //   let f4 = |x| point.x + x;
+    //   let p = f4(x);
+    //
+    //   return t + u + v + w + y + z + p;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
             // let a = 1;
             // let b = 2;
             // let x = 3;
@@ -71,8 +134,26 @@ fn main() {
             //
             // let f0 = { data: &(a)   , func: |data, x| (*data).a + x + 1 };
             // let f1 = { data: &(a, b), func: |data, x| (*data).a + x + (*data).b };
-            let f0 = construct_closure(module, &mut fbuilder, f0_funcid, &[a]);
-            let f1 = construct_closure(module, &mut fbuilder, f1_funcid, &[a, b]);
+            //
+            // Since these closures never leave `main`, the captures can live in its stack frame.
+            let f0 = construct_closure(
+                module,
+                fbuilder,
+                interpreted,
+                f0_funcid,
+                &[Capture::Scalar(a)],
+                CaptureStorage::Stack,
+            );
+            let f1 = construct_closure(
+                module,
+                fbuilder,
+                interpreted,
+                f1_funcid,
+                &[Capture::Scalar(a), Capture::Scalar(b)],
+                CaptureStorage::Stack,
+            );
+            closure_funcs.f0 = f0.func_id;
+            closure_funcs.f1 = f1.func_id;
 
             // let t = f0(x);
             // let u = f1(x);
@@ -81,49 +162,236 @@ fn main() {
             //
             // let t = (f0.func)(f0.data, x);
             // let u = (f1.func)(f1.data, x)
-            let t = f0.call(&mut fbuilder, &[x])[0];
-            let u = f1.call(&mut fbuilder, &[x])[0];
+            let t = f0.call(fbuilder, &[x])[0];
+            let u = f1.call(fbuilder, &[x])[0];
+
+            // let add10 = make_adder(10);
+            //
+            // The closure is returned as its two halves
+            let add10 = {
+                let ten = fbuilder.ins().iconst(cl::types::I32, 10);
+
+                let fref = module.declare_func_in_func(make_adder_funcid, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[ten]);
+                let &[data, func] = fbuilder.inst_results(call) else {
+                    unreachable!("make_adder returns a closure");
+                };
+
+                // The caller only knows the signature of the closure, not what it captures
+                let sig = closure_signature(module, &[cl::types::I32], &[cl::types::I32]);
 
-            // return t + u;
+                Closure {
+                    data,
+                    func,
+                    sig,
+                    func_id: None,
+                }
+            };
+
+            // let v = add10(x);
+            //
+            // `make_adder` has returned by now, so had the captures been in its stack frame, they
+            // would've been overwritten by the time we call the closure.
+            let v = add10.call(fbuilder, &[x])[0];
+
+            // let f0_again = |x| b + x + 1;
+            // let w = f0_again(x);
+            //
+            // The same underlying function can be wrapped by more than one closure, such as when
+            // a closure expression is evaluated several times with different values captured.
+            //
+            // Each closure gets its own forwarding function, so their symbols need to be unique.
+            let w = {
+                let f0_again = construct_closure(
+                    module,
+                    fbuilder,
+                    interpreted,
+                    f0_funcid,
+                    &[Capture::Scalar(b)],
+                    CaptureStorage::Stack,
+                );
+                closure_funcs.f0_again = f0_again.func_id;
+                f0_again.call(fbuilder, &[x])[0]
+            };
+
+            // let f2 = |x| x + x;
+            // let y = f2(x);
+            //
+            // A closure without captures has nothing to allocate or forward.
+            let y = {
+                let f2 = construct_closure(
+                    module,
+                    fbuilder,
+                    interpreted,
+                    f2_funcid,
+                    &[],
+                    CaptureStorage::Stack,
+                );
+                closure_funcs.f2 = f2.func_id;
+                f2.call(fbuilder, &[x])[0]
+            };
+
+            // let small: i8 = 1;
+            // let big: i64 = 4;
+            // let f3 = |x| small as int + big as int + x;
+            // let z = f3(x);
+            //
+            // Captures of different sizes need padding between them. Without it, `big` would be
+            // stored right after `small` at the misaligned offset 1.
+            let z = {
+                let small = fbuilder.ins().iconst(cl::types::I8, 1);
+                let big = fbuilder.ins().iconst(cl::types::I64, 4);
+
+                let f3 = construct_closure(
+                    module,
+                    fbuilder,
+                    interpreted,
+                    f3_funcid,
+                    &[Capture::Scalar(small), Capture::Scalar(big)],
+                    CaptureStorage::Stack,
+                );
+                closure_funcs.f3 = f3.func_id;
+                f3.call(fbuilder, &[x])[0]
+            };
+
+            // let point = Point { x: 5, y: 6 };
+            // let f4 = |x| point.x + x;
+            // let p = f4(x);
+            //
+            // Structs are captured by copying them into the captures. Since a struct isn't a
+            // single `Value`, we pass its pointer along with its fields.
+            let p = {
+                let capture = stack_alloc_point(module, fbuilder, [5, 6]);
+
+                let f4 = construct_closure(
+                    module,
+                    fbuilder,
+                    interpreted,
+                    f4_funcid,
+                    &[capture],
+                    CaptureStorage::Stack,
+                );
+                closure_funcs.f4 = f4.func_id;
+                f4.call(fbuilder, &[x])[0]
+            };
+
+            // return t + u + v + w + y + z + p;
             let sum = fbuilder.ins().iadd(t, u);
+            let sum = fbuilder.ins().iadd(sum, v);
+            let sum = fbuilder.ins().iadd(sum, w);
+            let sum = fbuilder.ins().iadd(sum, y);
+            let sum = fbuilder.ins().iadd(sum, z);
+            let sum = fbuilder.ins().iadd(sum, p);
             fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
 
-            fbuilder.finalize();
+    // fn make_adder(a: int) -> (int -> int) {
+    //   return |x| a + x;
+    // }
+    codegen
+        .define(make_adder_funcid, |module, fbuilder, block| {
+            let a = fbuilder.block_params(block)[0];
 
-            println!("fn main:\n{}", &ctx.func);
+            // The closure outlives this function, so the captures are put on the heap.
+            let closure = construct_closure(
+                module,
+                fbuilder,
+                interpreted,
+                adder_funcid,
+                &[Capture::Scalar(a)],
+                CaptureStorage::Heap,
+            );
+            closure_funcs.add10 = closure.func_id;
 
-            module.define_function(main_func_id, ctx).unwrap();
-        }
+            fbuilder.ins().return_(&[closure.data, closure.func]);
+        })
+        .unwrap();
+
+    // fn f2(_data: *void, x: int) -> int {
+    //   return x + x;
+    // }
+    codegen
+        .define(f2_funcid, |_, fbuilder, block| {
+            let x = fbuilder.block_params(block)[1];
+
+            let n = fbuilder.ins().iadd(x, x);
+
+            fbuilder.ins().return_(&[n]);
+        })
+        .unwrap();
+
+    // fn f3(small: i8, big: i64, x: int) -> int {
+    //   return small as int + big as int + x;
+    // }
+    codegen
+        .define(f3_funcid, |_, fbuilder, block| {
+            let small = fbuilder.block_params(block)[0];
+            let big = fbuilder.block_params(block)[1];
+            let x = fbuilder.block_params(block)[2];
+
+            let small = fbuilder.ins().sextend(cl::types::I32, small);
+            let big = fbuilder.ins().ireduce(cl::types::I32, big);
 
-        // fn f0(a: int, x: int) -> int {
-        //   return a + x + 1;
-        // }
-        {
-            let (mut fbuilder, block) =
-                function_builder_from_declaration(module, &mut ctx.func, fctx, f0_funcid);
+            let n = fbuilder.ins().iadd(small, big);
+            let n = fbuilder.ins().iadd(n, x);
 
+            fbuilder.ins().return_(&[n]);
+        })
+        .unwrap();
+
+    // fn f4(point: *Point, x: int) -> int {
+    //   return point.x + x;
+    // }
+    codegen
+        .define(f4_funcid, |_, fbuilder, block| {
+            let point = fbuilder.block_params(block)[0];
+            let x = fbuilder.block_params(block)[1];
+
+            // point.x is at offset 0
+            let point_x = fbuilder
+                .ins()
+                .load(cl::types::I32, MemFlags::new(), point, 0);
+            let n = fbuilder.ins().iadd(point_x, x);
+
+            fbuilder.ins().return_(&[n]);
+        })
+        .unwrap();
+
+    // fn adder(a: int, x: int) -> int {
+    //   return a + x;
+    // }
+    codegen
+        .define(adder_funcid, |_, fbuilder, block| {
             let a = fbuilder.block_params(block)[0];
             let x = fbuilder.block_params(block)[1];
 
             let n = fbuilder.ins().iadd(a, x);
-            let n = fbuilder.ins().iadd_imm(n, 1);
 
             fbuilder.ins().return_(&[n]);
+        })
+        .unwrap();
 
-            fbuilder.finalize();
-
-            println!("fn f0:\n{}", &ctx.func);
+    // fn f0(a: int, x: int) -> int {
+    //   return a + x + 1;
+    // }
+    codegen
+        .define(f0_funcid, |_, fbuilder, block| {
+            let a = fbuilder.block_params(block)[0];
+            let x = fbuilder.block_params(block)[1];
 
-            module.define_function(f0_funcid, ctx).unwrap();
-        }
+            let n = fbuilder.ins().iadd(a, x);
+            let n = fbuilder.ins().iadd_imm(n, 1);
 
-        // fn f1(a: int, b: int, x: int) -> int {
-        //   return a + x + b;
-        // }
-        {
-            let (mut fbuilder, block) =
-                function_builder_from_declaration(module, &mut ctx.func, fctx, f1_funcid);
+            fbuilder.ins().return_(&[n]);
+        })
+        .unwrap();
 
+    // fn f1(a: int, b: int, x: int) -> int {
+    //   return a + x + b;
+    // }
+    codegen
+        .define(f1_funcid, |_, fbuilder, block| {
             let a = fbuilder.block_params(block)[0];
             let b = fbuilder.block_params(block)[1];
             let x = fbuilder.block_params(block)[2];
@@ -132,14 +400,22 @@ fn main() {
             let n = fbuilder.ins().iadd(n, b);
 
             fbuilder.ins().return_(&[n]);
+        })
+        .unwrap();
 
-            fbuilder.finalize();
-
-            println!("fn f1:\n{}", &ctx.func);
+    closure_funcs
+}
 
-            module.define_function(f1_funcid, ctx).unwrap();
-        }
-    });
+// The functions `main`'s closures point to, recorded so that they can be called in the interpreter
+#[derive(Default)]
+pub(crate) struct ClosureFuncs {
+    pub(crate) f0: Option<FuncId>,
+    pub(crate) f1: Option<FuncId>,
+    pub(crate) add10: Option<FuncId>,
+    pub(crate) f0_again: Option<FuncId>,
+    pub(crate) f2: Option<FuncId>,
+    pub(crate) f3: Option<FuncId>,
+    pub(crate) f4: Option<FuncId>,
 }
 
 // Declare the underlying function for the closure `f0`.
@@ -147,17 +423,16 @@ fn main() {
 // All the captures are implicitly added as parameter.
 //
 // fn f0(a: int, x: int) -> int { a + x + 1 }
-fn declare_f0_real_function(module: &mut ObjectModule) -> FuncId {
+fn declare_f0_real_function(module: &mut impl Module) -> FuncId {
     // (a: int, x: int) -> int
-    let sig = cl::Signature {
-        call_conv: CallConv::Fast,
-        params: vec![cl::AbiParam::new(cl::types::I32); 2],
-        returns: vec![cl::AbiParam::new(cl::types::I32)],
-    };
-
-    module
-        .declare_function("f0_real_function", Linkage::Local, &sig)
-        .unwrap()
+    declare_function_from_types(
+        module,
+        "f0_real_function",
+        Linkage::Local,
+        &[cl::types::I32; 2],
+        &[cl::types::I32],
+        Some(CallConv::Fast),
+    )
 }
 
 // Declare the underlying function for the closure `f1`.
@@ -165,23 +440,188 @@ fn declare_f0_real_function(module: &mut ObjectModule) -> FuncId {
 // All the captures are implicitly added as parameter.
 //
 // fn f1(a: int, b: int, x: int) -> int { a + x + b }
-fn declare_f1_real_function(module: &mut ObjectModule) -> FuncId {
+fn declare_f1_real_function(module: &mut impl Module) -> FuncId {
     // (a: int, b: int, x: int) -> int
-    let sig = cl::Signature {
-        call_conv: CallConv::Fast,
-        params: vec![cl::AbiParam::new(cl::types::I32); 3],
-        returns: vec![cl::AbiParam::new(cl::types::I32)],
-    };
+    declare_function_from_types(
+        module,
+        "f1_real_function",
+        Linkage::Local,
+        &[cl::types::I32; 3],
+        &[cl::types::I32],
+        Some(CallConv::Fast),
+    )
+}
 
-    module
-        .declare_function("f1_real_function", Linkage::Local, &sig)
-        .unwrap()
+// Declare the underlying function for the closure `f2`.
+//
+// Since `f2` doesn't capture anything, there's nothing for a forwarding function to do. Instead
+// the function is declared with the signature of a closure directly, taking the opaque captures
+// pointer as its first parameter and ignoring it.
+//
+// fn f2(_data: *void, x: int) -> int { x + x }
+pub(crate) fn declare_f2_real_function(module: &mut impl Module) -> FuncId {
+    let size_t = module.isa().pointer_type();
+
+    // (_data: *void, x: int) -> int
+    declare_function_from_types(
+        module,
+        "f2_real_function",
+        Linkage::Local,
+        &[size_t, cl::types::I32],
+        &[cl::types::I32],
+        Some(CallConv::Fast),
+    )
+}
+
+// Declare the underlying function for the closure `f3`.
+//
+// fn f3(small: i8, big: i64, x: int) -> int { small as int + big as int + x }
+fn declare_f3_real_function(module: &mut impl Module) -> FuncId {
+    // (small: i8, big: i64, x: int) -> int
+    declare_function_from_types(
+        module,
+        "f3_real_function",
+        Linkage::Local,
+        &[cl::types::I8, cl::types::I64, cl::types::I32],
+        &[cl::types::I32],
+        Some(CallConv::Fast),
+    )
 }
 
-struct Closure {
+// Declare the underlying function for the closure `f4`.
+//
+// The captured struct is passed as a pointer, the same way as large structs are passed in the
+// `struct-layouts` example.
+//
+// fn f4(point: *Point, x: int) -> int { point.x + x }
+fn declare_f4_real_function(module: &mut impl Module) -> FuncId {
+    let size_t = module.isa().pointer_type();
+
+    // (point: *Point, x: int) -> int
+    declare_function_from_types(
+        module,
+        "f4_real_function",
+        Linkage::Local,
+        &[size_t, cl::types::I32],
+        &[cl::types::I32],
+        Some(CallConv::Fast),
+    )
+}
+
+// Declare the underlying function for the closure returned by `make_adder`.
+//
+// fn adder(a: int, x: int) -> int { a + x }
+fn declare_adder_real_function(module: &mut impl Module) -> FuncId {
+    // (a: int, x: int) -> int
+    declare_function_from_types(
+        module,
+        "adder_real_function",
+        Linkage::Local,
+        &[cl::types::I32; 2],
+        &[cl::types::I32],
+        Some(CallConv::Fast),
+    )
+}
+
+// fn make_adder(a: int) -> (int -> int);
+//
+// A closure is returned as its data pointer followed by its function pointer.
+fn declare_make_adder(module: &mut impl Module) -> FuncId {
+    let size_t = module.isa().pointer_type();
+
+    // (a: int) -> (data: *void, func: *void)
+    declare_function_from_types(
+        module,
+        "make_adder",
+        Linkage::Local,
+        &[cl::types::I32],
+        &[size_t; 2],
+        None,
+    )
+}
+
+// Where the captures of a closure are stored
+#[derive(Clone, Copy)]
+pub(crate) enum CaptureStorage {
+    // In the stack frame of the function creating the closure.
+    //
+    // This is cheap, but the closure must not be called after that function has returned.
+    Stack,
+    // In a heap allocation from `malloc`.
+    //
+    // The closure can be returned or stored and called at any point later. We never free the
+    // allocation in this example. In a real compiler, you'd free it once the closure is dropped
+    // or leave it to a garbage collector.
+    Heap,
+}
+
+// A value captured by a closure
+#[derive(Clone)]
+pub(crate) enum Capture {
+    Scalar(cl::Value),
+    // A struct stored in memory at `ptr`, which is copied into the captures as a whole
+    Struct {
+        ptr: cl::Value,
+        fields: Vec<CaptureType>,
+    },
+}
+
+impl Capture {
+    // The type of a scalar can be looked up from its `Value`, but for structs we only have a
+    // pointer, so the fields need to be provided by whoever constructs the closure.
+    fn type_(&self, fbuilder: &FunctionBuilder<'_>) -> CaptureType {
+        match self {
+            Capture::Scalar(v) => CaptureType::Scalar(type_of_value(fbuilder, *v)),
+            Capture::Struct { fields, .. } => CaptureType::Struct(fields.clone()),
+        }
+    }
+}
+
+// The layout of a captured value, or of a field within a captured struct
+#[derive(Clone, Debug)]
+pub(crate) enum CaptureType {
+    Scalar(Type),
+    Struct(Vec<CaptureType>),
+}
+
+impl CaptureType {
+    fn size(&self) -> u32 {
+        match self {
+            CaptureType::Scalar(ty) => ty.bytes(),
+            CaptureType::Struct(fields) => size_of_struct(fields),
+        }
+    }
+
+    fn alignment(&self) -> u32 {
+        match self {
+            CaptureType::Scalar(ty) => ty.bytes(),
+            CaptureType::Struct(fields) => alignment_of_struct(fields),
+        }
+    }
+}
+
+// Used for the symbols of forwarding functions, such as `i32` or `struct_i32_i32_end`
+impl fmt::Display for CaptureType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureType::Scalar(ty) => write!(f, "{ty}"),
+            CaptureType::Struct(fields) => {
+                write!(f, "struct")?;
+                for field in fields {
+                    write!(f, "_{field}")?;
+                }
+                write!(f, "_end")
+            }
+        }
+    }
+}
+
+pub(crate) struct Closure {
     data: cl::Value,
     func: cl::Value,
     sig: cl::Signature,
+    // The function `func` points to, unless the closure was handed to us such as by a call
+    func_id: Option<FuncId>,
 }
 
 impl Closure {
@@ -207,31 +647,67 @@ impl Closure {
 //
 // First, we'll box all the captures, and then create an intermediate function which
 // dereferences the captures, and forwards them to the 'real' function pointer.
-fn construct_closure(
-    module: &mut ObjectModule,
+pub(crate) fn construct_closure(
+    module: &mut impl Module,
     fbuilder: &mut FunctionBuilder<'_>,
+    interpreted: Option<&InterpretedFunctions>,
     closure_fn: FuncId,
-    captures: &[cl::Value],
+    captures: &[Capture],
+    storage: CaptureStorage,
 ) -> Closure {
-    let boxed_captures = stack_alloc_captures(module, fbuilder, captures);
+    // Non-capturing closures don't need to allocate anything, and their underlying function
+    // already has the signature of a closure. This is how real compilers avoid allocating for
+    // lambdas which don't capture anything.
+    //
+    // The data pointer is null, and should never be dereferenced.
+    if captures.is_empty() {
+        let size_t = module.isa().pointer_type();
+
+        let sig = signature_from_decl(module, closure_fn);
+        assert_eq!(
+            sig.params.first().map(|p| p.value_type),
+            Some(size_t),
+            "function of non-capturing closure must take the captures pointer"
+        );
 
-    let (forwarding_func_ref, sig) = {
+        let data = fbuilder.ins().iconst(size_t, 0);
+        let func = {
+            let fref = module.declare_func_in_func(closure_fn, fbuilder.func);
+            fbuilder.ins().func_addr(size_t, fref)
+        };
+
+        return Closure {
+            data,
+            func,
+            sig,
+            func_id: Some(closure_fn),
+        };
+    }
+
+    let boxed_captures = match storage {
+        CaptureStorage::Stack => stack_alloc_captures(module, fbuilder, captures),
+        CaptureStorage::Heap => heap_alloc_captures(module, fbuilder, captures),
+    };
+
+    let (forwarding_func_id, forwarding_func_ref, sig) = {
         let capture_types = captures
             .iter()
-            .map(|&v| fbuilder.func.stencil.dfg.value_type(v))
+            .map(|c| c.type_(fbuilder))
             .collect::<Vec<_>>();
 
-        let (func_id, sig) = create_forwarding_func(module, closure_fn, &capture_types);
+        let (func_id, sig) =
+            create_forwarding_func(module, interpreted, closure_fn, &capture_types);
 
-        let fref = module.declare_func_in_func(func_id, &mut fbuilder.func);
+        let fref = module.declare_func_in_func(func_id, fbuilder.func);
         let size_t = module.isa().pointer_type();
-        (fbuilder.ins().func_addr(size_t, fref), sig)
+        (func_id, fbuilder.ins().func_addr(size_t, fref), sig)
     };
 
     Closure {
         data: boxed_captures,
         func: forwarding_func_ref,
         sig,
+        func_id: Some(forwarding_func_id),
     }
 }
 
@@ -247,7 +723,7 @@ fn construct_closure(
 // So for the `f1` we'd define.
 //
 // ```
-// fn closure_forward_f1_real_function(captures: *void, x: int) -> int {
+// fn closure_forward_1_funcid2_i32_i32(captures: *void, x: int) -> int {
 //   let a = *(captures + 0);
 //   let b = *(captures + 4);
 //   return f1_real_function(a, b, x);
@@ -256,7 +732,7 @@ fn construct_closure(
 //
 // And then the actual values will be passed around in memory.
 // ```
-// let closure = { data: alloc([1, 2]), func: closure_forward_f1_real_function };
+// let closure = { data: alloc([1, 2]), func: closure_forward_1_funcid2_i32_i32 };
 // ```
 //
 // So that it may be called as
@@ -265,30 +741,46 @@ fn construct_closure(
 // closure.func(closure.data, 3)
 // ```
 fn create_forwarding_func(
-    module: &mut ObjectModule,
+    module: &mut impl Module,
+    interpreted: Option<&InterpretedFunctions>,
     f: FuncId,
-    captys: &[Type],
+    captys: &[CaptureType],
 ) -> (FuncId, cl::Signature) {
-    // In a real compiler, this symbol needs to be generated in a way that's guaranteed to be
-    // unique. You could for example use source code spans, capture type information, or a global counter.
-    let symbol = format!("closure_forward_{f}");
+    // Defining two functions with the same symbol is an error, so the symbol needs to be unique
+    // even if the same function is wrapped by multiple closures.
+    //
+    // The counter alone guarantees uniqueness, while the function and the capture types make
+    // the symbol easier to recognize when reading the disassembly.
+    //
+    // closure_forward_2_funcid1_i32
+    let symbol = {
+        static FORWARDING_FUNC_COUNTER: AtomicU32 = AtomicU32::new(0);
+        let n = FORWARDING_FUNC_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+        let mut symbol = format!("closure_forward_{n}_{f}");
+        for capty in captys {
+            symbol += &format!("_{capty}");
+        }
+        symbol
+    };
 
-    // Define the signature of the forwarding function to be that of the closure signature but
-    // with the opaque captures pointer added as the first parameter.
+    // The signature of the forwarding function is the signature of the real function, but with
+    // the parameters for the captures replaced by the opaque captures pointer.
     let sig = {
-        let mut sig = cl::Signature::new(CallConv::Fast);
+        let real_func_sig = signature_from_decl(module, f);
 
-        // The implicit parameters from the capture will be replaced by an opaque pointer instead.
-        let voidptr = cl::AbiParam::new(module.isa().pointer_type());
-        sig.params.insert(0, voidptr);
+        let params = real_func_sig.params[captys.len()..]
+            .iter()
+            .map(|p| p.value_type)
+            .collect::<Vec<_>>();
 
-        let real_func_sig = signature_from_decl(module, f);
-        for &p in real_func_sig.params.iter().skip(captys.len()) {
-            sig.params.push(p);
-        }
-        sig.returns = real_func_sig.returns.clone();
+        let returns = real_func_sig
+            .returns
+            .iter()
+            .map(|p| p.value_type)
+            .collect::<Vec<_>>();
 
-        sig
+        closure_signature(module, &params, &returns)
     };
 
     // Declare the closure forwarding function
@@ -312,12 +804,36 @@ fn create_forwarding_func(
             Vec::with_capacity(captys.len() + closure.func.signature.params.len() - 1);
 
         // Dereference the captures and add them as implicit parameters
-        let mut offset = 0;
-        for &ty in captys {
+        //
+        // The offsets need to match the ones used when the captures were written.
+        for (i, capty) in captys.iter().enumerate() {
             let ptr = closure.block_params(block)[0];
-            let v = closure.ins().load(ty, MemFlags::new(), ptr, offset);
-            real_call_params.push(v);
-            offset += ty.bytes() as i32;
+            let offset = offset_of_field(i, captys);
+
+            match capty {
+                CaptureType::Scalar(ty) => {
+                    let v = closure.ins().load(*ty, MemFlags::new(), ptr, offset);
+                    real_call_params.push(v);
+                }
+                // The real function takes the struct as a pointer.
+                //
+                // We copy the struct out of the captures first, so that the real function is free
+                // to modify its copy without affecting later calls to the closure.
+                CaptureType::Struct(fields) => {
+                    let slot = closure.create_sized_stack_slot(cl::StackSlotData::new(
+                        cl::StackSlotKind::ExplicitSlot,
+                        capty.size(),
+                        capty.alignment().trailing_zeros() as u8,
+                    ));
+
+                    let size_t = module.isa().pointer_type();
+                    let src = closure.ins().iadd_imm(ptr, offset as i64);
+                    let dst = closure.ins().stack_addr(size_t, slot, 0);
+                    copy_struct(&mut closure, fields, src, dst);
+
+                    real_call_params.push(dst);
+                }
+            }
         }
 
         // Add all other parameters from the forwarding function
@@ -325,48 +841,224 @@ fn create_forwarding_func(
             real_call_params.push(v);
         }
 
-        let f_ref = module.declare_func_in_func(f, &mut closure.func);
+        let f_ref = module.declare_func_in_func(f, closure.func);
         let call = closure.ins().call(f_ref, &real_call_params);
         let returned = closure.inst_results(call).to_vec();
         closure.ins().return_(&returned);
 
-        module.define_function(func_id, &mut ctx).unwrap();
+        define_maybe_interpreted(module, func_id, &mut ctx, interpreted).unwrap();
     };
 
     (func_id, sig)
 }
 
-fn stack_alloc_captures(
-    module: &ObjectModule,
+// The signature of calling a closure with the given user-facing parameters and returns.
+//
+// The opaque captures pointer is added as the first parameter.
+fn closure_signature(module: &impl Module, params: &[Type], returns: &[Type]) -> cl::Signature {
+    let mut sig = cl::Signature::new(CallConv::Fast);
+
+    let voidptr = cl::AbiParam::new(module.isa().pointer_type());
+    sig.params.push(voidptr);
+
+    sig.params
+        .extend(params.iter().map(|&ty| cl::AbiParam::new(ty)));
+    sig.returns
+        .extend(returns.iter().map(|&ty| cl::AbiParam::new(ty)));
+
+    sig
+}
+
+// let point = Point { x, y };
+//
+// The point is put in a stack slot, and captured by copying it from there.
+pub(crate) fn stack_alloc_point(
+    module: &mut impl Module,
+    fbuilder: &mut FunctionBuilder<'_>,
+    [x, y]: [i64; 2],
+) -> Capture {
+    let point_fields = vec![CaptureType::Scalar(cl::types::I32); 2];
+
+    let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+        cl::StackSlotKind::ExplicitSlot,
+        size_of_struct(&point_fields),
+        alignment_of_struct(&point_fields).trailing_zeros() as u8,
+    ));
+
+    for (i, n) in [x, y].into_iter().enumerate() {
+        let n = fbuilder.ins().iconst(cl::types::I32, n);
+        let offset = offset_of_field(i, &point_fields);
+        fbuilder.ins().stack_store(n, slot, offset);
+    }
+
+    let size_t = module.isa().pointer_type();
+    let ptr = fbuilder.ins().stack_addr(size_t, slot, 0);
+
+    Capture::Struct {
+        ptr,
+        fields: point_fields,
+    }
+}
+
+pub(crate) fn stack_alloc_captures(
+    module: &impl Module,
     fbuilder: &mut FunctionBuilder<'_>,
-    captures: &[cl::Value],
+    captures: &[Capture],
 ) -> cl::Value {
     let size_t = module.isa().pointer_type();
 
-    // Unlike the `struct-layouts` example, we will not care about alignment or padding here.
-    //
-    // So the size of the stack allocation will just be the sum of the fields we're allocating.
-    let size = captures
+    // The captures are laid out the same way as the fields of a struct, see the `struct-layouts`
+    // example. Reading a misaligned value is slow on most targets, and may even crash on some.
+    let captys = captures
         .iter()
-        .map(|&v| type_of_value(fbuilder, v).bytes())
-        .sum();
+        .map(|c| c.type_(fbuilder))
+        .collect::<Vec<_>>();
 
     // Create the stack slot for the captures
+    //
+    // Cranelift wants the alignment as a power of two exponent.
     let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
         cl::StackSlotKind::ExplicitSlot,
-        size,
-        0,
+        size_of_struct(&captys),
+        alignment_of_struct(&captys).trailing_zeros() as u8,
     ));
 
     // Write our captures to the stack allocation
+    let ptr = fbuilder.ins().stack_addr(size_t, slot, 0);
+    write_captures(fbuilder, captures, &captys, ptr);
+
+    ptr
+}
+
+// Same as `stack_alloc_captures`, but the captures are written to memory allocated with `malloc`.
+fn heap_alloc_captures(
+    module: &mut impl Module,
+    fbuilder: &mut FunctionBuilder<'_>,
+    captures: &[Capture],
+) -> cl::Value {
+    let size_t = module.isa().pointer_type();
+
+    let captys = captures
+        .iter()
+        .map(|c| c.type_(fbuilder))
+        .collect::<Vec<_>>();
+
+    // `malloc` returns memory aligned for any primitive type, so only the size is needed
+    let size = size_of_struct(&captys);
+
+    // extern "C" fn malloc(size: usize) -> *void;
+    //
+    // Declaring the same import multiple times gives us back the same `FuncId`.
+    let ptr = {
+        let malloc = declare_function_from_types(
+            module,
+            "malloc",
+            Linkage::Import,
+            &[size_t],
+            &[size_t],
+            None,
+        );
+
+        let fref = module.declare_func_in_func(malloc, fbuilder.func);
+        let size = fbuilder.ins().iconst(size_t, size as i64);
+        let call = fbuilder.ins().call(fref, &[size]);
+        fbuilder.inst_results(call)[0]
+    };
+
+    // Write our captures to the heap allocation
+    write_captures(fbuilder, captures, &captys, ptr);
+
+    ptr
+}
+
+// Write the captures to `ptr`, with scalars stored directly and structs copied as a whole.
+fn write_captures(
+    fbuilder: &mut FunctionBuilder<'_>,
+    captures: &[Capture],
+    captys: &[CaptureType],
+    ptr: cl::Value,
+) {
+    for (i, capture) in captures.iter().enumerate() {
+        let offset = offset_of_field(i, captys);
+
+        match capture {
+            Capture::Scalar(v) => {
+                fbuilder.ins().store(MemFlags::new(), *v, ptr, offset);
+            }
+            Capture::Struct { ptr: src, fields } => {
+                let dst = fbuilder.ins().iadd_imm(ptr, offset as i64);
+                copy_struct(fbuilder, fields, *src, dst);
+            }
+        }
+    }
+}
+
+// Copy all the fields of a struct from `src` to `dst`
+fn copy_struct(
+    fbuilder: &mut FunctionBuilder<'_>,
+    fields: &[CaptureType],
+    src: cl::Value,
+    dst: cl::Value,
+) {
+    for (i, field) in fields.iter().enumerate() {
+        let offset = offset_of_field(i, fields);
+
+        match field {
+            CaptureType::Scalar(ty) => {
+                let v = fbuilder.ins().load(*ty, MemFlags::new(), src, offset);
+                fbuilder.ins().store(MemFlags::new(), v, dst, offset);
+            }
+            CaptureType::Struct(inner) => {
+                let src = fbuilder.ins().iadd_imm(src, offset as i64);
+                let dst = fbuilder.ins().iadd_imm(dst, offset as i64);
+                copy_struct(fbuilder, inner, src, dst);
+            }
+        }
+    }
+}
+
+fn size_of_struct(fields: &[CaptureType]) -> u32 {
+    let mut size = 0;
+
+    for field in fields {
+        // Add padding to ensure the field is aligned
+        let align = field.alignment();
+        size += (align - size % align) % align;
+
+        size += field.size();
+    }
+
+    // Add padding to the end of the struct to make the struct itself aligned
+    let self_align = alignment_of_struct(fields);
+    size += (self_align - size % self_align) % self_align;
+
+    size
+}
+
+fn alignment_of_struct(fields: &[CaptureType]) -> u32 {
+    fields
+        .iter()
+        .map(|field| field.alignment())
+        .max()
+        .unwrap_or(1)
+}
+
+pub(crate) fn offset_of_field(field: usize, fields: &[CaptureType]) -> i32 {
     let mut offset = 0;
-    for &v in captures {
-        fbuilder.ins().stack_store(v, slot, offset);
-        offset += type_of_value(fbuilder, v).bytes() as i32;
+
+    for (i, ty) in fields.iter().enumerate() {
+        // Add padding to ensure the field is aligned
+        let align = ty.alignment();
+        offset += (align - offset % align) % align;
+
+        if i == field {
+            return offset as i32;
+        }
+
+        offset += ty.size();
     }
 
-    // Return the pointer
-    fbuilder.ins().stack_addr(size_t, slot, 0)
+    panic!("field not found");
 }
 
 fn type_of_value(fbuilder: &FunctionBuilder<'_>, v: cl::Value) -> Type {
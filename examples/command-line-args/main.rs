@@ -0,0 +1,125 @@
+//! This example shows how to receive command-line arguments in `main`, by declaring it with the
+//! same signature as `int main(int argc, char **argv)` in C.
+//!
+//! ```
+//! extern "C" fn puts(s: *const u8) -> i32;
+//!
+//! fn main(argc: i32, argv: *const *const u8) -> i32 {
+//!   let i = 0;
+//!   while i < argc {
+//!     puts(argv[i]);
+//!     i += 1;
+//!   }
+//!   return argc;
+//! }
+//! ```
+//!
+//! The C runtime which calls `main` passes the arguments regardless of how `main` was declared, so
+//! `declare_main` leaving them out is fine. To read them, `declare_main_with_args` declares the two
+//! parameters instead:
+//!
+//! * `argc` is the number of arguments, as a C `int`.
+//! * `argv` is a pointer to `argc` string pointers, followed by a null pointer. Since Cranelift
+//!   doesn't have pointer types, it's a `size_t` integer like every other pointer.
+//!
+//! `load_argv` loads the pointer to a single argument, which can then be given to C functions
+//! expecting a nul-terminated string such as `puts`.
+//!
+//! The first argument is the name the program was started with, so there's always at least one.
+//!
+//! The main function prints each argument on its own line and returns `argc`, so the exit code will
+//! be `1` plus the number of arguments given.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example command-line-args -- -o command-line-args.o`
+//! `$ clang command-line-args.o -o command-line-args`
+//! `$ ./command-line-args foo bar; echo $?`
+//!
+//! There's no `--jit` mode for this example, since the JIT calls `main` without any arguments.
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{InstBuilder, IntCC};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main_with_args, load_argv,
+    skip_boilerplate_object,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate_object(b"command-line-args", |ctx, fctx, module, _args| {
+        let size_t = module.isa().pointer_type();
+
+        let mut codegen = Codegen::new(ctx, fctx, module);
+
+        // fn main(argc: i32, argv: *const *const u8) -> i32;
+        let main_func_id = declare_main_with_args(codegen.module);
+
+        // extern "C" fn puts(s: *const u8) -> i32;
+        let puts_func_id = declare_function_from_types(
+            codegen.module,
+            "puts",
+            Linkage::Import,
+            &[size_t],
+            &[cl::types::I32],
+            None,
+        );
+
+        // fn main(argc: i32, argv: *const *const u8) -> i32 {
+        //   let i = 0;
+        //   while i < argc {
+        //     puts(argv[i]);
+        //     i += 1;
+        //   }
+        //   return argc;
+        // }
+        codegen
+            .define(main_func_id, |module, fbuilder, entry| {
+                let argc = fbuilder.block_params(entry)[0];
+                let argv = fbuilder.block_params(entry)[1];
+
+                // Takes `i` as a block parameter, since it changes each iteration
+                let header = fbuilder.create_block();
+                fbuilder.append_block_param(header, cl::types::I32);
+
+                let body = fbuilder.create_block();
+                let exit = fbuilder.create_block();
+
+                // let i = 0;
+                let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+                fbuilder.ins().jump(header, &[zero.into()]);
+
+                // while i < argc {
+                fbuilder.switch_to_block(header);
+                let i = fbuilder.block_params(header)[0];
+                let in_bounds = fbuilder.ins().icmp(IntCC::SignedLessThan, i, argc);
+                fbuilder.ins().brif(in_bounds, body, &[], exit, &[]);
+
+                // Only reached from `header`
+                fbuilder.seal_block(body);
+                fbuilder.seal_block(exit);
+
+                fbuilder.switch_to_block(body);
+
+                // puts(argv[i]);
+                {
+                    let arg = load_argv(module, fbuilder, argv, i);
+                    let fref = module.declare_func_in_func(puts_func_id, fbuilder.func);
+                    fbuilder.ins().call(fref, &[arg]);
+                }
+
+                // i += 1;
+                let next = fbuilder.ins().iadd_imm(i, 1);
+                fbuilder.ins().jump(header, &[next.into()]);
+
+                // Both the entry block and the end of the loop jump to the header, and now that
+                // both jumps have been added it can be sealed
+                fbuilder.seal_block(header);
+
+                // return argc;
+                fbuilder.switch_to_block(exit);
+                fbuilder.ins().return_(&[argc]);
+            })
+            .unwrap();
+    });
+}
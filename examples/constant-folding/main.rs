@@ -0,0 +1,101 @@
+//! This example shows how Cranelift's optimizer folds constant expressions, and how `--opt-level`
+//! decides whether it runs.
+//!
+//! ```
+//! fn main() -> i32 {
+//!   let x = 2 + 3;
+//!   return x * 4;
+//! }
+//! ```
+//!
+//! `FunctionBuilder` doesn't try to be clever. It emits exactly the instructions we ask for, so
+//! the IR we build contains both constants and the `iadd`, even though the result is known before
+//! the program ever runs:
+//!
+//! ```
+//! block0:
+//!     v0 = iconst.i32 2
+//!     v1 = iconst.i32 3
+//!     v2 = iadd v0, v1
+//!     v3 = imul_imm v2, 4
+//!     return v3
+//! ```
+//!
+//! Optimizations happen later, when the function is compiled. `Context::optimize` runs every pass
+//! up to but not including lowering to machine code, and leaves the optimized IR in `ctx.func`.
+//! With `opt_level` set to `speed` or `speed_and_size`, this includes the e-graph pass which
+//! rewrites expressions using a set of rules. One of those rules evaluates arithmetic on constants,
+//! turning the whole function into:
+//!
+//! ```
+//! block0:
+//!     v12 = iconst.i32 20
+//!     return v12
+//! ```
+//!
+//! With `opt_level` set to `none`, which is the default for these examples, the e-graph pass is
+//! skipped and the IR is compiled as it was built. That keeps the generated code easy to match up
+//! with the IR, and makes compilation faster.
+//!
+//! `Codegen::define` prints the IR of every function before optimizing it. When `--opt-level` isn't
+//! `none` it also calls `Context::optimize` itself and prints the result, which works for every
+//! example, not just this one.
+//!
+//! The main function returns `(2 + 3) * 4`, so the exit code will be `20`.
+//!
+//! To see the IR before and after optimization
+//!
+//! `$ cargo run --example constant-folding -- -o constant-folding.o -O speed`
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ clang constant-folding.o -o constant-folding`
+//! `$ ./constant-folding; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example constant-folding -- --jit -O speed`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{Codegen, declare_main, for_any_module, skip_boilerplate};
+use cranelift_module::Module;
+
+fn main() {
+    skip_boilerplate(b"constant-folding", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn main() -> i32 {
+    //   let x = 2 + 3;
+    //   return x * 4;
+    // }
+    codegen
+        .define(main_func_id, |_, fbuilder, _| {
+            // let x = 2 + 3;
+            //
+            // Nothing is folded here, the builder emits the `iadd` as-is
+            let x = {
+                let two = fbuilder.ins().iconst(cl::types::I32, 2);
+                let three = fbuilder.ins().iconst(cl::types::I32, 3);
+                fbuilder.ins().iadd(two, three)
+            };
+
+            // return x * 4;
+            let result = fbuilder.ins().imul_imm(x, 4);
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
@@ -0,0 +1,152 @@
+//! This example shows how to convert between integers and floats, and between types of different sizes.
+//!
+//! ```
+//! fn main() -> i32 {
+//!   let x: f64 = 3.9;
+//!
+//!   // float -> int
+//!   let saturated = x as i32;
+//!   let trapping = x.to_int_or_trap();
+//!   let huge = 1e10 as i32;
+//!   let nan = f64::NAN as i32;
+//!
+//!   // float -> float
+//!   let roundtrip = (x as f32) as f64;
+//!
+//!   // int -> float
+//!   let y = 7 as f64;
+//!
+//!   // int -> int
+//!   let byte = 300 as i8;
+//!   let signed = -1_i8 as i32;
+//!   let unsigned = -1_i8 as u8 as i32;
+//!
+//!   return saturated + trapping + roundtrip as i32;
+//! }
+//! ```
+//!
+//! Converting a float to an integer has two edge cases: the float might be `NaN`, or it might be
+//! too large to fit in the integer. Cranelift gives us two instructions to handle these.
+//!
+//! * `fcvt_to_sint` traps with `TrapCode::BAD_CONVERSION_TO_INTEGER` for `NaN` and
+//!   `TrapCode::INTEGER_OVERFLOW` for values out of range.
+//! * `fcvt_to_sint_sat` clamps out of range values to the nearest representable integer, and turns
+//!   `NaN` into `0`. This is what Rust's `as` does.
+//!
+//! Both round towards zero, so `3.9` becomes `3`.
+//!
+//! The main function returns `3 + 3 + 3`, so the exit code will be `9`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example conversions -- -o conversions.o`
+//! `$ clang conversions.o -o conversions`
+//! `$ ./conversions; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example conversions -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{Codegen, declare_main, for_any_module, skip_boilerplate};
+use cranelift_module::Module;
+
+fn main() {
+    skip_boilerplate(b"conversions", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    codegen
+        .define(main_func_id, |_, fbuilder, _| {
+            // let x: f64 = 3.9;
+            let x = fbuilder.ins().f64const(3.9);
+
+            // let saturated = x as i32;
+            //
+            // The `_sat` variant never traps, which makes it the safe default.
+            let saturated = fbuilder.ins().fcvt_to_sint_sat(cl::types::I32, x);
+
+            // let trapping = x.to_int_or_trap();
+            //
+            // `3.9` is in range so this doesn't trap. For `NaN` or `1e10`, the program would crash
+            // instead. Languages which consider such conversions to be bugs can use this variant to
+            // catch them at runtime without emitting the checks themselves.
+            let trapping = fbuilder.ins().fcvt_to_sint(cl::types::I32, x);
+
+            // let huge = 1e10 as i32;
+            //
+            // Clamped to `i32::MAX`
+            let _huge = {
+                let huge = fbuilder.ins().f64const(1e10);
+                fbuilder.ins().fcvt_to_sint_sat(cl::types::I32, huge)
+            };
+
+            // let nan = f64::NAN as i32;
+            //
+            // Becomes `0`
+            let _nan = {
+                let nan = fbuilder.ins().f64const(f64::NAN);
+                fbuilder.ins().fcvt_to_sint_sat(cl::types::I32, nan)
+            };
+
+            // let roundtrip = (x as f32) as f64;
+            //
+            // `fdemote` rounds to the nearest `f32`, losing precision. `fpromote` is always exact,
+            // so the value we get back is `3.9000000953674316` rather than `3.9`.
+            let roundtrip = {
+                let demoted = fbuilder.ins().fdemote(cl::types::F32, x);
+                fbuilder.ins().fpromote(cl::types::F64, demoted)
+            };
+
+            // let y = 7 as f64;
+            //
+            // There's also `fcvt_from_uint` for unsigned integers.
+            let _y = {
+                let seven = fbuilder.ins().iconst(cl::types::I32, 7);
+                fbuilder.ins().fcvt_from_sint(cl::types::F64, seven)
+            };
+
+            // let byte = 300 as i8;
+            //
+            // `ireduce` keeps the low bits, so `300` becomes `44`.
+            let _byte = {
+                let n = fbuilder.ins().iconst(cl::types::I32, 300);
+                fbuilder.ins().ireduce(cl::types::I8, n)
+            };
+
+            // let signed = -1_i8 as i32;
+            // let unsigned = -1_i8 as u8 as i32;
+            //
+            // Cranelift integers don't have a signedness, it's decided by the instruction instead.
+            // `sextend` copies the sign bit into the new bits giving `-1`, while `uextend` fills
+            // them with zeros giving `255`.
+            let (_signed, _unsigned) = {
+                let minus_one = fbuilder.ins().iconst(cl::types::I8, -1);
+                let signed = fbuilder.ins().sextend(cl::types::I32, minus_one);
+                let unsigned = fbuilder.ins().uextend(cl::types::I32, minus_one);
+                (signed, unsigned)
+            };
+
+            // return saturated + trapping + roundtrip as i32;
+            let result = {
+                let roundtrip = fbuilder.ins().fcvt_to_sint_sat(cl::types::I32, roundtrip);
+                let sum = fbuilder.ins().iadd(saturated, trapping);
+                fbuilder.ins().iadd(sum, roundtrip)
+            };
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
@@ -0,0 +1,240 @@
+//! This example shows how to store a function pointer in a struct field, and call it after loading
+//! it back out again.
+//!
+//! ```
+//! struct Handler {
+//!   callback: fn(i32) -> i32,
+//!   state: i32,
+//! }
+//!
+//! fn add_five(x: i32) -> i32 {
+//!   return x + 5;
+//! }
+//!
+//! fn run(h: &Handler) -> i32 {
+//!   return (h.callback)(h.state);
+//! }
+//!
+//! fn main() -> i32 {
+//!   let h = Handler { callback: add_five, state: 37 };
+//!   return run(&h);
+//! }
+//! ```
+//!
+//! This is how C-style callbacks are usually passed around, such as the comparison function given
+//! to `qsort` or a table of handlers in a struct.
+//!
+//! A function pointer is a plain `size_t` integer to Cranelift, so it's stored and loaded like any
+//! other pointer sized field. `func_addr` gives us the address of `add_five` to store.
+//!
+//! Since `run` doesn't know which function it's calling, it can't use `call` with a `FuncRef`.
+//! Instead it imports the signature of the callback with `import_signature`, and calls the loaded
+//! pointer with `call_indirect`. The signature describes how the arguments are passed, and comes
+//! from the type of the field rather than from the function which happens to be stored in it.
+//!
+//! Nothing checks at runtime that the pointer actually points to a function with that signature.
+//! Calling a function through the wrong signature is undefined behaviour, the same as casting
+//! between incompatible function pointer types in C. So it's up to our type checker to only allow
+//! storing functions whose signature matches the field, which `check_callback_signature` does
+//! before `add_five` is stored.
+//!
+//! The closures example uses the same `call_indirect`, but on a function pointer held in a local
+//! variable together with its captures.
+//!
+//! The main function returns `37 + 5`, so the exit code will be `42`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example fn-ptr-field -- -o fn-ptr-field.o`
+//! `$ clang fn-ptr-field.o -o fn-ptr-field`
+//! `$ ./fn-ptr-field; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example fn-ptr-field -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::codegen::Context;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, MemFlags};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, signature_from_decl,
+    skip_boilerplate,
+};
+use cranelift_module::{FuncId, Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"fn-ptr-field", for_any_module!(define_functions));
+}
+
+// The layout of `Handler`
+//
+// Handler {
+//   callback,  // size_t at offset 0
+//   state,     // i32    at offset size_t
+//   _pad0,     // i32 on 64-bit targets
+// }
+struct HandlerLayout {
+    callback_offset: i32,
+    state_offset: i32,
+    size: u32,
+    align: u32,
+}
+
+impl HandlerLayout {
+    fn new(size_t: cl::Type) -> Self {
+        let ptr_bytes = size_t.bytes();
+        HandlerLayout {
+            callback_offset: 0,
+            state_offset: ptr_bytes as i32,
+            // Rounded up to the alignment of the pointer
+            size: ptr_bytes * 2,
+            align: ptr_bytes,
+        }
+    }
+}
+
+// The signature of the `callback` field, `fn(i32) -> i32`
+fn callback_signature(module: &impl Module) -> cl::Signature {
+    let mut sig = module.make_signature();
+    sig.params.push(cl::AbiParam::new(cl::types::I32));
+    sig.returns.push(cl::AbiParam::new(cl::types::I32));
+    sig
+}
+
+// Our type checker's job, which has to happen before the function pointer is stored. Once it's
+// been stored, the pointer is only an integer and there's nothing left to check it against.
+fn check_callback_signature(module: &impl Module, func: FuncId, expected: &cl::Signature) {
+    let actual = signature_from_decl(module, func);
+    let name = &module.declarations().get_function_decl(func).name;
+
+    assert_eq!(
+        (&actual.params, &actual.returns, actual.call_conv),
+        (&expected.params, &expected.returns, expected.call_conv),
+        "{name:?} doesn't match the signature of Handler::callback",
+    );
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+    let layout = HandlerLayout::new(size_t);
+    let callback_sig = callback_signature(module);
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn add_five(x: i32) -> i32;
+    let add_five_func_id = declare_function_from_types(
+        codegen.module,
+        "add_five",
+        Linkage::Local,
+        &[cl::types::I32],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn run(h: &Handler) -> i32;
+    let run_func_id = declare_function_from_types(
+        codegen.module,
+        "run",
+        Linkage::Local,
+        &[size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn add_five(x: i32) -> i32 {
+    //   return x + 5;
+    // }
+    codegen
+        .define(add_five_func_id, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+            let result = fbuilder.ins().iadd_imm(x, 5);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn run(h: &Handler) -> i32 {
+    //   return (h.callback)(h.state);
+    // }
+    codegen
+        .define(run_func_id, |_, fbuilder, entry| {
+            let h = fbuilder.block_params(entry)[0];
+
+            // h.callback
+            let callback =
+                fbuilder
+                    .ins()
+                    .load(size_t, MemFlags::trusted(), h, layout.callback_offset);
+
+            // h.state
+            let state =
+                fbuilder
+                    .ins()
+                    .load(cl::types::I32, MemFlags::trusted(), h, layout.state_offset);
+
+            // (h.callback)(h.state)
+            //
+            // The signature comes from the type of the field, since we don't know which function
+            // the pointer points to.
+            let sigref = fbuilder.import_signature(callback_sig.clone());
+            let call = fbuilder.ins().call_indirect(sigref, callback, &[state]);
+            let result = fbuilder.inst_results(call)[0];
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // Handler { callback: add_five, .. }
+    //
+    // Checked while compiling, before any code which stores the pointer is emitted
+    check_callback_signature(codegen.module, add_five_func_id, &callback_sig);
+
+    // fn main() -> i32 {
+    //   let h = Handler { callback: add_five, state: 37 };
+    //   return run(&h);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let h = Handler { callback: add_five, state: 37 };
+            let h = {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    layout.size,
+                    layout.align.trailing_zeros() as u8,
+                ));
+                let h = fbuilder.ins().stack_addr(size_t, slot, 0);
+
+                // The address of `add_five`, as a plain pointer sized integer
+                let callback = {
+                    let fref = module.declare_func_in_func(add_five_func_id, fbuilder.func);
+                    fbuilder.ins().func_addr(size_t, fref)
+                };
+                fbuilder
+                    .ins()
+                    .store(MemFlags::trusted(), callback, h, layout.callback_offset);
+
+                let state = fbuilder.ins().iconst(cl::types::I32, 37);
+                fbuilder
+                    .ins()
+                    .store(MemFlags::trusted(), state, h, layout.state_offset);
+
+                h
+            };
+
+            // return run(&h);
+            let fref = module.declare_func_in_func(run_func_id, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &[h]);
+            let result = fbuilder.inst_results(call)[0];
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
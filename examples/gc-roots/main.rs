@@ -0,0 +1,379 @@
+//! This example shows how a garbage collector can find the pointers to its objects which are
+//! stored on the stack, known as GC roots.
+//!
+//! ```
+//! struct Object {
+//!   marked: i64,
+//! }
+//!
+//! struct Frame {
+//!   prev: *Frame,
+//!   roots: [*Object; 2],
+//! }
+//!
+//! static SHADOW_STACK: *Frame = null;
+//!
+//! fn collect() -> i64 {
+//!   let visited = 0;
+//!   let frame = SHADOW_STACK;
+//!   while frame != null {
+//!     frame.roots[0].marked = 1;
+//!     frame.roots[1].marked = 1;
+//!     visited += 2;
+//!     frame = frame.prev;
+//!   }
+//!   return visited;
+//! }
+//!
+//! fn main() -> i32 {
+//!   let a = gc_alloc(Object { marked: 0 });
+//!   let b = gc_alloc(Object { marked: 0 });
+//!
+//!   let frame = Frame { prev: SHADOW_STACK, roots: [a, b] };
+//!   SHADOW_STACK = &frame;
+//!
+//!   let visited = collect();
+//!
+//!   SHADOW_STACK = frame.prev;
+//!
+//!   return visited + a.marked + b.marked;
+//! }
+//! ```
+//!
+//! A precise garbage collector has to know every pointer to a live object, so it can mark the
+//! object as reachable and update the pointer if the object is moved. Pointers stored in other
+//! objects and in globals are easy to find since the collector knows their layout. But pointers
+//! held by functions which are in the middle of executing are in registers or in stack slots
+//! picked by the register allocator, neither of which the collector knows anything about.
+//!
+//! There are two common ways of solving this.
+//!
+//! # Stack maps
+//!
+//! Values can be marked with `FunctionBuilder::declare_value_needs_stack_map`. At every safepoint,
+//! which is any call where such a value is live across, Cranelift will spill the value to a stack
+//! slot before the call and reload it afterwards. It then records a stack map for the call which
+//! lists the offsets from the stack pointer where the GC roots were spilled.
+//!
+//! After a function is compiled, the stack maps can be read from the compiled code with
+//! `user_stack_maps`, which returns the code offset of each safepoint together with its stack map.
+//! This example prints them for `main`. A runtime would store them in a table keyed by return
+//! address. When collecting, it walks the stack, and uses the return address of each frame to look
+//! up which of its stack slots hold roots.
+//!
+//! Since the roots are reloaded from the stack after the call, the collector is free to move the
+//! objects and overwrite the spilled pointers.
+//!
+//! Older Cranelift versions instead had an `enable_safepoints` setting combined with the `r32` and
+//! `r64` reference types. Both have since been removed in favour of the stack maps described above.
+//!
+//! # Shadow stacks
+//!
+//! Walking the stack requires a runtime that understands the native stack layout, which is more
+//! than this example can fit. So the collector here instead uses a shadow stack, a linked list of
+//! frames which each function pushes its roots to. The roots are stored in memory rather than
+//! registers, so `collect` can follow the list from `SHADOW_STACK` and visit them.
+//!
+//! A shadow stack is slower since every function has to push and pop its frame, but it works
+//! without any support from the code generator or the unwinder. It's a good starting point before
+//! switching to stack maps.
+//!
+//! To keep things simple the objects are allocated on the stack of `main` instead of a GC heap.
+//!
+//! Both objects are visited and marked, so the exit code will be `2 + 1 + 1` which is `4`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example gc-roots -- -o gc-roots.o`
+//! `$ clang gc-roots.o -o gc-roots`
+//! `$ ./gc-roots; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example gc-roots -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, data_addr_in_func, declare_function_from_types, declare_main, for_any_module,
+    skip_boilerplate,
+};
+use cranelift_module::{DataDescription, Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"gc-roots", for_any_module!(define_functions));
+}
+
+// struct Object {
+//   marked: i64,
+// }
+//
+// A real object would have a header with the mark bit and type information, followed by its fields
+const OBJECT_MARKED: i32 = 0;
+const OBJECT_SIZE: u32 = 8;
+
+// struct Frame {
+//   prev: *Frame,
+//   roots: [*Object; 2],
+// }
+//
+// Every field is pointer-sized, so the offsets are multiples of the pointer size
+const FRAME_PREV: i32 = 0;
+const FRAME_ROOTS: i32 = 1;
+const FRAME_ROOT_COUNT: i32 = 2;
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+    let ptr_size = size_t.bytes() as i32;
+
+    // static SHADOW_STACK: *Frame = null;
+    let shadow_stack_data_id = {
+        let data_id = module
+            .declare_data("SHADOW_STACK", Linkage::Local, true, false)
+            .unwrap();
+
+        let mut desc = DataDescription::new();
+        desc.define_zeroinit(size_t.bytes() as usize);
+        module.define_data(data_id, &desc).unwrap();
+
+        data_id
+    };
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn collect() -> i64;
+    let collect_func_id = declare_function_from_types(
+        codegen.module,
+        "collect",
+        Linkage::Local,
+        &[],
+        &[cl::types::I64],
+        None,
+    );
+
+    // fn main() -> i32 {
+    //   let a = gc_alloc(Object { marked: 0 });
+    //   let b = gc_alloc(Object { marked: 0 });
+    //
+    //   let frame = Frame { prev: SHADOW_STACK, roots: [a, b] };
+    //   SHADOW_STACK = &frame;
+    //
+    //   let visited = collect();
+    //
+    //   SHADOW_STACK = frame.prev;
+    //
+    //   return visited + a.marked + b.marked;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let flags = cl::MemFlags::trusted();
+
+            // let a = gc_alloc(Object { marked: 0 });
+            // let b = gc_alloc(Object { marked: 0 });
+            let [a, b] = [(); 2].map(|_| {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    OBJECT_SIZE,
+                    3,
+                ));
+
+                let zero = fbuilder.ins().iconst(cl::types::I64, 0);
+                fbuilder.ins().stack_store(zero, slot, OBJECT_MARKED);
+
+                let ptr = fbuilder.ins().stack_addr(size_t, slot, 0);
+
+                // Both pointers are live across the call to `collect`, so they will be spilled
+                // before it and show up in its stack map
+                fbuilder.declare_value_needs_stack_map(ptr);
+
+                ptr
+            });
+
+            let shadow_stack = data_addr_in_func(module, shadow_stack_data_id, fbuilder);
+
+            // let frame = Frame { prev: SHADOW_STACK, roots: [a, b] };
+            // SHADOW_STACK = &frame;
+            let frame = {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    (ptr_size * (1 + FRAME_ROOT_COUNT)) as u32,
+                    size_t.bytes().trailing_zeros() as u8,
+                ));
+
+                let prev = fbuilder.ins().load(size_t, flags, shadow_stack, 0);
+                fbuilder
+                    .ins()
+                    .stack_store(prev, slot, FRAME_PREV * ptr_size);
+
+                for (i, root) in [a, b].into_iter().enumerate() {
+                    let offset = (FRAME_ROOTS + i as i32) * ptr_size;
+                    fbuilder.ins().stack_store(root, slot, offset);
+                }
+
+                let frame = fbuilder.ins().stack_addr(size_t, slot, 0);
+                fbuilder.ins().store(flags, frame, shadow_stack, 0);
+
+                frame
+            };
+
+            // let visited = collect();
+            let visited = {
+                let fref = module.declare_func_in_func(collect_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            // SHADOW_STACK = frame.prev;
+            //
+            // Popping the frame before returning, so that `collect` never follows a pointer into a
+            // stack frame which no longer exists
+            {
+                let prev = fbuilder
+                    .ins()
+                    .load(size_t, flags, frame, FRAME_PREV * ptr_size);
+                fbuilder.ins().store(flags, prev, shadow_stack, 0);
+            }
+
+            // return visited + a.marked + b.marked;
+            let result = {
+                let a_marked = fbuilder.ins().load(cl::types::I64, flags, a, OBJECT_MARKED);
+                let b_marked = fbuilder.ins().load(cl::types::I64, flags, b, OBJECT_MARKED);
+
+                let sum = fbuilder.ins().iadd(visited, a_marked);
+                let sum = fbuilder.ins().iadd(sum, b_marked);
+
+                // The exit code is an `i32`
+                fbuilder.ins().ireduce(cl::types::I32, sum)
+            };
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // The context still holds the compiled code of `main`, which is where its stack maps are.
+    //
+    // Each stack map is recorded at the code offset of the instruction right after the call, which
+    // is the return address the runtime would find when walking the stack.
+    {
+        let compiled = codegen.ctx.compiled_code().unwrap();
+        for (code_offset, _, stack_map) in compiled.buffer.user_stack_maps() {
+            println!("stack map for safepoint returning to main+{code_offset:#x}:");
+            for (ty, sp_offset) in stack_map.entries() {
+                println!("  {ty} root at sp+{sp_offset}");
+            }
+        }
+        println!();
+    }
+
+    // fn collect() -> i64 {
+    //   let visited = 0;
+    //   let frame = SHADOW_STACK;
+    //   while frame != null {
+    //     frame.roots[0].marked = 1;
+    //     frame.roots[1].marked = 1;
+    //     visited += 2;
+    //     frame = frame.prev;
+    //   }
+    //   return visited;
+    // }
+    //
+    // // -- Which we lower as -- //
+    //
+    // block0:
+    //   jump block1(SHADOW_STACK, 0)
+    //
+    // block1(frame, visited):
+    //   brif frame, block2, block3(visited)
+    //
+    // block2:
+    //   frame.roots[0].marked = 1;
+    //   frame.roots[1].marked = 1;
+    //   jump block1(frame.prev, visited + 2)
+    //
+    // block3(visited):
+    //   return visited
+    codegen
+        .define(collect_func_id, |module, fbuilder, _| {
+            let flags = cl::MemFlags::trusted();
+
+            let header = fbuilder.create_block();
+            let body = fbuilder.create_block();
+            let exit = fbuilder.create_block();
+
+            // header(frame: *Frame, visited: i64)
+            fbuilder.append_block_param(header, size_t);
+            fbuilder.append_block_param(header, cl::types::I64);
+
+            // exit(visited: i64)
+            fbuilder.append_block_param(exit, cl::types::I64);
+
+            // let visited = 0;
+            // let frame = SHADOW_STACK;
+            {
+                let shadow_stack = data_addr_in_func(module, shadow_stack_data_id, fbuilder);
+                let frame = fbuilder.ins().load(size_t, flags, shadow_stack, 0);
+                let zero = fbuilder.ins().iconst(cl::types::I64, 0);
+                fbuilder.ins().jump(header, &[frame.into(), zero.into()]);
+            }
+
+            // while frame != null
+            {
+                fbuilder.switch_to_block(header);
+
+                let frame = fbuilder.block_params(header)[0];
+                let visited = fbuilder.block_params(header)[1];
+
+                fbuilder
+                    .ins()
+                    .brif(frame, body, &[], exit, &[visited.into()]);
+            }
+
+            // frame.roots[0].marked = 1;
+            // frame.roots[1].marked = 1;
+            // visited += 2;
+            // frame = frame.prev;
+            {
+                fbuilder.seal_block(body);
+                fbuilder.switch_to_block(body);
+
+                let frame = fbuilder.block_params(header)[0];
+                let visited = fbuilder.block_params(header)[1];
+
+                let one = fbuilder.ins().iconst(cl::types::I64, 1);
+                for i in 0..FRAME_ROOT_COUNT {
+                    let offset = (FRAME_ROOTS + i) * ptr_size;
+                    let root = fbuilder.ins().load(size_t, flags, frame, offset);
+                    fbuilder.ins().store(flags, one, root, OBJECT_MARKED);
+                }
+
+                let visited = fbuilder.ins().iadd_imm(visited, FRAME_ROOT_COUNT as i64);
+                let prev = fbuilder
+                    .ins()
+                    .load(size_t, flags, frame, FRAME_PREV * ptr_size);
+
+                fbuilder.ins().jump(header, &[prev.into(), visited.into()]);
+            }
+
+            // The only predecessors of `header` are the entry block and `body`
+            fbuilder.seal_block(header);
+
+            // return visited;
+            {
+                fbuilder.seal_block(exit);
+                fbuilder.switch_to_block(exit);
+
+                let visited = fbuilder.block_params(exit)[0];
+                fbuilder.ins().return_(&[visited]);
+            }
+        })
+        .unwrap();
+}
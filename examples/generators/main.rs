@@ -0,0 +1,379 @@
+//! This example shows how to lower a generator into a state machine which can be resumed.
+//!
+//! ```
+//! fn numbers(start: i32) -> Generator<Yield = i32, Return = i32> {
+//!   let a = start + 1;
+//!   yield a;
+//!   let b = a * 2;
+//!   yield b;
+//!   return a + b;
+//! }
+//!
+//! fn main() -> i32 {
+//!   let gen = numbers(5);
+//!   let sum = 0;
+//!   loop {
+//!     let (value, done) = resume(&gen);
+//!     sum += value;
+//!     if done { return sum; }
+//!   }
+//! }
+//! ```
+//!
+//! Cranelift functions run to completion, there's no way to pause one half-way through and continue
+//! later. So instead of suspending the function, we split its body at every `yield` and turn it
+//! into a `resume` function which runs one piece each time it's called.
+//!
+//! Everything that's needed to continue after a `yield` has to survive between calls, so it's
+//! moved out of the stack frame into a struct owned by the caller:
+//!
+//! ```
+//! struct NumbersGen {
+//!   state: i8,   // Which piece to run on the next `resume`
+//!   start: i32,  // The parameter, needed by the first piece
+//!   a: i32,      // Live across both `yield`s
+//!   b: i32,      // Live across the second `yield`
+//! }
+//! ```
+//!
+//! The `state` field works the same way as the tag of a tagged union from the
+//! `tagged-union-layouts` example, where each state has its own set of live locals. Here we use
+//! a single struct with room for every local to keep things simple. A real compiler would lay out
+//! the locals of each state as the variants of an enum, so that locals which are never live at the
+//! same time can share memory. That's how Rust lowers `async fn`s.
+//!
+//! `resume` loads the state and `br_table`s to the piece for that state. Every piece ends by saving
+//! the locals that are still needed, storing the next state, and returning what it yielded.
+//!
+//! ```
+//! fn resume(gen: *NumbersGen) -> (i32, i8) {
+//!   match gen.state {
+//!     0 => { gen.a = gen.start + 1; gen.state = 1; return (gen.a, 0); }
+//!     1 => { gen.b = gen.a * 2; gen.state = 2; return (gen.b, 0); }
+//!     2 => { gen.state = 3; return (gen.a + gen.b, 1); }
+//!     _ => trap, // resumed after completion
+//!   }
+//! }
+//! ```
+//!
+//! Async functions are lowered the same way, except that `resume` is called by an executor once
+//! the thing being awaited is ready, instead of by a loop.
+//!
+//! The generator yields `6` and `12`, and returns `18`, so the exit code will be `36`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example generators -- -o generators.o`
+//! `$ clang generators.o -o generators`
+//! `$ ./generators; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example generators -- --jit`
+
+use cranelift::codegen::ir::BlockCall;
+use cranelift::prelude as cl;
+use cranelift::prelude::{
+    FunctionBuilder, FunctionBuilderContext, InstBuilder, JumpTableData, codegen::Context,
+};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+    trap_unreachable,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"generators", for_any_module!(define_functions));
+}
+
+// struct NumbersGen {
+//   state: i8,
+//   start: i32,
+//   a: i32,
+//   b: i32,
+// }
+const GEN_FIELDS: [cl::Type; 4] = [
+    cl::types::I8,
+    cl::types::I32,
+    cl::types::I32,
+    cl::types::I32,
+];
+const FIELD_STATE: usize = 0;
+const FIELD_START: usize = 1;
+const FIELD_A: usize = 2;
+const FIELD_B: usize = 3;
+
+// The pieces of `numbers` between each `yield`
+const STATE_START: i64 = 0;
+const STATE_AFTER_FIRST_YIELD: i64 = 1;
+const STATE_AFTER_SECOND_YIELD: i64 = 2;
+const STATE_DONE: i64 = 3;
+const STATE_COUNT: usize = 4;
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn resume(gen: *NumbersGen) -> (i32, i8);
+    let resume_func_id = declare_function_from_types(
+        codegen.module,
+        "resume",
+        Linkage::Local,
+        &[size_t],
+        &[cl::types::I32, cl::types::I8],
+        None,
+    );
+
+    // fn main() -> i32 {
+    //   let gen = numbers(5);
+    //   let sum = 0;
+    //   loop {
+    //     let (value, done) = resume(&gen);
+    //     sum += value;
+    //     if done { return sum; }
+    //   }
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let gen = numbers(5);
+            //
+            // Calling the generator function doesn't run any of its body. It only creates the
+            // struct in its starting state, with the parameters saved for the first `resume`.
+            let gen_ptr = {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    size_of_struct(&GEN_FIELDS),
+                    alignment_of_struct(&GEN_FIELDS).trailing_zeros() as u8,
+                ));
+
+                let state = fbuilder.ins().iconst(cl::types::I8, STATE_START);
+                let offset = offset_of_field(FIELD_STATE, &GEN_FIELDS);
+                fbuilder.ins().stack_store(state, slot, offset);
+
+                let start = fbuilder.ins().iconst(cl::types::I32, 5);
+                let offset = offset_of_field(FIELD_START, &GEN_FIELDS);
+                fbuilder.ins().stack_store(start, slot, offset);
+
+                fbuilder.ins().stack_addr(size_t, slot, 0)
+            };
+
+            // See the `loops` example for more details on lowering loops
+            let header = fbuilder.create_block();
+            let exit = fbuilder.create_block();
+
+            // header(sum: i32)
+            fbuilder.append_block_param(header, cl::types::I32);
+
+            // exit(sum: i32)
+            fbuilder.append_block_param(exit, cl::types::I32);
+
+            // let sum = 0;
+            let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+            fbuilder.ins().jump(header, &[zero.into()]);
+
+            // let (value, done) = resume(&gen);
+            // sum += value;
+            // if done { return sum; }
+            {
+                fbuilder.switch_to_block(header);
+
+                let sum = fbuilder.block_params(header)[0];
+
+                let fref = module.declare_func_in_func(resume_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[gen_ptr]);
+                let [value, done] = [0, 1].map(|i| fbuilder.inst_results(call)[i]);
+
+                let sum = fbuilder.ins().iadd(sum, value);
+
+                fbuilder
+                    .ins()
+                    .brif(done, exit, &[sum.into()], header, &[sum.into()]);
+            }
+
+            fbuilder.seal_block(header);
+            fbuilder.seal_block(exit);
+
+            // return sum;
+            {
+                fbuilder.switch_to_block(exit);
+
+                let sum = fbuilder.block_params(exit)[0];
+                fbuilder.ins().return_(&[sum]);
+            }
+        })
+        .unwrap();
+
+    // fn resume(gen: *NumbersGen) -> (i32, i8) {
+    //   match gen.state {
+    //     0 => { gen.a = gen.start + 1; gen.state = 1; return (gen.a, 0); }
+    //     1 => { gen.b = gen.a * 2; gen.state = 2; return (gen.b, 0); }
+    //     2 => { gen.state = 3; return (gen.a + gen.b, 1); }
+    //     _ => trap,
+    //   }
+    // }
+    codegen
+        .define(resume_func_id, |_, fbuilder, entry| {
+            let gen_ptr = fbuilder.block_params(entry)[0];
+
+            // The generator struct is owned by the caller, so every field access goes through memory
+            let load = |fbuilder: &mut FunctionBuilder<'_>, field: usize| {
+                let offset = offset_of_field(field, &GEN_FIELDS);
+                fbuilder
+                    .ins()
+                    .load(GEN_FIELDS[field], cl::MemFlags::trusted(), gen_ptr, offset)
+            };
+            let store = |fbuilder: &mut FunctionBuilder<'_>, field: usize, v: cl::Value| {
+                let offset = offset_of_field(field, &GEN_FIELDS);
+                fbuilder
+                    .ins()
+                    .store(cl::MemFlags::trusted(), v, gen_ptr, offset);
+            };
+            let set_state = |fbuilder: &mut FunctionBuilder<'_>, state: i64| {
+                let state = fbuilder.ins().iconst(cl::types::I8, state);
+                store(fbuilder, FIELD_STATE, state);
+            };
+
+            // One block for each state, same as for the variants of a tagged union
+            let branches: [BlockCall; STATE_COUNT] = [(); STATE_COUNT].map(|_| {
+                let block = fbuilder.create_block();
+                BlockCall::new(block, [], &mut fbuilder.func.dfg.value_lists)
+            });
+
+            let trap = {
+                let block = fbuilder.create_block();
+                BlockCall::new(block, [], &mut fbuilder.func.dfg.value_lists)
+            };
+
+            let table = {
+                let table_data = JumpTableData::new(trap, &branches);
+                fbuilder.func.create_jump_table(table_data)
+            };
+
+            // `br_table` expects an `I32` index, so the state needs to be extended first
+            let state = load(fbuilder, FIELD_STATE);
+            let state = fbuilder.ins().uextend(cl::types::I32, state);
+            fbuilder.ins().br_table(state, table);
+
+            // let a = start + 1;
+            // yield a;
+            {
+                switch_to_branch_block(fbuilder, branches[STATE_START as usize]);
+
+                let start = load(fbuilder, FIELD_START);
+                let a = fbuilder.ins().iadd_imm(start, 1);
+
+                // `a` is used again after the `yield`, so it's saved in the generator
+                store(fbuilder, FIELD_A, a);
+                set_state(fbuilder, STATE_AFTER_FIRST_YIELD);
+
+                let not_done = fbuilder.ins().iconst(cl::types::I8, 0);
+                fbuilder.ins().return_(&[a, not_done]);
+            }
+
+            // let b = a * 2;
+            // yield b;
+            {
+                switch_to_branch_block(fbuilder, branches[STATE_AFTER_FIRST_YIELD as usize]);
+
+                let a = load(fbuilder, FIELD_A);
+                let b = fbuilder.ins().imul_imm(a, 2);
+
+                store(fbuilder, FIELD_B, b);
+                set_state(fbuilder, STATE_AFTER_SECOND_YIELD);
+
+                let not_done = fbuilder.ins().iconst(cl::types::I8, 0);
+                fbuilder.ins().return_(&[b, not_done]);
+            }
+
+            // return a + b;
+            {
+                switch_to_branch_block(fbuilder, branches[STATE_AFTER_SECOND_YIELD as usize]);
+
+                let a = load(fbuilder, FIELD_A);
+                let b = load(fbuilder, FIELD_B);
+                let sum = fbuilder.ins().iadd(a, b);
+
+                // Nothing is live after returning, so only the state needs to be saved
+                set_state(fbuilder, STATE_DONE);
+
+                let done = fbuilder.ins().iconst(cl::types::I8, 1);
+                fbuilder.ins().return_(&[sum, done]);
+            }
+
+            // Resuming a generator which has already returned is a bug in the caller
+            {
+                switch_to_branch_block(fbuilder, branches[STATE_DONE as usize]);
+
+                const TRAP_RESUMED_AFTER_COMPLETION: u8 = 104;
+                fbuilder
+                    .ins()
+                    .trap(cl::TrapCode::user(TRAP_RESUMED_AFTER_COMPLETION).unwrap());
+            }
+
+            // _ => unreachable!(),
+            {
+                switch_to_branch_block(fbuilder, trap);
+                trap_unreachable(fbuilder);
+            }
+        })
+        .unwrap();
+}
+
+fn switch_to_branch_block(fbuilder: &mut FunctionBuilder<'_>, call: BlockCall) {
+    let block = call.block(&fbuilder.func.dfg.value_lists);
+    fbuilder.seal_block(block);
+    fbuilder.switch_to_block(block);
+}
+
+// The same layout rules as in the `struct-layouts` example.
+//
+// Fields are aligned to their own size, and the struct is aligned to its largest field.
+fn size_of_struct(fields: &[cl::Type]) -> u32 {
+    let mut size = 0;
+
+    for &field in fields {
+        // Add padding to ensure the field is aligned
+        let align = field.bytes();
+        size += (align - size % align) % align;
+
+        size += field.bytes();
+    }
+
+    // Add padding to the end of the struct to make the struct itself aligned
+    let self_align = alignment_of_struct(fields);
+    size += (self_align - size % self_align) % self_align;
+
+    size
+}
+
+fn alignment_of_struct(fields: &[cl::Type]) -> u32 {
+    fields.iter().map(|field| field.bytes()).max().unwrap_or(1)
+}
+
+fn offset_of_field(field: usize, fields: &[cl::Type]) -> i32 {
+    let mut offset = 0;
+
+    for (i, &ty) in fields.iter().enumerate() {
+        // Add padding to ensure the field is aligned
+        let align = ty.bytes();
+        offset += (align - offset % align) % align;
+
+        if i == field {
+            return offset as i32;
+        }
+
+        offset += ty.bytes();
+    }
+
+    panic!("field not found");
+}
@@ -0,0 +1,238 @@
+//! This example shows how generic functions can be compiled by monomorphization, which creates a
+//! separate copy of the function for each type it's used with.
+//!
+//! ```
+//! fn id<T>(x: T) -> T {
+//!   return x;
+//! }
+//!
+//! fn max<T: PartialOrd>(a: T, b: T) -> T {
+//!   if a > b { a } else { b }
+//! }
+//!
+//! fn main() -> i32 {
+//!   let a = id::<i32>(5);
+//!   let b = max::<i32>(a, 7);
+//!   let c = max::<f64>(id::<f64>(2.5), 4.5);
+//!   return id::<i32>(b) + c as i32;
+//! }
+//! ```
+//!
+//! Cranelift has no notion of generics. Every function has a signature with concrete types, and
+//! every instruction operates on concrete types. So for each combination of type arguments a
+//! generic function is used with, we declare and define a separate function where the type
+//! parameters have been replaced. These are called the instances of the generic function.
+//!
+//! The body of each instance is built from the same template, which takes the type as an argument.
+//! Most instructions such as `select` work the same for every type, while others have to be picked
+//! based on the type. `max` compares integers with `icmp` and floats with `fcmp`.
+//!
+//! Each instance needs its own symbol, so the type arguments are encoded into the name, such as
+//! `max_i32` and `max_f64`. Real compilers use a more thorough scheme which can't collide with the
+//! names of other functions, such as the Itanium C++ ABI or Rust's v0 mangling.
+//!
+//! Instances are created on demand while lowering the functions that call them. Since we can't
+//! define a function while in the middle of defining another one, the instance is only declared at
+//! first and queued up. Once the caller is done, the queued instances are defined. Neither `id` nor
+//! `max` call other generic functions, but if they did, their bodies would queue up more instances
+//! in the same way. That's why the queue is worked through until it's empty.
+//!
+//! `id::<i32>` is used twice, but only instantiated once, since each instance is remembered along
+//! with its type arguments.
+//!
+//! The main function returns `7 + 4`, so the exit code will be `11`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example generics -- -o generics.o`
+//! `$ nm generics.o`
+//! `$ clang generics.o -o generics`
+//! `$ ./generics; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example generics -- --jit`
+
+use std::collections::HashMap;
+
+use cranelift::prelude as cl;
+use cranelift::prelude::codegen::Context;
+use cranelift::prelude::{FloatCC, FunctionBuilder, FunctionBuilderContext, InstBuilder, IntCC};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{FuncId, Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"generics", for_any_module!(define_functions));
+}
+
+// The generic functions of our source language
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Generic {
+    // fn id<T>(x: T) -> T;
+    Id,
+    // fn max<T: PartialOrd>(a: T, b: T) -> T;
+    Max,
+}
+
+impl Generic {
+    fn name(self) -> &'static str {
+        match self {
+            Generic::Id => "id",
+            Generic::Max => "max",
+        }
+    }
+
+    // The signature with `T` replaced by `ty`
+    fn params_and_returns(self, ty: cl::Type) -> (Vec<cl::Type>, Vec<cl::Type>) {
+        match self {
+            Generic::Id => (vec![ty], vec![ty]),
+            Generic::Max => (vec![ty, ty], vec![ty]),
+        }
+    }
+
+    // The body with `T` replaced by `ty`
+    fn build(self, fbuilder: &mut FunctionBuilder<'_>, entry: cl::Block, ty: cl::Type) {
+        match self {
+            // fn id<T>(x: T) -> T {
+            //   return x;
+            // }
+            Generic::Id => {
+                let x = fbuilder.block_params(entry)[0];
+                fbuilder.ins().return_(&[x]);
+            }
+
+            // fn max<T: PartialOrd>(a: T, b: T) -> T {
+            //   if a > b { a } else { b }
+            // }
+            Generic::Max => {
+                let a = fbuilder.block_params(entry)[0];
+                let b = fbuilder.block_params(entry)[1];
+
+                // This is the part which differs between instances, since integers and floats
+                // are compared with different instructions
+                let a_is_greater = if ty.is_float() {
+                    fbuilder.ins().fcmp(FloatCC::GreaterThan, a, b)
+                } else {
+                    fbuilder.ins().icmp(IntCC::SignedGreaterThan, a, b)
+                };
+
+                let max = fbuilder.ins().select(a_is_greater, a, b);
+                fbuilder.ins().return_(&[max]);
+            }
+        }
+    }
+}
+
+// Keeps track of the instances which have been created so far
+#[derive(Default)]
+struct Instances {
+    declared: HashMap<(Generic, cl::Type), FuncId>,
+    // Instances which have been declared but not yet defined
+    queue: Vec<(Generic, cl::Type, FuncId)>,
+}
+
+impl Instances {
+    // Get the instance of `generic` for the type argument `ty`, declaring it if this is the first
+    // time it's used with that type
+    fn instance(&mut self, module: &mut impl Module, generic: Generic, ty: cl::Type) -> FuncId {
+        if let Some(&func_id) = self.declared.get(&(generic, ty)) {
+            return func_id;
+        }
+
+        // Encode the type argument into the symbol, such as `max_f64`
+        let name = format!("{}_{ty}", generic.name());
+
+        let (params, returns) = generic.params_and_returns(ty);
+        let func_id =
+            declare_function_from_types(module, &name, Linkage::Local, &params, &returns, None);
+
+        self.declared.insert((generic, ty), func_id);
+        self.queue.push((generic, ty, func_id));
+
+        func_id
+    }
+
+    // Define every instance which has been queued up
+    fn define_queued<M: Module>(&mut self, codegen: &mut Codegen<'_, M>) {
+        while let Some((generic, ty, func_id)) = self.queue.pop() {
+            codegen
+                .define(func_id, |_, fbuilder, entry| {
+                    generic.build(fbuilder, entry, ty);
+                })
+                .unwrap();
+        }
+    }
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    let mut instances = Instances::default();
+
+    // fn main() -> i32 {
+    //   let a = id::<i32>(5);
+    //   let b = max::<i32>(a, 7);
+    //   let c = max::<f64>(id::<f64>(2.5), 4.5);
+    //   return id::<i32>(b) + c as i32;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let mut call = |fbuilder: &mut FunctionBuilder<'_>,
+                            generic: Generic,
+                            ty: cl::Type,
+                            args: &[cl::Value]| {
+                let func_id = instances.instance(module, generic, ty);
+                let fref = module.declare_func_in_func(func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, args);
+                fbuilder.inst_results(call)[0]
+            };
+
+            // let a = id::<i32>(5);
+            let a = {
+                let five = fbuilder.ins().iconst(cl::types::I32, 5);
+                call(fbuilder, Generic::Id, cl::types::I32, &[five])
+            };
+
+            // let b = max::<i32>(a, 7);
+            let b = {
+                let seven = fbuilder.ins().iconst(cl::types::I32, 7);
+                call(fbuilder, Generic::Max, cl::types::I32, &[a, seven])
+            };
+
+            // let c = max::<f64>(id::<f64>(2.5), 4.5);
+            let c = {
+                let x = fbuilder.ins().f64const(2.5);
+                let x = call(fbuilder, Generic::Id, cl::types::F64, &[x]);
+
+                let y = fbuilder.ins().f64const(4.5);
+                call(fbuilder, Generic::Max, cl::types::F64, &[x, y])
+            };
+
+            // return id::<i32>(b) + c as i32;
+            //
+            // `id_i32` was already declared for `a`, so the same instance is called again
+            let b = call(fbuilder, Generic::Id, cl::types::I32, &[b]);
+            let c = fbuilder.ins().fcvt_to_sint(cl::types::I32, c);
+
+            let result = fbuilder.ins().iadd(b, c);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // Now that `main` is done, the instances it used can be defined
+    instances.define_queued(&mut codegen);
+
+    // id_i32, max_i32, id_f64 and max_f64
+    assert_eq!(instances.declared.len(), 4);
+}
@@ -0,0 +1,434 @@
+//! This example shows how to define global variables backed by the data section of the object file.
+//!
+//! ```
+//! static mut COUNTER: i64 = 0;
+//! static STEP: i64 = 1;
+//!
+//! fn increment() {
+//!   COUNTER += STEP;
+//! }
+//!
+//! fn main() -> i32 {
+//!   increment();
+//!   increment();
+//!   return COUNTER;
+//! }
+//! ```
+//!
+//! Globals are declared and defined in the module just like functions are. Declaring gives us a
+//! `DataId`, and defining it with a `DataDescription` sets its initial contents.
+//!
+//! To use a global from within a function, we map the `DataId` into a `GlobalValue` local to the
+//! function, and then materialize its address as a regular pointer which can be loaded from and
+//! stored to.
+//!
+//! The main function returns `COUNTER` after incrementing it twice, so the exit code will be `2`.
+//!
+//! # Thread-local globals
+//!
+//! ```
+//! thread_local static mut TLS_COUNTER: i64 = 0;
+//!
+//! fn increment_tls() -> i64 {
+//!   TLS_COUNTER += 1;
+//!   return TLS_COUNTER;
+//! }
+//! ```
+//!
+//! Declaring data with `tls` set to true gives every thread its own copy of it. Since the address
+//! differs between threads, it can't be materialized with `global_value`. Instead `tls_value` asks
+//! the runtime for the address belonging to the current thread.
+//!
+//! How that's done is decided by the `tls_model` setting, which has to match the object format:
+//!
+//! * `elf_gd` for Linux and other ELF targets. This is the "general dynamic" model, which calls
+//!   `__tls_get_addr` to look up the address. On x86-64 Linux the thread-local block is found
+//!   relative to the `%fs` segment register, and when linking an executable the linker is free to
+//!   relax the call into a plain `%fs`-relative access. Supported on x86-64, AArch64, s390x and
+//!   riscv64.
+//! * `macho` for MacOS, which calls the accessor function stored in the thread-local variable
+//!   descriptor. Supported on x86-64 and AArch64.
+//! * `coff` for Windows, which goes through the thread-local storage array of the thread.
+//!   Only supported on x86-64.
+//!
+//! The default is `none`, in which case Cranelift fails to compile `tls_value`. Every model
+//! produces position independent code, which is why `is_pic` is enabled alongside it.
+//!
+//! To show that each thread has its own counter, `main` increments `TLS_COUNTER` twice, then
+//! spawns a thread with `pthread_create` which increments it once. The thread sees its own
+//! counter starting from zero, so `main` traps unless it got `2` and the thread got `1`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example globals -- -o globals.o`
+//! `$ clang globals.o -o globals -pthread`
+//! `$ ./globals; echo $?`
+//!
+//! Or run it in-process without going through an object file. The JIT doesn't support
+//! thread-local data, so `TLS_COUNTER` is left out.
+//!
+//! `$ cargo run --example globals -- --jit`
+
+use cranelift::codegen::settings::TlsModel;
+use cranelift::prelude as cl;
+use cranelift::prelude::{Configurable, FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate_with,
+};
+use cranelift_module::{DataDescription, DataId, FuncId, Linkage, Module};
+use target_lexicon::{BinaryFormat, Triple};
+
+fn main() {
+    skip_boilerplate_with(
+        b"globals",
+        |settings, triple| settings.set("tls_model", tls_model(triple)).unwrap(),
+        for_any_module!(define_functions),
+    );
+}
+
+// The way thread-local data is accessed depends on the object format, see the module docs
+fn tls_model(triple: &Triple) -> &'static str {
+    match triple.binary_format {
+        BinaryFormat::Elf => "elf_gd",
+        BinaryFormat::Macho => "macho",
+        BinaryFormat::Coff => "coff",
+        _ => "none",
+    }
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    // static mut COUNTER: i64 = 0;
+    let counter_data_id = {
+        // `Linkage::Export` makes the symbol visible to other object files we might link with.
+        //
+        // Since `writable` is true, the data will be put into a writable section (`.bss` or `.data`
+        // on Linux).
+        let data_id = module
+            .declare_data("COUNTER", Linkage::Export, true, false)
+            .unwrap();
+
+        // Zero-initialized data doesn't need to be stored in the object file at all, only its size.
+        let mut desc = DataDescription::new();
+        desc.define_zeroinit(cl::types::I64.bytes() as usize);
+        desc.set_align(cl::types::I64.bytes() as u64);
+
+        module.define_data(data_id, &desc).unwrap();
+
+        data_id
+    };
+
+    // static STEP: i64 = 1;
+    let step_data_id = {
+        // Since `writable` is false, the data will be put into a read-only section (`.rodata` on
+        // Linux). Attempting to store to it will crash the program.
+        let data_id = module
+            .declare_data("STEP", Linkage::Local, false, false)
+            .unwrap();
+
+        // The initial contents are given as raw bytes, so we need to pick the endianness of the target
+        let bytes = match module.isa().endianness() {
+            cl::codegen::ir::Endianness::Little => 1_i64.to_le_bytes(),
+            cl::codegen::ir::Endianness::Big => 1_i64.to_be_bytes(),
+        };
+
+        let mut desc = DataDescription::new();
+        desc.define(Box::new(bytes));
+        desc.set_align(cl::types::I64.bytes() as u64);
+
+        module.define_data(data_id, &desc).unwrap();
+
+        data_id
+    };
+
+    // thread_local static mut TLS_COUNTER: i64 = 0;
+    //
+    // Left out when there's no TLS model to access it with, such as in the JIT.
+    let tls_counter_data_id = (module.isa().flags().tls_model() != TlsModel::None).then(|| {
+        // The last argument marks the data as thread-local. It's put into a section such as
+        // `.tdata` on ELF, which is the template each thread's copy is initialized from.
+        let data_id = module
+            .declare_data("TLS_COUNTER", Linkage::Local, true, true)
+            .unwrap();
+
+        // Unlike with `COUNTER` we give the zeroes explicitly, since COFF objects have no
+        // section for zero-initialized thread-local data.
+        let mut desc = DataDescription::new();
+        desc.define(Box::new(0_i64.to_ne_bytes()));
+        desc.set_align(cl::types::I64.bytes() as u64);
+
+        module.define_data(data_id, &desc).unwrap();
+
+        data_id
+    });
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    let check_thread_locals_func_id =
+        tls_counter_data_id.map(|data_id| define_thread_local_functions(&mut codegen, data_id));
+
+    // fn increment();
+    let increment_func_id =
+        declare_function_from_types(codegen.module, "increment", Linkage::Local, &[], &[], None);
+
+    // fn increment() {
+    //   COUNTER += STEP;
+    // }
+    codegen
+        .define(increment_func_id, |module, fbuilder, _| {
+            let flags = cl::MemFlags::trusted();
+
+            // Similarly to functions, the global `DataId` first needs to be mapped to a
+            // `GlobalValue` local to the current function.
+            let counter_gv = module.declare_data_in_func(counter_data_id, fbuilder.func);
+            let step_gv = module.declare_data_in_func(step_data_id, fbuilder.func);
+
+            // `global_value` materializes the address of the global as a pointer
+            let counter_ptr = fbuilder.ins().global_value(size_t, counter_gv);
+
+            // `symbol_value` does the same thing, but only works for globals that refer directly to
+            // a symbol. `global_value` also works for globals that are derived from other globals,
+            // such as an offset into another global.
+            let step_ptr = fbuilder.ins().symbol_value(size_t, step_gv);
+
+            let counter = fbuilder.ins().load(cl::types::I64, flags, counter_ptr, 0);
+            let step = fbuilder.ins().load(cl::types::I64, flags, step_ptr, 0);
+
+            let counter = fbuilder.ins().iadd(counter, step);
+
+            fbuilder.ins().store(flags, counter, counter_ptr, 0);
+
+            fbuilder.ins().return_(&[]);
+        })
+        .unwrap();
+
+    // fn main() -> i32 {
+    //   increment();
+    //   increment();
+    //   check_thread_locals();
+    //   return COUNTER;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // increment();
+            // increment();
+            for _ in 0..2 {
+                let fref = module.declare_func_in_func(increment_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[]);
+            }
+
+            // check_thread_locals();
+            if let Some(func_id) = check_thread_locals_func_id {
+                let fref = module.declare_func_in_func(func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[]);
+            }
+
+            // return COUNTER;
+            let counter = {
+                let gv = module.declare_data_in_func(counter_data_id, fbuilder.func);
+                let ptr = fbuilder.ins().global_value(size_t, gv);
+                let counter = fbuilder
+                    .ins()
+                    .load(cl::types::I64, cl::MemFlags::trusted(), ptr, 0);
+
+                // The exit code is an `i32`
+                fbuilder.ins().ireduce(cl::types::I32, counter)
+            };
+
+            fbuilder.ins().return_(&[counter]);
+        })
+        .unwrap();
+}
+
+// Define the functions using `TLS_COUNTER`, returning the `check_thread_locals` function
+fn define_thread_local_functions<M: Module>(
+    codegen: &mut Codegen<M>,
+    tls_counter_data_id: DataId,
+) -> FuncId {
+    let size_t = codegen.module.isa().pointer_type();
+
+    // fn increment_tls() -> i64;
+    let increment_tls_func_id = declare_function_from_types(
+        codegen.module,
+        "increment_tls",
+        Linkage::Local,
+        &[],
+        &[cl::types::I64],
+        None,
+    );
+
+    // extern "C" fn worker(arg: *void) -> *void;
+    //
+    // The signature `pthread_create` expects of the function it runs on the new thread
+    let worker_func_id = declare_function_from_types(
+        codegen.module,
+        "worker",
+        Linkage::Local,
+        &[size_t],
+        &[size_t],
+        None,
+    );
+
+    // fn check_thread_locals();
+    let check_thread_locals_func_id = declare_function_from_types(
+        codegen.module,
+        "check_thread_locals",
+        Linkage::Local,
+        &[],
+        &[],
+        None,
+    );
+
+    // extern "C" fn pthread_create(thread: *pthread_t, attr: *pthread_attr_t, start: fn, arg: *void) -> i32;
+    //
+    // `pthread_t` is an integer or a pointer depending on the platform, but always pointer-sized.
+    let pthread_create_func_id = declare_function_from_types(
+        codegen.module,
+        "pthread_create",
+        Linkage::Import,
+        &[size_t, size_t, size_t, size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    // extern "C" fn pthread_join(thread: pthread_t, retval: **void) -> i32;
+    let pthread_join_func_id = declare_function_from_types(
+        codegen.module,
+        "pthread_join",
+        Linkage::Import,
+        &[size_t, size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn increment_tls() -> i64 {
+    //   TLS_COUNTER += 1;
+    //   return TLS_COUNTER;
+    // }
+    codegen
+        .define(increment_tls_func_id, |module, fbuilder, _| {
+            let flags = cl::MemFlags::trusted();
+
+            let gv = module.declare_data_in_func(tls_counter_data_id, fbuilder.func);
+
+            // `tls_value` gives us the address of the copy belonging to the current thread. Using
+            // `global_value` here would instead fail to compile, since the address isn't fixed.
+            let ptr = fbuilder.ins().tls_value(size_t, gv);
+
+            let counter = fbuilder.ins().load(cl::types::I64, flags, ptr, 0);
+            let counter = fbuilder.ins().iadd_imm(counter, 1);
+            fbuilder.ins().store(flags, counter, ptr, 0);
+
+            fbuilder.ins().return_(&[counter]);
+        })
+        .unwrap();
+
+    // extern "C" fn worker(arg: *void) -> *void {
+    //   return increment_tls() as *void;
+    // }
+    codegen
+        .define(worker_func_id, |module, fbuilder, _| {
+            let fref = module.declare_func_in_func(increment_tls_func_id, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &[]);
+            let counter = fbuilder.inst_results(call)[0];
+
+            // The counter is handed back through the `*void` return value of the thread
+            let counter = if size_t.bits() < 64 {
+                fbuilder.ins().ireduce(size_t, counter)
+            } else {
+                counter
+            };
+
+            fbuilder.ins().return_(&[counter]);
+        })
+        .unwrap();
+
+    // fn check_thread_locals() {
+    //   increment_tls();
+    //   let on_main = increment_tls();
+    //
+    //   let thread: pthread_t;
+    //   pthread_create(&thread, null, worker, null);
+    //
+    //   let on_worker: *void;
+    //   pthread_join(thread, &on_worker);
+    //
+    //   if on_main != 2 || on_worker != 1 {
+    //     trap
+    //   }
+    // }
+    codegen
+        .define(check_thread_locals_func_id, |module, fbuilder, _| {
+            // increment_tls();
+            // let on_main = increment_tls();
+            let on_main = {
+                let fref = module.declare_func_in_func(increment_tls_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[]);
+                let call = fbuilder.ins().call(fref, &[]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            // Both `pthread_create` and `pthread_join` write their result through a pointer, so
+            // we give them a stack slot each.
+            let [thread_slot, retval_slot] = [(); 2].map(|_| {
+                fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    size_t.bytes(),
+                    size_t.bytes().trailing_zeros() as u8,
+                ))
+            });
+
+            // let thread: pthread_t;
+            // pthread_create(&thread, null, worker, null);
+            let thread = {
+                let thread_ptr = fbuilder.ins().stack_addr(size_t, thread_slot, 0);
+                let null = fbuilder.ins().iconst(size_t, 0);
+
+                let worker_ref = module.declare_func_in_func(worker_func_id, fbuilder.func);
+                let worker = fbuilder.ins().func_addr(size_t, worker_ref);
+
+                let fref = module.declare_func_in_func(pthread_create_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[thread_ptr, null, worker, null]);
+
+                fbuilder.ins().stack_load(size_t, thread_slot, 0)
+            };
+
+            // let on_worker: *void;
+            // pthread_join(thread, &on_worker);
+            let on_worker = {
+                let retval_ptr = fbuilder.ins().stack_addr(size_t, retval_slot, 0);
+
+                let fref = module.declare_func_in_func(pthread_join_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[thread, retval_ptr]);
+
+                fbuilder.ins().stack_load(size_t, retval_slot, 0)
+            };
+
+            // if on_main != 2 || on_worker != 1 { trap }
+            {
+                let main_ok = fbuilder.ins().icmp_imm(cl::IntCC::Equal, on_main, 2);
+                let worker_ok = fbuilder.ins().icmp_imm(cl::IntCC::Equal, on_worker, 1);
+                let ok = fbuilder.ins().band(main_ok, worker_ok);
+
+                const TRAP_SHARED_THREAD_LOCAL: u8 = 103;
+                fbuilder
+                    .ins()
+                    .trapz(ok, cl::TrapCode::user(TRAP_SHARED_THREAD_LOCAL).unwrap());
+            }
+
+            fbuilder.ins().return_(&[]);
+        })
+        .unwrap();
+
+    check_thread_locals_func_id
+}
@@ -0,0 +1,182 @@
+//! This example shows how to allocate memory on the heap using `malloc` and `free`.
+//!
+//! ```
+//! extern "C" fn malloc(size: usize) -> *mut u8;
+//! extern "C" fn free(ptr: *mut u8);
+//!
+//! fn main() -> i32 {
+//!   let numbers: *mut i32 = malloc(4 * 4);
+//!
+//!   for i in 0..4 {
+//!     numbers[i] = i + 1;
+//!   }
+//!
+//!   let sum = 0;
+//!   for i in 0..4 {
+//!     sum += numbers[i];
+//!   }
+//!
+//!   free(numbers);
+//!   return sum;
+//! }
+//! ```
+//!
+//! The other examples allocate their memory in stack slots, which are freed automatically once the
+//! function returns. Heap memory outlives the function that allocated it, at the cost of having to
+//! free it manually.
+//!
+//! Once we have the pointer returned by `malloc`, it's just an integer. Reading and writing
+//! elements is done by computing `ptr + index * size_of_element` ourselves.
+//!
+//! The main function returns the sum of `[1, 2, 3, 4]`, so the exit code will be `10`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example heap -- -o heap.o`
+//! `$ clang heap.o -o heap`
+//! `$ ./heap; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example heap -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilder, FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+const ELEMENT_TYPE: cl::Type = cl::types::I32;
+const LENGTH: i64 = 4;
+
+fn main() {
+    skip_boilerplate(b"heap", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // extern "C" fn malloc(size: usize) -> *mut u8;
+    let malloc_func_id = declare_function_from_types(
+        codegen.module,
+        "malloc",
+        Linkage::Import,
+        &[size_t],
+        &[size_t],
+        None,
+    );
+
+    // extern "C" fn free(ptr: *mut u8);
+    let free_func_id = declare_function_from_types(
+        codegen.module,
+        "free",
+        Linkage::Import,
+        &[size_t],
+        &[],
+        None,
+    );
+
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let numbers: *mut i32 = malloc(4 * 4);
+            let numbers = {
+                let size = fbuilder
+                    .ins()
+                    .iconst(size_t, LENGTH * ELEMENT_TYPE.bytes() as i64);
+
+                let fref = module.declare_func_in_func(malloc_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[size]);
+                let ptr = fbuilder.inst_results(call)[0];
+
+                // `malloc` returns null if it failed to allocate. Writing to it would be undefined
+                // behavior, so we trap instead.
+                //
+                // `trapz` traps if the value is zero.
+                const TRAP_OUT_OF_MEMORY: u8 = 101;
+                fbuilder
+                    .ins()
+                    .trapz(ptr, cl::TrapCode::user(TRAP_OUT_OF_MEMORY).unwrap());
+
+                ptr
+            };
+
+            // for i in 0..4 {
+            //   numbers[i] = i + 1;
+            // }
+            //
+            // To keep the example focused, we unroll the loop here. See the `loops` example for
+            // how to lower it as an actual loop.
+            for i in 0..LENGTH {
+                let i = fbuilder.ins().iconst(size_t, i);
+
+                let value = {
+                    let i = fbuilder.ins().ireduce(ELEMENT_TYPE, i);
+                    fbuilder.ins().iadd_imm(i, 1)
+                };
+
+                let element_ptr = element_address(fbuilder, numbers, i);
+                fbuilder
+                    .ins()
+                    .store(cl::MemFlags::trusted(), value, element_ptr, 0);
+            }
+
+            // let sum = 0;
+            // for i in 0..4 {
+            //   sum += numbers[i];
+            // }
+            let sum = {
+                let mut sum = fbuilder.ins().iconst(ELEMENT_TYPE, 0);
+
+                for i in 0..LENGTH {
+                    let i = fbuilder.ins().iconst(size_t, i);
+
+                    let element_ptr = element_address(fbuilder, numbers, i);
+                    let value =
+                        fbuilder
+                            .ins()
+                            .load(ELEMENT_TYPE, cl::MemFlags::trusted(), element_ptr, 0);
+
+                    sum = fbuilder.ins().iadd(sum, value);
+                }
+
+                sum
+            };
+
+            // free(numbers);
+            //
+            // The pointer must not be used after this point
+            {
+                let fref = module.declare_func_in_func(free_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[numbers]);
+            }
+
+            // return sum;
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+}
+
+// Compute the address of `ptr[index]`
+//
+// ptr + index * size_of_element
+fn element_address(
+    fbuilder: &mut FunctionBuilder<'_>,
+    ptr: cl::Value,
+    index: cl::Value,
+) -> cl::Value {
+    let offset = fbuilder.ins().imul_imm(index, ELEMENT_TYPE.bytes() as i64);
+
+    fbuilder.ins().iadd(ptr, offset)
+}
@@ -0,0 +1,159 @@
+//! This example shows how to lower conditional branching such as `if`/`else` expressions.
+//!
+//! ```
+//! fn abs(x: i32) -> i32 {
+//!   if x < 0 { -x } else { x }
+//! }
+//! ```
+//!
+//! Cranelift doesn't have any structured control flow. Instead, a function is made out of blocks
+//! which end by jumping to other blocks. An `if` expression becomes a conditional branch into two
+//! blocks, one for each branch, which then both jump to a third block where the control flow merges.
+//!
+//! Since `if` is an expression that produces a value, we need some way to get the result of
+//! whichever branch was taken into the merge block. Cranelift uses block parameters for this, which
+//! are similar to function parameters. Each branch passes its result as an argument when jumping to
+//! the merge block.
+//!
+//! The main function returns `abs(-5)`, so the exit code will be `5`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example if-else -- -o if-else.o`
+//! `$ clang if-else.o -o if-else`
+//! `$ ./if-else; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example if-else -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"if-else", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn abs(x: i32) -> i32;
+    let abs_func_id = declare_function_from_types(
+        codegen.module,
+        "abs",
+        Linkage::Local,
+        &[cl::types::I32],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn main() -> i32 {
+    //   return abs(-5);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let x = fbuilder.ins().iconst(cl::types::I32, -5);
+
+            let fref = module.declare_func_in_func(abs_func_id, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &[x]);
+            let result = fbuilder.inst_results(call)[0];
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn abs(x: i32) -> i32 {
+    //   return if x < 0 { -x } else { x };
+    // }
+    //
+    // // -- Although what we'll actually be lowering it into is something more like -- //
+    //
+    // block0(x):
+    //   if x < 0 { goto block1 } else { goto block2 }
+    //
+    // block1:
+    //   goto block3(-x)
+    //
+    // block2:
+    //   goto block3(x)
+    //
+    // block3(result):
+    //   return result
+    codegen
+        .define(abs_func_id, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+
+            // Declare all the blocks up front, since the conditional branch needs to refer to them
+            // before we've switched to them.
+            let then_block = fbuilder.create_block();
+            let else_block = fbuilder.create_block();
+            let merge_block = fbuilder.create_block();
+
+            // The merge block receives the value of whichever branch was taken
+            fbuilder.append_block_param(merge_block, cl::types::I32);
+
+            // if x < 0
+            {
+                let is_negative = fbuilder.ins().icmp_imm(cl::IntCC::SignedLessThan, x, 0);
+
+                // `brif` jumps to the first block if the condition is non-zero, otherwise to the second.
+                fbuilder
+                    .ins()
+                    .brif(is_negative, then_block, &[], else_block, &[]);
+            }
+
+            // A block can be sealed once all the branches into it have been created. Sealing tells
+            // Cranelift that no more predecessors will be added, so it can finish resolving any
+            // variables used inside the block.
+            //
+            // The only predecessor of both branches is the `brif` above, so they can be sealed right away.
+            fbuilder.seal_block(then_block);
+            fbuilder.seal_block(else_block);
+
+            // { -x }
+            {
+                fbuilder.switch_to_block(then_block);
+
+                let negated = fbuilder.ins().ineg(x);
+
+                fbuilder.ins().jump(merge_block, &[negated.into()]);
+            }
+
+            // else { x }
+            {
+                fbuilder.switch_to_block(else_block);
+
+                fbuilder.ins().jump(merge_block, &[x.into()]);
+            }
+
+            // The merge block has one predecessor from each branch. Those jumps didn't exist until
+            // we had lowered both branches, which is why it has to be sealed last.
+            //
+            // Sealing it any earlier would trip an assertion in Cranelift when the jump from the else
+            // branch is added.
+            fbuilder.seal_block(merge_block);
+
+            // return result;
+            {
+                fbuilder.switch_to_block(merge_block);
+
+                let result = fbuilder.block_params(merge_block)[0];
+
+                fbuilder.ins().return_(&[result]);
+            }
+        })
+        .unwrap();
+}
@@ -0,0 +1,214 @@
+//! This example shows how a compiler can inline a small function into its caller, by copying the
+//! callee's instructions instead of emitting a `call`.
+//!
+//! ```
+//! fn add(a: i32, b: i32) -> i32 {
+//!   return a + b;
+//! }
+//!
+//! fn main() -> i32 {
+//!   let inlined = #[inline] add(2, 3);
+//!   let called = add(10, 20);
+//!   return inlined + called;
+//! }
+//! ```
+//!
+//! Cranelift compiles one function at a time and never looks inside the functions it calls, so it
+//! can't inline on its own. A frontend which has the IR of both functions can do it instead.
+//!
+//! Inlining a callee with a single block works like this:
+//!
+//! * Map each block parameter of the callee's entry block to the argument we would've passed.
+//! * Copy every instruction of the callee into the caller, replacing the values it uses with the
+//!   caller's values they map to. Each result of the copied instruction is mapped as well, so
+//!   later instructions can find it.
+//! * The values given to the callee's `return` become the results of the "call".
+//!
+//! Besides skipping the overhead of the call itself, the copied instructions are now in the same
+//! function as the arguments. With `-O speed`, the optimizer folds `2 + 3` into `5` for the inlined
+//! call, but has to leave the real call to `add` alone.
+//!
+//! To keep this example small, `inline_call` only handles callees with a single block which don't
+//! call other functions or refer to their own stack slots or globals. A callee with multiple blocks
+//! would need each of its blocks recreated in the caller, with every `return` replaced by a jump
+//! to a block after the call that receives the returned values as block parameters.
+//!
+//! Inlining every function isn't a good idea either, since the copies make the program larger.
+//! Compilers usually only inline functions which are small or called in few places.
+//!
+//! The main function returns `5 + 30`, so the exit code will be `35`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example inlining -- -o inlining.o`
+//! `$ clang inlining.o -o inlining`
+//! `$ ./inlining; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example inlining -- --jit`
+
+use std::collections::HashMap;
+
+use cranelift::codegen::ir::{Function, InstBuilderBase, Opcode, ValueListPool};
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilder, FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"inlining", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn add(a: i32, b: i32) -> i32;
+    let add_func_id = declare_function_from_types(
+        codegen.module,
+        "add",
+        Linkage::Local,
+        &[cl::types::I32, cl::types::I32],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn add(a: i32, b: i32) -> i32 {
+    //   return a + b;
+    // }
+    codegen
+        .define(add_func_id, |_, fbuilder, entry| {
+            let a = fbuilder.block_params(entry)[0];
+            let b = fbuilder.block_params(entry)[1];
+
+            let sum = fbuilder.ins().iadd(a, b);
+
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+
+    // Keep the IR of `add` around so that it can be inlined.
+    //
+    // The context still holds it after defining `add`, although it's been through the same passes
+    // as when compiling. It's still valid IR, so it can be copied all the same. A real compiler
+    // would instead inline from its own IR, before lowering to Cranelift.
+    let add_ir = codegen.ctx.func.clone();
+
+    // fn main() -> i32 {
+    //   let inlined = #[inline] add(2, 3);
+    //   let called = add(10, 20);
+    //   return inlined + called;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let inlined = #[inline] add(2, 3);
+            //
+            // No `call` is emitted here, instead the `iadd` of `add` is copied into `main`
+            let inlined = {
+                let two = fbuilder.ins().iconst(cl::types::I32, 2);
+                let three = fbuilder.ins().iconst(cl::types::I32, 3);
+
+                inline_call(fbuilder, &add_ir, &[two, three])[0]
+            };
+
+            // let called = add(10, 20);
+            let called = {
+                let ten = fbuilder.ins().iconst(cl::types::I32, 10);
+                let twenty = fbuilder.ins().iconst(cl::types::I32, 20);
+
+                let fref = module.declare_func_in_func(add_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[ten, twenty]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            // return inlined + called;
+            let result = fbuilder.ins().iadd(inlined, called);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
+
+// Copy the body of `callee` into the current block, as if it had been called with `args`.
+//
+// Returns the values that the callee would've returned.
+fn inline_call(
+    fbuilder: &mut FunctionBuilder<'_>,
+    callee: &Function,
+    args: &[cl::Value],
+) -> Vec<cl::Value> {
+    let entry = callee.layout.entry_block().unwrap();
+    assert_eq!(
+        callee.layout.blocks().count(),
+        1,
+        "only single-block callees can be inlined"
+    );
+
+    // Maps values of the callee to the values in the caller that replace them, starting with
+    // the parameters being replaced by the arguments.
+    let mut values: HashMap<cl::Value, cl::Value> = callee
+        .dfg
+        .block_params(entry)
+        .iter()
+        .copied()
+        .zip(args.iter().copied())
+        .collect();
+
+    for inst in callee.layout.block_insts(entry) {
+        let mut data = callee.dfg.insts[inst];
+        let opcode = data.opcode();
+
+        // return v0
+        //
+        // Instead of returning from the caller, the returned values become the result of the call
+        if opcode == Opcode::Return {
+            return callee
+                .dfg
+                .inst_args(inst)
+                .iter()
+                .map(|v| values[v])
+                .collect();
+        }
+
+        // Instructions such as `call` or `brif` keep their arguments in a list owned by the
+        // callee's function, and refer to functions or blocks which don't exist in the caller.
+        assert!(
+            !opcode.is_call() && !opcode.is_branch(),
+            "cannot inline `{opcode}`"
+        );
+
+        // iadd v0, v1 => iadd v10, v11
+        //
+        // Since the instruction doesn't use a list, its arguments are stored inline and the pool
+        // is never touched.
+        for arg in data.arguments_mut(&mut ValueListPool::new()) {
+            *arg = values[arg];
+        }
+
+        // The controlling type variable is what decides the result type of polymorphic
+        // instructions such as `iadd`, which could otherwise be any integer type.
+        let ctrl_typevar = callee.dfg.ctrl_typevar(inst);
+        let (new_inst, dfg) = fbuilder.ins().build(data, ctrl_typevar);
+
+        for (&old, &new) in callee
+            .dfg
+            .inst_results(inst)
+            .iter()
+            .zip(dfg.inst_results(new_inst))
+        {
+            values.insert(old, new);
+        }
+    }
+
+    panic!("callee has no return");
+}
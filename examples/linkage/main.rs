@@ -0,0 +1,263 @@
+//! This example shows how the `Linkage` of a declaration decides the visibility of its symbol in
+//! the object file, and how calls to it are relocated.
+//!
+//! ```
+//! static fn helper() -> i32 { return 1; }                      // Linkage::Local
+//! #[visibility = "hidden"] fn hidden_helper() -> i32 { return 2; } // Linkage::Hidden
+//! #[weak] fn overridable() -> i32 { return 3; }                // Linkage::Preemptible
+//! extern "C" fn abs(x: i32) -> i32;                            // Linkage::Import
+//!
+//! // Linkage::Export
+//! fn main() -> i32 {
+//!   return helper() + hidden_helper() + overridable() + abs(-4);
+//! }
+//! ```
+//!
+//! * `Local` symbols are only visible within the object file, like a `static` function in C. Use
+//!   this for internal helpers, since the linker can't confuse them with a symbol of the same name
+//!   from another object file. `nm` shows them with a lowercase `t`.
+//! * `Hidden` symbols are visible to the other object files linked into the same executable or
+//!   shared library, but aren't exported from it. This is what most functions of a multi-file
+//!   compiler output want to be. `nm` shows them as `T`, while `readelf -s` shows them as `HIDDEN`.
+//! * `Export` symbols are visible to everything, including other shared libraries which the
+//!   program is dynamically linked with. `main` has to be exported so that libc can find it.
+//! * `Preemptible` symbols are exported as well, but may be replaced by a definition with the same
+//!   name elsewhere. Cranelift emits these as weak symbols, which `nm` shows as `W`.
+//! * `Import` symbols aren't defined by us at all, and are left for the linker to resolve. `nm`
+//!   shows them as `U`.
+//!
+//! The linkage also affects how calls are emitted. Since `Local`, `Hidden` and `Export` functions
+//! are known to be defined by us and can't be replaced, Cranelift marks them as `colocated` and
+//! calls them directly. `Preemptible` and `Import` functions might end up anywhere, so with
+//! `is_pic` the call goes through an address loaded from the GOT instead. This can be seen with
+//! `objdump -dr linkage.o`, where `overridable` and `abs` are called through `R_X86_64_GOTPCREL`
+//! relocations.
+//!
+//! After finishing the module, the example checks the scope of each symbol in the `ObjectProduct`.
+//!
+//! The main function returns `1 + 2 + 3 + 4`, so the exit code will be `10`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example linkage -- -o linkage.o`
+//! `$ nm linkage.o`
+//! `$ clang linkage.o -o linkage`
+//! `$ ./linkage; echo $?`
+//!
+//! Since `overridable` is weak, linking in another definition of it replaces ours without any
+//! errors about duplicate symbols, and changes the exit code to `37`.
+//!
+//! `$ echo 'int overridable(void) { return 30; }' > override.c`
+//! `$ clang linkage.o override.c -o linkage`
+//! `$ ./linkage; echo $?`
+//!
+//! There's no `--jit` mode for this example, since the JIT doesn't produce an object file.
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{Configurable, InstBuilder};
+use cranelift_examples::{
+    BoilerplateError, Codegen, DEFAULT_TARGET_TRIPLE, declare_function_from_types, declare_main,
+    parse_arguments,
+};
+use cranelift_module::{FuncId, Linkage, Module};
+use cranelift_object::object::write::SymbolScope;
+use cranelift_object::{ObjectBuilder, ObjectModule, ObjectProduct};
+use std::{fs::File, io::Write};
+
+fn main() {
+    let args = parse_arguments();
+
+    // The symbols are checked in the object file, which the JIT doesn't produce
+    assert!(
+        !args.get_flag("jit"),
+        "{}",
+        BoilerplateError::JitUnsupported
+    );
+
+    let triple = args
+        .get_one::<String>("target-triple")
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_TARGET_TRIPLE.to_string());
+
+    println!(" targeting {triple} ");
+
+    // The same setup as `skip_boilerplate`, except that we hold on to the `ObjectProduct` so that
+    // its symbols can be checked. See the `output-a-binary` example for what each step does.
+    let isa = {
+        let mut builder = cl::settings::builder();
+
+        let opt_level = args.get_one::<String>("opt-level").unwrap();
+        builder.set("opt_level", opt_level).unwrap();
+        builder.enable("is_pic").unwrap();
+
+        let flags = cl::settings::Flags::new(builder);
+
+        cl::isa::lookup_by_name(&triple)
+            .unwrap()
+            .finish(flags)
+            .unwrap()
+    };
+
+    let mut module = {
+        let libcall_names = cranelift_module::default_libcall_names();
+        let builder = ObjectBuilder::new(isa.clone(), b"linkage", libcall_names).unwrap();
+        ObjectModule::new(builder)
+    };
+
+    let mut ctx = cl::codegen::Context::new();
+    let mut fctx = cl::FunctionBuilderContext::new();
+
+    let func_ids = define_functions(&mut Codegen::new(&mut ctx, &mut fctx, &mut module));
+
+    let product = module.finish();
+
+    check_symbols(&product, &func_ids);
+
+    match args.get_one::<String>("output") {
+        Some(path) => {
+            let bytes = product.emit().unwrap();
+
+            let mut f = File::create(path).unwrap();
+            f.write_all(&bytes).unwrap();
+
+            println!(" wrote output to {path} ");
+        }
+        None => {
+            println!(" no `-o` path specified ");
+        }
+    }
+}
+
+struct FuncIds {
+    main: FuncId,
+    helper: FuncId,
+    hidden_helper: FuncId,
+    overridable: FuncId,
+    abs: FuncId,
+}
+
+fn define_functions(codegen: &mut Codegen<'_>) -> FuncIds {
+    let main_func_id = declare_main(codegen.module);
+
+    // fn helper() -> i32;
+    // fn hidden_helper() -> i32;
+    // fn overridable() -> i32;
+    let [helper_func_id, hidden_helper_func_id, overridable_func_id] = [
+        ("helper", Linkage::Local),
+        ("hidden_helper", Linkage::Hidden),
+        ("overridable", Linkage::Preemptible),
+    ]
+    .map(|(name, linkage)| {
+        declare_function_from_types(codegen.module, name, linkage, &[], &[cl::types::I32], None)
+    });
+
+    // extern "C" fn abs(x: i32) -> i32;
+    let abs_func_id = declare_function_from_types(
+        codegen.module,
+        "abs",
+        Linkage::Import,
+        &[cl::types::I32],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn helper() -> i32 { return 1; }
+    // fn hidden_helper() -> i32 { return 2; }
+    // fn overridable() -> i32 { return 3; }
+    //
+    // The linkage was picked when declaring, so all three are defined the same way
+    for (func_id, n) in [
+        (helper_func_id, 1),
+        (hidden_helper_func_id, 2),
+        (overridable_func_id, 3),
+    ] {
+        codegen
+            .define(func_id, |_, fbuilder, _| {
+                let n = fbuilder.ins().iconst(cl::types::I32, n);
+                fbuilder.ins().return_(&[n]);
+            })
+            .unwrap();
+    }
+
+    // fn main() -> i32 {
+    //   return helper() + hidden_helper() + overridable() + abs(-4);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // Whether each call is `colocated` comes from the linkage of the declaration, which
+            // can be seen in the printed IR
+            let results =
+                [helper_func_id, hidden_helper_func_id, overridable_func_id].map(|func_id| {
+                    let fref = module.declare_func_in_func(func_id, fbuilder.func);
+                    let call = fbuilder.ins().call(fref, &[]);
+                    fbuilder.inst_results(call)[0]
+                });
+
+            let abs = {
+                let x = fbuilder.ins().iconst(cl::types::I32, -4);
+                let fref = module.declare_func_in_func(abs_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[x]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            let sum = results
+                .into_iter()
+                .fold(abs, |sum, v| fbuilder.ins().iadd(sum, v));
+
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+
+    FuncIds {
+        main: main_func_id,
+        helper: helper_func_id,
+        hidden_helper: hidden_helper_func_id,
+        overridable: overridable_func_id,
+        abs: abs_func_id,
+    }
+}
+
+// The `ObjectProduct` maps each function to the symbol it was given in the object file, which
+// lets us check what each linkage turned into.
+fn check_symbols(product: &ObjectProduct, ids: &FuncIds) {
+    let symbol = |func_id: FuncId| {
+        let (symbol_id, _) = product.functions[func_id].unwrap();
+        product.object.symbol(symbol_id)
+    };
+
+    // Only visible within this object file
+    let helper = symbol(ids.helper);
+    assert_eq!(helper.scope, SymbolScope::Compilation);
+
+    // Visible to the linker, but not exported from the final executable
+    let hidden_helper = symbol(ids.hidden_helper);
+    assert_eq!(hidden_helper.scope, SymbolScope::Linkage);
+
+    // Visible to everything
+    let main = symbol(ids.main);
+    assert_eq!(main.scope, SymbolScope::Dynamic);
+    assert!(!main.weak);
+
+    // Visible to everything, and may be replaced by another definition
+    let overridable = symbol(ids.overridable);
+    assert_eq!(overridable.scope, SymbolScope::Dynamic);
+    assert!(overridable.weak);
+
+    // Left for the linker to find
+    let abs = symbol(ids.abs);
+    assert!(abs.is_undefined());
+
+    for sym in [helper, hidden_helper, main, overridable, abs] {
+        println!(
+            " {}: {:?}{}{}",
+            sym.name().unwrap(),
+            sym.scope,
+            if sym.weak { ", weak" } else { "" },
+            if sym.is_undefined() {
+                ", undefined"
+            } else {
+                ""
+            },
+        );
+    }
+}
@@ -0,0 +1,419 @@
+//! This example shows how to lower loops.
+//!
+//! ```
+//! fn sum_to(n: i32) -> i32 {
+//!   let mut acc = 0;
+//!   for i in 1..=n {
+//!     acc += i;
+//!   }
+//!   return acc;
+//! }
+//! ```
+//!
+//! Cranelift IR is in SSA form, which means that every value is assigned exactly once. So how do we
+//! lower `acc += i` when `acc` needs to change with every iteration?
+//!
+//! Just like with the merge block in the `if-else` example, the answer is block parameters. The loop
+//! header takes `i` and `acc` as block parameters, and the end of the loop body jumps back to the
+//! header with the updated values. This is what other compilers call "phi nodes".
+//!
+//! We'll lower the same function twice. Once by managing the block parameters ourselves, and once
+//! using Cranelift's `Variable` API, which creates the block parameters for us.
+//!
+//! The `Variable` API pays off once the control flow gets more complicated, since keeping track of
+//! which blocks need which parameters by hand gets tedious quickly. `sum_evens_with_variables` only
+//! updates `acc` in some iterations, which needs another block parameter where both paths meet.
+//!
+//! ```
+//! fn sum_evens_with_variables(n: i32) -> i32 {
+//!   let mut acc = 0;
+//!   for i in 1..=n {
+//!     if i % 2 == 0 {
+//!       acc += i;
+//!     }
+//!   }
+//!   return acc;
+//! }
+//! ```
+//!
+//! The main function returns `sum_to(5) + sum_to_with_variables(5) + sum_evens_with_variables(6)`,
+//! so the exit code will be `15 + 15 + 12 = 42`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example loops -- -o loops.o`
+//! `$ clang loops.o -o loops`
+//! `$ ./loops; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example loops -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"loops", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn sum_to(n: i32) -> i32;
+    // fn sum_to_with_variables(n: i32) -> i32;
+    // fn sum_evens_with_variables(n: i32) -> i32;
+    let [
+        sum_to_func_id,
+        sum_to_with_variables_func_id,
+        sum_evens_with_variables_func_id,
+    ] = [
+        "sum_to",
+        "sum_to_with_variables",
+        "sum_evens_with_variables",
+    ]
+    .map(|name| {
+        declare_function_from_types(
+            codegen.module,
+            name,
+            Linkage::Local,
+            &[cl::types::I32],
+            &[cl::types::I32],
+            None,
+        )
+    });
+
+    // fn main() -> i32 {
+    //   return sum_to(5) + sum_to_with_variables(5) + sum_evens_with_variables(6);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let [t, u, v] = [
+                (sum_to_func_id, 5),
+                (sum_to_with_variables_func_id, 5),
+                (sum_evens_with_variables_func_id, 6),
+            ]
+            .map(|(id, n)| {
+                let n = fbuilder.ins().iconst(cl::types::I32, n);
+
+                let fref = module.declare_func_in_func(id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[n]);
+                fbuilder.inst_results(call)[0]
+            });
+
+            let sum = fbuilder.ins().iadd(t, u);
+            let sum = fbuilder.ins().iadd(sum, v);
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+
+    // fn sum_to(n: i32) -> i32 {
+    //   let mut acc = 0;
+    //   for i in 1..=n {
+    //     acc += i;
+    //   }
+    //   return acc;
+    // }
+    //
+    // // -- Although what we'll actually be lowering it into is something more like -- //
+    //
+    // block0(n):
+    //   goto block1(1, 0)
+    //
+    // block1(i, acc):
+    //   if i <= n { goto block2 } else { goto block3 }
+    //
+    // block2:
+    //   goto block1(i + 1, acc + i)
+    //
+    // block3:
+    //   return acc
+    codegen
+        .define(sum_to_func_id, |_, fbuilder, entry| {
+            let n = fbuilder.block_params(entry)[0];
+
+            let header = fbuilder.create_block();
+            let body = fbuilder.create_block();
+            let exit = fbuilder.create_block();
+
+            // The loop header receives the current `i` and `acc`, both from the entry block and
+            // from the end of each iteration.
+            fbuilder.append_block_param(header, cl::types::I32);
+            fbuilder.append_block_param(header, cl::types::I32);
+
+            // let mut acc = 0;
+            // for i in 1..=n
+            //
+            // Enter the loop with the initial values
+            {
+                let i = fbuilder.ins().iconst(cl::types::I32, 1);
+                let acc = fbuilder.ins().iconst(cl::types::I32, 0);
+
+                fbuilder.ins().jump(header, &[i.into(), acc.into()]);
+            }
+
+            // Check whether we should do another iteration
+            {
+                fbuilder.switch_to_block(header);
+
+                let i = fbuilder.block_params(header)[0];
+
+                let in_range = fbuilder.ins().icmp(cl::IntCC::SignedLessThanOrEqual, i, n);
+                fbuilder.ins().brif(in_range, body, &[], exit, &[]);
+
+                // The header is the only predecessor of the body and the exit, so they can be
+                // sealed right away.
+                fbuilder.seal_block(body);
+                fbuilder.seal_block(exit);
+            }
+
+            // acc += i;
+            {
+                fbuilder.switch_to_block(body);
+
+                let i = fbuilder.block_params(header)[0];
+                let acc = fbuilder.block_params(header)[1];
+
+                let acc = fbuilder.ins().iadd(acc, i);
+                let i = fbuilder.ins().iadd_imm(i, 1);
+
+                // Jump back to the header with the updated values
+                fbuilder.ins().jump(header, &[i.into(), acc.into()]);
+            }
+
+            // The header has two predecessors. The jump from the entry block, and the back-edge
+            // from the end of the body. The back-edge doesn't exist until we've lowered the entire
+            // body, which is why the header can only be sealed now.
+            fbuilder.seal_block(header);
+
+            // return acc;
+            {
+                fbuilder.switch_to_block(exit);
+
+                let acc = fbuilder.block_params(header)[1];
+
+                fbuilder.ins().return_(&[acc]);
+            }
+        })
+        .unwrap();
+
+    // fn sum_to_with_variables(n: i32) -> i32 {
+    //   let mut acc = 0;
+    //   for i in 1..=n {
+    //     acc += i;
+    //   }
+    //   return acc;
+    // }
+    //
+    // With the `Variable` API, we can treat `i` and `acc` as mutable variables instead.
+    //
+    // `def_var` assigns a new value to the variable, and `use_var` gets the current value. When we
+    // `use_var` inside the header, Cranelift doesn't yet know all the values the variable might
+    // have, so it adds block parameters to the header for us. This is also why sealing matters,
+    // as it's what tells Cranelift that it has now seen every predecessor.
+    //
+    // The emitted CLIF will be the same as for `sum_to`, apart from the numbering of the values.
+    codegen
+        .define(sum_to_with_variables_func_id, |_, fbuilder, entry| {
+            let n = fbuilder.block_params(entry)[0];
+
+            let i = fbuilder.declare_var(cl::types::I32);
+            let acc = fbuilder.declare_var(cl::types::I32);
+
+            let header = fbuilder.create_block();
+            let body = fbuilder.create_block();
+            let exit = fbuilder.create_block();
+
+            // let mut acc = 0;
+            // for i in 1..=n
+            {
+                let one = fbuilder.ins().iconst(cl::types::I32, 1);
+                let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+                fbuilder.def_var(i, one);
+                fbuilder.def_var(acc, zero);
+
+                // No block arguments, Cranelift will fill them in for us
+                fbuilder.ins().jump(header, &[]);
+            }
+
+            // Check whether we should do another iteration
+            {
+                fbuilder.switch_to_block(header);
+
+                let i = fbuilder.use_var(i);
+
+                let in_range = fbuilder.ins().icmp(cl::IntCC::SignedLessThanOrEqual, i, n);
+                fbuilder.ins().brif(in_range, body, &[], exit, &[]);
+
+                fbuilder.seal_block(body);
+                fbuilder.seal_block(exit);
+            }
+
+            // acc += i;
+            {
+                fbuilder.switch_to_block(body);
+
+                let new_acc = {
+                    let acc = fbuilder.use_var(acc);
+                    let i = fbuilder.use_var(i);
+                    fbuilder.ins().iadd(acc, i)
+                };
+                fbuilder.def_var(acc, new_acc);
+
+                let new_i = {
+                    let i = fbuilder.use_var(i);
+                    fbuilder.ins().iadd_imm(i, 1)
+                };
+                fbuilder.def_var(i, new_i);
+
+                fbuilder.ins().jump(header, &[]);
+            }
+
+            // Just like before, the header can only be sealed once the back-edge exists
+            fbuilder.seal_block(header);
+
+            // return acc;
+            {
+                fbuilder.switch_to_block(exit);
+
+                let acc = fbuilder.use_var(acc);
+
+                fbuilder.ins().return_(&[acc]);
+            }
+        })
+        .unwrap();
+    // fn sum_evens_with_variables(n: i32) -> i32 {
+    //   let mut acc = 0;
+    //   for i in 1..=n {
+    //     if i % 2 == 0 {
+    //       acc += i;
+    //     }
+    //   }
+    //   return acc;
+    // }
+    //
+    // `acc` is only assigned in the `then` block, so the block after the `if` can be reached with
+    // either the old or the new `acc`. When lowering by hand we'd have to give that block a
+    // parameter and pass the right `acc` from both predecessors, while `use_var` adds it for us.
+    //
+    // `i` isn't assigned inside the `if`, so every path reaches the end of the body with the same
+    // `i` and no block parameter is added for it there.
+    //
+    // block2:
+    //     v5 = band_imm.i32 v3, 1
+    //     brif v5, block4(v6), block3
+    //
+    // block3:
+    //     v7 = iadd.i32 v6, v3
+    //     jump block4(v7)
+    //
+    // block4(v10: i32):
+    //     v9 = iadd_imm.i32 v3, 1
+    //     jump block1(v9, v10)
+    codegen
+        .define(sum_evens_with_variables_func_id, |_, fbuilder, entry| {
+            let n = fbuilder.block_params(entry)[0];
+
+            let i = fbuilder.declare_var(cl::types::I32);
+            let acc = fbuilder.declare_var(cl::types::I32);
+
+            let header = fbuilder.create_block();
+            let body = fbuilder.create_block();
+            let then_block = fbuilder.create_block();
+            let latch = fbuilder.create_block();
+            let exit = fbuilder.create_block();
+
+            // let mut acc = 0;
+            // for i in 1..=n
+            {
+                let one = fbuilder.ins().iconst(cl::types::I32, 1);
+                let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+                fbuilder.def_var(i, one);
+                fbuilder.def_var(acc, zero);
+
+                fbuilder.ins().jump(header, &[]);
+            }
+
+            // Check whether we should do another iteration
+            {
+                fbuilder.switch_to_block(header);
+
+                let i = fbuilder.use_var(i);
+
+                let in_range = fbuilder.ins().icmp(cl::IntCC::SignedLessThanOrEqual, i, n);
+                fbuilder.ins().brif(in_range, body, &[], exit, &[]);
+
+                fbuilder.seal_block(body);
+                fbuilder.seal_block(exit);
+            }
+
+            // if i % 2 == 0
+            {
+                fbuilder.switch_to_block(body);
+
+                let i = fbuilder.use_var(i);
+                let is_odd = fbuilder.ins().band_imm(i, 1);
+
+                // Odd numbers skip straight to the end of the body
+                fbuilder.ins().brif(is_odd, latch, &[], then_block, &[]);
+
+                fbuilder.seal_block(then_block);
+            }
+
+            // acc += i;
+            {
+                fbuilder.switch_to_block(then_block);
+
+                let new_acc = {
+                    let acc = fbuilder.use_var(acc);
+                    let i = fbuilder.use_var(i);
+                    fbuilder.ins().iadd(acc, i)
+                };
+                fbuilder.def_var(acc, new_acc);
+
+                fbuilder.ins().jump(latch, &[]);
+            }
+
+            // Both the body and the `then` block jump here, which is where the block parameter for
+            // `acc` ends up
+            fbuilder.seal_block(latch);
+
+            // i += 1;
+            {
+                fbuilder.switch_to_block(latch);
+
+                let new_i = {
+                    let i = fbuilder.use_var(i);
+                    fbuilder.ins().iadd_imm(i, 1)
+                };
+                fbuilder.def_var(i, new_i);
+
+                fbuilder.ins().jump(header, &[]);
+            }
+
+            // The back-edge now exists, so the header can be sealed
+            fbuilder.seal_block(header);
+
+            // return acc;
+            {
+                fbuilder.switch_to_block(exit);
+
+                let acc = fbuilder.use_var(acc);
+
+                fbuilder.ins().return_(&[acc]);
+            }
+        })
+        .unwrap();
+}
@@ -1,29 +1,101 @@
+use super::types::{Chunk, Convention, FloatWidth, StructPassingMode, Type};
 use super::{VirtualValue, types};
-use crate::types::Type;
 use cranelift::codegen::ir;
-use cranelift::frontend::FuncInstBuilder;
+use cranelift::frontend::{FuncInstBuilder, Switch};
 use cranelift::prelude::InstBuilder;
 use cranelift::prelude::{self as cl, MemFlags};
-use cranelift_module::{FuncId, Module};
+use cranelift_examples::{SigCache, trap_unreachable};
+use cranelift_module::{DataDescription, FuncId, Linkage, Module};
 use cranelift_object::ObjectModule;
+use std::collections::{HashMap, HashSet};
+
+/// A range of bytes in the source code, such as where an expression was written
+///
+/// In a real compiler these would come from the parser and be kept on every AST node.
+#[derive(Clone, Copy, Debug)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// Arithmetic operators on integers, such as `a / b`
+///
+/// Not every operator is used by this example, but they show which ones depend on the signedness.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shr,
+}
+
+/// Comparison operators on integers, such as `a < b`
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Structs larger than this many bytes are copied with `memcpy` instead of field by field
+pub const DEFAULT_MEMCPY_THRESHOLD: u32 = 64;
 
 /// The lowering of a single function to a Cranelift function
-pub struct FuncLower<'a, 'f> {
+pub struct FuncLower<'a, 'f, M: Module = ObjectModule> {
     pub fbuilder: &'a mut cl::FunctionBuilder<'f>,
-    pub module: &'a mut ObjectModule,
+    pub module: &'a mut M,
+    /// See `copy_struct_fields`
+    pub memcpy_threshold: u32,
+    /// Whether `debug_print` emits anything
+    pub debug_prints: bool,
+    /// The calling convention of the function being lowered, decides how structs are received
+    /// and returned
+    pub convention: Convention,
     types: &'a types::LookupTable,
+    // The signatures of the functions being called, see `call_func`
+    sigs: &'a mut SigCache,
+
+    // Stack slots which are no longer used, keyed by their size and alignment
+    //
+    // See `stack_alloc_struct` and `free_stack_struct`
+    free_slots: HashMap<(u32, u8), Vec<ir::StackSlot>>,
+    // The stack slot behind each pointer returned by `stack_alloc_struct`
+    slot_of_ptr: HashMap<cl::Value, ir::StackSlot>,
+    // The stack slot each pointer handed out by `track_view` points into
+    slot_of_view: HashMap<cl::Value, ir::StackSlot>,
+    // Stack slots which have had pointers into them handed out, and can therefore never be reused
+    viewed_slots: HashSet<ir::StackSlot>,
+    // Structs written to the stack only to be passed to the call currently being lowered
+    call_temporaries: Vec<cl::Value>,
 }
 
-impl<'a, 'f> FuncLower<'a, 'f> {
+impl<'a, 'f, M: Module> FuncLower<'a, 'f, M> {
     pub fn new(
         types: &'a types::LookupTable,
         fbuilder: &'a mut cl::FunctionBuilder<'f>,
-        module: &'a mut ObjectModule,
+        module: &'a mut M,
+        sigs: &'a mut SigCache,
     ) -> Self {
         Self {
             fbuilder,
             module,
+            sigs,
+            memcpy_threshold: DEFAULT_MEMCPY_THRESHOLD,
+            debug_prints: false,
+            convention: Convention::Internal,
             types,
+            free_slots: HashMap::new(),
+            slot_of_ptr: HashMap::new(),
+            slot_of_view: HashMap::new(),
+            viewed_slots: HashSet::new(),
+            call_temporaries: vec![],
         }
     }
 
@@ -31,6 +103,19 @@ impl<'a, 'f> FuncLower<'a, 'f> {
         self.fbuilder.ins()
     }
 
+    /// Set the source location of all instructions emitted from now on.
+    ///
+    /// Call this before lowering each node, so that every instruction can be traced back to the
+    /// code it came from. Cranelift carries the location through to the machine code, which is
+    /// what a line table in the debug info or a backtrace would be built from.
+    ///
+    /// A `SourceLoc` is a single opaque `u32`, so we only keep the start of the span. If your
+    /// compiler has multiple files, you could instead use it as an index into a table of spans.
+    pub fn set_span(&mut self, span: Span) {
+        assert!(span.start <= span.end, "malformed span {span:?}");
+        self.fbuilder.set_srcloc(ir::SourceLoc::new(span.start));
+    }
+
     // // In a real compiler, you'd most likely have something like this.
     // // Which would then match over the Expr and call the various helper methods we've defined here.
     //
@@ -38,62 +123,105 @@ impl<'a, 'f> FuncLower<'a, 'f> {
 
     /// Create the entry block with the appropriate Cranelift type signature
     ///
-    /// Maps the Cranelift function parameters to our virtual values.
+    /// Maps the Cranelift function parameters to our virtual values. Structs passed in chunks are
+    /// written to the stack as part of this, so the builder is left positioned in the new block.
     pub fn create_entry_block(&mut self, params: &[Type]) -> (cl::Block, Vec<VirtualValue>) {
         let block = self.fbuilder.create_block();
         self.fbuilder.seal_block(block);
 
-        // See `LookupTable::create_signature` for more information
+        // Block parameters can't be added once the block has instructions, so they're all added
+        // up-front from the signature created by `LookupTable::create_signature`.
+        self.fbuilder.append_block_params_for_function_params(block);
+        self.fbuilder.switch_to_block(block);
+
+        let mut block_params = self.fbuilder.block_params(block).to_vec().into_iter();
+
+        // The out pointer is looked up separately, see `struct_return_pointer`
         if self.fbuilder.func.signature.uses_struct_return_param() {
-            let size_t = self.module.isa().pointer_type();
-            self.fbuilder.append_block_param(block, size_t);
+            block_params.next();
         }
 
         let vparams = params
             .iter()
-            .map(|&p| self.type_to_block_params(block, true, p))
+            .map(|&p| {
+                self.type_to_virtual_value(
+                    &mut |_, _| block_params.next().unwrap(),
+                    Some(self.convention),
+                    p,
+                )
+            })
             .collect();
 
         (block, vparams)
     }
 
-    // Turns a parameter from our source language into Cranelift block parameters.
-    //
-    // Since Cranelift parameters can only be primitive types, a single struct will either
-    // become a single Cranelift pointer block parameter or multiple block parameters.
-    fn type_to_block_params(&mut self, block: cl::Block, is_root: bool, p: Type) -> VirtualValue {
-        self.type_to_virtual_value(
-            &mut |this, clty| this.fbuilder.append_block_param(block, clty),
-            is_root,
-            p,
-        )
-    }
-
     // Maps our abstract Type to our abstract VirtualValue
-    fn type_to_virtual_value<F>(&mut self, f: &mut F, is_root: bool, p: Type) -> VirtualValue
+    //
+    // `root` is the calling convention the value is passed with, or `None` for the fields of a
+    // struct which is passed by scalars.
+    fn type_to_virtual_value<F>(
+        &mut self,
+        f: &mut F,
+        root: Option<Convention>,
+        p: Type,
+    ) -> VirtualValue
     where
         F: FnMut(&mut Self, cl::Type) -> cl::Value,
     {
         match p {
-            Type::Int => {
+            Type::Int | Type::UInt => {
                 let v = f(self, cl::types::I32);
                 VirtualValue::Scalar(v)
             }
-            Type::Struct(type_) => {
-                if is_root
-                    && self.types.struct_passing_mode(type_) == types::StructPassingMode::ByPointer
-                {
-                    let size_t = self.module.isa().pointer_type();
-                    let ptr = f(self, size_t);
-                    VirtualValue::StackStruct { type_, ptr }
-                } else {
-                    let fields = self
-                        .types
-                        .fields_of_struct(type_)
-                        .map(|(_, _, ty)| self.type_to_virtual_value(f, false, ty))
-                        .collect();
+            Type::Usize => {
+                let size_t = self.module.isa().pointer_type();
+                let v = f(self, size_t);
+                VirtualValue::Scalar(v)
+            }
+            Type::Bool => {
+                let v = f(self, cl::types::I8);
+                VirtualValue::Scalar(v)
+            }
+            Type::Float(width) => {
+                let v = f(self, width.cranelift_type());
+                VirtualValue::Scalar(v)
+            }
+            Type::Boxed(type_) => {
+                let size_t = self.module.isa().pointer_type();
+                let ptr = f(self, size_t);
+                VirtualValue::HeapStruct { type_: *type_, ptr }
+            }
+            type_ @ (Type::Struct(_) | Type::Array(..) | Type::Slice(_) | Type::Enum(_)) => {
+                let mode = match root {
+                    Some(convention) => self.types.struct_passing_mode(type_, convention),
+                    None => StructPassingMode::ByScalars,
+                };
 
-                    VirtualValue::UnstableStruct { type_, fields }
+                match mode {
+                    StructPassingMode::ByPointer => {
+                        let size_t = self.module.isa().pointer_type();
+                        let ptr = f(self, size_t);
+                        VirtualValue::StackStruct { type_, ptr }
+                    }
+                    // The chunks don't line up with the fields, so we put them back into memory
+                    // where the fields can be read at their offsets.
+                    StructPassingMode::ByChunks(chunks) => {
+                        let ptr = self.stack_alloc_struct(type_);
+                        for Chunk { offset, type_ } in chunks {
+                            let v = f(self, type_);
+                            self.ins().store(MemFlags::new(), v, ptr, offset);
+                        }
+                        VirtualValue::StackStruct { type_, ptr }
+                    }
+                    StructPassingMode::ByScalars => {
+                        let fields = self
+                            .types
+                            .fields_of_struct(type_)
+                            .map(|(_, _, ty)| self.type_to_virtual_value(f, None, ty))
+                            .collect();
+
+                        VirtualValue::UnstableStruct { type_, fields }
+                    }
                 }
             }
         }
@@ -103,43 +231,91 @@ impl<'a, 'f> FuncLower<'a, 'f> {
     //
     // Since Cranelift parameters can only be primitive types, a single struct will either
     // become a single Cranelift pointer value or multiple Cranelift values.
-    fn virtual_value_to_func_params(&mut self, buf: &mut Vec<cl::Value>, v: VirtualValue) {
+    fn virtual_value_to_func_params(
+        &mut self,
+        buf: &mut Vec<cl::Value>,
+        convention: Convention,
+        v: VirtualValue,
+    ) {
         match v {
             VirtualValue::Scalar(value) => buf.push(value),
+            // A boxed value is passed as its pointer
+            VirtualValue::HeapStruct { ptr, .. } => buf.push(ptr),
             VirtualValue::StackStruct { type_, ptr: src } => {
-                match self.types.struct_passing_mode(type_) {
-                    types::StructPassingMode::ByScalars => {
+                match self.types.struct_passing_mode(type_, convention) {
+                    StructPassingMode::ByScalars => {
                         self.deref_fields(buf, type_, src, 0);
                     }
-                    types::StructPassingMode::ByPointer => buf.push(src),
+                    StructPassingMode::ByChunks(chunks) => self.load_chunks(buf, &chunks, src),
+                    StructPassingMode::ByPointer => buf.push(src),
                 }
             }
             VirtualValue::UnstableStruct { type_, fields } => {
-                match self.types.struct_passing_mode(type_) {
-                    types::StructPassingMode::ByScalars => {
-                        self.virtual_values_to_func_params(buf, fields)
+                match self.types.struct_passing_mode(type_, convention) {
+                    StructPassingMode::ByScalars => {
+                        self.virtual_values_to_func_params(buf, convention, fields)
+                    }
+                    StructPassingMode::ByChunks(chunks) => {
+                        let ptr = self.stack_alloc_struct(type_);
+                        for (field, v) in fields.into_iter().enumerate() {
+                            self.write_struct_field(type_, field, ptr, v);
+                        }
+                        self.load_chunks(buf, &chunks, ptr);
+
+                        // The chunks have been loaded into registers, so the copy is already dead
+                        self.free_stack_struct(ptr);
                     }
-                    types::StructPassingMode::ByPointer => {
+                    StructPassingMode::ByPointer => {
                         let ptr = self.stack_alloc_struct(type_);
                         for (field, v) in fields.into_iter().enumerate() {
                             self.write_struct_field(type_, field, ptr, v);
                         }
                         buf.push(ptr);
+
+                        // Nothing else has access to this copy, so it's dead once the call returns
+                        self.call_temporaries.push(ptr);
                     }
                 }
             }
+            // Enums are always passed by pointer
+            VirtualValue::Enum { type_, .. } => {
+                let ptr = self.stack_alloc_struct(type_);
+                self.store_value(type_, v, ptr, 0);
+                buf.push(ptr);
+
+                self.call_temporaries.push(ptr);
+            }
         }
     }
 
-    fn virtual_values_to_func_params(&mut self, buf: &mut Vec<cl::Value>, vs: Vec<VirtualValue>) {
+    fn virtual_values_to_func_params(
+        &mut self,
+        buf: &mut Vec<cl::Value>,
+        convention: Convention,
+        vs: Vec<VirtualValue>,
+    ) {
         vs.into_iter()
-            .for_each(|v| self.virtual_value_to_func_params(buf, v));
+            .for_each(|v| self.virtual_value_to_func_params(buf, convention, v));
+    }
+
+    // Load each chunk of the struct behind `src` so that it can be passed in a register
+    fn load_chunks(&mut self, buf: &mut Vec<cl::Value>, chunks: &[Chunk], src: cl::Value) {
+        for chunk in chunks {
+            let v = self
+                .ins()
+                .load(chunk.type_, MemFlags::new(), src, chunk.offset);
+            buf.push(v);
+        }
     }
 
     // Get the pointer parameter declared by the `LookupTable::create_signature` method
     //
     // This will for most targets be the first parameter.
     fn struct_return_pointer(&mut self) -> cl::Value {
+        // The entry block is only added to the function once it has an instruction, and until then
+        // there are no parameters to look through.
+        self.fbuilder.ensure_inserted_block();
+
         self.fbuilder
             .func
             .special_param(ir::ArgumentPurpose::StructReturn)
@@ -150,37 +326,69 @@ impl<'a, 'f> FuncLower<'a, 'f> {
         let mut call_params = vec![];
 
         let ret = self.types.return_type_of(func);
+        let convention = self.types.convention_of(func);
 
         // If the return type is too large to fit in return registers, we allocate space for it in
         // the current stack frame and pass a pointer as the first parameter for the child function to
         // write its return values to.
         let mut out_ptr_return = None;
-        if let Type::Struct(name) = ret {
-            if self.types.struct_passing_mode(name) == types::StructPassingMode::ByPointer {
-                let ptr = self.stack_alloc_struct(name);
-                call_params.push(ptr);
-                out_ptr_return = Some(VirtualValue::StackStruct { type_: name, ptr });
-            }
+        if let Type::Struct(_) | Type::Array(..) | Type::Slice(_) | Type::Enum(_) = ret
+            && self.types.struct_passing_mode(ret, convention) == StructPassingMode::ByPointer
+        {
+            let ptr = self.stack_alloc_struct(ret);
+            call_params.push(ptr);
+            out_ptr_return = Some(VirtualValue::StackStruct { type_: ret, ptr });
         }
 
-        self.virtual_values_to_func_params(&mut call_params, params);
+        // Structs passed by value are moved into the call, so once it returns, the stack slots
+        // they were stored in can be reused.
+        let moved = params
+            .iter()
+            .filter_map(|p| match p {
+                VirtualValue::StackStruct { ptr, .. } => Some(*ptr),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        self.virtual_values_to_func_params(&mut call_params, convention, params);
+
+        // The lowered arguments have to match what the callee was declared with. Checking it here
+        // points at the call, instead of leaving it to the verifier once the function is done.
+        {
+            let sig = self.sigs.get(self.module, func);
+            let expected = sig.params.iter().map(|p| p.value_type);
+            let actual = call_params
+                .iter()
+                .map(|&v| self.fbuilder.func.dfg.value_type(v));
+            assert!(
+                expected.eq(actual),
+                "arguments of the call to {func} don't match its signature"
+            );
+        }
 
         let mut register_returns = {
             // In order to call a function, we need to first map a global FuncId into a local FuncRef
             // inside the current.
-            let fref = self
-                .module
-                .declare_func_in_func(func, &mut self.fbuilder.func);
+            let fref = self.module.declare_func_in_func(func, self.fbuilder.func);
 
             let call = self.ins().call(fref, &call_params);
 
             self.fbuilder.inst_results(call).to_vec().into_iter()
         };
 
+        let temporaries = std::mem::take(&mut self.call_temporaries);
+        for ptr in moved.into_iter().chain(temporaries) {
+            self.free_stack_struct(ptr);
+        }
+
         // If the return values were handled through an out pointer, return that pointer
         // Otherwise; collect the returned scalar values into a VirtualValue to turn it back into our typed abstraction.
         out_ptr_return.unwrap_or_else(|| {
-            self.type_to_virtual_value(&mut |_, _| register_returns.next().unwrap(), false, ret)
+            self.type_to_virtual_value(
+                &mut |_, _| register_returns.next().unwrap(),
+                Some(convention),
+                ret,
+            )
         })
     }
 
@@ -189,12 +397,172 @@ impl<'a, 'f> FuncLower<'a, 'f> {
         VirtualValue::Scalar(v)
     }
 
+    pub fn uint(&mut self, n: u32) -> VirtualValue {
+        let v = self.ins().iconst(cl::types::I32, n as i64);
+        VirtualValue::Scalar(v)
+    }
+
+    pub fn bool(&mut self, b: bool) -> VirtualValue {
+        let v = self.ins().iconst(cl::types::I8, b as i64);
+        VirtualValue::Scalar(v)
+    }
+
+    /// Turn a boolean into a value that can be used as a condition.
+    ///
+    /// We only ever construct booleans as `0` or `1`, but a boolean loaded from memory could've
+    /// been written by someone else and contain any byte. So instead of assuming it's `1`, we
+    /// treat anything that's not `0` as `true`.
+    pub fn condition(&mut self, b: &VirtualValue) -> cl::Value {
+        let b = b.as_scalar();
+        self.ins().icmp_imm(cl::IntCC::NotEqual, b, 0)
+    }
+
+    /// Terminate the current block with a trap, for blocks which are impossible to reach such as
+    /// the default block of a `match`.
+    ///
+    /// See `trap_unreachable` for how this differs from the other kinds of traps.
+    pub fn unreachable(&mut self) {
+        trap_unreachable(self.fbuilder);
+    }
+
+    /// Lower an arithmetic operation on two integers of type `type_`.
+    ///
+    /// Cranelift's integer types don't have a signedness. Addition, subtraction and multiplication
+    /// produce the same bits either way, but division, remainder and right shifts don't. For
+    /// those, the instruction is picked based on the signedness of `type_`.
+    pub fn binary(
+        &mut self,
+        op: BinOp,
+        lhs: &VirtualValue,
+        rhs: &VirtualValue,
+        type_: Type,
+    ) -> VirtualValue {
+        let (lhs, rhs) = (lhs.as_scalar(), rhs.as_scalar());
+        let signed = type_.is_signed();
+
+        let v = match op {
+            BinOp::Add => self.ins().iadd(lhs, rhs),
+            BinOp::Sub => self.ins().isub(lhs, rhs),
+            BinOp::Mul => self.ins().imul(lhs, rhs),
+            // -7 / 2 is -3 when signed, but 0xFFFFFFF9 / 2 is 0x7FFFFFFC when unsigned
+            BinOp::Div if signed => self.ins().sdiv(lhs, rhs),
+            BinOp::Div => self.ins().udiv(lhs, rhs),
+            BinOp::Rem if signed => self.ins().srem(lhs, rhs),
+            BinOp::Rem => self.ins().urem(lhs, rhs),
+            // A signed shift copies the sign bit into the top, an unsigned shift fills it with zeros
+            BinOp::Shr if signed => self.ins().sshr(lhs, rhs),
+            BinOp::Shr => self.ins().ushr(lhs, rhs),
+        };
+
+        VirtualValue::Scalar(v)
+    }
+
+    /// Compare two integers of type `type_`, producing a `bool`.
+    ///
+    /// Equality doesn't depend on the signedness, but ordering does. `-1` is the largest possible
+    /// value when the same bits are read as unsigned, so `IntCC` has a signed and an unsigned
+    /// version of each ordering.
+    pub fn compare(
+        &mut self,
+        op: CmpOp,
+        lhs: &VirtualValue,
+        rhs: &VirtualValue,
+        type_: Type,
+    ) -> VirtualValue {
+        let (lhs, rhs) = (lhs.as_scalar(), rhs.as_scalar());
+
+        let cc = match (op, type_.is_signed()) {
+            (CmpOp::Eq, _) => cl::IntCC::Equal,
+            (CmpOp::Ne, _) => cl::IntCC::NotEqual,
+            (CmpOp::Lt, true) => cl::IntCC::SignedLessThan,
+            (CmpOp::Lt, false) => cl::IntCC::UnsignedLessThan,
+            (CmpOp::Le, true) => cl::IntCC::SignedLessThanOrEqual,
+            (CmpOp::Le, false) => cl::IntCC::UnsignedLessThanOrEqual,
+            (CmpOp::Gt, true) => cl::IntCC::SignedGreaterThan,
+            (CmpOp::Gt, false) => cl::IntCC::UnsignedGreaterThan,
+            (CmpOp::Ge, true) => cl::IntCC::SignedGreaterThanOrEqual,
+            (CmpOp::Ge, false) => cl::IntCC::UnsignedGreaterThanOrEqual,
+        };
+
+        // `icmp` produces an `i8` which is either `0` or `1`, the same as our booleans
+        let v = self.ins().icmp(cc, lhs, rhs);
+        VirtualValue::Scalar(v)
+    }
+
+    /// Compute both `a / b` and `a % b` of two integers of type `type_`, trapping with
+    /// `INTEGER_DIVISION_BY_ZERO` if `b` is zero.
+    ///
+    /// Lowering `a / b` and `a % b` separately emits two divisions, and Cranelift doesn't combine
+    /// them. On x86-64 `idiv` produces both the quotient and the remainder, but each of `sdiv` and
+    /// `srem` gets an `idiv` of its own, and AArch64 has no remainder instruction at all. Since
+    /// division is one of the slowest integer instructions, the remainder is instead computed from
+    /// the quotient as `a - (a / b) * b`, which is what AArch64 would do for `srem` anyway.
+    ///
+    /// This works for both signed and unsigned division, since both round towards zero.
+    pub fn divmod(
+        &mut self,
+        a: &VirtualValue,
+        b: &VirtualValue,
+        type_: Type,
+    ) -> (VirtualValue, VirtualValue) {
+        let is_nonzero = self.fbuilder.create_block();
+        let trap = self.fbuilder.create_block();
+
+        // if b == 0 { trap } else { is_nonzero }
+        //
+        // Cranelift's division instructions already trap when dividing by zero, but the separate
+        // block lets the frontend decide what happens instead, such as panicking with a message.
+        {
+            let b = b.as_scalar();
+            self.ins().brif(b, is_nonzero, &[], trap, &[]);
+        }
+
+        self.fbuilder.seal_block(trap);
+        self.fbuilder.seal_block(is_nonzero);
+
+        {
+            self.fbuilder.switch_to_block(trap);
+            self.ins().trap(cl::TrapCode::INTEGER_DIVISION_BY_ZERO);
+        }
+
+        self.fbuilder.switch_to_block(is_nonzero);
+
+        // a / b
+        //
+        // Dividing the smallest signed integer by `-1` overflows, which still traps on its own
+        let quotient = self.binary(BinOp::Div, a, b, type_);
+
+        // a % b == a - (a / b) * b
+        let remainder = {
+            let product = self.binary(BinOp::Mul, &quotient, b, type_);
+            self.binary(BinOp::Sub, a, &product, type_)
+        };
+
+        (quotient, remainder)
+    }
+
+    pub fn float(&mut self, width: FloatWidth, n: f64) -> VirtualValue {
+        let v = match width {
+            FloatWidth::F32 => self.ins().f32const(n as f32),
+            FloatWidth::F64 => self.ins().f64const(n),
+        };
+        VirtualValue::Scalar(v)
+    }
+
+    /// Construct a struct from the given fields.
+    ///
+    /// Fields which aren't given default to zero. Since the struct can then no longer be kept as
+    /// one value per field, it's allocated on the stack and zeroed with `zero_struct` before the
+    /// given fields are written over it. This also zeroes the padding between fields, so the
+    /// whole struct is well-defined when copied as bytes.
     pub fn construct_struct(
         &mut self,
         type_: &'static str,
         fields: &[(&str, VirtualValue)],
     ) -> VirtualValue {
-        let fields = self
+        let type_ = Type::Struct(type_);
+
+        let given: Vec<Option<VirtualValue>> = self
             .types
             .fields_of_struct(type_)
             .map(|(_, fname, _)| {
@@ -202,58 +570,532 @@ impl<'a, 'f> FuncLower<'a, 'f> {
                     .iter()
                     .find_map(|(name, v)| (*fname == **name).then_some(v))
                     .cloned()
-                    .expect("missing field in struct constructor")
             })
             .collect();
 
-        VirtualValue::UnstableStruct { type_, fields }
+        if given.iter().all(Option::is_some) {
+            let fields = given.into_iter().map(Option::unwrap).collect();
+            return VirtualValue::UnstableStruct { type_, fields };
+        }
+
+        let ptr = self.stack_alloc_struct(type_);
+        self.zero_struct(ptr, type_);
+
+        for (field, v) in given.into_iter().enumerate() {
+            if let Some(v) = v {
+                self.write_struct_field(type_, field, ptr, v);
+            }
+        }
+
+        VirtualValue::StackStruct { type_, ptr }
+    }
+
+    // An array such as `[1, 2, 3]` is constructed the same way as a struct
+    pub fn construct_array(&mut self, type_: Type, elems: Vec<VirtualValue>) -> VirtualValue {
+        VirtualValue::UnstableStruct {
+            type_,
+            fields: elems,
+        }
+    }
+
+    /// Read an element from an array using an index which isn't known until runtime.
+    ///
+    /// No bounds checks are performed here, so an index which is out of range will read whatever
+    /// happens to be in memory after the array.
+    pub fn index(&mut self, array: &VirtualValue, index: cl::Value) -> VirtualValue {
+        let (elem, _, ptr) = self.array_ptr(array);
+        self.load_element(*elem, ptr, index)
+    }
+
+    /// Read an element from an array, trapping with `HEAP_OUT_OF_BOUNDS` if `index` isn't below `len`.
+    ///
+    /// Unlike `slice_index` which uses `trapnz`, the check branches to a separate trap block. This
+    /// keeps the failing path out of the way of the loads, and gives a place to call a panic
+    /// handler with a message instead of trapping right away.
+    pub fn index_checked(
+        &mut self,
+        array: &VirtualValue,
+        index: cl::Value,
+        len: cl::Value,
+    ) -> VirtualValue {
+        let (elem, _, ptr) = self.array_ptr(array);
+
+        let in_bounds = self.fbuilder.create_block();
+        let trap = self.fbuilder.create_block();
+
+        // if index >= len { trap } else { in_bounds }
+        //
+        // Since the comparison is unsigned, a negative index becomes a very large number and is
+        // caught by the same check.
+        {
+            let out_of_bounds = self
+                .ins()
+                .icmp(cl::IntCC::UnsignedGreaterThanOrEqual, index, len);
+
+            self.ins().brif(out_of_bounds, trap, &[], in_bounds, &[]);
+        }
+
+        self.fbuilder.seal_block(trap);
+        self.fbuilder.seal_block(in_bounds);
+
+        {
+            self.fbuilder.switch_to_block(trap);
+            self.ins().trap(cl::TrapCode::HEAP_OUT_OF_BOUNDS);
+        }
+
+        self.fbuilder.switch_to_block(in_bounds);
+        self.load_element(*elem, ptr, index)
+    }
+
+    /// Create a slice of type `type_` from a pointer to the first element and the amount of elements.
+    pub fn slice_from_parts(
+        &mut self,
+        type_: Type,
+        ptr: cl::Value,
+        len: cl::Value,
+    ) -> VirtualValue {
+        VirtualValue::UnstableStruct {
+            type_,
+            fields: vec![VirtualValue::Scalar(ptr), VirtualValue::Scalar(len)],
+        }
+    }
+
+    /// Create a slice viewing all elements of an array, such as `&array[..]`.
+    ///
+    /// The slice points into the memory of the array, so it must not outlive it.
+    pub fn slice_of_array(&mut self, array: &VirtualValue) -> VirtualValue {
+        let size_t = self.module.isa().pointer_type();
+
+        let (elem, len, ptr) = self.array_ptr(array);
+        let len = self.ins().iconst(size_t, len as i64);
+
+        // The slice holds on to the pointer itself, which is just as much a view into the array
+        self.pin_slot(ptr);
+
+        self.slice_from_parts(Type::Slice(elem), ptr, len)
+    }
+
+    pub fn slice_len(&mut self, slice: &VirtualValue) -> VirtualValue {
+        self.destruct_field(slice, 1)
+    }
+
+    /// Read an element from a slice, trapping with `HEAP_OUT_OF_BOUNDS` if the index is out of
+    /// bounds, the same as `index_checked`.
+    ///
+    /// Unlike arrays, the length of a slice isn't known until runtime, which is why we check it here.
+    pub fn slice_index(&mut self, slice: &VirtualValue, index: cl::Value) -> VirtualValue {
+        let elem = match slice {
+            VirtualValue::StackStruct { type_, .. }
+            | VirtualValue::HeapStruct { type_, .. }
+            | VirtualValue::UnstableStruct { type_, .. } => match type_ {
+                Type::Slice(elem) => **elem,
+                _ => panic!("cannot slice index into non-slice"),
+            },
+            VirtualValue::Scalar(_) | VirtualValue::Enum { .. } => {
+                panic!("cannot slice index into non-slice")
+            }
+        };
+
+        let ptr = self.destruct_field(slice, 0).as_scalar();
+        let len = self.slice_len(slice).as_scalar();
+
+        // The length is a `size_t`, so the index is widened once to compare against it. The
+        // widened index is then reused to compute the address of the element.
+        let index = self.index_to_size_t(index);
+
+        // if index >= len { trap }
+        //
+        // Since the comparison is unsigned, a negative index becomes a very large number and is
+        // caught by the same check.
+        {
+            let out_of_bounds = self
+                .ins()
+                .icmp(cl::IntCC::UnsignedGreaterThanOrEqual, index, len);
+
+            self.ins()
+                .trapnz(out_of_bounds, cl::TrapCode::HEAP_OUT_OF_BOUNDS);
+        }
+
+        self.load_element(elem, ptr, index)
+    }
+
+    // Get a pointer to the first element of an array, along with the element type and length
+    //
+    // Since we can't know which element to pick until runtime, the array needs to be in memory
+    // so that we can compute the address of the element.
+    fn array_ptr(&mut self, array: &VirtualValue) -> (&'static Type, u32, cl::Value) {
+        match array {
+            VirtualValue::StackStruct {
+                type_: Type::Array(elem, len),
+                ptr,
+            }
+            | VirtualValue::HeapStruct {
+                type_: Type::Array(elem, len),
+                ptr,
+            } => (*elem, *len, *ptr),
+            VirtualValue::UnstableStruct {
+                type_: type_ @ Type::Array(elem, len),
+                ..
+            } => {
+                let ptr = self.stack_alloc_struct(*type_);
+                self.store_value(*type_, array.clone(), ptr, 0);
+                (*elem, *len, ptr)
+            }
+            _ => panic!("cannot index into non-array"),
+        }
+    }
+
+    // Widen an index to `size_t`, so that it can be compared with a length or added to a pointer.
+    //
+    // An index which already is a `size_t` is returned as is.
+    fn index_to_size_t(&mut self, index: cl::Value) -> cl::Value {
+        let size_t = self.module.isa().pointer_type();
+        let ty = self.fbuilder.func.dfg.value_type(index);
+
+        if ty.bits() < size_t.bits() {
+            self.ins().uextend(size_t, index)
+        } else {
+            index
+        }
+    }
+
+    // Load the element at `ptr[index]`
+    fn load_element(&mut self, elem: Type, ptr: cl::Value, index: cl::Value) -> VirtualValue {
+        // base + index * stride
+        let elem_ptr = {
+            let stride = self.types.size_of(elem) as i64;
+            let index = self.index_to_size_t(index);
+            let offset = self.ins().imul_imm(index, stride);
+            self.ins().iadd(ptr, offset)
+        };
+
+        let v = self.load_value(elem, elem_ptr, 0);
+        self.track_view(ptr, &v);
+        v
     }
 
     pub fn destruct_field(&mut self, of: &VirtualValue, field: usize) -> VirtualValue {
         match of {
-            VirtualValue::Scalar(_) => panic!("cannot destruct field from non-struct"),
-
-            VirtualValue::StackStruct { type_, ptr } => {
-                let offset = self.types.offset_of_field(type_, field);
-
-                match self.types.type_of_field(type_, field) {
-                    // Instead of actually dereferencing the inner struct here,
-                    // we create another implicit stack pointer that's offset to where the inner struct starts.
-                    //
-                    // This makes dereferencing lazy.
-                    Type::Struct(type_) => {
-                        let nptr = self.ins().iadd_imm(*ptr, offset as i64);
-                        VirtualValue::StackStruct { type_, ptr: nptr }
-                    }
-                    Type::Int => {
-                        let v = self
-                            .ins()
-                            .load(cl::types::I32, MemFlags::new(), *ptr, offset);
-                        VirtualValue::Scalar(v)
-                    }
-                }
+            VirtualValue::Scalar(_) | VirtualValue::Enum { .. } => {
+                panic!("cannot destruct field from non-struct")
+            }
+
+            // Since fields of heap structs are read the same way as those of stack structs, an inner
+            // struct of a heap struct becomes a `StackStruct` pointing into the heap allocation.
+            VirtualValue::StackStruct { type_, ptr } | VirtualValue::HeapStruct { type_, ptr } => {
+                let offset = self.types.offset_of_field(*type_, field);
+                let fty = self.types.type_of_field(*type_, field);
+                let v = self.load_value(fty, *ptr, offset);
+                self.track_view(*ptr, &v);
+                v
             }
 
             VirtualValue::UnstableStruct { fields, .. } => fields[field].clone(),
         }
     }
 
+    // Read a value of type `type_` from `ptr + offset`
+    fn load_value(&mut self, type_: Type, ptr: cl::Value, offset: i32) -> VirtualValue {
+        match type_ {
+            // Instead of actually dereferencing the inner struct here,
+            // we create another implicit stack pointer that's offset to where the inner struct starts.
+            //
+            // This makes dereferencing lazy.
+            Type::Struct(_) | Type::Array(..) | Type::Slice(_) | Type::Enum(_) => {
+                let nptr = self.ins().iadd_imm(ptr, offset as i64);
+                VirtualValue::StackStruct { type_, ptr: nptr }
+            }
+            Type::Int | Type::UInt => {
+                let v = self
+                    .ins()
+                    .load(cl::types::I32, MemFlags::new(), ptr, offset);
+                VirtualValue::Scalar(v)
+            }
+            Type::Usize => {
+                let size_t = self.module.isa().pointer_type();
+                let v = self.ins().load(size_t, MemFlags::new(), ptr, offset);
+                VirtualValue::Scalar(v)
+            }
+            Type::Bool => {
+                let v = self.ins().load(cl::types::I8, MemFlags::new(), ptr, offset);
+                VirtualValue::Scalar(v)
+            }
+            Type::Float(width) => {
+                let v = self
+                    .ins()
+                    .load(width.cranelift_type(), MemFlags::new(), ptr, offset);
+                VirtualValue::Scalar(v)
+            }
+            Type::Boxed(type_) => {
+                let size_t = self.module.isa().pointer_type();
+                let v = self.ins().load(size_t, MemFlags::new(), ptr, offset);
+                VirtualValue::HeapStruct {
+                    type_: *type_,
+                    ptr: v,
+                }
+            }
+        }
+    }
+
+    /// Construct a variant of an enum, such as `Outcome::Ok(5)`.
+    ///
+    /// Same as with `construct_struct`, nothing is written to memory until it's needed.
+    pub fn construct_variant(
+        &mut self,
+        type_: &'static str,
+        variant: &str,
+        payload: VirtualValue,
+    ) -> VirtualValue {
+        let type_ = Type::Enum(type_);
+
+        let variant = self.types.resolve_variant(type_name(type_), variant);
+
+        let tag = {
+            let tag_type = self.types.tag_type_of(type_);
+            self.ins().iconst(tag_type, variant as i64)
+        };
+
+        VirtualValue::Enum {
+            type_,
+            variant,
+            tag,
+            payload: Box::new(payload),
+        }
+    }
+
+    /// Lower a `match` over the variants of an enum which produces a scalar of type `ret`.
+    ///
+    /// `arm` is called once per variant with the builder positioned in that variant's block, and
+    /// is given the variant index along with its payload. The values it returns are merged
+    /// through a block parameter, and the builder is left positioned after the match.
+    pub fn match_on<F>(&mut self, of: &VirtualValue, ret: Type, mut arm: F) -> VirtualValue
+    where
+        F: FnMut(&mut Self, usize, VirtualValue) -> VirtualValue,
+    {
+        let (type_, ptr) = self.enum_ptr(of);
+
+        let ret = {
+            let mut scalars = vec![];
+            self.types.for_scalars(&mut |clty| scalars.push(clty), ret);
+            match scalars[..] {
+                [clty] => clty,
+                _ => panic!("match_on only supports scalar results"),
+            }
+        };
+
+        let tag_type = self.types.tag_type_of(type_);
+        let tag = self.ins().load(tag_type, MemFlags::new(), ptr, 0);
+
+        let variants = self.types.variants_of_enum(type_name(type_));
+
+        let blocks = variants
+            .clone()
+            .map(|_| self.fbuilder.create_block())
+            .collect::<Vec<_>>();
+        let trap = self.fbuilder.create_block();
+        let merge = self.fbuilder.create_block();
+        self.fbuilder.append_block_param(merge, ret);
+
+        // See the `switch-matching` example for how `Switch` picks between a jump table and
+        // comparisons.
+        let mut switch = Switch::new();
+        for (i, &block) in blocks.iter().enumerate() {
+            switch.set_entry(i as u128, block);
+        }
+        switch.emit(self.fbuilder, tag, trap);
+
+        blocks
+            .iter()
+            .for_each(|&block| self.fbuilder.seal_block(block));
+        self.fbuilder.seal_block(trap);
+
+        let offset = self.types.payload_offset(type_);
+        for ((i, _, pty), &block) in variants.zip(&blocks) {
+            self.fbuilder.switch_to_block(block);
+
+            let payload = self.load_value(pty, ptr, offset);
+            self.track_view(ptr, &payload);
+            let v = arm(self, i, payload).as_scalar();
+            self.ins().jump(merge, &[v.into()]);
+        }
+
+        // A tag which doesn't belong to any variant means the enum was never initialized
+        {
+            self.fbuilder.switch_to_block(trap);
+            self.unreachable();
+        }
+
+        self.fbuilder.seal_block(merge);
+        self.fbuilder.switch_to_block(merge);
+
+        VirtualValue::Scalar(self.fbuilder.block_params(merge)[0])
+    }
+
+    /// Compare two enums of the same type for equality, producing a `bool`.
+    ///
+    /// The tags are compared first, and if they differ we jump straight to the result with `false`
+    /// without reading either payload. Otherwise both payloads are read as the variant the tag
+    /// says they hold, the same way as in `match_on`, and compared with `eq_value`.
+    pub fn eq_enum(&mut self, a: &VirtualValue, b: &VirtualValue) -> VirtualValue {
+        let (type_, aptr) = self.enum_ptr(a);
+        let (_, bptr) = self.enum_ptr(b);
+
+        let tag_type = self.types.tag_type_of(type_);
+        let atag = self.ins().load(tag_type, MemFlags::new(), aptr, 0);
+        let btag = self.ins().load(tag_type, MemFlags::new(), bptr, 0);
+
+        let variants = self.types.variants_of_enum(type_name(type_));
+
+        let same_tag = self.fbuilder.create_block();
+        let blocks = variants
+            .clone()
+            .map(|_| self.fbuilder.create_block())
+            .collect::<Vec<_>>();
+        let trap = self.fbuilder.create_block();
+        let merge = self.fbuilder.create_block();
+        self.fbuilder.append_block_param(merge, cl::types::I8);
+
+        // if atag != btag { false } else { compare the payloads }
+        {
+            let ne = self.ins().icmp(cl::IntCC::NotEqual, atag, btag);
+            let false_ = self.ins().iconst(cl::types::I8, 0);
+            self.ins().brif(ne, merge, &[false_.into()], same_tag, &[]);
+            self.fbuilder.seal_block(same_tag);
+        }
+
+        // Since the tags are equal, we only need to switch on one of them
+        {
+            self.fbuilder.switch_to_block(same_tag);
+
+            let mut switch = Switch::new();
+            for (i, &block) in blocks.iter().enumerate() {
+                switch.set_entry(i as u128, block);
+            }
+            switch.emit(self.fbuilder, atag, trap);
+
+            blocks
+                .iter()
+                .for_each(|&block| self.fbuilder.seal_block(block));
+            self.fbuilder.seal_block(trap);
+        }
+
+        let offset = self.types.payload_offset(type_);
+        for ((_, _, pty), &block) in variants.zip(&blocks) {
+            self.fbuilder.switch_to_block(block);
+
+            let apayload = self.load_value(pty, aptr, offset);
+            let bpayload = self.load_value(pty, bptr, offset);
+            let eq = self.eq_value(pty, &apayload, &bpayload).as_scalar();
+            self.ins().jump(merge, &[eq.into()]);
+        }
+
+        {
+            self.fbuilder.switch_to_block(trap);
+            self.unreachable();
+        }
+
+        self.fbuilder.seal_block(merge);
+        self.fbuilder.switch_to_block(merge);
+
+        VirtualValue::Scalar(self.fbuilder.block_params(merge)[0])
+    }
+
+    // Compare two values of type `type_` for equality, producing a `bool`
+    //
+    // Structs are equal if all of their fields are. Every field is compared rather than stopping
+    // at the first difference, since the comparisons are cheap compared to the branches needed
+    // to skip them. Arrays are compared element-wise the same way.
+    //
+    // Slices and boxes are compared by identity rather than by what they point to, so two slices
+    // are only equal if they have the same pointer and length.
+    fn eq_value(&mut self, type_: Type, a: &VirtualValue, b: &VirtualValue) -> VirtualValue {
+        match type_ {
+            Type::Int | Type::UInt | Type::Usize => self.compare(CmpOp::Eq, a, b, type_),
+            // Any byte other than `0` is `true`, see `condition`
+            Type::Bool => {
+                let a = self.condition(a);
+                let b = self.condition(b);
+                let v = self.ins().icmp(cl::IntCC::Equal, a, b);
+                VirtualValue::Scalar(v)
+            }
+            Type::Float(_) => {
+                let v = self
+                    .ins()
+                    .fcmp(cl::FloatCC::Equal, a.as_scalar(), b.as_scalar());
+                VirtualValue::Scalar(v)
+            }
+            // A slice is treated as a struct of its pointer and length, see `fields_of_struct`
+            Type::Struct(_) | Type::Array(..) | Type::Slice(_) => {
+                let mut eq = self.bool(true).as_scalar();
+
+                for (field, _, fty) in self.types.fields_of_struct(type_) {
+                    let afield = self.destruct_field(a, field);
+                    let bfield = self.destruct_field(b, field);
+                    let feq = self.eq_value(fty, &afield, &bfield).as_scalar();
+                    eq = self.ins().band(eq, feq);
+                }
+
+                VirtualValue::Scalar(eq)
+            }
+            Type::Enum(_) => self.eq_enum(a, b),
+            Type::Boxed(_) => match (a, b) {
+                (
+                    VirtualValue::HeapStruct { ptr: a, .. },
+                    VirtualValue::HeapStruct { ptr: b, .. },
+                ) => {
+                    let v = self.ins().icmp(cl::IntCC::Equal, *a, *b);
+                    VirtualValue::Scalar(v)
+                }
+                _ => panic!("boxed value is not a heap pointer"),
+            },
+        }
+    }
+
+    // Get a pointer to an enum so that its tag can be read and its payload reinterpreted
+    //
+    // Even when we constructed the enum ourselves, the payload type is only known inside the arm
+    // of a `match`, so the enum is written to memory first.
+    fn enum_ptr(&mut self, of: &VirtualValue) -> (Type, cl::Value) {
+        match of {
+            VirtualValue::StackStruct {
+                type_: type_ @ Type::Enum(_),
+                ptr,
+            }
+            | VirtualValue::HeapStruct {
+                type_: type_ @ Type::Enum(_),
+                ptr,
+            } => (*type_, *ptr),
+            VirtualValue::Enum { type_, .. } => {
+                let ptr = self.stack_alloc_struct(*type_);
+                self.store_value(*type_, of.clone(), ptr, 0);
+                (*type_, ptr)
+            }
+            _ => panic!("cannot match on non-enum"),
+        }
+    }
+
     /// Return a value, either by writing to the return struct out pointer or by returning values directly.
     pub fn return_(&mut self, vv: VirtualValue) {
         match vv {
-            VirtualValue::Scalar(value) => {
+            // Since the heap allocation outlives our stack frame, we can return the pointer directly
+            VirtualValue::Scalar(value) | VirtualValue::HeapStruct { ptr: value, .. } => {
                 self.fbuilder.ins().return_(&[value]);
             }
             VirtualValue::StackStruct { type_, ptr: src } => {
-                match self.types.struct_passing_mode(type_) {
+                match self.types.struct_passing_mode(type_, self.convention) {
                     // We have a stack pointer but want to return in return registers
-                    types::StructPassingMode::ByScalars => {
+                    StructPassingMode::ByScalars => {
                         let mut buf = vec![];
                         self.deref_fields(&mut buf, type_, src, 0);
                         self.ins().return_(&buf);
                     }
+                    StructPassingMode::ByChunks(chunks) => {
+                        let mut buf = vec![];
+                        self.load_chunks(&mut buf, &chunks, src);
+                        self.ins().return_(&buf);
+                    }
                     // We have a stack pointer and we want to return by writing to the out pointer
-                    types::StructPassingMode::ByPointer => {
+                    StructPassingMode::ByPointer => {
                         let dst = self.struct_return_pointer();
                         self.copy_struct_fields(type_, src, dst);
                         self.ins().return_(&[]);
@@ -261,17 +1103,28 @@ impl<'a, 'f> FuncLower<'a, 'f> {
                 }
             }
             VirtualValue::UnstableStruct { type_, fields } => {
-                match self.types.struct_passing_mode(type_) {
-                    types::StructPassingMode::ByScalars => {
-                        let fields = fields
-                            .iter()
-                            .map(VirtualValue::as_scalar)
-                            .collect::<Vec<_>>();
-
-                        self.fbuilder.ins().return_(&fields);
+                match self.types.struct_passing_mode(type_, self.convention) {
+                    StructPassingMode::ByScalars => {
+                        // Nested structs are flattened the same way as when passing parameters
+                        let mut buf = vec![];
+                        self.virtual_values_to_func_params(&mut buf, self.convention, fields);
+
+                        self.fbuilder.ins().return_(&buf);
+                    }
+                    // The chunks don't line up with the fields, so the struct has to be written
+                    // to memory first.
+                    StructPassingMode::ByChunks(chunks) => {
+                        let ptr = self.stack_alloc_struct(type_);
+                        for (field, v) in fields.into_iter().enumerate() {
+                            self.write_struct_field(type_, field, ptr, v);
+                        }
+
+                        let mut buf = vec![];
+                        self.load_chunks(&mut buf, &chunks, ptr);
+                        self.ins().return_(&buf);
                     }
                     // We have an abstract struct and we want to write the fields to an out pointer
-                    types::StructPassingMode::ByPointer => {
+                    StructPassingMode::ByPointer => {
                         let dst = self.struct_return_pointer();
 
                         for (field, v) in fields.into_iter().enumerate() {
@@ -282,13 +1135,19 @@ impl<'a, 'f> FuncLower<'a, 'f> {
                     }
                 }
             }
+            // Enums are always returned through the out pointer
+            VirtualValue::Enum { type_, .. } => {
+                let dst = self.struct_return_pointer();
+                self.store_value(type_, vv, dst, 0);
+                self.ins().return_(&[]);
+            }
         }
     }
 
     fn deref_fields(
         &mut self,
         buf: &mut Vec<cl::Value>,
-        type_: &str,
+        type_: Type,
         src: cl::Value,
         src_offset: i32,
     ) {
@@ -296,83 +1155,482 @@ impl<'a, 'f> FuncLower<'a, 'f> {
             let offset = self.types.offset_of_field(type_, field) + src_offset;
             let fty = self.types.type_of_field(type_, field);
             match fty {
-                Type::Int => {
-                    let v = self
-                        .ins()
-                        .load(cl::types::I32, MemFlags::new(), src, offset);
-
-                    buf.push(v);
-                }
-                Type::Struct(type_) => {
-                    self.deref_fields(buf, type_, src, offset);
+                // Flattened into the same buffer, so that each scalar becomes its own parameter
+                Type::Struct(_) | Type::Array(..) | Type::Slice(_) => {
+                    self.deref_fields(buf, fty, src, offset);
                 }
+                Type::Enum(_) => unreachable!("structs containing enums are passed by pointer"),
+                _ => match self.load_value(fty, src, offset) {
+                    VirtualValue::Scalar(v) | VirtualValue::HeapStruct { ptr: v, .. } => {
+                        buf.push(v)
+                    }
+                    _ => unreachable!(),
+                },
             }
         }
     }
 
-    fn copy_struct_fields(&mut self, type_: &str, src: cl::Value, dst: cl::Value) {
+    // Copy a struct from one pointer to another
+    //
+    // Copying field by field lets Cranelift keep each field in a register, but the amount of
+    // instructions grows with the size of the struct. Above `memcpy_threshold` we instead emit a
+    // single call to `memcpy`, which is optimized for copying large blocks of memory.
+    fn copy_struct_fields(&mut self, type_: Type, src: cl::Value, dst: cl::Value) {
+        let size = self.types.size_of(type_);
+
+        // We don't know which variant is stored until runtime, so the whole enum is copied as bytes
+        if let Type::Enum(_) = type_ {
+            let config = self.module.target_config();
+            let align = self.types.alignment_of(type_) as u8;
+            self.fbuilder.emit_small_memory_copy(
+                config,
+                dst,
+                src,
+                size as u64,
+                align,
+                align,
+                true,
+                MemFlags::new(),
+            );
+
+            return;
+        }
+        if size > self.memcpy_threshold {
+            let size_t = self.module.isa().pointer_type();
+            let size = self.ins().iconst(size_t, size as i64);
+
+            // `call_memcpy` declares `memcpy` as a libcall for us, which the module resolves to
+            // the symbol named by `default_libcall_names`.
+            let config = self.module.target_config();
+            self.fbuilder.call_memcpy(config, dst, src, size);
+
+            return;
+        }
+
+        // Inner structs are loaded as pointers into `src`, which `store_value` copies from in turn
         for (field, _, fty) in self.types.fields_of_struct(type_) {
             let offset = self.types.offset_of_field(type_, field);
 
-            match fty {
-                Type::Int => {
-                    let n = self
-                        .ins()
-                        .load(cl::types::I32, MemFlags::new(), src, offset);
+            let v = self.load_value(fty, src, offset);
+            self.store_value(fty, v, dst, offset);
+        }
+    }
+
+    /// Set every byte of the struct behind `ptr` to zero.
+    ///
+    /// Same as with `copy_struct_fields`, small structs are zeroed with stores emitted inline,
+    /// while structs above `memcpy_threshold` use a single call to `memset`.
+    pub fn zero_struct(&mut self, ptr: cl::Value, type_: Type) {
+        let size = self.types.size_of(type_);
+        let config = self.module.target_config();
+
+        if size > self.memcpy_threshold {
+            let size_t = self.module.isa().pointer_type();
+            let size = self.ins().iconst(size_t, size as i64);
+            let zero = self.ins().iconst(cl::types::I32, 0);
+
+            // Declared as a libcall the same way as `memcpy` in `copy_struct_fields`
+            self.fbuilder.call_memset(config, ptr, zero, size);
+        } else {
+            let align = self.types.alignment_of(type_) as u8;
+            self.fbuilder
+                .emit_small_memset(config, ptr, 0, size as u64, align, MemFlags::new());
+        }
+    }
+
+    fn write_struct_field(&mut self, type_: Type, field: usize, ptr: cl::Value, v: VirtualValue) {
+        let offset = self.types.offset_of_field(type_, field);
+        let fty = self.types.type_of_field(type_, field);
+        self.store_value(fty, v, ptr, offset);
+    }
 
-                    self.ins().store(MemFlags::new(), n, dst, offset);
+    // Write a value of type `type_` to `ptr + offset`
+    //
+    // The counterpart to `load_value`. Scalars are written with a single store of whichever
+    // Cranelift type they already have, while structs are written field by field. Values which
+    // are already in memory are copied with `copy_struct_fields` instead.
+    fn store_value(&mut self, type_: Type, v: VirtualValue, ptr: cl::Value, offset: i32) {
+        match v {
+            // Storing a boxed struct only stores the pointer to it
+            VirtualValue::Scalar(value) | VirtualValue::HeapStruct { ptr: value, .. } => {
+                self.ins().store(MemFlags::new(), value, ptr, offset);
+            }
+
+            VirtualValue::UnstableStruct { fields, .. } => {
+                for (field, v) in fields.into_iter().enumerate() {
+                    let foffset = offset + self.types.offset_of_field(type_, field);
+                    let fty = self.types.type_of_field(type_, field);
+                    self.store_value(fty, v, ptr, foffset);
                 }
-                Type::Struct(type_) => {
-                    let src = self.ins().iadd_imm(src, offset as i64);
-                    let dst = self.ins().iadd_imm(dst, offset as i64);
+            }
 
-                    self.copy_struct_fields(type_, src, dst);
+            VirtualValue::StackStruct { ptr: src, .. } => {
+                let dst = self.ins().iadd_imm(ptr, offset as i64);
+                self.copy_struct_fields(type_, src, dst);
+            }
+
+            // The tag followed by the payload of the variant
+            VirtualValue::Enum {
+                variant,
+                tag,
+                payload,
+                ..
+            } => {
+                self.ins().store(MemFlags::new(), tag, ptr, offset);
+
+                let (_, _, pty) = self
+                    .types
+                    .variants_of_enum(type_name(type_))
+                    .nth(variant)
+                    .unwrap();
+                let poffset = offset + self.types.payload_offset(type_);
+                self.store_value(pty, *payload, ptr, poffset);
+            }
+        }
+    }
+
+    /// Print a value and all of its fields at runtime, which is useful for checking that structs
+    /// are laid out and passed the way you expect.
+    ///
+    /// Emits one `printf` call per line, with nested structs indented. Does nothing unless
+    /// `debug_prints` is enabled, so that the calls can be left in place while developing.
+    pub fn debug_print(&mut self, vv: &VirtualValue, type_: Type) {
+        if self.debug_prints {
+            self.debug_print_value("", vv, type_, 0);
+        }
+    }
+
+    fn debug_print_value(&mut self, name: &str, vv: &VirtualValue, type_: Type, depth: usize) {
+        let indent = "  ".repeat(depth);
+        let label = if name.is_empty() {
+            indent
+        } else {
+            format!("{indent}{name}: ")
+        };
+
+        match type_ {
+            Type::Int => {
+                let v = vv.as_scalar();
+                self.printf(&format!("{label}%d\n"), &[v]);
+            }
+            Type::UInt => {
+                let v = vv.as_scalar();
+                self.printf(&format!("{label}%u\n"), &[v]);
+            }
+            Type::Usize => {
+                let v = vv.as_scalar();
+                self.printf(&format!("{label}%zu\n"), &[v]);
+            }
+            // Variadic arguments smaller than `int` need to be extended
+            Type::Bool => {
+                let v = self.ins().uextend(cl::types::I32, vv.as_scalar());
+                self.printf(&format!("{label}%d\n"), &[v]);
+            }
+            // Passing floats to variadic functions isn't reliable with our signature trick, see
+            // the `printf` example. Printing the raw bits instead is good enough to check layouts.
+            Type::Float(width) => {
+                let bits = match width {
+                    FloatWidth::F32 => cl::types::I32,
+                    FloatWidth::F64 => cl::types::I64,
+                };
+                let v = self.ins().bitcast(bits, MemFlags::new(), vv.as_scalar());
+                let format = match width {
+                    FloatWidth::F32 => "%#x",
+                    FloatWidth::F64 => "%#lx",
+                };
+                self.printf(&format!("{label}{format}\n"), &[v]);
+            }
+            // Only the pointer is printed, following it could lead us in circles
+            Type::Boxed(_) => {
+                let ptr = match vv {
+                    VirtualValue::HeapStruct { ptr, .. } => *ptr,
+                    _ => vv.as_scalar(),
+                };
+                self.printf(&format!("{label}%p\n"), &[ptr]);
+            }
+            Type::Struct(_) | Type::Array(..) | Type::Slice(_) => {
+                let header = match type_ {
+                    Type::Struct(sname) => format!("{label}{sname} {{\n"),
+                    Type::Slice(_) => format!("{label}slice {{\n"),
+                    _ => format!("{label}[\n"),
+                };
+                self.printf(&header, &[]);
+
+                for (field, fname, fty) in self.types.fields_of_struct(type_) {
+                    // Array elements don't have names, so we use their index instead
+                    let fname = if fname.is_empty() {
+                        format!("[{field}]")
+                    } else {
+                        fname.to_string()
+                    };
+
+                    let v = self.destruct_field(vv, field);
+                    self.debug_print_value(&fname, &v, fty, depth + 1);
                 }
+
+                let indent = "  ".repeat(depth);
+                let footer = match type_ {
+                    Type::Array(..) => format!("{indent}]\n"),
+                    _ => format!("{indent}}}\n"),
+                };
+                self.printf(&footer, &[]);
+            }
+            // Only the tag is printed, since the payload depends on the variant
+            Type::Enum(ename) => {
+                let (type_, ptr) = self.enum_ptr(vv);
+                let tag_type = self.types.tag_type_of(type_);
+                let tag = self.ins().load(tag_type, MemFlags::new(), ptr, 0);
+                // Variadic arguments smaller than `int` need to be extended
+                let tag = if tag_type.bits() < 32 {
+                    self.ins().uextend(cl::types::I32, tag)
+                } else {
+                    tag
+                };
+                self.printf(&format!("{label}{ename} {{ tag: %d }}\n"), &[tag]);
             }
         }
     }
 
-    fn write_struct_field(&mut self, name: &str, field: usize, ptr: cl::Value, v: VirtualValue) {
-        let offset = self.types.offset_of_field(name, field);
+    // Call `printf` with a format string embedded as anonymous data
+    //
+    // See the `printf` example for how variadic functions are called.
+    fn printf(&mut self, format: &str, args: &[cl::Value]) {
+        let size_t = self.module.isa().pointer_type();
 
-        match v {
-            VirtualValue::Scalar(value) => {
-                self.ins().store(MemFlags::new(), value, ptr, offset);
+        // Since the data is anonymous, we don't need to come up with a unique symbol for each string
+        let format = {
+            let data_id = self.module.declare_anonymous_data(false, false).unwrap();
+
+            let mut bytes = format.as_bytes().to_vec();
+            bytes.push(0);
+
+            let mut desc = DataDescription::new();
+            desc.define(bytes.into_boxed_slice());
+            self.module.define_data(data_id, &desc).unwrap();
+
+            let gv = self
+                .module
+                .declare_data_in_func(data_id, self.fbuilder.func);
+            self.ins().global_value(size_t, gv)
+        };
+
+        // extern "C" fn printf(format: *const u8, ...) -> i32;
+        let printf = {
+            let mut sig = self.module.make_signature();
+            sig.params.push(cl::AbiParam::new(size_t));
+            sig.returns.push(cl::AbiParam::new(cl::types::I32));
+
+            let id = self
+                .module
+                .declare_function("printf", Linkage::Import, &sig)
+                .unwrap();
+
+            let fref = self.module.declare_func_in_func(id, self.fbuilder.func);
+            self.ins().func_addr(size_t, fref)
+        };
+
+        // Each call gets a signature matching the arguments actually passed
+        let sig = {
+            let mut sig = self.module.make_signature();
+            sig.params.push(cl::AbiParam::new(size_t));
+            for &v in args {
+                let ty = self.fbuilder.func.dfg.value_type(v);
+                sig.params.push(cl::AbiParam::new(ty));
             }
+            sig.returns.push(cl::AbiParam::new(cl::types::I32));
+            self.fbuilder.import_signature(sig)
+        };
+
+        let args = [&[format], args].concat();
+        self.ins().call_indirect(sig, printf, &args);
+    }
 
+    /// Move a struct onto the heap so that it can outlive the current stack frame.
+    ///
+    /// We never free the allocation in this example. In a real compiler you'd either insert calls
+    /// to `free` when the box goes out of scope, or hand the memory over to a garbage collector.
+    pub fn box_struct(&mut self, vv: VirtualValue) -> VirtualValue {
+        match vv {
+            VirtualValue::Scalar(_) => panic!("cannot box non-struct"),
+            VirtualValue::StackStruct { type_, ptr: src } => {
+                let ptr = self.heap_alloc_struct(type_);
+                self.copy_struct_fields(type_, src, ptr);
+                VirtualValue::HeapStruct { type_, ptr }
+            }
             VirtualValue::UnstableStruct { type_, fields } => {
+                let ptr = self.heap_alloc_struct(type_);
                 for (field, v) in fields.into_iter().enumerate() {
-                    // let offset = offset + self.types.offset_of_field(type_, field);
-                    let nptr = self.ins().iadd_imm(ptr, offset as i64);
-                    self.write_struct_field(type_, field, nptr, v);
+                    self.write_struct_field(type_, field, ptr, v);
                 }
+                VirtualValue::HeapStruct { type_, ptr }
             }
+            VirtualValue::Enum { type_, .. } => {
+                let ptr = self.heap_alloc_struct(type_);
+                self.store_value(type_, vv, ptr, 0);
+                VirtualValue::HeapStruct { type_, ptr }
+            }
+            // Already on the heap
+            VirtualValue::HeapStruct { .. } => vv,
+        }
+    }
 
-            VirtualValue::StackStruct {
-                type_: src_type,
-                ptr: src_ptr,
-            } => {
-                let nptr = self.ins().iadd_imm(ptr, offset as i64);
-                self.copy_struct_fields(src_type, src_ptr, nptr);
+    /// Check whether a pointer is null, producing `1` if it is and `0` otherwise.
+    ///
+    /// Pointers are plain integers in Cranelift, so this is an `icmp` against a zero constant.
+    /// Use `IntCC::NotEqual` instead for the opposite check.
+    pub fn is_null(&mut self, vv: &VirtualValue) -> cl::Value {
+        let size_t = self.module.isa().pointer_type();
+
+        match vv {
+            VirtualValue::StackStruct { ptr, .. }
+            | VirtualValue::HeapStruct { ptr, .. }
+            | VirtualValue::Scalar(ptr) => {
+                let null = self.ins().iconst(size_t, 0);
+                self.ins().icmp(cl::IntCC::Equal, *ptr, null)
+            }
+            // Values we're still holding on to in registers aren't behind a pointer at all
+            VirtualValue::UnstableStruct { .. } | VirtualValue::Enum { .. } => {
+                self.ins().iconst(cl::types::I8, 0)
             }
         }
     }
 
+    /// Trap if the pointer is null, such as when unwrapping an `Option<&T>` which is `None`.
+    ///
+    /// The builder is left positioned in the block where the pointer is known to be non-null.
+    pub fn null_check(&mut self, vv: &VirtualValue) {
+        let non_null = self.fbuilder.create_block();
+        let trap = self.fbuilder.create_block();
+
+        // if ptr == null { trap } else { non_null }
+        let is_null = self.is_null(vv);
+        self.ins().brif(is_null, trap, &[], non_null, &[]);
+
+        self.fbuilder.seal_block(trap);
+        self.fbuilder.seal_block(non_null);
+
+        {
+            self.fbuilder.switch_to_block(trap);
+
+            const TRAP_NULL_POINTER: u8 = 105;
+            self.ins()
+                .trap(cl::TrapCode::user(TRAP_NULL_POINTER).unwrap());
+        }
+
+        self.fbuilder.switch_to_block(non_null);
+    }
+
+    // Allocate the struct on the heap using `malloc` and return the heap pointer
+    //
+    // `malloc` returns memory aligned for any primitive type, so we don't need to care about
+    // alignment here the same way we do for stack slots.
+    fn heap_alloc_struct(&mut self, type_: Type) -> cl::Value {
+        let size_t = self.module.isa().pointer_type();
+
+        // fn malloc(size: usize) -> *void;
+        //
+        // Declaring the same import multiple times gives us back the same `FuncId`.
+        let malloc = {
+            let mut sig = self.module.make_signature();
+            sig.params.push(cl::AbiParam::new(size_t));
+            sig.returns.push(cl::AbiParam::new(size_t));
+
+            let id = self
+                .module
+                .declare_function("malloc", Linkage::Import, &sig)
+                .unwrap();
+
+            self.module.declare_func_in_func(id, self.fbuilder.func)
+        };
+
+        let size = self.types.size_of(type_);
+        let size = self.ins().iconst(size_t, size as i64);
+        let call = self.ins().call(malloc, &[size]);
+        self.fbuilder.inst_results(call)[0]
+    }
+
     // Allocate the struct on the stack and return the stack pointer
     //
-    // For this example we will be skipping caring about alignment, even though alignment is a
-    // requirement for performance.
-    pub(super) fn stack_alloc_struct(&mut self, name: &str) -> cl::Value {
-        let size = self.types.size_of_struct(name);
-        let slot = self
-            .fbuilder
-            .create_sized_stack_slot(cl::StackSlotData::new(
-                cl::StackSlotKind::ExplicitSlot,
-                size,
-                0,
-            ));
+    // If a previously freed stack slot of the same size and alignment is available, it's reused
+    // instead of growing the stack frame.
+    pub(super) fn stack_alloc_struct(&mut self, type_: Type) -> cl::Value {
+        let size = self.types.size_of(type_);
+
+        // The offsets of our fields assume that the struct itself starts at an aligned address.
+        //
+        // Cranelift wants the alignment as a power of two exponent.
+        let align_shift = self.types.alignment_of(type_).trailing_zeros() as u8;
+
+        let reused = self
+            .free_slots
+            .get_mut(&(size, align_shift))
+            .and_then(Vec::pop);
+
+        let slot = reused.unwrap_or_else(|| {
+            self.fbuilder
+                .create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    size,
+                    align_shift,
+                ))
+        });
 
         let size_t = self.module.isa().pointer_type();
-        self.ins().stack_addr(size_t, slot, 0)
+        let ptr = self.ins().stack_addr(size_t, slot, 0);
+        self.slot_of_ptr.insert(ptr, slot);
+        ptr
+    }
+
+    // Mark the stack slot behind `ptr` as free to be reused by `stack_alloc_struct`
+    //
+    // The lifetime tracking here is coarse. A struct is considered dead once it has been moved
+    // into a call, which is only sound since our source language doesn't let a struct be used
+    // again after it has been moved. Pointers which weren't returned by `stack_alloc_struct`,
+    // such as pointer parameters, are ignored.
+    //
+    // Fields read from the struct before it was moved may however still be around, see
+    // `track_view`. Slots with such views into them are kept for the rest of the function.
+    fn free_stack_struct(&mut self, ptr: cl::Value) {
+        if let Some(slot) = self.slot_of_ptr.remove(&ptr)
+            && !self.viewed_slots.contains(&slot)
+        {
+            let data = &self.fbuilder.func.sized_stack_slots[slot];
+            let key = (data.size, data.align_shift);
+            self.free_slots.entry(key).or_default().push(slot);
+        }
+    }
+
+    // Record that `view` may point into the same stack slot as `ptr`
+    //
+    // `load_value` reads inner structs lazily, as a pointer into the struct they're part of. Since
+    // a `VirtualValue` can be cloned, that pointer may still be read from after the struct it
+    // points into has been moved. Such as with `let pos = p.position; f(p); pos.x`.
+    //
+    // We can't tell when the last clone of the view is dropped, so the slot is pinned instead.
+    fn track_view(&mut self, ptr: cl::Value, view: &VirtualValue) {
+        if let VirtualValue::StackStruct { ptr: view, .. } = view
+            && let Some(slot) = self.pin_slot(ptr)
+        {
+            self.slot_of_view.insert(*view, slot);
+        }
+    }
+
+    // Keep the stack slot `ptr` points into from being reused, if it points into one
+    fn pin_slot(&mut self, ptr: cl::Value) -> Option<ir::StackSlot> {
+        let slot = self
+            .slot_of_ptr
+            .get(&ptr)
+            .or_else(|| self.slot_of_view.get(&ptr))
+            .copied()?;
+
+        self.viewed_slots.insert(slot);
+        Some(slot)
+    }
+}
+
+fn type_name(type_: Type) -> &'static str {
+    match type_ {
+        Type::Struct(name) | Type::Enum(name) => name,
+        _ => panic!("type has no name"),
     }
 }
@@ -8,28 +8,48 @@
 //! Things to keep in mind for your own compiler:
 //!
 //! * Usually, things like field names and stringly identifiers would've already been desugared in
-//! a previous IR before they are lower into LLVM/Cranelift IR.
+//!   a previous IR before they are lower into LLVM/Cranelift IR.
 //!
-//! * This example will *not* go over alignment. Which makes it inefficient and incompatible with ABI's.
-//!   See the `struct-layouts` example for suggestions on alignment.
+//! * Fields are aligned the same way as in the `struct-layouts` example, with padding inserted
+//!   between fields and at the end of structs. Nested structs are aligned to their most-aligned field.
+//!
+//! * Enums are laid out with their tag first followed by the payload, see `tagged-union-layouts`
+//!   for other ways to represent them.
+//!
+//! * Structs passed to and from `extern "C"` functions have to follow the C ABI of the target,
+//!   which differs between architectures. See `LookupTable::struct_passing_mode` for how they're
+//!   classified, and try `-t aarch64-unknown-linux` to see `quad_double` receive its `Quad` in
+//!   four float registers.
 //!
 //! `$ cargo run --example lowering-structs -- -o lowering-structs.o`
 //! `$ clang lowering-structs.o -o lowering-structs`
 //! `$ ./lowering-structs; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example lowering-structs -- --jit`
+//!
+//! Pass `--debug-prints` to have `main` print the values it computes, such as the fields of the
+//! `Player` it constructs, using `printf` calls emitted by `FuncLower::debug_print`.
 
 use cranelift::{
-    codegen::Context,
+    codegen::{
+        Context,
+        ir::{ExternalName, LibCall},
+    },
     prelude::{self as cl, FunctionBuilderContext, InstBuilder},
 };
-use cranelift_examples::{signature_from_decl, skip_boilerplate};
-use cranelift_module::{FuncId, Linkage, Module};
+use cranelift_examples::{
+    InterpretedFunctions, SigCache, define_function, define_maybe_interpreted, for_any_module,
+    print_clif, skip_boilerplate,
+};
+use cranelift_module::{FuncId, FuncOrDataId, Linkage, Module};
 
 mod lower;
-mod types;
+pub(crate) mod types;
 
-use cranelift_object::ObjectModule;
-use lower::FuncLower;
-use types::{LookupTable, Type};
+use lower::{BinOp, CmpOp, FuncLower, Span};
+use types::{Abi, Convention, FloatWidth, LookupTable, Type};
 
 // The `VirtualValue` enum keeps track of how our original values are mapped to Cranelift values.
 //
@@ -42,17 +62,42 @@ enum VirtualValue {
 
     // Our primary way of storing structs will be to create stackslots and write the fields at
     // offsets of the stackslot pointers.
+    //
+    // Arrays are represented the same way, as they are essentially structs where all fields have the same type.
     StackStruct {
-        type_: &'static str,
+        type_: Type,
         ptr: cl::Value,
     },
 
     // Instead of writing structs to stack pointers right away, we can try holding on to them in
     // registers for a bit in-case they're temporary or will be written to other struct pointers.
     UnstableStruct {
-        type_: &'static str,
+        type_: Type,
         fields: Vec<VirtualValue>,
     },
+
+    // A struct that's been allocated on the heap, which is what a value of type `Type::Boxed` becomes.
+    //
+    // Fields are accessed the same way as for `StackStruct`. However; since the struct isn't tied
+    // to the stack frame it was created in, moving it around or returning it only moves the
+    // pointer instead of memcpy'ing the entire struct every time.
+    HeapStruct {
+        type_: Type,
+        ptr: cl::Value,
+    },
+
+    // A variant of an enum which we've constructed ourselves, such as `Outcome::Ok(5)`.
+    //
+    // Similar to `UnstableStruct`, the tag and payload are held on to until the enum needs to be
+    // written to memory. Enums we receive as parameters or from calls are `StackStruct`s instead,
+    // since we don't know which variant they hold until we match on them.
+    Enum {
+        type_: Type,
+        // The index of the variant, which decides the type of the payload
+        variant: usize,
+        tag: cl::Value,
+        payload: Box<VirtualValue>,
+    },
 }
 
 impl VirtualValue {
@@ -66,24 +111,161 @@ impl VirtualValue {
 }
 
 fn main() {
-    skip_boilerplate(b"lowering-structs", |ctx, fctx, module, _args| {
-        let mut types = types::LookupTable::hardcoded(module.isa().pointer_bytes() as u32);
+    skip_boilerplate(b"lowering-structs", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    args: clap::ArgMatches,
+) {
+    define_program(ctx, fctx, module, None, args.get_flag("debug-prints"));
+}
 
-        let main_func_id = declare_main(module, &types);
-        let move_right_func_id = declare_move_right(module, &types);
+// Define `main` along with all the functions it calls.
+//
+// If `interpreted` is given, `frame_roundtrip` and `position_after_move` are kept for the
+// interpreter as well, which `tests/lowering_structs.rs` uses to run them. Unlike the functions
+// calling `malloc` or `printf`, they only touch stack memory. `move_right` is kept too, since
+// `position_after_move` calls it.
+//
+// `debug_prints` decides whether `main` prints the values it constructs at runtime.
+pub(crate) fn define_program<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    interpreted: Option<&InterpretedFunctions>,
+    debug_prints: bool,
+) {
+    let abi = Abi::of_triple(module.isa().triple());
+    let mut types = types::LookupTable::hardcoded(module.isa().pointer_bytes() as u32, abi);
 
-        types.function_names.insert(main_func_id, "main");
-        types
-            .function_names
-            .insert(move_right_func_id, "move_right");
+    let main_func_id = declare_main(module, &types);
+    let move_right_func_id = declare_move_right(module, &types);
+    let scale_func_id = declare_scale(module, &types);
+    let cell_func_id = declare_cell(module, &types);
+    let cell_checked_func_id = declare_cell_checked(module, &types);
+    let cell_past_end_func_id = declare_cell_past_end(module, &types);
+    let spawn_func_id = declare_spawn(module, &types);
+    let id_of_func_id = declare_id_of(module, &types);
+    let is_alive_func_id = declare_is_alive(module, &types);
+    let sum_func_id = declare_sum(module, &types);
+    let copy_grid_func_id = declare_copy_grid(module, &types);
+    let move_four_times_func_id = declare_move_four_times(module, &types);
+    let quad_double_func_id = declare_quad_double(module, &types);
+    let checked_div_func_id = declare_checked_div(module, &types);
+    let unwrap_or_func_id = declare_unwrap_or(module, &types);
+    let frame_roundtrip_func_id = declare_frame_roundtrip(module, &types);
+    let position_after_move_func_id = declare_position_after_move(module, &types);
 
-        define_main(module, &types, ctx, fctx, move_right_func_id, main_func_id);
-        define_move_right(module, &types, ctx, fctx, move_right_func_id);
-    });
+    types.function_names.insert(main_func_id, "main");
+    types
+        .function_names
+        .insert(move_right_func_id, "move_right");
+    types.function_names.insert(scale_func_id, "scale");
+    types.function_names.insert(cell_func_id, "cell");
+    types
+        .function_names
+        .insert(cell_checked_func_id, "cell_checked");
+    types
+        .function_names
+        .insert(cell_past_end_func_id, "cell_past_end");
+    types.function_names.insert(spawn_func_id, "spawn");
+    types.function_names.insert(id_of_func_id, "id_of");
+    types.function_names.insert(is_alive_func_id, "is_alive");
+    types.function_names.insert(sum_func_id, "sum");
+    types.function_names.insert(copy_grid_func_id, "copy_grid");
+    types
+        .function_names
+        .insert(move_four_times_func_id, "move_four_times");
+    types
+        .function_names
+        .insert(quad_double_func_id, "quad_double");
+    types
+        .function_names
+        .insert(checked_div_func_id, "checked_div");
+    types.function_names.insert(unwrap_or_func_id, "unwrap_or");
+    types
+        .function_names
+        .insert(frame_roundtrip_func_id, "frame_roundtrip");
+    types
+        .function_names
+        .insert(position_after_move_func_id, "position_after_move");
+
+    // Every function looks up its own signature, and `FuncLower` those of the functions it calls
+    let mut sigs = SigCache::new();
+
+    define_main(
+        module,
+        &types,
+        &mut sigs,
+        ctx,
+        fctx,
+        main_func_id,
+        debug_prints,
+    );
+    define_move_right(
+        module,
+        &types,
+        &mut sigs,
+        interpreted,
+        ctx,
+        fctx,
+        move_right_func_id,
+    );
+    define_scale(module, &types, &mut sigs, ctx, fctx, scale_func_id);
+    define_cell(module, &types, &mut sigs, ctx, fctx, cell_func_id);
+    define_cell_checked(module, &types, &mut sigs, ctx, fctx, cell_checked_func_id);
+    define_cell_past_end(module, &types, &mut sigs, ctx, fctx, cell_past_end_func_id);
+    define_spawn(module, &types, &mut sigs, ctx, fctx, spawn_func_id);
+    define_id_of(module, &types, &mut sigs, ctx, fctx, id_of_func_id);
+    define_is_alive(module, &types, &mut sigs, ctx, fctx, is_alive_func_id);
+    define_sum(module, &types, &mut sigs, ctx, fctx, sum_func_id);
+    define_copy_grid(module, &types, &mut sigs, ctx, fctx, copy_grid_func_id);
+    define_move_four_times(
+        module,
+        &types,
+        &mut sigs,
+        ctx,
+        fctx,
+        move_four_times_func_id,
+    );
+    define_quad_double(module, &types, &mut sigs, ctx, fctx, quad_double_func_id);
+    define_checked_div(module, &types, &mut sigs, ctx, fctx, checked_div_func_id);
+    define_unwrap_or(module, &types, &mut sigs, ctx, fctx, unwrap_or_func_id);
+    define_frame_roundtrip(
+        module,
+        &types,
+        &mut sigs,
+        interpreted,
+        ctx,
+        fctx,
+        frame_roundtrip_func_id,
+    );
+    define_position_after_move(
+        module,
+        &types,
+        &mut sigs,
+        interpreted,
+        ctx,
+        fctx,
+        position_after_move_func_id,
+    );
+}
+
+// Look up a previously declared function by its symbol
+fn func_id_of(module: &impl Module, name: &str) -> FuncId {
+    match module.get_name(name) {
+        Some(FuncOrDataId::Func(id)) => id,
+        _ => panic!("function {name} has not been declared"),
+    }
 }
 
 // fn main() -> int;
-fn declare_main(module: &mut ObjectModule, types: &LookupTable) -> FuncId {
+fn declare_main(module: &mut impl Module, types: &LookupTable) -> FuncId {
     let call_conv = module.isa().default_call_conv();
     let sig = types.create_signature(call_conv, "main");
 
@@ -93,7 +275,7 @@ fn declare_main(module: &mut ObjectModule, types: &LookupTable) -> FuncId {
 }
 
 // fn move_right(p: Player, by: int) -> Player;
-fn declare_move_right(module: &mut ObjectModule, types: &LookupTable) -> FuncId {
+fn declare_move_right(module: &mut impl Module, types: &LookupTable) -> FuncId {
     let call_conv = module.isa().default_call_conv();
     let sig = types.create_signature(call_conv, "move_right");
 
@@ -102,27 +284,214 @@ fn declare_move_right(module: &mut ObjectModule, types: &LookupTable) -> FuncId
         .unwrap()
 }
 
+// fn scale(v: Vec2, by: f32) -> Vec2;
+fn declare_scale(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "scale");
+
+    module
+        .declare_function("scale", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn cell(b: Board, i: int) -> int;
+fn declare_cell(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "cell");
+
+    module
+        .declare_function("cell", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn cell_checked(b: Board, i: int) -> int;
+fn declare_cell_checked(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "cell_checked");
+
+    module
+        .declare_function("cell_checked", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn cell_past_end(b: Board) -> int;
+fn declare_cell_past_end(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "cell_past_end");
+
+    module
+        .declare_function("cell_past_end", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn spawn(id: int) -> Box<Player>;
+fn declare_spawn(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "spawn");
+
+    module
+        .declare_function("spawn", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn id_of(p: Option<Box<Player>>) -> int;
+fn declare_id_of(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "id_of");
+
+    module
+        .declare_function("id_of", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn is_alive(p: Box<Player>) -> bool;
+fn declare_is_alive(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "is_alive");
+
+    module
+        .declare_function("is_alive", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn sum(xs: &[int]) -> int;
+fn declare_sum(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "sum");
+
+    module
+        .declare_function("sum", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn copy_grid(g: Grid) -> Grid;
+fn declare_copy_grid(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "copy_grid");
+
+    module
+        .declare_function("copy_grid", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn move_four_times(p: Player) -> Player;
+fn declare_move_four_times(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "move_four_times");
+
+    module
+        .declare_function("move_four_times", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// extern "C" fn quad_double(q: Quad) -> Quad;
+fn declare_quad_double(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "quad_double");
+
+    module
+        .declare_function("quad_double", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn checked_div(a: int, b: int) -> Outcome;
+fn declare_checked_div(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "checked_div");
+
+    module
+        .declare_function("checked_div", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn unwrap_or(o: Outcome, default: int) -> int;
+fn declare_unwrap_or(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "unwrap_or");
+
+    module
+        .declare_function("unwrap_or", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn frame_roundtrip(seq: int, x: int, y: int) -> int;
+fn declare_frame_roundtrip(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "frame_roundtrip");
+
+    module
+        .declare_function("frame_roundtrip", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn position_after_move(x: int, y: int) -> int;
+fn declare_position_after_move(module: &mut impl Module, types: &LookupTable) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let sig = types.create_signature(call_conv, "position_after_move");
+
+    module
+        .declare_function("position_after_move", Linkage::Export, &sig)
+        .unwrap()
+}
+
 // fn main() -> int {
 //   move_right(Player {
 //      id: 5,
 //      position: Point { x: 10, y: 20 },
+//      alive: true,
 //   }, 2);
-//   return 0;
+//   scale(Vec2 { x: 1.5, y: 2.5 }, 2.0);
+//   spawn(7).position.x;
+//   sum(&[1, 2, 3, 4][..]);
+//   copy_grid(Grid { cells: [0; 32] });
+//   quad_double(Quad { a: 1.0, b: 2.0, c: 3.0, d: 4.0 });
+//   unwrap_or(checked_div(20, 4), 0);
+//   (-1 as uint) > 0;
+//   -1 > 0;
+//   (-2 as uint) / 2;
+//   -2 / 2;
+//   (-17 / 5, -17 % 5);
+//   Player { id: 9, alive: true, .. };
+//   Packet::Data(Frame { seq: 1, origin: Point { x: 2, y: 3 } })
+//     == Packet::Data(Frame { seq: 1, origin: Point { x: 2, y: 3 } });
+//   Packet::Data(Frame { seq: 1, origin: Point { x: 2, y: 3 } })
+//     == Packet::Data(Frame { seq: 1, origin: Point { x: 2, y: 4 } });
+//   Packet::Data(Frame { seq: 1, origin: Point { x: 2, y: 3 } }) == Packet::Failed(1);
+//   return cell(Board { cells: [1, 2, 3, 4] }, 2);
 // }
 fn define_main(
-    module: &mut ObjectModule,
+    module: &mut impl Module,
     types: &LookupTable,
+    sigs: &mut SigCache,
     ctx: &mut Context,
     fctx: &mut FunctionBuilderContext,
-    move_right_func_id: FuncId,
     id: FuncId,
+    debug_prints: bool,
 ) {
+    let move_right_func_id = func_id_of(module, "move_right");
+    let scale_func_id = func_id_of(module, "scale");
+    let cell_func_id = func_id_of(module, "cell");
+    let cell_checked_func_id = func_id_of(module, "cell_checked");
+    let spawn_func_id = func_id_of(module, "spawn");
+    let id_of_func_id = func_id_of(module, "id_of");
+    let is_alive_func_id = func_id_of(module, "is_alive");
+    let sum_func_id = func_id_of(module, "sum");
+    let copy_grid_func_id = func_id_of(module, "copy_grid");
+    let quad_double_func_id = func_id_of(module, "quad_double");
+    let checked_div_func_id = func_id_of(module, "checked_div");
+    let unwrap_or_func_id = func_id_of(module, "unwrap_or");
+
     let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
-    builder.func.signature = signature_from_decl(module, id);
+    builder.func.signature = sigs.get(module, id).clone();
 
-    let mut lower = FuncLower::new(&types, &mut builder, module);
-    let (entry, _vparams) = lower.create_entry_block(&[]);
-    lower.fbuilder.switch_to_block(entry);
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, _vparams) = lower.create_entry_block(&[]);
+
+    // Print the fields of the `Player` we construct before passing it along, as well as the other
+    // values `main` computes
+    //
+    // The `printf` calls are left out unless `--debug-prints` was passed.
+    lower.debug_prints = debug_prints;
 
     let player: VirtualValue = {
         let id = lower.int(5);
@@ -134,32 +503,301 @@ fn define_main(
             lower.construct_struct("Point", &[("x", x), ("y", y)])
         };
 
-        lower.construct_struct("Player", &[("id", id), ("position", position)])
+        let alive = lower.bool(true);
+
+        lower.construct_struct(
+            "Player",
+            &[("id", id), ("position", position), ("alive", alive)],
+        )
     };
 
+    lower.debug_print(&player, Type::Struct("Player"));
+
     let _moved_player: VirtualValue = {
         let two = lower.ins().iconst(cl::types::I32, 2);
         lower.call_func(move_right_func_id, vec![player, VirtualValue::Scalar(two)])
     };
 
-    let exit_code = lower.int(0);
+    let _scaled_vec: VirtualValue = {
+        let v = {
+            let x = lower.float(FloatWidth::F32, 1.5);
+            let y = lower.float(FloatWidth::F32, 2.5);
+
+            lower.construct_struct("Vec2", &[("x", x), ("y", y)])
+        };
+
+        let by = lower.float(FloatWidth::F32, 2.0);
+        lower.call_func(scale_func_id, vec![v, by])
+    };
+
+    let spawned: VirtualValue = {
+        let id = lower.int(7);
+        lower.call_func(spawn_func_id, vec![id])
+    };
+
+    let _spawned_x: VirtualValue = {
+        // Reading fields through the heap pointer works the same way as for stack pointers
+        let position = lower.destruct_field(&spawned, types.resolve_field("Player", "position"));
+        lower.destruct_field(&position, types.resolve_field("Point", "x"))
+    };
+
+    // A `Box` is never null, so passing it where an `Option<Box<Player>>` is expected is the
+    // same as wrapping it in `Some`
+    let spawned_alive: VirtualValue = lower.call_func(is_alive_func_id, vec![spawned.clone()]);
+
+    lower.debug_print(&spawned_alive, Type::Bool);
+
+    let spawned_id: VirtualValue = lower.call_func(id_of_func_id, vec![spawned]);
+
+    lower.debug_print(&spawned_id, Type::Int);
+
+    let _sum: VirtualValue = {
+        let numbers = {
+            let elems = [1, 2, 3, 4].map(|n| lower.int(n)).to_vec();
+            lower.construct_array(Type::Array(&Type::Int, 4), elems)
+        };
+
+        // The slice only holds a pointer to the array, which is spilled to the stack for us
+        let slice = lower.slice_of_array(&numbers);
+        lower.call_func(sum_func_id, vec![slice])
+    };
+
+    let _grid: VirtualValue = {
+        let cells = [0; 32].map(|n| lower.int(n)).to_vec();
+        let cells = lower.construct_array(Type::Array(&Type::Int, 32), cells);
+        let grid = lower.construct_struct("Grid", &[("cells", cells)]);
+
+        lower.call_func(copy_grid_func_id, vec![grid])
+    };
+
+    let _quad: VirtualValue = {
+        let fields = [("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)]
+            .map(|(name, n)| (name, lower.float(FloatWidth::F32, n)));
+        let quad = lower.construct_struct("Quad", &fields);
+
+        lower.call_func(quad_double_func_id, vec![quad])
+    };
+
+    // The `Outcome` returned by `checked_div` is passed straight along to `unwrap_or`
+    let quotient: VirtualValue = {
+        let a = lower.int(20);
+        let b = lower.int(4);
+        let outcome = lower.call_func(checked_div_func_id, vec![a, b]);
+
+        let default = lower.int(0);
+        lower.call_func(unwrap_or_func_id, vec![outcome, default])
+    };
+
+    lower.debug_print(&quotient, Type::Int);
+
+    let checked_cell: VirtualValue = {
+        let board = {
+            let cells = [5, 6, 7, 8].map(|n| lower.int(n)).to_vec();
+            let cells = lower.construct_array(Type::Array(&Type::Int, 4), cells);
+
+            lower.construct_struct("Board", &[("cells", cells)])
+        };
+
+        let three = lower.int(3);
+        lower.call_func(cell_checked_func_id, vec![board, three])
+    };
+
+    lower.debug_print(&checked_cell, Type::Int);
+
+    // The same bits give different results depending on the signedness of the type
+    {
+        let zero = lower.int(0);
+
+        // (-1 as uint) > 0
+        //
+        // `-1 as uint` is `0xFFFFFFFF`, the largest `uint`, so this is `true`
+        let max = lower.uint(u32::MAX);
+        let unsigned_gt = lower.compare(CmpOp::Gt, &max, &zero, Type::UInt);
+        lower.debug_print(&unsigned_gt, Type::Bool);
+
+        // -1 > 0
+        let minus_one = lower.int(-1);
+        let signed_gt = lower.compare(CmpOp::Gt, &minus_one, &zero, Type::Int);
+        lower.debug_print(&signed_gt, Type::Bool);
+
+        let two = lower.int(2);
+
+        // (-2 as uint) / 2
+        //
+        // `udiv` gives 2147483647
+        let big = lower.uint(-2i32 as u32);
+        let unsigned_div = lower.binary(BinOp::Div, &big, &two, Type::UInt);
+        lower.debug_print(&unsigned_div, Type::UInt);
+
+        // -2 / 2
+        //
+        // `sdiv` gives -1
+        let minus_two = lower.int(-2);
+        let signed_div = lower.binary(BinOp::Div, &minus_two, &two, Type::Int);
+        lower.debug_print(&signed_div, Type::Int);
+    }
+
+    // (-17 / 5, -17 % 5)
+    //
+    // Computed with a single division, which gives -3 and -2
+    {
+        let a = lower.int(-17);
+        let b = lower.int(5);
+
+        let (quotient, remainder) = lower.divmod(&a, &b, Type::Int);
+        lower.debug_print(&quotient, Type::Int);
+        lower.debug_print(&remainder, Type::Int);
+    }
+
+    // Player { id: 9, alive: true, .. }
+    //
+    // `position` isn't given, so it's left as zero by `zero_struct`
+    {
+        let id = lower.int(9);
+        let alive = lower.bool(true);
+
+        let player = lower.construct_struct("Player", &[("id", id), ("alive", alive)]);
+        lower.debug_print(&player, Type::Struct("Player"));
+    }
+
+    // Packet::Data(Frame { seq: 1, origin: Point { x: 2, y: 3 } }) == ...
+    //
+    // Only the first comparison is `true`. The second differs in a field of the nested `Point`,
+    // and the third never gets to compare payloads since the tags already differ.
+    {
+        let data = |lower: &mut FuncLower<_>, seq, x, y| {
+            let seq = lower.int(seq);
+            let origin = {
+                let x = lower.int(x);
+                let y = lower.int(y);
+                lower.construct_struct("Point", &[("x", x), ("y", y)])
+            };
+            let frame = lower.construct_struct("Frame", &[("seq", seq), ("origin", origin)]);
+            lower.construct_variant("Packet", "Data", frame)
+        };
+
+        let a = data(&mut lower, 1, 2, 3);
+        let b = data(&mut lower, 1, 2, 3);
+        let c = data(&mut lower, 1, 2, 4);
+        let failed = {
+            let code = lower.int(1);
+            lower.construct_variant("Packet", "Failed", code)
+        };
+
+        let same = lower.eq_enum(&a, &b);
+        lower.debug_print(&same, Type::Bool);
+
+        let different_payload = lower.eq_enum(&a, &c);
+        lower.debug_print(&different_payload, Type::Bool);
+
+        let different_tag = lower.eq_enum(&a, &failed);
+        lower.debug_print(&different_tag, Type::Bool);
+    }
+
+    // Contents::Cells([1, 2, 3, 4]) == ...
+    //
+    // Arrays are compared element-wise, so `[1, 2, 3, 4]` equals another `[1, 2, 3, 4]` but not
+    // `[1, 2, 0, 4]`. Slices and boxes are compared by identity, so a slice is only equal to itself
+    // and not to a slice of another array with the same elements. The same goes for a box compared
+    // with a copy of itself.
+    {
+        let array = |lower: &mut FuncLower<_>, ns: [i64; 4]| {
+            let cells = ns.map(|n| lower.int(n)).to_vec();
+            lower.construct_array(Type::Array(&Type::Int, 4), cells)
+        };
+
+        let cells = array(&mut lower, [1, 2, 3, 4]);
+        let same_cells = array(&mut lower, [1, 2, 3, 4]);
+        let other_cells = array(&mut lower, [1, 2, 0, 4]);
+
+        let a = lower.construct_variant("Contents", "Cells", cells.clone());
+        let b = lower.construct_variant("Contents", "Cells", same_cells.clone());
+        let c = lower.construct_variant("Contents", "Cells", other_cells);
+        let elementwise = lower.eq_enum(&a, &b);
+        lower.debug_print(&elementwise, Type::Bool);
+        let different_element = lower.eq_enum(&a, &c);
+        lower.debug_print(&different_element, Type::Bool);
+
+        // `cells` isn't in memory yet, so each slice of it would get a copy of its own
+        let view = lower.slice_of_array(&cells);
+        let copy_view = lower.slice_of_array(&same_cells);
+
+        let a = lower.construct_variant("Contents", "View", view.clone());
+        let b = lower.construct_variant("Contents", "View", view);
+        let c = lower.construct_variant("Contents", "View", copy_view);
+        let same_view = lower.eq_enum(&a, &b);
+        lower.debug_print(&same_view, Type::Bool);
+        let other_view = lower.eq_enum(&a, &c);
+        lower.debug_print(&other_view, Type::Bool);
+
+        let player = {
+            let id = lower.int(9);
+            lower.construct_struct("Player", &[("id", id)])
+        };
+        let boxed = lower.box_struct(player.clone());
+        let copy = lower.box_struct(player);
+
+        let a = lower.construct_variant("Contents", "Owner", boxed.clone());
+        let b = lower.construct_variant("Contents", "Owner", boxed);
+        let c = lower.construct_variant("Contents", "Owner", copy);
+        let same_box = lower.eq_enum(&a, &b);
+        lower.debug_print(&same_box, Type::Bool);
+        let other_box = lower.eq_enum(&a, &c);
+        lower.debug_print(&other_box, Type::Bool);
+    }
+
+    let exit_code: VirtualValue = {
+        let board = {
+            let cells = [1, 2, 3, 4].map(|n| lower.int(n)).to_vec();
+            let cells = lower.construct_array(Type::Array(&Type::Int, 4), cells);
+
+            lower.construct_struct("Board", &[("cells", cells)])
+        };
+
+        let two = lower.int(2);
+        lower.call_func(cell_func_id, vec![board, two])
+    };
+
     lower.return_(exit_code);
 
     builder.finalize();
 
-    println!("fn main:\n{}", &ctx.func);
+    print_clif("fn main", &ctx.func);
 
-    module.define_function(id, ctx).unwrap();
+    define_function(module, id, ctx).unwrap();
     ctx.clear();
 }
 
+// The source code of `move_right`, which we pretend to have parsed
+const MOVE_RIGHT_SOURCE: &str = "\
+fn move_right(p: Player, by: int) -> Player {
+   Player {
+     id: p.id,
+     position: Point {
+         x: if p.alive { p.position.x + by } else { p.position.x },
+         y: p.position.y,
+     },
+     alive: p.alive,
+   }
+}";
+
+// Find the span of the first occurrence of `snippet`, standing in for the spans a parser would give us
+fn span_of(source: &str, snippet: &str) -> Span {
+    let start = source.find(snippet).expect("snippet not in source") as u32;
+    Span {
+        start,
+        end: start + snippet.len() as u32,
+    }
+}
+
 // fn move_right(p: Player, by: int) -> Player {
 //    Player {
 //      id: p.id,
 //      position: Point {
-//          x: p.position.x + by,
+//          x: if p.alive { p.position.x + by } else { p.position.x },
 //          y: p.position.y,
-//      }
+//      },
+//      alive: p.alive,
 //    }
 // }
 //
@@ -167,27 +805,36 @@ fn define_main(
 //
 // fn move_right(ret: *Player, p: *Player, by: int) -> () {
 //    *(ret+0) = *(p+0);
-//    *(ret+8) = *(p+8) + by;
-//    *(ret+16) = *(p+16);
+//    *(ret+4) = if *(p+12) != 0 { *(p+4) + by } else { *(p+4) };
+//    *(ret+8) = *(p+8);
+//    *(ret+12) = *(p+12);
 // }
+//
+// Each expression is given the span it has in `MOVE_RIGHT_SOURCE`, which shows up as `@xxxx` in
+// front of the instructions in the printed CLIF.
 fn define_move_right(
-    module: &mut ObjectModule,
+    module: &mut impl Module,
     types: &LookupTable,
+    sigs: &mut SigCache,
+    interpreted: Option<&InterpretedFunctions>,
     ctx: &mut Context,
     fctx: &mut FunctionBuilderContext,
     id: FuncId,
 ) {
-    ctx.func.signature = signature_from_decl(module, id);
+    ctx.func.signature = sigs.get(module, id).clone();
     let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
 
-    let mut lower = FuncLower::new(&types, &mut builder, module);
-    let (entry, vparams) = lower.create_entry_block(&[Type::Struct("Player"), Type::Int]);
-    lower.fbuilder.switch_to_block(entry);
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Struct("Player"), Type::Int]);
+
+    let span = |snippet| span_of(MOVE_RIGHT_SOURCE, snippet);
 
     let player = {
+        lower.set_span(span("p.id"));
         let id = lower.destruct_field(&vparams[0], types.resolve_field("Player", "id"));
 
         let position = {
+            lower.set_span(span("p.position.x + by"));
             let p_position =
                 lower.destruct_field(&vparams[0], types.resolve_field("Player", "position"));
 
@@ -197,21 +844,935 @@ fn define_move_right(
                     .as_scalar();
 
                 let by = vparams[1].as_scalar();
-                VirtualValue::Scalar(lower.ins().iadd(x, by))
+                let moved = lower.ins().iadd(x, by);
+
+                // Only players that are alive are able to move
+                let alive = {
+                    lower.set_span(span("if p.alive"));
+                    let alive =
+                        lower.destruct_field(&vparams[0], types.resolve_field("Player", "alive"));
+                    lower.condition(&alive)
+                };
+
+                VirtualValue::Scalar(lower.ins().select(alive, moved, x))
             };
 
+            lower.set_span(span("p.position.y"));
             let y = lower.destruct_field(&p_position, types.resolve_field("Point", "y"));
             lower.construct_struct("Point", &[("x", x), ("y", y)])
         };
 
-        lower.construct_struct("Player", &[("id", id), ("position", position)])
+        lower.set_span(span("alive: p.alive"));
+        let alive = lower.destruct_field(&vparams[0], types.resolve_field("Player", "alive"));
+
+        lower.construct_struct(
+            "Player",
+            &[("id", id), ("position", position), ("alive", alive)],
+        )
     };
 
     lower.return_(player);
     builder.finalize();
 
-    println!("fn move_right:\n{}", &ctx.func);
+    print_clif("fn move_right", &ctx.func);
+
+    define_maybe_interpreted(module, id, ctx, interpreted).unwrap();
+    ctx.clear();
+}
+
+// fn scale(v: Vec2, by: f32) -> Vec2 {
+//    Vec2 {
+//      x: v.x * by,
+//      y: v.y * by,
+//    }
+// }
+//
+// Since `Vec2` is small enough to be passed by scalars, its fields are passed and returned in
+// floating-point registers.
+fn define_scale(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) =
+        lower.create_entry_block(&[Type::Struct("Vec2"), Type::Float(FloatWidth::F32)]);
+
+    let scaled = {
+        let by = vparams[1].as_scalar();
+
+        let [x, y] = ["x", "y"].map(|name| {
+            let field = lower
+                .destruct_field(&vparams[0], types.resolve_field("Vec2", name))
+                .as_scalar();
+
+            // Floats use their own set of arithmetic instructions
+            VirtualValue::Scalar(lower.ins().fmul(field, by))
+        });
+
+        lower.construct_struct("Vec2", &[("x", x), ("y", y)])
+    };
+
+    lower.return_(scaled);
+    builder.finalize();
+
+    print_clif("fn scale", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn cell(b: Board, i: int) -> int {
+//    b.cells[i]
+// }
+//
+// // -- Although what we'll actually be lowering it into is something more like -- //
+//
+// fn cell(b: *Board, i: int) -> int {
+//    *(b + i * 4)
+// }
+fn define_cell(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Struct("Board"), Type::Int]);
+
+    let cell = {
+        let cells = lower.destruct_field(&vparams[0], types.resolve_field("Board", "cells"));
+        let i = vparams[1].as_scalar();
+
+        lower.index(&cells, i)
+    };
+
+    lower.return_(cell);
+    builder.finalize();
+
+    print_clif("fn cell", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn cell_checked(b: Board, i: int) -> int {
+//    b.cells[i]
+// }
+//
+// // -- The same as `cell`, except that the index is checked against the length of the array -- //
+//
+// fn cell_checked(b: *Board, i: int) -> int {
+//    if i >= 4 { trap }
+//    *(b + i * 4)
+// }
+fn define_cell_checked(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Struct("Board"), Type::Int]);
+
+    let cell = {
+        let cells = lower.destruct_field(&vparams[0], types.resolve_field("Board", "cells"));
+        let i = vparams[1].as_scalar();
+
+        // The length of an array is part of its type, so it's a constant here
+        let len = lower.int(4).as_scalar();
+
+        lower.index_checked(&cells, i, len)
+    };
+
+    lower.return_(cell);
+    builder.finalize();
+
+    print_clif("fn cell_checked", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn cell_past_end(b: Board) -> int {
+//    b.cells[4]
+// }
+//
+// The index is known at compile time to be out of bounds, but we still lower it the same way and
+// let it trap at runtime. A compiler could report this as an error instead, although it can't
+// catch every case since most indices aren't constants.
+//
+// `main` doesn't call this function since it would end the program. Call it from C to see it
+// trap with `SIGILL` on x86-64.
+fn define_cell_past_end(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Struct("Board")]);
+
+    let cell = {
+        let cells = lower.destruct_field(&vparams[0], types.resolve_field("Board", "cells"));
+        let i = lower.int(4).as_scalar();
+        let len = lower.int(4).as_scalar();
+
+        lower.index_checked(&cells, i, len)
+    };
+
+    lower.return_(cell);
+    builder.finalize();
+
+    print_clif("fn cell_past_end", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn spawn(id: int) -> Box<Player> {
+//    Box::new(Player {
+//      id,
+//      position: Point { x: 0, y: 0 },
+//      alive: true,
+//    })
+// }
+//
+// // -- Although what we'll actually be lowering it into is something more like -- //
+//
+// fn spawn(id: int) -> *Player {
+//    let p = malloc(16);
+//    *(p+0) = id;
+//    *(p+4) = 0;
+//    *(p+8) = 0;
+//    *(p+12) = 1;
+//    return p;
+// }
+//
+// Had we returned the `Player` by value, the caller would've had to provide an out pointer for us
+// to copy the struct into. Since the heap allocation outlives our stack frame, we can just return
+// the pointer instead.
+fn define_spawn(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Int]);
+
+    let player = {
+        let position = {
+            let x = lower.int(0);
+            let y = lower.int(0);
+
+            lower.construct_struct("Point", &[("x", x), ("y", y)])
+        };
+
+        let alive = lower.bool(true);
+
+        lower.construct_struct(
+            "Player",
+            &[
+                ("id", vparams[0].clone()),
+                ("position", position),
+                ("alive", alive),
+            ],
+        )
+    };
+
+    let boxed = lower.box_struct(player);
+
+    lower.return_(boxed);
+    builder.finalize();
+
+    print_clif("fn spawn", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn id_of(p: Option<Box<Player>>) -> int {
+//    match p {
+//      Some(p) => p.id,
+//      None => trap,
+//    }
+// }
+//
+// // -- Although what we'll actually be lowering it into is something more like -- //
+//
+// fn id_of(p: *Player) -> int {
+//    if p == null { trap }
+//    *p
+// }
+//
+// An `Option` of a pointer doesn't need a tag, since a pointer which is never null can use null to
+// represent `None`. Rust makes the same guarantee for `Option<&T>` and `Option<Box<T>>`.
+fn define_id_of(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Boxed(&Type::Struct("Player"))]);
+
+    let player = &vparams[0];
+
+    // None => trap
+    //
+    // Reading the field without this check would dereference address zero, which would also
+    // crash on most platforms but without telling us why.
+    lower.null_check(player);
+
+    // Some(p) => p.id
+    let player_id = lower.destruct_field(player, types.resolve_field("Player", "id"));
+
+    lower.return_(player_id);
+    builder.finalize();
+
+    print_clif("fn id_of", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn is_alive(p: Box<Player>) -> bool {
+//    p.alive
+// }
+//
+// The `bool` is returned as an `i8`, which is marked to be zero extended in the signature. See
+// `scalar_param` in `types.rs`.
+fn define_is_alive(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Boxed(&Type::Struct("Player"))]);
+
+    let player = &vparams[0];
+
+    // p.alive
+    let alive = lower.destruct_field(player, types.resolve_field("Player", "alive"));
+
+    lower.return_(alive);
+    builder.finalize();
+
+    print_clif("fn is_alive", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn sum(xs: &[int]) -> int {
+//    let total = 0;
+//    let i = 0;
+//    while i < xs.len() {
+//      total += xs[i];
+//      i += 1;
+//    }
+//    return total;
+// }
+//
+// // -- Although what we'll actually be lowering it into is something more like -- //
+//
+// fn sum(xs_ptr: usize, xs_len: usize) -> int {
+//    loop(i = 0, total = 0) {
+//      if i >= xs_len { return total }
+//      if i >= xs_len { trap } // from the bounds check
+//      continue(i + 1, total + *(xs_ptr + i * 4));
+//    }
+// }
+//
+// Since a slice is only two scalars, it's passed in two registers.
+fn define_sum(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Slice(&Type::Int)]);
+
+    let xs = &vparams[0];
+
+    // See the `loops` example for more details on lowering loops
+    let header = lower.fbuilder.create_block();
+    let body = lower.fbuilder.create_block();
+    let exit = lower.fbuilder.create_block();
+
+    // header(i: int, total: int)
+    lower.fbuilder.append_block_param(header, cl::types::I32);
+    lower.fbuilder.append_block_param(header, cl::types::I32);
+
+    // exit(total: int)
+    lower.fbuilder.append_block_param(exit, cl::types::I32);
+
+    // let total = 0;
+    // let i = 0;
+    {
+        let zero = lower.ins().iconst(cl::types::I32, 0);
+        lower.ins().jump(header, &[zero.into(), zero.into()]);
+    }
+
+    // while i < xs.len()
+    let (i, total) = {
+        lower.fbuilder.switch_to_block(header);
+
+        let i = lower.fbuilder.block_params(header)[0];
+        let total = lower.fbuilder.block_params(header)[1];
+
+        // The length is a `usize` while our index is an `int`, so they need to be the same width
+        // before they can be compared.
+        let len = lower.slice_len(xs).as_scalar();
+        let size_t = lower.module.isa().pointer_type();
+        let wide_i = if size_t.bits() > 32 {
+            lower.ins().uextend(size_t, i)
+        } else {
+            i
+        };
+        let in_range = lower.ins().icmp(cl::IntCC::UnsignedLessThan, wide_i, len);
+
+        lower.ins().brif(in_range, body, &[], exit, &[total.into()]);
+
+        (i, total)
+    };
+
+    lower.fbuilder.seal_block(body);
+
+    // total += xs[i];
+    // i += 1;
+    //
+    // The bounds check in `slice_index` is redundant here since the loop condition already
+    // checked `i`, but we'd need to prove that to remove it.
+    {
+        lower.fbuilder.switch_to_block(body);
+
+        let x = lower.slice_index(xs, i).as_scalar();
+        let total = lower.ins().iadd(total, x);
+        let i = lower.ins().iadd_imm(i, 1);
+
+        lower.ins().jump(header, &[i.into(), total.into()]);
+    }
+
+    lower.fbuilder.seal_block(header);
+
+    // return total;
+    let total = {
+        lower.fbuilder.seal_block(exit);
+        lower.fbuilder.switch_to_block(exit);
+
+        VirtualValue::Scalar(lower.fbuilder.block_params(exit)[0])
+    };
+
+    lower.return_(total);
+    builder.finalize();
+
+    print_clif("fn sum", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn copy_grid(g: Grid) -> Grid {
+//    g
+// }
+//
+// // -- Although what we'll actually be lowering it into is something more like -- //
+//
+// fn copy_grid(ret: *Grid, g: *Grid) -> () {
+//    memcpy(ret, g, 128);
+// }
+//
+// `Grid` is 128 bytes, which is above the `memcpy_threshold` of `FuncLower`. So instead of 32
+// loads and 32 stores, the struct is copied with a single call to `memcpy`.
+fn define_copy_grid(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Struct("Grid")]);
+
+    lower.return_(vparams[0].clone());
+    builder.finalize();
+
+    print_clif("fn copy_grid", &ctx.func);
+
+    let calls_memcpy = ctx
+        .func
+        .dfg
+        .ext_funcs
+        .values()
+        .any(|f| matches!(f.name, ExternalName::LibCall(LibCall::Memcpy)));
+    assert!(calls_memcpy, "large structs should be copied with memcpy");
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn move_four_times(p: Player) -> Player {
+//    let a = move_right(p, 1);
+//    let b = move_right(a, 2);
+//    let c = move_right(b, 3);
+//    move_right(c, 4)
+// }
+//
+// Each call returns its `Player` through an out pointer into a new stack slot. However; once a
+// `Player` has been moved into the next call, its stack slot is dead. So instead of four stack
+// slots, `FuncLower` only needs two, alternating between them.
+//
+// // -- Although what we'll actually be lowering it into is something more like -- //
+//
+// fn move_four_times(ret: *Player, p: *Player) -> () {
+//    let slot0: Player;
+//    let slot1: Player;
+//    move_right(&slot0, p, 1);
+//    move_right(&slot1, &slot0, 2);
+//    move_right(&slot0, &slot1, 3);
+//    move_right(&slot1, &slot0, 4);
+//    *ret = slot1;
+// }
+fn define_move_four_times(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    let move_right_func_id = func_id_of(module, "move_right");
+
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Struct("Player")]);
+
+    let moved = [1, 2, 3, 4].into_iter().fold(vparams[0].clone(), |p, by| {
+        let by = lower.int(by);
+        lower.call_func(move_right_func_id, vec![p, by])
+    });
+
+    lower.return_(moved);
+    builder.finalize();
+
+    print_clif("fn move_four_times", &ctx.func);
+
+    let frame_size = ctx
+        .func
+        .sized_stack_slots
+        .values()
+        .map(|slot| slot.size)
+        .sum::<u32>();
+    let player_size = types.size_of(Type::Struct("Player"));
+
+    // Without reusing stack slots, every call would've had its own
+    println!(
+        "frame size: {frame_size} bytes, instead of {} bytes\n",
+        player_size * 4
+    );
+    assert_eq!(frame_size, player_size * 2);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// extern "C" fn quad_double(q: Quad) -> Quad {
+//    Quad {
+//      a: q.a * 2.0,
+//      b: q.b * 2.0,
+//      c: q.c * 2.0,
+//      d: q.d * 2.0,
+//    }
+// }
+//
+// // -- Although what we'll actually be lowering it into depends on the target -- //
+//
+// On AArch64, `Quad` is a Homogeneous Floating-point Aggregate, so each field is passed and
+// returned in its own float register.
+//
+// extern "C" fn quad_double(a: f32, b: f32, c: f32, d: f32) -> (f32, f32, f32, f32);
+//
+// On x86-64 it's split into two eightbytes of two floats each, and each pair is passed and
+// returned in a single SSE register.
+//
+// extern "C" fn quad_double(ab: f64, cd: f64) -> (f64, f64);
+fn define_quad_double(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    lower.convention = Convention::C;
+    let (_, vparams) = lower.create_entry_block(&[Type::Struct("Quad")]);
+
+    let doubled = {
+        let two = lower.float(FloatWidth::F32, 2.0).as_scalar();
+
+        let fields = ["a", "b", "c", "d"].map(|name| {
+            let field = lower
+                .destruct_field(&vparams[0], types.resolve_field("Quad", name))
+                .as_scalar();
+
+            (name, VirtualValue::Scalar(lower.ins().fmul(field, two)))
+        });
+
+        lower.construct_struct("Quad", &fields)
+    };
+
+    lower.return_(doubled);
+    builder.finalize();
+
+    print_clif("fn quad_double", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn checked_div(a: int, b: int) -> Outcome {
+//    if b == 0 {
+//      Outcome::Err(a)
+//    } else {
+//      Outcome::Ok(a / b)
+//    }
+// }
+//
+// // -- Although what we'll actually be lowering it into is something more like -- //
+//
+// fn checked_div(ret: *Outcome, a: int, b: int) -> () {
+//    if b == 0 {
+//      *(ret+0) = 1;
+//      *(ret+4) = a;
+//    } else {
+//      *(ret+0) = 0;
+//      *(ret+4) = a / b;
+//    }
+// }
+//
+// Since each branch returns on its own, the variants never need to be merged into one value.
+fn define_checked_div(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Int, Type::Int]);
+
+    let [a, b] = [0, 1].map(|i| vparams[i].as_scalar());
+
+    let err_block = lower.fbuilder.create_block();
+    let ok_block = lower.fbuilder.create_block();
+
+    lower.ins().brif(b, ok_block, &[], err_block, &[]);
+    lower.fbuilder.seal_block(err_block);
+    lower.fbuilder.seal_block(ok_block);
+
+    // Outcome::Err(a)
+    {
+        lower.fbuilder.switch_to_block(err_block);
+
+        let err = lower.construct_variant("Outcome", "Err", VirtualValue::Scalar(a));
+        lower.return_(err);
+    }
+
+    // Outcome::Ok(a / b)
+    {
+        lower.fbuilder.switch_to_block(ok_block);
+
+        let quotient = lower.binary(
+            BinOp::Div,
+            &VirtualValue::Scalar(a),
+            &VirtualValue::Scalar(b),
+            Type::Int,
+        );
+        let ok = lower.construct_variant("Outcome", "Ok", quotient);
+        lower.return_(ok);
+    }
+
+    builder.finalize();
+
+    print_clif("fn checked_div", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn unwrap_or(o: Outcome, default: int) -> int {
+//    match o {
+//      Outcome::Ok(n) => n,
+//      Outcome::Err(_) => default,
+//    }
+// }
+//
+// // -- Although what we'll actually be lowering it into is something more like -- //
+//
+// fn unwrap_or(o: *Outcome, default: int) -> int {
+//    let result = switch *(o+0) {
+//      0 => *(o+4),
+//      1 => default,
+//      _ => trap,
+//    };
+//    return result;
+// }
+fn define_unwrap_or(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Enum("Outcome"), Type::Int]);
+
+    let ok = types.resolve_variant("Outcome", "Ok");
+
+    let result = lower.match_on(&vparams[0], Type::Int, |_, variant, payload| {
+        if variant == ok {
+            payload
+        } else {
+            vparams[1].clone()
+        }
+    });
+
+    lower.return_(result);
+    builder.finalize();
+
+    print_clif("fn unwrap_or", &ctx.func);
+
+    define_function(module, id, ctx).unwrap();
+    ctx.clear();
+}
+
+// fn frame_roundtrip(seq: int, x: int, y: int) -> int {
+//    let frames = [
+//      Frame { seq, origin: Point { x, y } },
+//      Frame { seq: 4, .. },
+//    ];
+//    let p = Packet::Data(frames[0]);
+//    let first = match p {
+//      Packet::Data(f) => f.seq * 100 + f.origin.x * 10 + f.origin.y,
+//      _ => 0,
+//    };
+//    return first + frames[1].seq + frames[1].origin.x;
+// }
+//
+// Each value goes through memory at least once, which makes this a check of `load_value` and
+// `store_value` for every kind of value they handle:
+//
+// * Writing the array writes the first `Frame` field by field, and copies the second from the
+//   stack slot `construct_struct` zeroed it in.
+// * `frames[0]` is loaded as a pointer into the array, and copied again as the payload of `p`.
+// * The payload is read back in the arm of the `match`, along with the nested `Point`.
+fn define_frame_roundtrip(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    interpreted: Option<&InterpretedFunctions>,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Int, Type::Int, Type::Int]);
+
+    let frames = {
+        let first = {
+            let origin = lower.construct_struct(
+                "Point",
+                &[("x", vparams[1].clone()), ("y", vparams[2].clone())],
+            );
+            lower.construct_struct("Frame", &[("seq", vparams[0].clone()), ("origin", origin)])
+        };
+
+        let second = {
+            let seq = lower.int(4);
+            lower.construct_struct("Frame", &[("seq", seq)])
+        };
+
+        lower.construct_array(Type::Array(&Type::Struct("Frame"), 2), vec![first, second])
+    };
+
+    let seq_field = types.resolve_field("Frame", "seq");
+    let origin_field = types.resolve_field("Frame", "origin");
+    let [x_field, y_field] = ["x", "y"].map(|name| types.resolve_field("Point", name));
+
+    let first = {
+        let zero = lower.ins().iconst(cl::types::I32, 0);
+        let frame = lower.index(&frames, zero);
+        let p = lower.construct_variant("Packet", "Data", frame);
+
+        let data = types.resolve_variant("Packet", "Data");
+
+        lower.match_on(&p, Type::Int, |lower, variant, payload| {
+            if variant != data {
+                return lower.int(0);
+            }
+
+            let seq = lower.destruct_field(&payload, seq_field);
+            let origin = lower.destruct_field(&payload, origin_field);
+            let x = lower.destruct_field(&origin, x_field);
+            let y = lower.destruct_field(&origin, y_field);
+
+            let [hundred, ten] = [100, 10].map(|n| lower.int(n));
+            let seq = lower.binary(BinOp::Mul, &seq, &hundred, Type::Int);
+            let x = lower.binary(BinOp::Mul, &x, &ten, Type::Int);
+            let sum = lower.binary(BinOp::Add, &seq, &x, Type::Int);
+            lower.binary(BinOp::Add, &sum, &y, Type::Int)
+        })
+    };
+
+    let result = {
+        let one = lower.ins().iconst(cl::types::I32, 1);
+        let second = lower.index(&frames, one);
+
+        let seq = lower.destruct_field(&second, seq_field);
+        let origin = lower.destruct_field(&second, origin_field);
+        let x = lower.destruct_field(&origin, x_field);
+
+        let sum = lower.binary(BinOp::Add, &first, &seq, Type::Int);
+        lower.binary(BinOp::Add, &sum, &x, Type::Int)
+    };
+
+    lower.return_(result);
+    builder.finalize();
+
+    print_clif("fn frame_roundtrip", &ctx.func);
+
+    define_maybe_interpreted(module, id, ctx, interpreted).unwrap();
+    ctx.clear();
+}
+
+// fn position_after_move(x: int, y: int) -> int {
+//    let p = move_right(Player { id: 1, position: Point { x, y }, alive: true }, 0);
+//    let pos = p.position;
+//    let q = move_right(p, 10);
+//    let r = move_right(q, 20);
+//    pos.x + pos.y
+// }
+//
+// `pos` is read lazily, as a pointer into the stack slot of `p`. So even though `p` has been moved
+// into a call, its stack slot must not be reused for `r`, or `pos` would read the position of `r`
+// instead.
+fn define_position_after_move(
+    module: &mut impl Module,
+    types: &LookupTable,
+    sigs: &mut SigCache,
+    interpreted: Option<&InterpretedFunctions>,
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    id: FuncId,
+) {
+    let move_right_func_id = func_id_of(module, "move_right");
+
+    ctx.func.signature = sigs.get(module, id).clone();
+    let mut builder = cl::FunctionBuilder::new(&mut ctx.func, fctx);
+
+    let mut lower = FuncLower::new(types, &mut builder, module, sigs);
+    let (_, vparams) = lower.create_entry_block(&[Type::Int, Type::Int]);
+
+    let p = {
+        let position = lower.construct_struct(
+            "Point",
+            &[("x", vparams[0].clone()), ("y", vparams[1].clone())],
+        );
+        let id = lower.int(1);
+        let alive = lower.bool(true);
+        let player = lower.construct_struct(
+            "Player",
+            &[("id", id), ("position", position), ("alive", alive)],
+        );
+
+        let zero = lower.int(0);
+        lower.call_func(move_right_func_id, vec![player, zero])
+    };
+
+    let pos = lower.destruct_field(&p, types.resolve_field("Player", "position"));
+
+    let _r = [10, 20].into_iter().fold(p, |p, by| {
+        let by = lower.int(by);
+        lower.call_func(move_right_func_id, vec![p, by])
+    });
+
+    let sum = {
+        let x = lower.destruct_field(&pos, types.resolve_field("Point", "x"));
+        let y = lower.destruct_field(&pos, types.resolve_field("Point", "y"));
+        lower.binary(BinOp::Add, &x, &y, Type::Int)
+    };
+
+    lower.return_(sum);
+    builder.finalize();
+
+    print_clif("fn position_after_move", &ctx.func);
 
-    module.define_function(id, ctx).unwrap();
+    define_maybe_interpreted(module, id, ctx, interpreted).unwrap();
     ctx.clear();
 }
@@ -1,7 +1,8 @@
 use cranelift::codegen::ir::ArgumentPurpose;
 use cranelift::prelude as cl;
 use cranelift_module::FuncId;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use target_lexicon::{Architecture, Triple};
 
 type Name = &'static str;
 
@@ -9,17 +10,105 @@ type Name = &'static str;
 // structs for the size and offsets.
 #[derive(Clone, Copy, Debug)]
 pub enum Type {
+    // A signed 32-bit integer
     Int,
+    // An unsigned 32-bit integer
+    //
+    // It's lowered to the same `i32` as `Int`, since Cranelift's integer types don't have a
+    // signedness. Instead, the signedness decides which instruction is used for operations whose
+    // result depends on it, such as division and comparisons. See `FuncLower::binary` and
+    // `FuncLower::compare`.
+    UInt,
+    // An unsigned integer the size of a pointer, used for lengths and addresses
+    Usize,
+    Bool,
+    Float(FloatWidth),
     Struct(Name),
+    // A fixed-size array of elements such as `[int; 4]`
+    //
+    // The element type is a `&'static` reference for the same reason that `Name` is, so that `Type` can stay `Copy`.
+    Array(&'static Type, u32),
+    // A pointer to a value which lives on the heap, such as `Box<Player>`
+    //
+    // Moving a boxed value only moves the pointer, so it's a scalar just like `Int`.
+    Boxed(&'static Type),
+    // A view into a sequence of elements stored elsewhere, such as `&[int]`
+    //
+    // It's a "fat pointer" made up of the pointer to the first element and the amount of elements,
+    // and is lowered the same way as a `struct { ptr: usize, len: usize }` would be.
+    Slice(&'static Type),
+    // A tagged union such as `enum Outcome { Ok(int), Err(int) }`
+    //
+    // It's laid out like a struct with the tag as its first field, followed by the payload of
+    // whichever variant it holds. Since the variant isn't known until runtime, enums are always
+    // passed by pointer, and can't be the field of a struct which is passed by scalars.
+    Enum(Name),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FloatWidth {
+    F32,
+    F64,
+}
+
+impl FloatWidth {
+    pub fn cranelift_type(self) -> cl::Type {
+        match self {
+            FloatWidth::F32 => cl::types::F32,
+            FloatWidth::F64 => cl::types::F64,
+        }
+    }
 }
 
 // Whether a struct will be passed as a pointer or as a set of independent values directly
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum StructPassingMode {
     ByScalars,
+    // The bytes of the struct are loaded as these chunks and passed in their registers, which is
+    // how C passes small structs. Only used for `extern "C"` functions.
+    ByChunks(Vec<Chunk>),
     ByPointer,
 }
 
+// A part of a struct which is passed in a single register
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Chunk {
+    pub offset: i32,
+    // Whether this is an integer or float type decides which kind of register it's passed in
+    pub type_: cl::Type,
+}
+
+// Which rules for passing structs to and from C functions we need to follow
+//
+// Cranelift picks the registers for each parameter, but has no notion of structs. How a struct
+// is split into parameters is decided by the C ABI of the target, which differs between
+// architectures.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Abi {
+    X86_64,
+    Aarch64,
+}
+
+impl Abi {
+    pub fn of_triple(triple: &Triple) -> Self {
+        match triple.architecture {
+            Architecture::X86_64 => Abi::X86_64,
+            Architecture::Aarch64(_) => Abi::Aarch64,
+            arch => panic!("struct passing is not implemented for {arch}"),
+        }
+    }
+}
+
+// The calling convention of a function in our source language
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Convention {
+    // Our own functions, where we're free to pass structs however we like
+    Internal,
+    // `extern "C"` functions, which need to pass structs the same way a C compiler would
+    C,
+}
+
 /// We need to know the typing details of defined types and functions.
 ///
 /// How exactly that should be provided will depend a lot on the rest of your compiler.
@@ -27,9 +116,40 @@ pub enum StructPassingMode {
 #[derive(Debug)]
 pub struct LookupTable {
     struct_fields: HashMap<Name, Vec<(Name, Type)>>,
+    // Each variant has exactly one payload, variants without data use the empty `unit` struct
+    enum_variants: HashMap<Name, Vec<(Name, Type)>>,
     function_types: HashMap<Name, (Vec<Type>, Type)>,
+    extern_c_functions: HashSet<Name>,
     pub function_names: HashMap<FuncId, Name>,
     ptr_size: u32,
+    abi: Abi,
+}
+
+// Integers narrower than 32 bits don't fill a whole register. Some ABIs require them to be extended
+// to the full register, such as Apple's AArch64 ABI where the caller extends them to 32 bits, while
+// others leave the upper bits undefined. Without an extension on the `AbiParam`, Cranelift assumes
+// nobody extends them, so a C caller or callee could see garbage in the upper bits.
+//
+// Whether to zero or sign extend depends on the signedness of the source type. The only narrow
+// integers in our language are booleans and enum tags, which are both unsigned.
+pub fn scalar_param(ty: cl::Type) -> cl::AbiParam {
+    if ty.is_int() && ty.bits() < 32 {
+        cl::AbiParam::new(ty).uext()
+    } else {
+        cl::AbiParam::new(ty)
+    }
+}
+
+impl Type {
+    // Whether the integer is read as two's complement, which decides the instruction used for
+    // operations such as division and comparisons
+    pub fn is_signed(self) -> bool {
+        match self {
+            Type::Int => true,
+            Type::UInt | Type::Usize | Type::Bool => false,
+            _ => panic!("{self:?} is not an integer"),
+        }
+    }
 }
 
 impl LookupTable {
@@ -40,6 +160,7 @@ impl LookupTable {
     pub fn create_signature(&self, call_conv: cl::isa::CallConv, fname: &str) -> cl::Signature {
         // Get the type signatures from our source language
         let (fparams, fret) = self.function_types.get(fname).expect("function not found");
+        let convention = self.convention_of_name(fname);
 
         // Buffers for the Cranelift type signature.
         let mut params = vec![];
@@ -49,36 +170,66 @@ impl LookupTable {
         // values directly, we use an out pointer as the first parameter. The callee will write
         // the result to that pointer, instead of returning directly through the return registers.
         match fret {
-            Type::Int => returns.push(cl::AbiParam::new(cl::types::I32)),
-            Type::Struct(name) => match self.struct_passing_mode(name) {
-                StructPassingMode::ByScalars => {
-                    self.for_scalars_of_struct(&mut |ty| returns.push(cl::AbiParam::new(ty)), name)
-                }
-                StructPassingMode::ByPointer => {
-                    // The `ArgumentPurpose` is needed in-case our target architecture expects the
-                    // out pointer to use a specific register.
-                    let size_t = cl::Type::int_with_byte_size(self.ptr_size as u16).unwrap();
-                    let param = cl::AbiParam::special(size_t, ArgumentPurpose::StructReturn);
-                    params.push(param);
+            Type::Int | Type::UInt => returns.push(cl::AbiParam::new(cl::types::I32)),
+            Type::Usize => returns.push(cl::AbiParam::new(self.size_t())),
+            Type::Bool => returns.push(scalar_param(cl::types::I8)),
+            // Floats are passed in floating-point registers rather than the general-purpose ones.
+            //
+            // Cranelift will pick the correct registers for us as long as the type is a float type.
+            Type::Float(width) => returns.push(cl::AbiParam::new(width.cranelift_type())),
+            Type::Boxed(_) => returns.push(cl::AbiParam::new(self.size_t())),
+            // Arrays, slices and enums are passed the same way as structs
+            Type::Struct(_) | Type::Array(..) | Type::Slice(_) | Type::Enum(_) => {
+                match self.struct_passing_mode(*fret, convention) {
+                    StructPassingMode::ByScalars => {
+                        self.for_scalars(&mut |ty| returns.push(scalar_param(ty)), *fret)
+                    }
+                    StructPassingMode::ByChunks(chunks) => chunks
+                        .iter()
+                        .for_each(|chunk| returns.push(cl::AbiParam::new(chunk.type_))),
+                    StructPassingMode::ByPointer => {
+                        // The `ArgumentPurpose` is needed in-case our target architecture expects
+                        // the out pointer to use a specific register.
+                        let param =
+                            cl::AbiParam::special(self.size_t(), ArgumentPurpose::StructReturn);
+                        params.push(param);
+                    }
                 }
-            },
+            }
         };
 
         for p in fparams {
             match p {
-                Type::Int => params.push(cl::AbiParam::new(cl::types::I32)),
-                Type::Struct(name) => match self.struct_passing_mode(name) {
-                    StructPassingMode::ByScalars => {
-                        self.for_scalars_of_struct(
-                            &mut |clty| params.push(cl::AbiParam::new(clty)),
-                            name,
-                        );
+                Type::Int | Type::UInt => params.push(cl::AbiParam::new(cl::types::I32)),
+                Type::Usize => params.push(cl::AbiParam::new(self.size_t())),
+                Type::Bool => params.push(scalar_param(cl::types::I8)),
+                Type::Float(width) => params.push(cl::AbiParam::new(width.cranelift_type())),
+                Type::Boxed(_) => params.push(cl::AbiParam::new(self.size_t())),
+                Type::Struct(_) | Type::Array(..) | Type::Slice(_) | Type::Enum(_) => {
+                    match self.struct_passing_mode(*p, convention) {
+                        StructPassingMode::ByScalars => {
+                            self.for_scalars(&mut |clty| params.push(scalar_param(clty)), *p);
+                        }
+                        StructPassingMode::ByChunks(chunks) => chunks
+                            .iter()
+                            .for_each(|chunk| params.push(cl::AbiParam::new(chunk.type_))),
+                        // System V passes large structs by copying them onto the stack along with
+                        // the other arguments, which Cranelift does for us with `StructArgument`.
+                        // The callee still receives it as a pointer to the copy.
+                        StructPassingMode::ByPointer
+                            if convention == Convention::C && self.abi == Abi::X86_64 =>
+                        {
+                            let size = self.size_of(*p);
+                            params.push(cl::AbiParam::special(
+                                self.size_t(),
+                                ArgumentPurpose::StructArgument(size),
+                            ));
+                        }
+                        StructPassingMode::ByPointer => {
+                            params.push(cl::AbiParam::new(self.size_t()));
+                        }
                     }
-                    StructPassingMode::ByPointer => {
-                        let size_t = cl::Type::int_with_byte_size(self.ptr_size as u16).unwrap();
-                        params.push(cl::AbiParam::new(size_t));
-                    }
-                },
+                }
             }
         }
 
@@ -89,7 +240,11 @@ impl LookupTable {
         }
     }
 
-    pub fn hardcoded(ptr_size: u32) -> Self {
+    pub fn size_t(&self) -> cl::Type {
+        cl::Type::int_with_byte_size(self.ptr_size as u16).unwrap()
+    }
+
+    pub fn hardcoded(ptr_size: u32, abi: Abi) -> Self {
         let function_types = [
             ("main", (vec![], Type::Int)),
             (
@@ -99,36 +254,189 @@ impl LookupTable {
                     Type::Struct("Player"),
                 ),
             ),
+            ("cell", (vec![Type::Struct("Board"), Type::Int], Type::Int)),
+            (
+                "cell_checked",
+                (vec![Type::Struct("Board"), Type::Int], Type::Int),
+            ),
+            ("cell_past_end", (vec![Type::Struct("Board")], Type::Int)),
+            (
+                "move_four_times",
+                (vec![Type::Struct("Player")], Type::Struct("Player")),
+            ),
+            (
+                "copy_grid",
+                (vec![Type::Struct("Grid")], Type::Struct("Grid")),
+            ),
+            ("sum", (vec![Type::Slice(&Type::Int)], Type::Int)),
+            (
+                "spawn",
+                (vec![Type::Int], Type::Boxed(&Type::Struct("Player"))),
+            ),
+            (
+                "id_of",
+                (vec![Type::Boxed(&Type::Struct("Player"))], Type::Int),
+            ),
+            (
+                "is_alive",
+                (vec![Type::Boxed(&Type::Struct("Player"))], Type::Bool),
+            ),
+            (
+                "scale",
+                (
+                    vec![Type::Struct("Vec2"), Type::Float(FloatWidth::F32)],
+                    Type::Struct("Vec2"),
+                ),
+            ),
+            (
+                "quad_double",
+                (vec![Type::Struct("Quad")], Type::Struct("Quad")),
+            ),
+            (
+                "checked_div",
+                (vec![Type::Int, Type::Int], Type::Enum("Outcome")),
+            ),
+            (
+                "unwrap_or",
+                (vec![Type::Enum("Outcome"), Type::Int], Type::Int),
+            ),
+            (
+                "frame_roundtrip",
+                (vec![Type::Int, Type::Int, Type::Int], Type::Int),
+            ),
+            (
+                "position_after_move",
+                (vec![Type::Int, Type::Int], Type::Int),
+            ),
         ]
         .into();
 
+        // Functions which can be called from C, or which are implemented in C
+        let extern_c_functions = ["quad_double"].into();
+
         let struct_fields = [
             (
                 "Player",
-                vec![("id", Type::Int), ("position", Type::Struct("Point"))],
+                vec![
+                    ("id", Type::Int),
+                    ("position", Type::Struct("Point")),
+                    ("alive", Type::Bool),
+                ],
             ),
             ("Point", vec![("x", Type::Int), ("y", Type::Int)]),
+            (
+                "Frame",
+                vec![("seq", Type::Int), ("origin", Type::Struct("Point"))],
+            ),
+            (
+                "Vec2",
+                vec![
+                    ("x", Type::Float(FloatWidth::F32)),
+                    ("y", Type::Float(FloatWidth::F32)),
+                ],
+            ),
+            (
+                "Quad",
+                vec![
+                    ("a", Type::Float(FloatWidth::F32)),
+                    ("b", Type::Float(FloatWidth::F32)),
+                    ("c", Type::Float(FloatWidth::F32)),
+                    ("d", Type::Float(FloatWidth::F32)),
+                ],
+            ),
+            // Only used to check how structs mixing integers and floats are classified
+            (
+                "Sample",
+                vec![
+                    ("count", Type::Int),
+                    ("weight", Type::Float(FloatWidth::F32)),
+                    ("total", Type::Float(FloatWidth::F64)),
+                ],
+            ),
+            (
+                "Vec3",
+                vec![
+                    ("x", Type::Float(FloatWidth::F32)),
+                    ("y", Type::Float(FloatWidth::F32)),
+                    ("z", Type::Float(FloatWidth::F32)),
+                ],
+            ),
+            ("Board", vec![("cells", Type::Array(&Type::Int, 4))]),
+            ("Grid", vec![("cells", Type::Array(&Type::Int, 32))]),
             ("unit", vec![]),
         ]
         .into();
 
+        // enum Outcome {
+        //   Ok(int),
+        //   Err(int),
+        // }
+        //
+        // enum Packet {
+        //   Pending,
+        //   Data(Frame),
+        //   Failed(int),
+        // }
+        //
+        // enum Contents {
+        //   Cells([int; 4]),
+        //   View([int]),
+        //   Owner(Box<Player>),
+        // }
+        //
+        // A variant without a payload is given the empty `unit` struct as its payload.
+        let enum_variants = [
+            ("Outcome", vec![("Ok", Type::Int), ("Err", Type::Int)]),
+            (
+                "Packet",
+                vec![
+                    ("Pending", Type::Struct("unit")),
+                    ("Data", Type::Struct("Frame")),
+                    ("Failed", Type::Int),
+                ],
+            ),
+            (
+                "Contents",
+                vec![
+                    ("Cells", Type::Array(&Type::Int, 4)),
+                    ("View", Type::Slice(&Type::Int)),
+                    ("Owner", Type::Boxed(&Type::Struct("Player"))),
+                ],
+            ),
+        ]
+        .into();
+
         let function_names = HashMap::new();
 
         Self {
             ptr_size,
+            abi,
             function_names,
             function_types,
+            extern_c_functions,
             struct_fields,
+            enum_variants,
         }
     }
 
-    fn for_scalars<F>(&self, f: &mut F, ty: Type)
+    pub fn for_scalars<F>(&self, f: &mut F, ty: Type)
     where
         F: FnMut(cl::Type),
     {
         match ty {
-            Type::Int => f(cl::types::I32),
+            Type::Int | Type::UInt => f(cl::types::I32),
+            Type::Usize => f(self.size_t()),
+            // Booleans only need a single byte
+            Type::Bool => f(cl::types::I8),
+            Type::Float(width) => f(width.cranelift_type()),
             Type::Struct(name) => self.for_scalars_of_struct(f, name),
+            Type::Slice(_) => self
+                .fields_of_struct(ty)
+                .for_each(|(_, _, fty)| self.for_scalars(f, fty)),
+            Type::Array(elem, len) => (0..len).for_each(|_| self.for_scalars(f, *elem)),
+            Type::Boxed(_) => f(self.size_t()),
+            // Which scalars the payload is made of depends on the variant
+            Type::Enum(name) => panic!("enum {name} can not be split into scalars"),
         }
     }
 
@@ -148,39 +456,282 @@ impl LookupTable {
         self.function_types[fname].1
     }
 
-    // If a struct fits in two registers, then avoid stack allocating it.
-    pub fn struct_passing_mode(&self, name: &str) -> StructPassingMode {
-        let mut scalars = 0;
-        self.for_scalars_of_struct(&mut |_| scalars += 1, name);
-        if scalars < 3 {
-            StructPassingMode::ByScalars
+    pub fn convention_of(&self, id: FuncId) -> Convention {
+        self.convention_of_name(self.function_names[&id])
+    }
+
+    fn convention_of_name(&self, fname: &str) -> Convention {
+        if self.extern_c_functions.contains(fname) {
+            Convention::C
         } else {
-            StructPassingMode::ByPointer
+            Convention::Internal
+        }
+    }
+
+    pub fn struct_passing_mode(&self, ty: Type, convention: Convention) -> StructPassingMode {
+        // Enums aren't C types, and we don't know which registers the payload would need
+        if let Type::Enum(_) = ty {
+            return StructPassingMode::ByPointer;
+        }
+
+        match (convention, self.abi) {
+            (Convention::C, Abi::Aarch64) => self.classify_aarch64(ty),
+            (Convention::C, Abi::X86_64) => self.classify_sysv(ty),
+
+            // If a struct fits in two registers, then avoid stack allocating it.
+            (Convention::Internal, _) => {
+                let mut scalars = 0;
+                self.for_scalars(&mut |_| scalars += 1, ty);
+                if scalars < 3 {
+                    StructPassingMode::ByScalars
+                } else {
+                    StructPassingMode::ByPointer
+                }
+            }
+        }
+    }
+
+    // The AArch64 procedure call standard (AAPCS64) classifies structs as follows
+    //
+    // * A struct made up of one to four floats of the same type is a Homogeneous Floating-point
+    //   Aggregate (HFA). Each float is passed in its own SIMD register, `v0` to `v7`, as if they
+    //   were separate parameters. Nested structs and arrays count as long as every float inside
+    //   them is of that same type.
+    // * Otherwise, a struct of at most 16 bytes is passed in one or two general-purpose registers,
+    //   as if its bytes were loaded into 64-bit integers.
+    // * Larger structs are copied by the caller, and a pointer to the copy is passed instead.
+    //   When returned, the caller passes the pointer to write to in `x8`.
+    //
+    // Compared to x86-64, the float and integer fields of a struct are never mixed into the same
+    // register. An HFA can also use up to four registers, which is why `{ f32, f32, f32, f32 }` is
+    // passed in registers on AArch64 but not on Windows x64.
+    pub fn classify_aarch64(&self, ty: Type) -> StructPassingMode {
+        let mut floats = vec![];
+        let mut all_floats = true;
+        self.for_scalars(
+            &mut |clty| {
+                all_floats &= clty.is_float();
+                floats.push(clty);
+            },
+            ty,
+        );
+
+        let is_hfa =
+            all_floats && (1..=4).contains(&floats.len()) && floats.iter().all(|&f| f == floats[0]);
+
+        if is_hfa {
+            let chunks = floats
+                .iter()
+                .enumerate()
+                .map(|(i, &type_)| Chunk {
+                    offset: (i as u32 * type_.bytes()) as i32,
+                    type_,
+                })
+                .collect();
+
+            return StructPassingMode::ByChunks(chunks);
+        }
+
+        let size = self.size_of(ty);
+        if size > 16 {
+            return StructPassingMode::ByPointer;
+        }
+
+        StructPassingMode::ByChunks(integer_chunks(size))
+    }
+
+    // The System V x86-64 ABI splits a struct into eightbytes, 8-byte chunks of the struct, and
+    // classifies each of them on its own
+    //
+    // * An eightbyte which only holds floats is of the SSE class, and is passed in the next free
+    //   SSE register `xmm0` to `xmm7`. Two `f32` in the same eightbyte share one register, which we
+    //   get by loading them together as a single `f64`.
+    // * An eightbyte holding any integer, even alongside a float, is of the INTEGER class and is
+    //   passed in the next free general-purpose register.
+    // * A struct larger than two eightbytes is of the MEMORY class. It's copied onto the stack as
+    //   part of the arguments, and returned through an out pointer passed in `rdi`.
+    //
+    // So `{ int, f32, f64 }` is passed as an `i64` and an `f64`, while `{ f32, f32, f32 }` is passed
+    // as an `f64` and an `f32`.
+    //
+    // If there aren't enough registers left for every eightbyte, the whole struct is passed on
+    // the stack instead. That's something only the register allocator of Cranelift knows about,
+    // which we don't handle here.
+    pub fn classify_sysv(&self, ty: Type) -> StructPassingMode {
+        let size = self.size_of(ty);
+        if size > 16 {
+            return StructPassingMode::ByPointer;
+        }
+
+        let mut is_sse = [true; 2];
+        self.for_scalars_with_offsets(
+            &mut |offset, clty| is_sse[offset as usize / 8] &= clty.is_float(),
+            ty,
+            0,
+        );
+
+        let chunks = integer_chunks(size)
+            .into_iter()
+            .map(|chunk| {
+                if !is_sse[chunk.offset as usize / 8] {
+                    return chunk;
+                }
+
+                let type_ = match chunk.type_.bytes() {
+                    4 => cl::types::F32,
+                    8 => cl::types::F64,
+                    _ => unreachable!("floats are always 4 or 8 bytes"),
+                };
+                Chunk { type_, ..chunk }
+            })
+            .collect();
+
+        StructPassingMode::ByChunks(chunks)
+    }
+
+    // Same as `for_scalars` but also gives the offset of each scalar, counting from `offset`
+    pub fn for_scalars_with_offsets<F>(&self, f: &mut F, ty: Type, offset: u32)
+    where
+        F: FnMut(u32, cl::Type),
+    {
+        match ty {
+            Type::Struct(_) | Type::Array(..) | Type::Slice(_) => {
+                for (i, _, fty) in self.fields_of_struct(ty) {
+                    let field_offset = self.offset_of_field(ty, i) as u32;
+                    self.for_scalars_with_offsets(f, fty, offset + field_offset);
+                }
+            }
+            _ => self.for_scalars(&mut |clty| f(offset, clty), ty),
         }
     }
 
-    pub fn fields_of_struct(
+    // Arrays are treated as structs where all fields have the same type, and no names.
+    //
+    // Slices are treated as structs with a pointer and a length field.
+    pub fn fields_of_struct(&self, ty: Type) -> impl Iterator<Item = (usize, Name, Type)> + Clone {
+        let fields = match ty {
+            Type::Struct(name) => self
+                .struct_fields
+                .get(name)
+                .expect("struct not found")
+                .clone(),
+            Type::Array(elem, len) => vec![("", *elem); len as usize],
+            Type::Slice(_) => vec![("ptr", Type::Usize), ("len", Type::Usize)],
+            _ => panic!("not a struct or array"),
+        };
+
+        fields
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, ty))| (i, name, ty))
+    }
+
+    // The size of a struct includes the padding needed to keep its fields aligned, as well as the
+    // padding at the end which keeps the next element aligned if the struct is put in an array.
+    pub fn size_of(&self, ty: Type) -> u32 {
+        match ty {
+            Type::Struct(_) | Type::Slice(_) => {
+                let mut size = 0;
+
+                for (_, _, fty) in self.fields_of_struct(ty) {
+                    // Add padding to ensure the field is aligned
+                    size = align_up(size, self.alignment_of(fty));
+                    size += self.size_of(fty);
+                }
+
+                // Add padding to the end of the struct to make the struct itself aligned
+                align_up(size, self.alignment_of(ty))
+            }
+            // Since the size of the element already includes its trailing padding, the
+            // elements of an array are laid out back to back.
+            Type::Array(elem, len) => self.size_of(*elem) * len,
+            // The enum needs to be large enough to hold the largest payload of its variants
+            Type::Enum(name) => {
+                let largest = self
+                    .variants_of_enum(name)
+                    .map(|(_, _, pty)| self.size_of(pty));
+                let size = self.payload_offset(ty) as u32 + largest.max().unwrap_or(0);
+                align_up(size, self.alignment_of(ty))
+            }
+            _ => {
+                let mut size = 0;
+                self.for_scalars(&mut |clty| size += clty.bytes(), ty);
+                size
+            }
+        }
+    }
+
+    // Scalars are aligned to their own size, while a struct is aligned to its most-aligned field.
+    //
+    // Since a nested struct is aligned to its own most-aligned field, this applies recursively.
+    pub fn alignment_of(&self, ty: Type) -> u32 {
+        match ty {
+            Type::Struct(_) | Type::Slice(_) => self
+                .fields_of_struct(ty)
+                .map(|(_, _, fty)| self.alignment_of(fty))
+                .max()
+                // An empty struct still needs a valid alignment
+                .unwrap_or(1),
+            Type::Array(elem, _) => self.alignment_of(*elem),
+            Type::Enum(name) => self
+                .variants_of_enum(name)
+                .map(|(_, _, pty)| self.alignment_of(pty))
+                .chain([self.tag_type_of(ty).bytes()])
+                .max()
+                .unwrap(),
+            _ => self.size_of(ty),
+        }
+    }
+
+    pub fn variants_of_enum(
         &self,
         name: &str,
     ) -> impl Iterator<Item = (usize, Name, Type)> + Clone {
-        self.struct_fields
+        self.enum_variants
             .get(name)
-            .unwrap()
-            .iter()
+            .expect("enum not found")
+            .clone()
+            .into_iter()
             .enumerate()
-            .map(|(i, &(name, ty))| (i, name, ty))
+            .map(|(i, (name, ty))| (i, name, ty))
     }
 
-    pub fn size_of_struct(&self, name: &str) -> u32 {
-        let mut size = 0;
-        self.for_scalars_of_struct(&mut |clty| size += clty.bytes(), name);
-        size
+    pub fn resolve_variant(&self, type_: &str, variant: &str) -> usize {
+        self.enum_variants
+            .get(type_)
+            .expect("enum not found")
+            .iter()
+            .position(|(name, _)| *name == variant)
+            .expect("variant not found")
     }
 
-    pub fn size_of(&self, ty: Type) -> u32 {
-        let mut size = 0;
-        self.for_scalars(&mut |clty| size += clty.bytes(), ty);
-        size
+    // The tag uses the smallest integer type which can fit all variants, same as in the
+    // `tagged-union-layouts` example.
+    pub fn tag_type_of(&self, enum_: Type) -> cl::Type {
+        let Type::Enum(name) = enum_ else {
+            panic!("not an enum");
+        };
+
+        match self.enum_variants.get(name).expect("enum not found").len() {
+            0..=0xFF => cl::types::I8,
+            0x100..=0xFFFF => cl::types::I16,
+            _ => cl::types::I32,
+        }
+    }
+
+    // The tag is stored at offset zero, followed by the payload aligned for every variant
+    pub fn payload_offset(&self, enum_: Type) -> i32 {
+        let Type::Enum(name) = enum_ else {
+            panic!("not an enum");
+        };
+
+        let align = self
+            .variants_of_enum(name)
+            .map(|(_, _, pty)| self.alignment_of(pty))
+            .max()
+            .unwrap_or(1);
+
+        align_up(self.tag_type_of(enum_).bytes(), align) as i32
     }
 
     pub fn resolve_field(&self, type_: &str, field: &str) -> usize {
@@ -192,22 +743,54 @@ impl LookupTable {
             .expect("field not found")
     }
 
-    pub fn type_of_field(&self, struct_: &str, field: usize) -> Type {
-        self.struct_fields.get(struct_).expect("struct not found")[field].1
+    pub fn type_of_field(&self, struct_: Type, field: usize) -> Type {
+        match struct_ {
+            Type::Struct(name) => self.struct_fields.get(name).expect("struct not found")[field].1,
+            Type::Array(elem, _) => *elem,
+            Type::Slice(_) => Type::Usize,
+            _ => panic!("not a struct or array"),
+        }
     }
 
-    pub fn offset_of_field(&self, struct_: &str, field: usize) -> i32 {
-        let fields = self.struct_fields.get(struct_).expect("struct not found");
-
+    pub fn offset_of_field(&self, struct_: Type, field: usize) -> i32 {
         let mut offset = 0;
-        for (i, (_, fty)) in fields.iter().enumerate() {
+        for (i, _, fty) in self.fields_of_struct(struct_) {
+            // Add padding to ensure the field is aligned
+            offset = align_up(offset, self.alignment_of(fty));
+
             if i == field {
-                return offset;
+                return offset as i32;
             }
 
-            offset += self.size_of(*fty) as i32;
+            offset += self.size_of(fty);
         }
 
         panic!("field not found");
     }
 }
+
+// Split `size` bytes into 8-byte integer chunks, with a smaller integer for the remainder
+//
+// The remainder has to be the size of an integer type, so structs such as `{ i8, i8, i8 }`
+// aren't supported. A real compiler would load those bytes one at a time and combine them.
+fn integer_chunks(size: u32) -> Vec<Chunk> {
+    (0..size)
+        .step_by(8)
+        .map(|offset| {
+            let bytes = (size - offset).min(8);
+            let type_ = cl::Type::int_with_byte_size(bytes as u16)
+                .expect("struct size can not be split into integers");
+
+            Chunk {
+                offset: offset as i32,
+                type_,
+            }
+        })
+        .collect()
+}
+
+// Round `n` up to the nearest multiple of `align`
+fn align_up(n: u32, align: u32) -> u32 {
+    let padding = (align - n % align) % align;
+    n + padding
+}
@@ -0,0 +1,149 @@
+//! This example shows how to target MacOS, on both Apple Silicon and Intel Macs.
+//!
+//! ```
+//! extern "C" fn puts(s: *const u8) -> i32;
+//!
+//! fn main() -> i32 {
+//!   puts("Hello from arm64 MacOS\0");
+//!   return 0;
+//! }
+//! ```
+//!
+//! The examples default to `x86_64-unknown-linux`, which produces an ELF object that a Mac linker
+//! won't accept. To target MacOS, pass the triple of the Mac you're on.
+//!
+//! * `aarch64-apple-darwin` for Apple Silicon
+//! * `x86_64-apple-darwin` for Intel Macs
+//!
+//! Cranelift then emits a Mach-O object, and picks the calling convention C uses on that target
+//! as `isa.default_call_conv()`.
+//!
+//! * On Apple Silicon it's `CallConv::AppleAarch64`. It's mostly the same as the standard
+//!   aarch64 calling convention, but differs in how variadic arguments and arguments smaller than
+//!   32 bits are passed. See the `printf` example for why this matters.
+//! * On Intel Macs it's `CallConv::SystemV`, the same as on x86-64 Linux.
+//!
+//! Mach-O prefixes C symbols with an underscore, so the entrypoint libc calls is `_main` and
+//! `puts` is `_puts`. We don't have to do anything for this, `cranelift-object` adds the prefix
+//! when writing a Mach-O object. So we still declare `main` with `declare_main` and import `puts`
+//! by its C name.
+//!
+//! To link against system libraries and produce a binary on MacOS, you can use `clang`
+//!
+//! `$ cargo run --example macos -- -t aarch64-apple-darwin -o macos.o`
+//! `$ clang macos.o -o macos`
+//! `$ ./macos`
+//!
+//! Without `-t` the same example targets Linux, where you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example macos -- -o macos.o`
+//! `$ clang macos.o -o macos`
+//! `$ ./macos`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example macos -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, data_addr_in_func, declare_data_string, declare_function_from_types, declare_main,
+    for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+use target_lexicon::{Architecture, BinaryFormat, Triple};
+
+fn main() {
+    skip_boilerplate(b"macos", for_any_module!(define_functions));
+}
+
+// The name the linker will see for a C symbol
+//
+// This mirrors what `cranelift-object` does for us, and is only used to show the difference.
+fn linker_symbol(triple: &Triple, name: &str) -> String {
+    match triple.binary_format {
+        BinaryFormat::Macho => format!("_{name}"),
+        _ => name.to_string(),
+    }
+}
+
+// The greeting for the target we're compiling for
+fn greeting(triple: &Triple) -> String {
+    let os = match triple.binary_format {
+        BinaryFormat::Macho => "MacOS",
+        BinaryFormat::Coff => "Windows",
+        _ => "Linux",
+    };
+
+    let arch = match triple.architecture {
+        Architecture::Aarch64(_) => "arm64",
+        Architecture::X86_64 => "x86-64",
+        _ => "an unknown architecture",
+    };
+
+    format!("Hello from {arch} {os}")
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+    let triple = module.isa().triple().clone();
+
+    println!(
+        " using the {} calling convention, `main` is emitted as `{}` ",
+        module.isa().default_call_conv(),
+        linker_symbol(&triple, "main"),
+    );
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    // Declared as `main` on every target, see `declare_main` for why not `_main`
+    let main_func_id = declare_main(codegen.module);
+
+    // extern "C" fn puts(s: *const u8) -> i32;
+    //
+    // Since `puts` is a C function, we use the default calling convention of the target, which is
+    // what C compilers use as well.
+    let puts_func_id = declare_function_from_types(
+        codegen.module,
+        "puts",
+        Linkage::Import,
+        &[size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    // The message is decided while compiling, based on the target we're compiling for
+    let greeting_data_id = declare_data_string(
+        codegen.module,
+        "greeting",
+        greeting(&triple).as_bytes(),
+        true,
+    );
+
+    // fn main() -> i32 {
+    //   puts("Hello from arm64 MacOS\0");
+    //   return 0;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // puts("Hello from arm64 MacOS\0");
+            {
+                let greeting = data_addr_in_func(module, greeting_data_id, fbuilder);
+
+                let fref = module.declare_func_in_func(puts_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[greeting]);
+            }
+
+            // return 0;
+            let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+            fbuilder.ins().return_(&[zero]);
+        })
+        .unwrap();
+}
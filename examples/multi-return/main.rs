@@ -0,0 +1,135 @@
+//! This example shows how to return multiple values of different types from a function.
+//!
+//! ```
+//! fn split(x: i32) -> (i32, i64, f32) {
+//!   return (x, x as i64 + 1, x as f32 + 0.5);
+//! }
+//!
+//! fn main() -> i32 {
+//!   let (a, b, c) = split(3);
+//!   return a + b as i32 + c as i32;
+//! }
+//! ```
+//!
+//! A Cranelift signature can have any amount of `returns`, and each return value is given back
+//! to the caller as a separate `Value`. This is the same mechanism that the `struct-layouts`
+//! example uses to return small structs, but there's nothing struct-specific about it.
+//!
+//! The calling convention decides where each value ends up. On x86-64 SystemV, the two integers
+//! are returned in `rax` and `rdx`, and the float in `xmm0`. Once there are more return values
+//! than return registers, Cranelift puts the rest in a return area on the stack.
+//!
+//! This differs from the `StructReturn` parameter used for larger structs in `struct-layouts`,
+//! where the caller passes a pointer and the callee writes the result through it. With multiple
+//! returns the values never go through memory we manage ourselves.
+//!
+//! Keep in mind that C can't return more than one value, so a function with several `returns`
+//! won't be callable from C with the signature you'd expect. For functions only called by our own
+//! code, this doesn't matter.
+//!
+//! The main function returns `3 + 4 + 3`, so the exit code will be `10`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example multi-return -- -o multi-return.o`
+//! `$ clang multi-return.o -o multi-return`
+//! `$ ./multi-return; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example multi-return -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"multi-return", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn split(x: i32) -> (i32, i64, f32);
+    //
+    // Each element of the tuple becomes its own entry in `returns`
+    let split_func_id = declare_function_from_types(
+        codegen.module,
+        "split",
+        Linkage::Local,
+        &[cl::types::I32],
+        &[cl::types::I32, cl::types::I64, cl::types::F32],
+        None,
+    );
+
+    // fn main() -> i32 {
+    //   let (a, b, c) = split(3);
+    //   return a + b as i32 + c as i32;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let (a, b, c) = split(3);
+            let [a, b, c] = {
+                let x = fbuilder.ins().iconst(cl::types::I32, 3);
+
+                let fref = module.declare_func_in_func(split_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[x]);
+
+                // `inst_results` has one value for each entry in `returns`, in the same order
+                let &[a, b, c] = fbuilder.inst_results(call) else {
+                    unreachable!("split returns three values");
+                };
+
+                [a, b, c]
+            };
+
+            // return a + b as i32 + c as i32;
+            let result = {
+                let b = fbuilder.ins().ireduce(cl::types::I32, b);
+                let c = fbuilder.ins().fcvt_to_sint_sat(cl::types::I32, c);
+
+                let sum = fbuilder.ins().iadd(a, b);
+                fbuilder.ins().iadd(sum, c)
+            };
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn split(x: i32) -> (i32, i64, f32) {
+    //   return (x, x as i64 + 1, x as f32 + 0.5);
+    // }
+    codegen
+        .define(split_func_id, |_, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+
+            // x as i64 + 1
+            let b = {
+                let x = fbuilder.ins().sextend(cl::types::I64, x);
+                fbuilder.ins().iadd_imm(x, 1)
+            };
+
+            // x as f32 + 0.5
+            let c = {
+                let x = fbuilder.ins().fcvt_from_sint(cl::types::F32, x);
+                let half = fbuilder.ins().f32const(0.5);
+                fbuilder.ins().fadd(x, half)
+            };
+
+            // The values given to `return_` must match the types of `returns` in the signature
+            fbuilder.ins().return_(&[x, b, c]);
+        })
+        .unwrap();
+}
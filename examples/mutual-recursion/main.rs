@@ -0,0 +1,162 @@
+//! This example shows how two functions can call each other.
+//!
+//! ```
+//! fn is_even(n: i32) -> i32 {
+//!   if n == 0 { 1 } else { is_odd(n - 1) }
+//! }
+//!
+//! fn is_odd(n: i32) -> i32 {
+//!   if n == 0 { 0 } else { is_even(n - 1) }
+//! }
+//! ```
+//!
+//! Whichever of these two functions we build first will need to call the other one, whose body
+//! doesn't exist yet. This works because declaring a function and defining it are separate steps.
+//! As long as both functions are *declared* before either is *defined*, both bodies can import the
+//! other's `FuncId` with `declare_func_in_func`.
+//!
+//! The order in which functions are defined doesn't matter to the `Module` either. To show this,
+//! we'll define `is_odd` before `is_even`, even though `is_even` was declared first.
+//!
+//! In a real compiler, the simplest approach is to declare every function in a first pass and
+//! then define them in a second pass.
+//!
+//! The main function returns `is_even(10)`, so the exit code will be `1`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example mutual-recursion -- -o mutual-recursion.o`
+//! `$ clang mutual-recursion.o -o mutual-recursion`
+//! `$ ./mutual-recursion; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example mutual-recursion -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilder, FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{FuncId, Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"mutual-recursion", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    // Declare all functions up front, before any of them are defined.
+    //
+    // fn main() -> i32;
+    // fn is_even(n: i32) -> i32;
+    // fn is_odd(n: i32) -> i32;
+    let main_func_id = declare_main(codegen.module);
+    let [is_even_func_id, is_odd_func_id] = ["is_even", "is_odd"].map(|name| {
+        declare_function_from_types(
+            codegen.module,
+            name,
+            Linkage::Local,
+            &[cl::types::I32],
+            &[cl::types::I32],
+            None,
+        )
+    });
+
+    // fn is_odd(n: i32) -> i32 {
+    //   if n == 0 { return 0; } else { return is_even(n - 1); }
+    // }
+    //
+    // Defined before `is_even`, even though it calls `is_even`.
+    codegen
+        .define(is_odd_func_id, |module, fbuilder, entry| {
+            let n = fbuilder.block_params(entry)[0];
+            lower_parity_check(module, fbuilder, n, 0, is_even_func_id);
+        })
+        .unwrap();
+
+    // fn is_even(n: i32) -> i32 {
+    //   if n == 0 { return 1; } else { return is_odd(n - 1); }
+    // }
+    codegen
+        .define(is_even_func_id, |module, fbuilder, entry| {
+            let n = fbuilder.block_params(entry)[0];
+            lower_parity_check(module, fbuilder, n, 1, is_odd_func_id);
+        })
+        .unwrap();
+
+    // fn main() -> i32 {
+    //   return is_even(10);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let ten = fbuilder.ins().iconst(cl::types::I32, 10);
+
+            let fref = module.declare_func_in_func(is_even_func_id, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &[ten]);
+            let result = fbuilder.inst_results(call)[0];
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
+
+// if n == 0 {
+//   return on_zero;
+// } else {
+//   return other(n - 1);
+// }
+fn lower_parity_check(
+    module: &mut impl Module,
+    fbuilder: &mut FunctionBuilder<'_>,
+    n: cl::Value,
+    on_zero: i64,
+    other: FuncId,
+) {
+    let base_case = fbuilder.create_block();
+    let recursive_case = fbuilder.create_block();
+
+    // if n == 0
+    {
+        let is_zero = fbuilder.ins().icmp_imm(cl::IntCC::Equal, n, 0);
+
+        fbuilder
+            .ins()
+            .brif(is_zero, base_case, &[], recursive_case, &[]);
+
+        fbuilder.seal_block(base_case);
+        fbuilder.seal_block(recursive_case);
+    }
+
+    // return on_zero;
+    {
+        fbuilder.switch_to_block(base_case);
+
+        let result = fbuilder.ins().iconst(cl::types::I32, on_zero);
+
+        fbuilder.ins().return_(&[result]);
+    }
+
+    // return other(n - 1);
+    {
+        fbuilder.switch_to_block(recursive_case);
+
+        let n_minus_one = fbuilder.ins().iadd_imm(n, -1);
+
+        // The other function might not have been defined yet, but since it's been declared we can
+        // still import and call it.
+        let fref = module.declare_func_in_func(other, fbuilder.func);
+        let call = fbuilder.ins().call(fref, &[n_minus_one]);
+        let result = fbuilder.inst_results(call)[0];
+
+        fbuilder.ins().return_(&[result]);
+    }
+}
@@ -117,7 +117,7 @@ fn main() {
         // Use the result of the addition as an exit code
         builder.ins().return_(&[two]);
 
-        if let Err(err) = codegen::verify_function(&builder.func, isa.as_ref()) {
+        if let Err(err) = codegen::verify_function(builder.func, isa.as_ref()) {
             panic!("verifier error: {err}");
         }
 
@@ -0,0 +1,212 @@
+//! This example shows which relocations Cranelift emits for calls when generating position
+//! independent code, and how they differ from code which isn't position independent.
+//!
+//! ```
+//! extern "C" fn abs(x: i32) -> i32;
+//!
+//! fn double(x: i32) -> i32 {
+//!   return x * 2;
+//! }
+//!
+//! fn main() -> i32 {
+//!   return double(abs(-5));
+//! }
+//! ```
+//!
+//! When `main` is compiled, Cranelift doesn't know the address of `abs` or `double` yet. Instead it
+//! leaves a placeholder in the machine code together with a relocation, which tells the linker
+//! (or the dynamic loader) how to fill in the address once it's known.
+//!
+//! Position independent code can be loaded at any address, which is required for shared libraries
+//! and the default for executables on most Linux distributions. `skip_boilerplate` enables
+//! `is_pic` for this reason. With it enabled on x86-64:
+//!
+//! * Calls to `abs`, which is imported from libc, load the address from the GOT (global offset
+//!   table) with `R_X86_64_GOTPCREL`. The dynamic loader fills in the GOT entry when the program
+//!   starts, wherever libc happened to be loaded.
+//! * Calls to `double`, which is defined by us and therefore `colocated`, are a direct `call` with
+//!   a 32-bit offset relative to the instruction. `cranelift-object` writes it as `R_X86_64_PLT32`,
+//!   which the linker resolves to `double` directly since both functions end up in the same
+//!   executable. Had `double` been in a shared library, it would've pointed the call at a PLT stub
+//!   instead, which jumps to the address found in the GOT.
+//!
+//! Without `is_pic`, the code assumes every function has a fixed address:
+//!
+//! * Calls to `abs` put the full 64-bit address into the instruction stream with `R_X86_64_64`.
+//!   Since libc is loaded at a different address every time, the loader has to patch the code
+//!   itself. The linker warns about this with `creating DT_TEXTREL in a PIE`, and the code can't be
+//!   shared between processes anymore.
+//! * Calls to `double` are relocated the same way as with `is_pic`.
+//!
+//! `main` is compiled a second time without `is_pic` to compare the two. The relocations of both
+//! are printed and checked when targeting x86-64. Other architectures use their own relocations,
+//! such as `R_AARCH64_ADR_GOT_PAGE` for loading from the GOT on AArch64.
+//!
+//! The relocations in the object file can be inspected with `objdump -dr pic-calls.o`, which
+//! shows each relocation below the instruction it applies to.
+//!
+//! The main function returns `double(abs(-5))`, so the exit code will be `10`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example pic-calls -- -o pic-calls.o`
+//! `$ objdump -dr pic-calls.o`
+//! `$ clang pic-calls.o -o pic-calls`
+//! `$ ./pic-calls; echo $?`
+
+use cranelift::codegen::{
+    FinalizedRelocTarget,
+    binemit::Reloc,
+    control::ControlPlane,
+    ir::{ExternalName, Function},
+};
+use cranelift::prelude as cl;
+use cranelift::prelude::{Configurable, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, skip_boilerplate_object,
+};
+use cranelift_module::{FuncId, Linkage, Module};
+use cranelift_object::ObjectModule;
+use target_lexicon::Architecture;
+
+fn main() {
+    skip_boilerplate_object(b"pic-calls", |ctx, fctx, module, _args| {
+        let mut codegen = Codegen::new(ctx, fctx, module);
+
+        let main_func_id = declare_main(codegen.module);
+
+        // extern "C" fn abs(x: i32) -> i32;
+        let abs_func_id = declare_function_from_types(
+            codegen.module,
+            "abs",
+            Linkage::Import,
+            &[cl::types::I32],
+            &[cl::types::I32],
+            None,
+        );
+
+        // fn double(x: i32) -> i32;
+        let double_func_id = declare_function_from_types(
+            codegen.module,
+            "double",
+            Linkage::Local,
+            &[cl::types::I32],
+            &[cl::types::I32],
+            None,
+        );
+
+        // fn double(x: i32) -> i32 {
+        //   return x * 2;
+        // }
+        codegen
+            .define(double_func_id, |_, fbuilder, entry| {
+                let x = fbuilder.block_params(entry)[0];
+                let doubled = fbuilder.ins().imul_imm(x, 2);
+                fbuilder.ins().return_(&[doubled]);
+            })
+            .unwrap();
+
+        // fn main() -> i32 {
+        //   return double(abs(-5));
+        // }
+        codegen
+            .define(main_func_id, |module, fbuilder, _| {
+                let x = fbuilder.ins().iconst(cl::types::I32, -5);
+
+                let fref = module.declare_func_in_func(abs_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[x]);
+                let x = fbuilder.inst_results(call)[0];
+
+                let fref = module.declare_func_in_func(double_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[x]);
+                let result = fbuilder.inst_results(call)[0];
+
+                fbuilder.ins().return_(&[result]);
+            })
+            .unwrap();
+
+        // The context still holds `main` after it's been compiled, including its relocations
+        let pic_relocs = relocs(codegen.module, &codegen.ctx.func, codegen.ctx);
+        println!(" relocations with is_pic:");
+        for (name, kind) in &pic_relocs {
+            println!("  {name}: {kind}");
+        }
+
+        // Compile the same function again with a copy of the ISA that has `is_pic` disabled.
+        //
+        // This is only for comparison, the object file keeps the position independent version.
+        let non_pic_relocs = {
+            let isa = {
+                let mut builder = cl::settings::builder();
+                builder.set("opt_level", "none").unwrap();
+                builder.set("is_pic", "false").unwrap();
+
+                let flags = cl::settings::Flags::new(builder);
+
+                cl::isa::lookup(codegen.module.isa().triple().clone())
+                    .unwrap()
+                    .finish(flags)
+                    .unwrap()
+            };
+
+            let mut ctx = Context::for_function(codegen.ctx.func.clone());
+            ctx.compile(&*isa, &mut ControlPlane::default()).unwrap();
+
+            relocs(codegen.module, &ctx.func, &ctx)
+        };
+        println!(" relocations without is_pic:");
+        for (name, kind) in &non_pic_relocs {
+            println!("  {name}: {kind}");
+        }
+
+        // `Reloc` is Cranelift's name for the relocation, which `cranelift-object` translates
+        // into the one of the object format. The comments show what they become in an ELF file.
+        if codegen.module.isa().triple().architecture == Architecture::X86_64 {
+            assert_eq!(
+                pic_relocs,
+                [
+                    // R_X86_64_GOTPCREL
+                    ("abs".to_string(), Reloc::X86GOTPCRel4),
+                    // R_X86_64_PLT32, since it's a branch
+                    ("double".to_string(), Reloc::X86CallPCRel4),
+                ]
+            );
+            assert_eq!(
+                non_pic_relocs,
+                [
+                    // R_X86_64_64
+                    ("abs".to_string(), Reloc::Abs8),
+                    // R_X86_64_PLT32, since it's a branch
+                    ("double".to_string(), Reloc::X86CallPCRel4),
+                ]
+            );
+        }
+    });
+}
+
+// The name of the function each relocation refers to, along with the kind of relocation
+fn relocs(module: &ObjectModule, func: &Function, ctx: &Context) -> Vec<(String, Reloc)> {
+    let compiled = ctx.compiled_code().unwrap();
+
+    compiled
+        .buffer
+        .relocs()
+        .iter()
+        .map(|reloc| {
+            // Functions are referred to by the `FuncId` they were declared with, which is how we
+            // find their name again.
+            let name = match &reloc.target {
+                FinalizedRelocTarget::ExternalName(ExternalName::User(name)) => {
+                    let name = &func.params.user_named_funcs()[*name];
+                    let decl = module
+                        .declarations()
+                        .get_function_decl(FuncId::from_u32(name.index));
+                    decl.name.clone().unwrap()
+                }
+                target => format!("{target:?}"),
+            };
+
+            (name, reloc.kind)
+        })
+        .collect()
+}
@@ -0,0 +1,170 @@
+//! This example shows how to call variadic C functions, such as `printf`.
+//!
+//! ```
+//! extern "C" fn printf(format: *const u8, ...) -> i32;
+//!
+//! fn main() -> i32 {
+//!   let a = 2;
+//!   let b = 3;
+//!   printf("%d + %d = %d\n", a, b, a + b);
+//!   printf("done\n");
+//!   return 0;
+//! }
+//! ```
+//!
+//! Cranelift doesn't have a notion of variadic functions. A `Signature` is a fixed list of
+//! parameters, so we can't declare `printf` as taking "any amount of arguments".
+//!
+//! What we can do instead is to give each call its own signature, matching the arguments we're
+//! actually passing. On most targets, variadic arguments are passed the same way as regular
+//! arguments, so the callee can't tell the difference.
+//!
+//! Since a symbol can only be declared once per module, we declare `printf` with only its fixed
+//! parameter and then use `call_indirect` on its address with a signature for each call.
+//!
+//! Keep in mind that this doesn't hold for every target:
+//!
+//! * On x86-64 with `CallConv::SystemV`, the caller of a variadic function is expected to put an
+//!   upper bound of how many vector registers were used for arguments in the hidden `%al` register.
+//!   Cranelift doesn't know that the call is variadic, so it won't set `%al`. This is fine as long
+//!   as we only pass integers and pointers. glibc's `printf` only uses `%al` to decide whether to
+//!   save the vector registers, so a garbage value only costs a few extra stores.
+//!   Passing floats to variadic functions this way is *not* reliable.
+//!
+//! * On aarch64 MacOS, variadic arguments are always passed on the stack, while regular arguments
+//!   are passed in registers. This trick does *not* work there, and you'd need to write the
+//!   variadic arguments to the stack yourself.
+//!
+//! * Variadic functions also promote their arguments, so `i8`/`i16` should be extended to `i32`
+//!   and `f32` to `f64` before being passed.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example printf -- -o printf.o`
+//! `$ clang printf.o -o printf`
+//! `$ ./printf`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example printf -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilder, FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, data_addr_in_func, declare_data_string, declare_function_from_types, declare_main,
+    for_any_module, skip_boilerplate,
+};
+use cranelift_module::{FuncId, Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"printf", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // extern "C" fn printf(format: *const u8, ...) -> i32;
+    //
+    // Only the fixed parameters are declared here. The signature of each call will be decided at
+    // the call site.
+    let printf_func_id = declare_function_from_types(
+        codegen.module,
+        "printf",
+        Linkage::Import,
+        &[size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    let sum_format = declare_data_string(codegen.module, "sum_format", b"%d + %d = %d\n", true);
+    let done_format = declare_data_string(codegen.module, "done_format", b"done\n", true);
+
+    // fn main() -> i32 {
+    //   let a = 2;
+    //   let b = 3;
+    //   printf("%d + %d = %d\n", a, b, a + b);
+    //   printf("done\n");
+    //   return 0;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let a = 2;
+            // let b = 3;
+            let a = fbuilder.ins().iconst(cl::types::I32, 2);
+            let b = fbuilder.ins().iconst(cl::types::I32, 3);
+
+            // printf("%d + %d = %d\n", a, b, a + b);
+            {
+                let format = data_addr_in_func(module, sum_format, fbuilder);
+                let sum = fbuilder.ins().iadd(a, b);
+
+                call_variadic(module, fbuilder, printf_func_id, &[format], &[a, b, sum]);
+            }
+
+            // printf("done\n");
+            //
+            // This call has a different signature than the previous one, which is why we can't
+            // just use the signature from the declaration.
+            {
+                let format = data_addr_in_func(module, done_format, fbuilder);
+
+                call_variadic(module, fbuilder, printf_func_id, &[format], &[]);
+            }
+
+            // return 0;
+            let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+            fbuilder.ins().return_(&[zero]);
+        })
+        .unwrap();
+}
+
+// Call a variadic function with a signature created from the types of the arguments.
+//
+// Returns the return values of the call.
+fn call_variadic(
+    module: &mut impl Module,
+    fbuilder: &mut FunctionBuilder<'_>,
+    func: FuncId,
+    fixed: &[cl::Value],
+    variadic: &[cl::Value],
+) -> Vec<cl::Value> {
+    let size_t = module.isa().pointer_type();
+
+    let args = [fixed, variadic].concat();
+
+    // Start with the declared signature, and add the variadic arguments after the fixed ones.
+    let sig = {
+        let mut sig = module
+            .declarations()
+            .get_function_decl(func)
+            .signature
+            .clone();
+
+        for &v in variadic {
+            let ty = fbuilder.func.dfg.value_type(v);
+            sig.params.push(cl::AbiParam::new(ty));
+        }
+
+        fbuilder.import_signature(sig)
+    };
+
+    // Get the address of the function so that we can call it with our own signature
+    let fptr = {
+        let fref = module.declare_func_in_func(func, fbuilder.func);
+        fbuilder.ins().func_addr(size_t, fref)
+    };
+
+    let call = fbuilder.ins().call_indirect(sig, fptr, &args);
+    fbuilder.inst_results(call).to_vec()
+}
@@ -0,0 +1,130 @@
+//! This example shows how a function can call itself.
+//!
+//! ```
+//! fn fact(n: i32) -> i32 {
+//!   if n <= 1 { 1 } else { n * fact(n - 1) }
+//! }
+//! ```
+//!
+//! In Cranelift, a function is first *declared* in the module, which gives us a `FuncId`, and is
+//! then *defined* by building its body. Since the `FuncId` exists before the body is built, the body
+//! can import its own `FuncId` with `declare_func_in_func` and call it like any other function.
+//!
+//! The main function returns `fact(5)`, so the exit code will be `120`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example recursion -- -o recursion.o`
+//! `$ clang recursion.o -o recursion`
+//! `$ ./recursion; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example recursion -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"recursion", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn fact(n: i32) -> i32;
+    let fact_func_id = declare_function_from_types(
+        codegen.module,
+        "fact",
+        Linkage::Local,
+        &[cl::types::I32],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn main() -> i32 {
+    //   return fact(5);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let five = fbuilder.ins().iconst(cl::types::I32, 5);
+
+            let fref = module.declare_func_in_func(fact_func_id, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &[five]);
+            let result = fbuilder.inst_results(call)[0];
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn fact(n: i32) -> i32 {
+    //   if n <= 1 {
+    //     return 1;
+    //   } else {
+    //     return n * fact(n - 1);
+    //   }
+    // }
+    codegen
+        .define(fact_func_id, |module, fbuilder, entry| {
+            let n = fbuilder.block_params(entry)[0];
+
+            let base_case = fbuilder.create_block();
+            let recursive_case = fbuilder.create_block();
+
+            // if n <= 1
+            {
+                let is_base_case = fbuilder
+                    .ins()
+                    .icmp_imm(cl::IntCC::SignedLessThanOrEqual, n, 1);
+
+                fbuilder
+                    .ins()
+                    .brif(is_base_case, base_case, &[], recursive_case, &[]);
+
+                fbuilder.seal_block(base_case);
+                fbuilder.seal_block(recursive_case);
+            }
+
+            // return 1;
+            {
+                fbuilder.switch_to_block(base_case);
+
+                let one = fbuilder.ins().iconst(cl::types::I32, 1);
+
+                fbuilder.ins().return_(&[one]);
+            }
+
+            // return n * fact(n - 1);
+            {
+                fbuilder.switch_to_block(recursive_case);
+
+                let n_minus_one = fbuilder.ins().iadd_imm(n, -1);
+
+                // Import `fact` into itself.
+                //
+                // This is no different from calling any other function, since all we need is the
+                // `FuncId` from when it was declared.
+                let fref = module.declare_func_in_func(fact_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[n_minus_one]);
+                let rest = fbuilder.inst_results(call)[0];
+
+                let result = fbuilder.ins().imul(n, rest);
+
+                fbuilder.ins().return_(&[result]);
+            }
+        })
+        .unwrap();
+}
@@ -0,0 +1,424 @@
+//! This example shows how to lower recursive tagged unions such as a linked list.
+//!
+//! ```
+//! enum List {
+//!   Nil,
+//!   Cons(int, List),
+//! }
+//! ```
+//!
+//! If we tried to lay out `List` the same way as in the `tagged-union-layouts` example, the payload
+//! of `Cons` would contain a `List`, which would contain another `Cons` payload, and so on. The type
+//! would be infinitely large.
+//!
+//! To break the cycle, we detect fields which refer back to the type they're part of and
+//! automatically box them. A boxed field is only a pointer to a heap allocation, which gives it a
+//! known size regardless of how large the value it points to is.
+//!
+//! ```
+//! enum List {
+//!   Nil,
+//!   Cons(int, Box<List>),
+//! }
+//! ```
+//!
+//! Fields of other enums which don't lead back to the enum they're part of, such as a `List` in
+//! `Cursor::At(int, List)`, are stored inline as a tag and payload instead.
+//!
+//! We'll be using the tag+payload representation from the `tagged-union-layouts` example, where
+//! larger payloads are stored behind a pointer. Since the list needs to outlive the stack frame of
+//! whoever created it, all the allocations will be made using `malloc`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example recursive-enums -- -o recursive-enums.o`
+//! `$ clang recursive-enums.o -o recursive-enums`
+//! `$ ./recursive-enums; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example recursive-enums -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilder, FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{FuncId, Linkage, Module};
+
+const TAG_TYPE: cl::Type = cl::types::I8;
+
+type Name = &'static str;
+
+// The types of our source language
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    Int,
+    Enum(Name),
+}
+
+// enum List {
+//   Nil,
+//   Cons(int, List),
+// }
+const LIST: Name = "List";
+const LIST_VARIANTS: [&[Type]; 2] = [&[], &[Type::Int, Type::Enum(LIST)]];
+const TAG_LIST_NIL: i64 = 0;
+const TAG_LIST_CONS: i64 = 1;
+
+// enum Cursor {
+//   Done,
+//   At(int, List),
+// }
+//
+// `List` never contains a `Cursor`, so the list in `At` doesn't need to be boxed.
+const CURSOR: Name = "Cursor";
+const CURSOR_VARIANTS: [&[Type]; 2] = [&[], &[Type::Int, Type::Enum(LIST)]];
+const TAG_CURSOR_AT: i64 = 1;
+
+// Look up the variants of an enum by name
+//
+// In a real compiler this would be some kind of lookup table, but we only have two enums.
+fn variants_of(name: Name) -> &'static [&'static [Type]] {
+    match name {
+        LIST => &LIST_VARIANTS,
+        CURSOR => &CURSOR_VARIANTS,
+        _ => panic!("enum {name} not found"),
+    }
+}
+
+// How a field of a variant is stored in memory
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FieldLayout {
+    // The field is stored directly
+    Inline(cl::Type),
+    // An enum which doesn't contain the type the field is part of. It's stored directly as its tag
+    // followed by its payload, the same way as a boxed field's heap allocation is laid out.
+    InlineEnum,
+    // The field would make the type infinitely large, so it's stored as a pointer to a heap allocation
+    Boxed,
+}
+
+impl FieldLayout {
+    fn size(self, size_t: cl::Type) -> u32 {
+        match self {
+            FieldLayout::Inline(ty) => ty.bytes(),
+            // The payload is aligned to `size_t`, so the tag takes up as much space as it does
+            FieldLayout::InlineEnum => size_t.bytes() * 2,
+            FieldLayout::Boxed => size_t.bytes(),
+        }
+    }
+
+    fn align(self, size_t: cl::Type) -> u32 {
+        match self {
+            FieldLayout::Inline(ty) => ty.bytes(),
+            FieldLayout::InlineEnum | FieldLayout::Boxed => size_t.bytes(),
+        }
+    }
+}
+
+// Check whether `ty` contains the enum `root`, either directly or through the fields of other enums.
+//
+// `visited` keeps us from looping forever on recursive types other than `root`.
+fn contains_enum(root: Name, ty: Type, visited: &mut Vec<Name>) -> bool {
+    match ty {
+        Type::Int => false,
+        Type::Enum(name) if name == root => true,
+        Type::Enum(name) if visited.contains(&name) => false,
+        Type::Enum(name) => {
+            visited.push(name);
+            variants_of(name)
+                .iter()
+                .flat_map(|fields| fields.iter())
+                .any(|&field| contains_enum(root, field, visited))
+        }
+    }
+}
+
+// Decide the layout of each field of a variant of the enum `of`
+//
+// Any field which contains `of` is what makes the type infinitely sized, so those are the fields we box.
+fn field_layouts(of: Name, fields: &[Type]) -> Vec<FieldLayout> {
+    fields
+        .iter()
+        .map(|&field| {
+            if contains_enum(of, field, &mut vec![]) {
+                FieldLayout::Boxed
+            } else {
+                match field {
+                    Type::Int => FieldLayout::Inline(cl::types::I32),
+                    Type::Enum(_) => FieldLayout::InlineEnum,
+                }
+            }
+        })
+        .collect()
+}
+
+fn main() {
+    skip_boilerplate(b"recursive-enums", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    // With the recursive field boxed, the payload of `Cons` becomes `(i32, *List)`
+    let cons_fields = field_layouts(LIST, LIST_VARIANTS[TAG_LIST_CONS as usize]);
+
+    // While the payload of `Cursor::At` is `(i32, List)`, with the list stored as a tag and payload
+    let at_fields = field_layouts(CURSOR, CURSOR_VARIANTS[TAG_CURSOR_AT as usize]);
+    assert_eq!(
+        at_fields,
+        [FieldLayout::Inline(cl::types::I32), FieldLayout::InlineEnum]
+    );
+    assert_eq!(
+        offset_of_field(size_t, &at_fields, at_fields.len()),
+        (size_t.bytes() * 3) as i32
+    );
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn malloc(size: usize) -> *void;
+    let malloc_func_id = declare_function_from_types(
+        codegen.module,
+        "malloc",
+        Linkage::Import,
+        &[size_t],
+        &[size_t],
+        None,
+    );
+
+    // fn main() -> int {
+    //   let list = Cons(1, Cons(2, Cons(3, Nil)));
+    //
+    //   let sum = 0;
+    //   while let Cons(x, tail) = list {
+    //     sum += x;
+    //     list = tail;
+    //   }
+    //
+    //   return sum;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let list = Cons(1, Cons(2, Cons(3, Nil)));
+            let list = {
+                let nil = construct_list(module, fbuilder, malloc_func_id, &cons_fields, None);
+
+                [3, 2, 1].into_iter().fold(nil, |tail, n| {
+                    let head = fbuilder.ins().iconst(cl::types::I32, n);
+                    let cons = Some((head, tail));
+                    construct_list(module, fbuilder, malloc_func_id, &cons_fields, cons)
+                })
+            };
+
+            // The loop header receives the current sum and the current list through block
+            // parameters, since they change with each iteration.
+            let header = fbuilder.create_block();
+            fbuilder.append_block_param(header, cl::types::I32);
+            fbuilder.append_block_param(header, TAG_TYPE);
+            fbuilder.append_block_param(header, size_t);
+
+            let body = fbuilder.create_block();
+            let exit = fbuilder.create_block();
+            fbuilder.append_block_param(exit, cl::types::I32);
+
+            // let sum = 0;
+            {
+                let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+                let (tag, payload) = list;
+                fbuilder
+                    .ins()
+                    .jump(header, &[zero.into(), tag.into(), payload.into()]);
+            }
+
+            // while let Cons(x, tail) = list {
+            {
+                fbuilder.switch_to_block(header);
+
+                let sum = fbuilder.block_params(header)[0];
+                let tag = fbuilder.block_params(header)[1];
+
+                // Since `List` only has two variants, checking for `Cons` is enough to know we
+                // haven't hit `Nil` yet.
+                let is_cons = fbuilder
+                    .ins()
+                    .icmp_imm(cl::IntCC::Equal, tag, TAG_LIST_CONS);
+                fbuilder.ins().brif(is_cons, body, &[], exit, &[sum.into()]);
+            }
+
+            //   sum += x;
+            //   list = tail;
+            // }
+            {
+                fbuilder.switch_to_block(body);
+                fbuilder.seal_block(body);
+
+                let sum = fbuilder.block_params(header)[0];
+                let payload = fbuilder.block_params(header)[2];
+
+                let (x, (tail_tag, tail_payload)) =
+                    read_cons_payload(fbuilder, &cons_fields, payload);
+
+                let sum = fbuilder.ins().iadd(sum, x);
+
+                fbuilder
+                    .ins()
+                    .jump(header, &[sum.into(), tail_tag.into(), tail_payload.into()]);
+
+                // Both predecessors of the header are known now
+                fbuilder.seal_block(header);
+            }
+
+            // return sum;
+            {
+                fbuilder.switch_to_block(exit);
+                fbuilder.seal_block(exit);
+
+                let sum = fbuilder.block_params(exit)[0];
+                fbuilder.ins().return_(&[sum]);
+            }
+        })
+        .unwrap();
+}
+
+// Construct either `List::Nil` if `cons` is `None`, or `List::Cons(head, tail)`.
+//
+// Just like in the `tagged-union-layouts` example, a `List` is represented as a tag and a payload.
+fn construct_list(
+    module: &mut impl Module,
+    fbuilder: &mut FunctionBuilder<'_>,
+    malloc: FuncId,
+    cons_fields: &[FieldLayout],
+    cons: Option<(cl::Value, (cl::Value, cl::Value))>,
+) -> (cl::Value, cl::Value) {
+    let size_t = module.isa().pointer_type();
+
+    match cons {
+        // Even though this variant doesn't have a payload, all values of type `List` still needs
+        // to have the same size. Therefore, we still create a zeroed payload.
+        None => {
+            let tag = fbuilder.ins().iconst(TAG_TYPE, TAG_LIST_NIL);
+            let payload = fbuilder.ins().iconst(size_t, 0);
+            (tag, payload)
+        }
+
+        Some((head, (tail_tag, tail_payload))) => {
+            // Box the tail by copying it onto the heap
+            //
+            // A `List` in memory is laid out as `{ tag, payload }`, with the payload aligned to `size_t`.
+            let boxed_tail = {
+                let size = size_t.bytes() * 2;
+                let ptr = call_malloc(module, fbuilder, malloc, size);
+
+                let flags = cl::MemFlags::new();
+                fbuilder.ins().store(flags, tail_tag, ptr, 0);
+                fbuilder
+                    .ins()
+                    .store(flags, tail_payload, ptr, size_t.bytes() as i32);
+
+                ptr
+            };
+
+            // Since `(i32, *List)` doesn't fit in a `size_t`, the payload is stored behind a pointer.
+            //
+            // It needs to outlive the current stack frame just like the boxed tail, so we allocate
+            // it on the heap as well.
+            let payload = {
+                let size = offset_of_field(size_t, cons_fields, cons_fields.len()) as u32;
+                let ptr = call_malloc(module, fbuilder, malloc, size);
+
+                let flags = cl::MemFlags::new();
+                for (i, v) in [head, boxed_tail].into_iter().enumerate() {
+                    let offset = offset_of_field(size_t, cons_fields, i);
+                    fbuilder.ins().store(flags, v, ptr, offset);
+                }
+
+                ptr
+            };
+
+            let tag = fbuilder.ins().iconst(TAG_TYPE, TAG_LIST_CONS);
+            (tag, payload)
+        }
+    }
+}
+
+// Read the head and the unboxed tail from the payload of a `List::Cons`
+fn read_cons_payload(
+    fbuilder: &mut FunctionBuilder<'_>,
+    cons_fields: &[FieldLayout],
+    payload: cl::Value,
+) -> (cl::Value, (cl::Value, cl::Value)) {
+    let size_t = fbuilder.func.dfg.value_type(payload);
+    let flags = cl::MemFlags::new();
+
+    let head = fbuilder.ins().load(
+        cl::types::I32,
+        flags,
+        payload,
+        offset_of_field(size_t, cons_fields, 0),
+    );
+
+    // Dereference the box to get the tail back as a tag and payload
+    let tail = {
+        let boxed = fbuilder.ins().load(
+            size_t,
+            flags,
+            payload,
+            offset_of_field(size_t, cons_fields, 1),
+        );
+
+        let tag = fbuilder.ins().load(TAG_TYPE, flags, boxed, 0);
+        let payload = fbuilder
+            .ins()
+            .load(size_t, flags, boxed, size_t.bytes() as i32);
+
+        (tag, payload)
+    };
+
+    (head, tail)
+}
+
+// Fields are aligned to their own size, the same way as in the `struct-layouts` example. An inline
+// enum is aligned to its payload.
+//
+// Passing `fields.len()` as `field` gives the size of the fields, without trailing padding.
+fn offset_of_field(size_t: cl::Type, fields: &[FieldLayout], field: usize) -> i32 {
+    let mut offset = 0;
+
+    for (i, layout) in fields.iter().enumerate() {
+        // Add padding to ensure the field is aligned
+        let align = layout.align(size_t);
+        offset += (align - offset % align) % align;
+
+        if i == field {
+            return offset as i32;
+        }
+
+        offset += layout.size(size_t);
+    }
+
+    offset as i32
+}
+
+fn call_malloc(
+    module: &mut impl Module,
+    fbuilder: &mut FunctionBuilder<'_>,
+    malloc: FuncId,
+    size: u32,
+) -> cl::Value {
+    let size_t = module.isa().pointer_type();
+
+    let fref = module.declare_func_in_func(malloc, fbuilder.func);
+    let size = fbuilder.ins().iconst(size_t, size as i64);
+    let call = fbuilder.ins().call(fref, &[size]);
+    fbuilder.inst_results(call)[0]
+}
@@ -0,0 +1,215 @@
+//! This example shows how to use `select` to pick between two values without branching.
+//!
+//! ```
+//! fn max(a: i32, b: i32) -> i32 {
+//!   if a > b { a } else { b }
+//! }
+//! ```
+//!
+//! In the `if-else` example, an `if` expression is lowered into a conditional branch, two blocks
+//! and a merge block. When both branches are cheap and have no side effects, we can instead compute
+//! both values and use `select` to pick one of them based on the condition.
+//!
+//! `select` usually compiles into a conditional move, which avoids the cost of the CPU mispredicting
+//! which way the branch would go. For tiny expressions such as `max` and `min`, this is almost
+//! always faster.
+//!
+//! Keep in mind that with `select`, both values are always computed. If a branch has side effects
+//! or is expensive, it should still be lowered as an actual branch.
+//!
+//! The main function returns `max(3, 7)`, so the exit code will be `7`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example select -- -o select.o`
+//! `$ clang select.o -o select`
+//! `$ ./select; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example select -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"select", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn max(a: i32, b: i32) -> i32;
+    // fn min(a: i32, b: i32) -> i32;
+    let [max_func_id, min_func_id] = ["max", "min"].map(|name| {
+        declare_function_from_types(
+            codegen.module,
+            name,
+            Linkage::Local,
+            &[cl::types::I32; 2],
+            &[cl::types::I32],
+            None,
+        )
+    });
+
+    // fn get_or_zero(array: *const i32, len: usize, index: usize) -> i32;
+    let get_or_zero_func_id = declare_function_from_types(
+        codegen.module,
+        "get_or_zero",
+        Linkage::Local,
+        &[size_t, size_t, size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn main() -> i32 {
+    //   let numbers = [10, 20];
+    //   get_or_zero(&numbers, 2, 5);
+    //   min(3, 7);
+    //   return max(3, 7);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // get_or_zero(&numbers, 2, 5);
+            {
+                let numbers = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    cl::types::I32.bytes() * 2,
+                    2,
+                ));
+
+                for (i, n) in [10, 20].into_iter().enumerate() {
+                    let n = fbuilder.ins().iconst(cl::types::I32, n);
+                    let offset = i as i32 * cl::types::I32.bytes() as i32;
+                    fbuilder.ins().stack_store(n, numbers, offset);
+                }
+
+                let array = fbuilder.ins().stack_addr(size_t, numbers, 0);
+                let len = fbuilder.ins().iconst(size_t, 2);
+                let index = fbuilder.ins().iconst(size_t, 5);
+
+                let fref = module.declare_func_in_func(get_or_zero_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[array, len, index]);
+            }
+
+            let [a, b] = [3, 7].map(|n| fbuilder.ins().iconst(cl::types::I32, n));
+
+            // min(3, 7);
+            {
+                let fref = module.declare_func_in_func(min_func_id, fbuilder.func);
+                fbuilder.ins().call(fref, &[a, b]);
+            }
+
+            // return max(3, 7);
+            let result = {
+                let fref = module.declare_func_in_func(max_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[a, b]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn max(a: i32, b: i32) -> i32 {
+    //   return if a > b { a } else { b };
+    // }
+    codegen
+        .define(max_func_id, |_, fbuilder, entry| {
+            let a = fbuilder.block_params(entry)[0];
+            let b = fbuilder.block_params(entry)[1];
+
+            // `icmp` produces an `i8` which is `1` if the condition holds and `0` otherwise
+            let a_is_greater = fbuilder.ins().icmp(cl::IntCC::SignedGreaterThan, a, b);
+
+            // Picks `a` if the condition is non-zero, otherwise `b`
+            let result = fbuilder.ins().select(a_is_greater, a, b);
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn min(a: i32, b: i32) -> i32 {
+    //   return if a < b { a } else { b };
+    // }
+    codegen
+        .define(min_func_id, |_, fbuilder, entry| {
+            let a = fbuilder.block_params(entry)[0];
+            let b = fbuilder.block_params(entry)[1];
+
+            // Cranelift also has the `smin`/`smax`/`umin`/`umax` instructions, which do the same
+            // thing. We use `select` here since it works for any condition, not just comparisons.
+            let a_is_less = fbuilder.ins().icmp(cl::IntCC::SignedLessThan, a, b);
+            let result = fbuilder.ins().select(a_is_less, a, b);
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn get_or_zero(array: *const i32, len: usize, index: usize) -> i32 {
+    //   return if index < len { array[index] } else { 0 };
+    // }
+    //
+    // This time, one of the values is a load from memory. We can't just compute `array[index]`
+    // before checking the bounds, since it might read memory we're not allowed to read.
+    //
+    // Instead, we first pick which address to load from. If the index is out of bounds, we load
+    // from the start of the array instead, which is always safe as long as `len > 0`. Then we pick
+    // between the loaded value and `0`.
+    //
+    // For picking the address, we use `select_spectre_guard` instead of `select`. Modern CPUs will
+    // speculatively execute code before they know the result of a comparison, and a regular `select`
+    // could be turned into a branch by the optimizer. If it speculatively reads out of bounds, the
+    // value can be leaked through side channels even though the result is thrown away. This is the
+    // Spectre vulnerability. `select_spectre_guard` is guaranteed to never be turned into a branch
+    // or be speculated past, so the address is always masked to the safe one.
+    //
+    // This matters when running untrusted code, such as in a WebAssembly runtime. For most
+    // compilers of native languages, a regular `select` or an explicit bounds check is enough.
+    codegen
+        .define(get_or_zero_func_id, |_, fbuilder, entry| {
+            let array = fbuilder.block_params(entry)[0];
+            let len = fbuilder.block_params(entry)[1];
+            let index = fbuilder.block_params(entry)[2];
+
+            let in_bounds = fbuilder.ins().icmp(cl::IntCC::UnsignedLessThan, index, len);
+
+            // array + index * 4
+            let element_ptr = {
+                let offset = fbuilder
+                    .ins()
+                    .imul_imm(index, cl::types::I32.bytes() as i64);
+                fbuilder.ins().iadd(array, offset)
+            };
+
+            // Fall back to the start of the array if the index is out of bounds
+            let safe_ptr = fbuilder
+                .ins()
+                .select_spectre_guard(in_bounds, element_ptr, array);
+
+            let value = fbuilder
+                .ins()
+                .load(cl::types::I32, cl::MemFlags::trusted(), safe_ptr, 0);
+
+            let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+            let result = fbuilder.ins().select(in_bounds, value, zero);
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
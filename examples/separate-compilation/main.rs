@@ -0,0 +1,170 @@
+//! This example shows how to compile a program as several units, each emitted as its own object
+//! file, which are then linked together.
+//!
+//! ```
+//! // math.o
+//! extern "C" fn base() -> i32;
+//!
+//! fn square_plus_base(x: i32) -> i32 {
+//!   return x * x + base();
+//! }
+//!
+//! // main.o
+//! extern "C" fn square_plus_base(x: i32) -> i32;
+//!
+//! fn base() -> i32 {
+//!   return 1;
+//! }
+//!
+//! fn main() -> i32 {
+//!   return square_plus_base(3);
+//! }
+//! ```
+//!
+//! Compiling each unit separately means only the units which changed have to be compiled again,
+//! and lets units be compiled in parallel. It's also how a library can be compiled once and then
+//! linked into many programs.
+//!
+//! Each unit gets its own `ObjectModule`, and the modules don't know about each other. A function
+//! defined in one unit is declared with `Linkage::Export` there, and with `Linkage::Import` in every
+//! other unit which calls it. The signatures have to match, since nothing checks them across
+//! units. Getting them wrong is undefined behaviour, the same as with mismatched `extern`
+//! declarations in C.
+//!
+//! Calls work in both directions, `main.o` calls `square_plus_base` from `math.o`, which in turn
+//! calls `base` from `main.o`. Since the callee is imported, the call goes through the GOT the same
+//! way as calls to libc do, see the `pic-calls` example.
+//!
+//! `skip_boilerplate_units` creates one module per unit name, and writes each of them to
+//! `<DIR>/<unit name>.o` when given `-o <DIR>`.
+//!
+//! The main function returns `3 * 3 + 1`, so the exit code will be `10`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example separate-compilation -- -o separate-compilation`
+//! `$ clang separate-compilation/math.o separate-compilation/main.o -o separate-compilation/main`
+//! `$ ./separate-compilation/main; echo $?`
+//!
+//! Linking only one of the objects fails with an undefined reference to the function from the
+//! other one.
+//!
+//! There's no `--jit` mode for this example, since a `JITModule` can't import from another module.
+
+use cranelift::prelude as cl;
+use cranelift::prelude::InstBuilder;
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, skip_boilerplate_units,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate_units(&[b"math", b"main"], |ctx, fctx, modules, _args| {
+        let [math_module, main_module] = modules else {
+            unreachable!();
+        };
+
+        define_math_unit(&mut Codegen::new(ctx, fctx, math_module));
+        define_main_unit(&mut Codegen::new(ctx, fctx, main_module));
+    });
+}
+
+// math.o
+fn define_math_unit(codegen: &mut Codegen<'_>) {
+    // extern "C" fn base() -> i32;
+    //
+    // Defined by the main unit
+    let base_func_id = declare_function_from_types(
+        codegen.module,
+        "base",
+        Linkage::Import,
+        &[],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn square_plus_base(x: i32) -> i32;
+    //
+    // Exported so that the main unit can call it
+    let square_plus_base_func_id = declare_function_from_types(
+        codegen.module,
+        "square_plus_base",
+        Linkage::Export,
+        &[cl::types::I32],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn square_plus_base(x: i32) -> i32 {
+    //   return x * x + base();
+    // }
+    codegen
+        .define(square_plus_base_func_id, |module, fbuilder, entry| {
+            let x = fbuilder.block_params(entry)[0];
+            let square = fbuilder.ins().imul(x, x);
+
+            let base = {
+                let fref = module.declare_func_in_func(base_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            let result = fbuilder.ins().iadd(square, base);
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
+
+// main.o
+fn define_main_unit(codegen: &mut Codegen<'_>) {
+    let main_func_id = declare_main(codegen.module);
+
+    // extern "C" fn square_plus_base(x: i32) -> i32;
+    //
+    // Defined by the math unit. The signature has to be the same as the one it was exported with.
+    let square_plus_base_func_id = declare_function_from_types(
+        codegen.module,
+        "square_plus_base",
+        Linkage::Import,
+        &[cl::types::I32],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn base() -> i32;
+    //
+    // Exported so that the math unit can call it
+    let base_func_id = declare_function_from_types(
+        codegen.module,
+        "base",
+        Linkage::Export,
+        &[],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn base() -> i32 {
+    //   return 1;
+    // }
+    codegen
+        .define(base_func_id, |_, fbuilder, _| {
+            let one = fbuilder.ins().iconst(cl::types::I32, 1);
+            fbuilder.ins().return_(&[one]);
+        })
+        .unwrap();
+
+    // fn main() -> i32 {
+    //   return square_plus_base(3);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let three = fbuilder.ins().iconst(cl::types::I32, 3);
+
+            let fref = module.declare_func_in_func(square_plus_base_func_id, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &[three]);
+            let result = fbuilder.inst_results(call)[0];
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
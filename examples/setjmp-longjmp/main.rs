@@ -0,0 +1,283 @@
+//! This example shows how to jump out of a function call early with the C library's `setjmp` and
+//! `longjmp`, the same way some language runtimes implement exceptions.
+//!
+//! ```
+//! fn checked_div(a: i32, b: i32, env: &JmpBuf) -> i32 {
+//!   if b == 0 {
+//!     longjmp(env, DIVIDE_BY_ZERO);
+//!   }
+//!   return a / b;
+//! }
+//!
+//! fn main() -> i32 {
+//!   let env: JmpBuf;
+//!   let partial = 0;
+//!
+//!   let code = setjmp(&env);
+//!   if code != 0 {
+//!     // catch
+//!     return partial + code;
+//!   }
+//!
+//!   // try
+//!   partial = checked_div(20, 4, &env);
+//!   return checked_div(partial, 0, &env);
+//! }
+//! ```
+//!
+//! `setjmp` saves the stack pointer, the return address and the callee-saved registers into a
+//! jump buffer, then returns `0`. Any function further down the call stack can later call
+//! `longjmp` with that buffer, which restores those registers and makes the original `setjmp`
+//! call return a second time, this time with the value that was passed to `longjmp`. Every frame
+//! in-between is thrown away without running any of its remaining code.
+//!
+//! The size of the jump buffer depends on the platform and C library, so we can't use a type for
+//! it. Instead we give it a stack slot which is larger than the `jmp_buf` of any platform this
+//! example targets.
+//!
+//! # Caveats
+//!
+//! Cranelift has no idea that `setjmp` can return twice. It compiles `main` as if each call
+//! returns exactly once, which makes this undefined behaviour in the same way as in C:
+//!
+//! * `longjmp` restores the callee-saved registers to what they held during `setjmp`. If Cranelift
+//!   put a local in one of those registers and it changed after `setjmp`, the catch block will see
+//!   the old value. C has the same problem, and requires such locals to be `volatile`.
+//! * Values spilled to the stack aren't restored at all, so a spill slot might hold a value that
+//!   was never meant to be live in the catch block.
+//!
+//! To stay on the safe side, everything the catch block needs is kept in a stack slot and stored
+//! to or loaded from explicitly, and nothing else is kept live across `setjmp`. The catch block
+//! only uses the value returned by `setjmp` and what it loads from memory.
+//!
+//! `longjmp` never returns, but Cranelift has no way to mark a function as such. So the block
+//! containing the call still needs a terminator, for which we use a trap.
+//!
+//! Jumping over frames also skips their destructors and cleanup code, and code written in a
+//! language that unwinds such as C++ or Rust must never be jumped over. That's why this is
+//! usually a stopgap until a compiler has proper unwinding, such as Cranelift's `try_call` with
+//! unwind info emitted for every function.
+//!
+//! The main function catches the division by zero after `partial` was set to `5`, so the exit
+//! code will be `5 + 7` which is `12`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example setjmp-longjmp -- -o setjmp-longjmp.o`
+//! `$ clang setjmp-longjmp.o -o setjmp-longjmp`
+//! `$ ./setjmp-longjmp; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example setjmp-longjmp -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, IntCC, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+    trap_unreachable,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"setjmp-longjmp", for_any_module!(define_functions));
+}
+
+// Large enough to fit the `jmp_buf` of glibc, musl and MacOS on both x86-64 and AArch64
+const JMP_BUF_SIZE: u32 = 512;
+const JMP_BUF_ALIGN: u32 = 16;
+
+// The value passed to `longjmp`, which becomes the second return value of `setjmp`.
+//
+// It has to be non-zero, since `setjmp` returning `0` is how we tell the first return apart.
+const DIVIDE_BY_ZERO: i64 = 7;
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // extern "C" fn setjmp(env: *jmp_buf) -> i32;
+    let setjmp_func_id = declare_function_from_types(
+        codegen.module,
+        "setjmp",
+        Linkage::Import,
+        &[size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    // extern "C" fn longjmp(env: *jmp_buf, val: i32) -> !;
+    let longjmp_func_id = declare_function_from_types(
+        codegen.module,
+        "longjmp",
+        Linkage::Import,
+        &[size_t, cl::types::I32],
+        &[],
+        None,
+    );
+
+    // fn checked_div(a: i32, b: i32, env: &JmpBuf) -> i32;
+    let checked_div_func_id = declare_function_from_types(
+        codegen.module,
+        "checked_div",
+        Linkage::Local,
+        &[cl::types::I32, cl::types::I32, size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn main() -> i32 {
+    //   let env: JmpBuf;
+    //   let partial = 0;
+    //
+    //   let code = setjmp(&env);
+    //   if code != 0 {
+    //     return partial + code;
+    //   }
+    //
+    //   partial = checked_div(20, 4, &env);
+    //   return checked_div(partial, 0, &env);
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            let try_block = fbuilder.create_block();
+            let catch_block = fbuilder.create_block();
+
+            // let env: JmpBuf;
+            let env = {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    JMP_BUF_SIZE,
+                    JMP_BUF_ALIGN.trailing_zeros() as u8,
+                ));
+
+                fbuilder.ins().stack_addr(size_t, slot, 0)
+            };
+
+            // let partial = 0;
+            //
+            // `partial` is read in the catch block after being changed in the try block, so it
+            // must live in memory rather than in a register. This is the equivalent of declaring
+            // it `volatile` in C.
+            let partial = {
+                let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                    cl::StackSlotKind::ExplicitSlot,
+                    4,
+                    2,
+                ));
+
+                let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+                fbuilder.ins().stack_store(zero, slot, 0);
+
+                slot
+            };
+
+            // let code = setjmp(&env);
+            // if code != 0 { ... }
+            {
+                let fref = module.declare_func_in_func(setjmp_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[env]);
+                let code = fbuilder.inst_results(call)[0];
+
+                fbuilder
+                    .ins()
+                    .brif(code, catch_block, &[code.into()], try_block, &[]);
+            }
+
+            // catch_block(code: i32)
+            fbuilder.append_block_param(catch_block, cl::types::I32);
+
+            // partial = checked_div(20, 4, &env);
+            // return checked_div(partial, 0, &env);
+            {
+                fbuilder.seal_block(try_block);
+                fbuilder.switch_to_block(try_block);
+
+                let fref = module.declare_func_in_func(checked_div_func_id, fbuilder.func);
+
+                let twenty = fbuilder.ins().iconst(cl::types::I32, 20);
+                let four = fbuilder.ins().iconst(cl::types::I32, 4);
+                let call = fbuilder.ins().call(fref, &[twenty, four, env]);
+                let quotient = fbuilder.inst_results(call)[0];
+                fbuilder.ins().stack_store(quotient, partial, 0);
+
+                // Since `checked_div` might not return, the store above has to happen before this call
+                let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+                let call = fbuilder.ins().call(fref, &[quotient, zero, env]);
+                let result = fbuilder.inst_results(call)[0];
+
+                fbuilder.ins().return_(&[result]);
+            }
+
+            // return partial + code;
+            {
+                fbuilder.seal_block(catch_block);
+                fbuilder.switch_to_block(catch_block);
+
+                let code = fbuilder.block_params(catch_block)[0];
+
+                // Reload `partial` from memory, since a register holding it from before `setjmp`
+                // would still contain `0`
+                let partial = fbuilder.ins().stack_load(cl::types::I32, partial, 0);
+                let result = fbuilder.ins().iadd(partial, code);
+
+                fbuilder.ins().return_(&[result]);
+            }
+        })
+        .unwrap();
+
+    // fn checked_div(a: i32, b: i32, env: &JmpBuf) -> i32 {
+    //   if b == 0 {
+    //     longjmp(env, DIVIDE_BY_ZERO);
+    //   }
+    //   return a / b;
+    // }
+    codegen
+        .define(checked_div_func_id, |module, fbuilder, entry| {
+            let [a, b, env] = [0, 1, 2].map(|i| fbuilder.block_params(entry)[i]);
+
+            let throw_block = fbuilder.create_block();
+            let divide_block = fbuilder.create_block();
+
+            // if b == 0 { ... }
+            {
+                let is_zero = fbuilder.ins().icmp_imm(IntCC::Equal, b, 0);
+                fbuilder
+                    .ins()
+                    .brif(is_zero, throw_block, &[], divide_block, &[]);
+            }
+
+            // longjmp(env, DIVIDE_BY_ZERO);
+            {
+                fbuilder.seal_block(throw_block);
+                fbuilder.switch_to_block(throw_block);
+
+                let fref = module.declare_func_in_func(longjmp_func_id, fbuilder.func);
+                let code = fbuilder.ins().iconst(cl::types::I32, DIVIDE_BY_ZERO);
+                fbuilder.ins().call(fref, &[env, code]);
+
+                // `longjmp` never returns, but every block still needs a terminator
+                trap_unreachable(fbuilder);
+            }
+
+            // return a / b;
+            {
+                fbuilder.seal_block(divide_block);
+                fbuilder.switch_to_block(divide_block);
+
+                let quotient = fbuilder.ins().sdiv(a, b);
+                fbuilder.ins().return_(&[quotient]);
+            }
+        })
+        .unwrap();
+}
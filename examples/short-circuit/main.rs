@@ -0,0 +1,348 @@
+//! This example shows how to lower the short-circuiting boolean operators `&&` and `||`.
+//!
+//! ```
+//! fn check(calls: &mut i32, result: bool) -> bool {
+//!   *calls += 1;
+//!   return result;
+//! }
+//!
+//! fn and(calls: &mut i32) -> bool {
+//!   return check(calls, false) && check(calls, true);
+//! }
+//!
+//! fn or(calls: &mut i32) -> bool {
+//!   return check(calls, true) || check(calls, false);
+//! }
+//!
+//! fn bitwise_and(calls: &mut i32) -> bool {
+//!   return check(calls, false) & check(calls, true);
+//! }
+//!
+//! fn main() -> i32 {
+//!   let calls = 0;
+//!   let x = and(&mut calls);
+//!   let y = or(&mut calls);
+//!   let z = bitwise_and(&mut calls);
+//!   return calls * 10 + x + y + z;
+//! }
+//! ```
+//!
+//! `a && b` only evaluates `b` if `a` is true, and `a || b` only evaluates `b` if `a` is false.
+//! This matters as soon as `b` has side effects, such as calling a function, or might fail, such as
+//! in `ptr != null && ptr.x > 0`.
+//!
+//! Cranelift does have `band` and `bor`, but they're plain instructions which take two values.
+//! Both operands have to be computed before they can be used, so lowering `&&` into `band` would
+//! always call `b`. Instead, `&&` is lowered the same way as `if a { b } else { false }`:
+//!
+//! ```
+//! block0:
+//!   let a = check(calls, false);
+//!   brif a, block1, block2(a)
+//!
+//! block1:
+//!   let b = check(calls, true);
+//!   jump block2(b)
+//!
+//! block2(result):
+//!   return result
+//! ```
+//!
+//! The evaluation of `b` lives in its own block which the branch skips over, and the merge block
+//! receives the result through a block parameter. When skipping `b` we already know the result is
+//! the same as `a`, so `a` itself can be passed along. `||` is the same with the branches swapped.
+//!
+//! Non-short-circuiting operators such as `&` on booleans do map directly to `band` and `bor`.
+//! Since they operate on every bit, they're only correct if booleans are always `0` or `1`, as
+//! `2 & 1` would be `0`. Comparison instructions such as `icmp` produce `0` or `1` for us.
+//!
+//! `and` and `or` each call `check` once while `bitwise_and` calls it twice, and only `or` returns
+//! true. So the exit code will be `4 * 10 + 1` which is `41`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example short-circuit -- -o short-circuit.o`
+//! `$ clang short-circuit.o -o short-circuit`
+//! `$ ./short-circuit; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example short-circuit -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{FuncId, Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"short-circuit", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn check(calls: &mut i32, result: bool) -> bool;
+    let check_func_id = declare_function_from_types(
+        codegen.module,
+        "check",
+        Linkage::Local,
+        &[size_t, cl::types::I8],
+        &[cl::types::I8],
+        None,
+    );
+
+    // fn and(calls: &mut i32) -> bool;
+    // fn or(calls: &mut i32) -> bool;
+    // fn bitwise_and(calls: &mut i32) -> bool;
+    let [and_func_id, or_func_id, bitwise_and_func_id] = ["and", "or", "bitwise_and"].map(|name| {
+        declare_function_from_types(
+            codegen.module,
+            name,
+            Linkage::Local,
+            &[size_t],
+            &[cl::types::I8],
+            None,
+        )
+    });
+
+    // fn main() -> i32 {
+    //   let calls = 0;
+    //   let x = and(&mut calls);
+    //   let y = or(&mut calls);
+    //   let z = bitwise_and(&mut calls);
+    //   return calls * 10 + x + y + z;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let calls = 0;
+            let calls_slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+                cl::StackSlotKind::ExplicitSlot,
+                4,
+                2,
+            ));
+            let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+            fbuilder.ins().stack_store(zero, calls_slot, 0);
+            let calls = fbuilder.ins().stack_addr(size_t, calls_slot, 0);
+
+            // let x = and(&mut calls);
+            // let y = or(&mut calls);
+            // let z = bitwise_and(&mut calls);
+            let [x, y, z] = [and_func_id, or_func_id, bitwise_and_func_id].map(|func_id| {
+                let fref = module.declare_func_in_func(func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[calls]);
+                let b = fbuilder.inst_results(call)[0];
+
+                // Booleans are `i8`s, so they need to be extended before being added to an `i32`
+                fbuilder.ins().uextend(cl::types::I32, b)
+            });
+
+            // return calls * 10 + x + y + z;
+            let result = {
+                let calls = fbuilder.ins().stack_load(cl::types::I32, calls_slot, 0);
+                let result = fbuilder.ins().imul_imm(calls, 10);
+                let result = fbuilder.ins().iadd(result, x);
+                let result = fbuilder.ins().iadd(result, y);
+                fbuilder.ins().iadd(result, z)
+            };
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn check(calls: &mut i32, result: bool) -> bool {
+    //   *calls += 1;
+    //   return result;
+    // }
+    codegen
+        .define(check_func_id, |_, fbuilder, entry| {
+            let calls = fbuilder.block_params(entry)[0];
+            let result = fbuilder.block_params(entry)[1];
+
+            // *calls += 1;
+            //
+            // This is the side effect which shows whether `check` was called
+            {
+                let flags = cl::MemFlags::trusted();
+                let n = fbuilder.ins().load(cl::types::I32, flags, calls, 0);
+                let n = fbuilder.ins().iadd_imm(n, 1);
+                fbuilder.ins().store(flags, n, calls, 0);
+            }
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn and(calls: &mut i32) -> bool {
+    //   return check(calls, false) && check(calls, true);
+    // }
+    //
+    // // -- Which we lower as -- //
+    //
+    // block0(calls):
+    //   let a = check(calls, false);
+    //   brif a, block1, block2(a)
+    //
+    // block1:
+    //   let b = check(calls, true);
+    //   jump block2(b)
+    //
+    // block2(result):
+    //   return result
+    codegen
+        .define(and_func_id, |module, fbuilder, entry| {
+            let calls = fbuilder.block_params(entry)[0];
+
+            let rhs_block = fbuilder.create_block();
+            let merge_block = fbuilder.create_block();
+
+            // The merge block receives the result of the whole `&&` expression
+            fbuilder.append_block_param(merge_block, cl::types::I8);
+
+            // let a = check(calls, false);
+            //
+            // If `a` is false, the result is false without evaluating `b`. Since `a` is that false
+            // value, we can pass it to the merge block as-is.
+            {
+                let a = call_check(module, fbuilder, check_func_id, calls, false);
+
+                fbuilder
+                    .ins()
+                    .brif(a, rhs_block, &[], merge_block, &[a.into()]);
+            }
+
+            // The only predecessor of the right-hand side is the `brif` above
+            fbuilder.seal_block(rhs_block);
+
+            // let b = check(calls, true);
+            //
+            // If we got here `a` was true, so the result is whatever `b` is
+            {
+                fbuilder.switch_to_block(rhs_block);
+
+                let b = call_check(module, fbuilder, check_func_id, calls, true);
+
+                fbuilder.ins().jump(merge_block, &[b.into()]);
+            }
+
+            fbuilder.seal_block(merge_block);
+
+            // return result;
+            {
+                fbuilder.switch_to_block(merge_block);
+
+                let result = fbuilder.block_params(merge_block)[0];
+
+                fbuilder.ins().return_(&[result]);
+            }
+        })
+        .unwrap();
+
+    // fn or(calls: &mut i32) -> bool {
+    //   return check(calls, true) || check(calls, false);
+    // }
+    //
+    // // -- Which we lower as -- //
+    //
+    // block0(calls):
+    //   let a = check(calls, true);
+    //   brif a, block2(a), block1
+    //
+    // block1:
+    //   let b = check(calls, false);
+    //   jump block2(b)
+    //
+    // block2(result):
+    //   return result
+    codegen
+        .define(or_func_id, |module, fbuilder, entry| {
+            let calls = fbuilder.block_params(entry)[0];
+
+            let rhs_block = fbuilder.create_block();
+            let merge_block = fbuilder.create_block();
+
+            // The merge block receives the result of the whole `||` expression
+            fbuilder.append_block_param(merge_block, cl::types::I8);
+
+            // let a = check(calls, true);
+            //
+            // If `a` is true, the result is true without evaluating `b`. Same as for `&&` except
+            // that the branches are swapped.
+            {
+                let a = call_check(module, fbuilder, check_func_id, calls, true);
+
+                fbuilder
+                    .ins()
+                    .brif(a, merge_block, &[a.into()], rhs_block, &[]);
+            }
+
+            // The only predecessor of the right-hand side is the `brif` above
+            fbuilder.seal_block(rhs_block);
+
+            // let b = check(calls, false);
+            //
+            // If we got here `a` was false, so the result is whatever `b` is
+            {
+                fbuilder.switch_to_block(rhs_block);
+
+                let b = call_check(module, fbuilder, check_func_id, calls, false);
+
+                fbuilder.ins().jump(merge_block, &[b.into()]);
+            }
+
+            fbuilder.seal_block(merge_block);
+
+            // return result;
+            {
+                fbuilder.switch_to_block(merge_block);
+
+                let result = fbuilder.block_params(merge_block)[0];
+
+                fbuilder.ins().return_(&[result]);
+            }
+        })
+        .unwrap();
+
+    // fn bitwise_and(calls: &mut i32) -> bool {
+    //   return check(calls, false) & check(calls, true);
+    // }
+    codegen
+        .define(bitwise_and_func_id, |module, fbuilder, entry| {
+            let calls = fbuilder.block_params(entry)[0];
+
+            // Both operands are evaluated up front, so `check` is called twice no matter what `a` is
+            let a = call_check(module, fbuilder, check_func_id, calls, false);
+            let b = call_check(module, fbuilder, check_func_id, calls, true);
+
+            let result = fbuilder.ins().band(a, b);
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+}
+
+// let v = check(calls, result);
+fn call_check<M: Module>(
+    module: &mut M,
+    fbuilder: &mut cl::FunctionBuilder<'_>,
+    check_func_id: FuncId,
+    calls: cl::Value,
+    result: bool,
+) -> cl::Value {
+    let fref = module.declare_func_in_func(check_func_id, fbuilder.func);
+    let result = fbuilder.ins().iconst(cl::types::I8, result as i64);
+    let call = fbuilder.ins().call(fref, &[calls, result]);
+    fbuilder.inst_results(call)[0]
+}
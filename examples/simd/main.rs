@@ -0,0 +1,137 @@
+//! This example shows how to use SIMD vector types to operate on several values at once.
+//!
+//! ```
+//! fn add(a: f32x4, b: f32x4) -> f32x4 {
+//!   return a + b;
+//! }
+//!
+//! fn main() -> i32 {
+//!   let a = f32x4::splat(1.0).replace(0, 5.0); // [5.0, 1.0, 1.0, 1.0]
+//!   let b = f32x4::splat(2.0);                 // [2.0, 2.0, 2.0, 2.0]
+//!   let c = add(a, b);                         // [7.0, 3.0, 3.0, 3.0]
+//!   return c[0] as i32;
+//! }
+//! ```
+//!
+//! Cranelift has vector types such as `I32X4` and `F32X4`, which hold a fixed amount of lanes
+//! of the same scalar type. Arithmetic instructions such as `fadd` and `iadd` work on vectors
+//! lane-wise, so a single instruction does the work of four.
+//!
+//! * `splat` creates a vector with the same value in every lane.
+//! * `insertlane` replaces a single lane, and `extractlane` reads a single lane.
+//!
+//! Vectors are passed and returned in vector registers, such as `xmm0` on x86-64 and `v0` on
+//! aarch64. Every 128-bit vector type is 16 bytes and also 16-byte aligned, which matters when
+//! they're stored in memory. See `struct-layouts` for a struct with a vector field.
+//!
+//! All x86-64 CPUs support SSE2, which is enough for the instructions used here. Some vector
+//! instructions are lowered more efficiently with newer extensions, which can be enabled with
+//! `skip_boilerplate_with` by setting ISA flags such as `has_sse41`.
+//!
+//! The main function returns `5.0 + 2.0` as an integer, so the exit code will be `7`.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example simd -- -o simd.o`
+//! `$ clang simd.o -o simd`
+//! `$ ./simd; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example simd -- --jit`
+
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+};
+use cranelift_module::{Linkage, Module};
+
+fn main() {
+    skip_boilerplate(b"simd", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let f32x4 = cl::types::F32X4;
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // fn add(a: f32x4, b: f32x4) -> f32x4;
+    let add_func_id = declare_function_from_types(
+        codegen.module,
+        "add",
+        Linkage::Local,
+        &[f32x4, f32x4],
+        &[f32x4],
+        None,
+    );
+
+    // fn main() -> i32 {
+    //   let a = f32x4::splat(1.0).replace(0, 5.0);
+    //   let b = f32x4::splat(2.0);
+    //   let c = add(a, b);
+    //   return c[0] as i32;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let a = f32x4::splat(1.0).replace(0, 5.0);
+            //
+            // [5.0, 1.0, 1.0, 1.0]
+            let a = {
+                let one = fbuilder.ins().f32const(1.0);
+                let ones = fbuilder.ins().splat(f32x4, one);
+
+                // The lane index is an immediate, so it has to be known at compile time
+                let five = fbuilder.ins().f32const(5.0);
+                fbuilder.ins().insertlane(ones, five, 0)
+            };
+
+            // let b = f32x4::splat(2.0);
+            //
+            // [2.0, 2.0, 2.0, 2.0]
+            let b = {
+                let two = fbuilder.ins().f32const(2.0);
+                fbuilder.ins().splat(f32x4, two)
+            };
+
+            // let c = add(a, b);
+            let c = {
+                let fref = module.declare_func_in_func(add_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[a, b]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            // return c[0] as i32;
+            let result = {
+                let lane = fbuilder.ins().extractlane(c, 0);
+                fbuilder.ins().fcvt_to_sint_sat(cl::types::I32, lane)
+            };
+
+            fbuilder.ins().return_(&[result]);
+        })
+        .unwrap();
+
+    // fn add(a: f32x4, b: f32x4) -> f32x4 {
+    //   return a + b;
+    // }
+    codegen
+        .define(add_func_id, |_, fbuilder, entry| {
+            let a = fbuilder.block_params(entry)[0];
+            let b = fbuilder.block_params(entry)[1];
+
+            // Adds each lane of `a` to the same lane of `b`, all in a single instruction
+            let c = fbuilder.ins().fadd(a, b);
+
+            fbuilder.ins().return_(&[c]);
+        })
+        .unwrap();
+}
@@ -3,9 +3,15 @@
 //! We will ensure all our structs are aligned. This substantially improves performance since it
 //! lowers the amount of loads a CPU has to do, and is a hard requirement for a lot of ABI's.
 //!
-//! We will not be covering nested structs or circular structs here. But if you are, keep in
-//! mind that you will need to check for recursive data types, and either fail to compile or
-//! automatically box the fields to make the structs finitely sized.
+//! Aligning fields means inserting padding between them. If the struct doesn't need to match the
+//! C layout, the padding can be reduced by reordering the fields, which `layout_of_struct` shows.
+//!
+//! Some ABIs and file formats instead need a packed struct, with no padding at all.
+//!
+//! Structs may contain other structs, in which case the inner struct is laid out on its own and
+//! then placed in the outer struct like any other field. We will not be covering circular structs
+//! here. But if you are, keep in mind that you will need to check for recursive data types, and
+//! either fail to compile or automatically box the fields to make the structs finitely sized.
 //!
 //! The main function will construct two structs: One small and one large.
 //! These structs will then be given as parameter to a function that returns a new struct
@@ -18,225 +24,409 @@
 //! `$ cargo run --example struct-layouts -- -o struct-layouts.o`
 //! `$ clang struct-layouts.o -o struct-layouts`
 //! `$ ./struct-layouts; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example struct-layouts -- --jit`
 
 use cranelift::codegen::ir::ArgumentPurpose;
 use cranelift::prelude::isa::CallConv;
-use cranelift::prelude::{InstBuilder, types};
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, codegen::Context, types};
 use cranelift::{codegen::ir::StackSlot, prelude as cl};
 use cranelift_module::{FuncId, Linkage, Module};
-use cranelift_object::ObjectModule;
 
-use cranelift_examples::{declare_main, function_builder_from_declaration, skip_boilerplate};
+use cranelift_examples::{
+    declare_main, define_function, for_any_module, function_builder_from_declaration, print_clif,
+    skip_boilerplate,
+};
 
 fn main() {
-    skip_boilerplate(b"struct-layouts", |ctx, fctx, module, _args| {
-        let size_t = module.isa().pointer_type();
-
-        let small_struct_fields = &[types::I32, types::I32];
-        let large_struct_fields = &[types::I32, types::I8, types::I32, types::I16];
+    skip_boilerplate(b"struct-layouts", for_any_module!(define_functions));
+}
 
-        let main_func_id = declare_main(module);
-        let inc_large_funcid = declare_increment_large(module, large_struct_fields);
-        let inc_small_funcid = declare_increment_small(module, small_struct_fields);
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
 
-        // fn main() {
-        //   let large_struct = LargeStruct {...};
-        //   let small_struct = SmallStruct {...};
-        //
-        //   let _ = inc_large_struct(large_struct);
-        //   let incremented_small_struct = inc_small_struct(small_struct);
-        //
-        //   let small_sum = incremented_small_struct.a + incremented_small_struct.b;
-        //
-        //   return small_sum;
-        // }
-        {
-            let (mut fbuilder, _) =
-                function_builder_from_declaration(module, &mut ctx.func, fctx, main_func_id);
+    let small_struct_fields = &[Field::Scalar(types::I32), Field::Scalar(types::I32)];
+    let large_struct_fields = &[
+        Field::Scalar(types::I32),
+        Field::Scalar(types::I8),
+        Field::Scalar(types::I32),
+        Field::Scalar(types::I16),
+    ];
+
+    // struct Point {
+    //   x: i32,
+    //   y: i32,
+    // }
+    //
+    // struct Flagged {
+    //   point: Point,
+    //   flag: i8,
+    // }
+    let point_fields = &[Field::Scalar(types::I32), Field::Scalar(types::I32)];
+    let flagged_fields = &[Field::Struct(point_fields), Field::Scalar(types::I8)];
+
+    // The inner struct is aligned to its largest field, and so is the outer struct since the
+    // inner struct is its most aligned field.
+    //
+    // Flagged {
+    //   point: Point {
+    //     x,    // i32 at offset 0
+    //     y,    // i32 at offset 4
+    //   },
+    //   flag,   // i8  at offset 8
+    //   _pad0,  // i24
+    // }
+    println!(
+        "Flagged {{ point: {}, flag: {} }} size={} align={}",
+        offset_of_field(0, flagged_fields, false),
+        offset_of_field(1, flagged_fields, false),
+        size_of_struct(flagged_fields, false),
+        alignment_of_struct(flagged_fields, false),
+    );
+    println!(
+        "Point {{ x: {}, y: {} }} size={} align={}\n",
+        offset_of_field(0, point_fields, false),
+        offset_of_field(1, point_fields, false),
+        size_of_struct(point_fields, false),
+        alignment_of_struct(point_fields, false),
+    );
+    assert_eq!(offset_of_field(1, flagged_fields, false), 8);
+    assert_eq!(size_of_struct(flagged_fields, false), 12);
+
+    // struct Mixed {
+    //   a: i8,
+    //   b: i32,
+    //   c: i8,
+    // }
+    //
+    // Laid out in declaration order, both `i8` fields need padding after them. By moving the
+    // most aligned fields first, the small fields are packed together at the end instead. This
+    // is what Rust does for structs without `#[repr(C)]`.
+    let mixed_fields = &[
+        Field::Scalar(types::I8),
+        Field::Scalar(types::I32),
+        Field::Scalar(types::I8),
+    ];
+    let c_layout = layout_of_struct(mixed_fields, true, false);
+    let reordered_layout = layout_of_struct(mixed_fields, false, false);
+
+    println!("field  repr(C)  reordered");
+    for (i, name) in ["a", "b", "c"].into_iter().enumerate() {
+        println!(
+            "{name:<6} {:<8} {}",
+            c_layout.offsets[i], reordered_layout.offsets[i]
+        );
+    }
+    println!("size   {:<8} {}\n", c_layout.size, reordered_layout.size);
+    assert_eq!(c_layout.size, 12);
+    assert_eq!(reordered_layout.size, 8);
+
+    // #[repr(packed)]
+    // struct Packed {
+    //   a: i8,
+    //   b: i32,
+    // }
+    //
+    // Without padding, `b` ends up at the misaligned offset 1.
+    let packed_fields = &[Field::Scalar(types::I8), Field::Scalar(types::I32)];
+    assert_eq!(offset_of_field(1, packed_fields, true), 1);
+    assert_eq!(size_of_struct(packed_fields, true), 5);
+
+    // struct WithVector {
+    //   flag: i8,
+    //   v: i32x4,
+    // }
+    //
+    // SIMD vectors are aligned to their full size, so `v` needs 15 bytes of padding before it.
+    // See the `simd` example for how to operate on them.
+    let vector_fields = &[Field::Scalar(types::I8), Field::Scalar(types::I32X4)];
+    assert_eq!(offset_of_field(1, vector_fields, false), 16);
+    assert_eq!(size_of_struct(vector_fields, false), 32);
+
+    let main_func_id = declare_main(module);
+    let inc_large_funcid = declare_increment_large(module, large_struct_fields);
+    let inc_small_funcid = declare_increment_small(module, small_struct_fields);
+
+    // fn main() {
+    //   let large_struct = LargeStruct {...};
+    //   let small_struct = SmallStruct {...};
+    //   let flagged = Flagged {...};
+    //   let mixed = Mixed {...};
+    //   let packed = Packed {...};
+    //   let _ = packed.b;
+    //
+    //   let _ = inc_large_struct(large_struct);
+    //   let incremented_small_struct = inc_small_struct(small_struct);
+    //
+    //   let small_sum = incremented_small_struct.a + incremented_small_struct.b;
+    //
+    //   return small_sum;
+    // }
+    {
+        let (mut fbuilder, _) =
+            function_builder_from_declaration(module, &mut ctx.func, fctx, main_func_id);
+
+        // let large_struct = LargeStruct {
+        //   a: 1, // i32
+        //   b: 2, // i8
+        //   c: 3, // i32
+        //   d: 4, // i16
+        // };
+        let large_struct: cl::Value = {
+            // For larger structs, we reserve space on the stack and pass it around as a pointer.
+            //
+            // Assigning a field will be loading from / storing to that pointer.
+            let struct_stack_slot: StackSlot =
+                stack_alloc(&mut fbuilder, size_of_struct(large_struct_fields, false));
+
+            // Here we use the `stack_` prefixed instructions to act upon the `cl::StackSlot` directly.
+            // In a real compiler it might be easier to first get the pointer as a `cl::Value` with
+            // `FunctionBuilder::ins().stack_addr(...)` and then using `FunctionBuilder::ins().store(...)`
+
+            for (i, n) in [1, 2, 3, 4].into_iter().enumerate() {
+                let offset = offset_of_field(i, large_struct_fields, false);
+                let value = fbuilder.ins().iconst(large_struct_fields[i].as_scalar(), n);
+                fbuilder.ins().stack_store(value, struct_stack_slot, offset);
+            }
 
+            // Since our structs are aligned, padding was added.
+            //
             // let large_struct = LargeStruct {
-            //   a: 1, // i32
-            //   b: 2, // i8
-            //   c: 3, // i32
-            //   d: 4, // i16
+            //   a: 1,  // i32,
+            //   b: 2,  // i8,
+            //   _pad0: // i24
+            //   c: 3   // i32
+            //   d: 4   // i16
+            //   _pad1: // i16
             // };
-            let large_struct: cl::Value = {
-                // For larger structs, we reserve space on the stack and pass it around as a pointer.
-                //
-                // Assigning a field will be loading from / storing to that pointer.
-                let struct_stack_slot: StackSlot =
-                    stack_alloc(&mut fbuilder, size_of_struct(large_struct_fields));
-
-                // Here we use the `stack_` prefixed instructions to act upon the `cl::StackSlot` directly.
-                // In a real compiler it might be easier to first get the pointer as a `cl::Value` with
-                // `FunctionBuilder::ins().stack_addr(...)` and then using `FunctionBuilder::ins().store(...)`
-
-                for (i, n) in [1, 2, 3, 4].into_iter().enumerate() {
-                    let offset = offset_of_field(i, large_struct_fields);
-                    let value = fbuilder.ins().iconst(large_struct_fields[i], n);
-                    fbuilder.ins().stack_store(value, struct_stack_slot, offset);
-                }
-
-                // Since our structs are aligned, padding was added.
-                //
-                // let large_struct = LargeStruct {
-                //   a: 1,  // i32,
-                //   b: 2,  // i8,
-                //   _pad0: // i24
-                //   c: 3   // i32
-                //   d: 4   // i16
-                //   _pad1: // i16
-                // };
-
-                // Convert the stack slot to a `cl::Value` pointer
-                fbuilder.ins().stack_addr(size_t, struct_stack_slot, 0)
-            };
 
-            // let small_struct = SmallStruct {
-            //   a: 1, // i32
-            //   b: 2, // i32
-            // };
-            let small_struct: Vec<cl::Value> = {
-                // For smaller structs, it's often unnecessary to introduce indirection.
-                // Just passing around the fields as values can allow the struct to remain entirely in
-                // registers.
-                [1, 2]
-                    .into_iter()
-                    .enumerate()
-                    .map(|(i, n)| fbuilder.ins().iconst(small_struct_fields[i], n))
-                    .collect()
-            };
+            // Convert the stack slot to a `cl::Value` pointer
+            fbuilder.ins().stack_addr(size_t, struct_stack_slot, 0)
+        };
+
+        // let small_struct = SmallStruct {
+        //   a: 1, // i32
+        //   b: 2, // i32
+        // };
+        let small_struct: Vec<cl::Value> = {
+            // For smaller structs, it's often unnecessary to introduce indirection.
+            // Just passing around the fields as values can allow the struct to remain entirely in
+            // registers.
+            [1, 2]
+                .into_iter()
+                .enumerate()
+                .map(|(i, n)| fbuilder.ins().iconst(small_struct_fields[i].as_scalar(), n))
+                .collect()
+        };
+
+        // let flagged = Flagged {
+        //   point: Point { x: 5, y: 6 },
+        //   flag: 1,
+        // };
+        {
+            let struct_stack_slot =
+                stack_alloc(&mut fbuilder, size_of_struct(flagged_fields, false));
+
+            // The offset of a nested field is the offset of the inner struct within the outer
+            // struct, plus the offset of the field within the inner struct.
+            let point_offset = offset_of_field(0, flagged_fields, false);
+            for (i, n) in [5, 6].into_iter().enumerate() {
+                let offset = point_offset + offset_of_field(i, point_fields, false);
+                let value = fbuilder.ins().iconst(point_fields[i].as_scalar(), n);
+                fbuilder.ins().stack_store(value, struct_stack_slot, offset);
+            }
 
-            // let _ = inc_large_struct(large_struct);
-            let _incremented_large_struct: cl::Value = {
-                let fref = module.declare_func_in_func(inc_large_funcid, &mut fbuilder.func);
+            let flag_offset = offset_of_field(1, flagged_fields, false);
+            let flag = fbuilder.ins().iconst(types::I8, 1);
+            fbuilder
+                .ins()
+                .stack_store(flag, struct_stack_slot, flag_offset);
+        }
 
-                let out_ptr = {
-                    let out_stack_slot =
-                        stack_alloc(&mut fbuilder, size_of_struct(large_struct_fields));
+        // let mixed = Mixed {
+        //   a: 1, // i8
+        //   b: 2, // i32
+        //   c: 3, // i8
+        // };
+        //
+        // The offsets are still looked up by the declared index of the field, so accessing a
+        // field works the same regardless of where the field ended up.
+        {
+            let struct_stack_slot = stack_alloc(&mut fbuilder, reordered_layout.size);
 
-                    fbuilder.ins().stack_addr(size_t, out_stack_slot, 0)
-                };
+            for (i, n) in [1, 2, 3].into_iter().enumerate() {
+                let offset = reordered_layout.offsets[i];
+                let value = fbuilder.ins().iconst(mixed_fields[i].as_scalar(), n);
+                fbuilder.ins().stack_store(value, struct_stack_slot, offset);
+            }
+        }
 
-                fbuilder.ins().call(fref, &[large_struct, out_ptr]);
+        // let packed = Packed {
+        //   a: 1, // i8
+        //   b: 2, // i32
+        // };
+        // let _ = packed.b;
+        {
+            let struct_stack_slot = stack_alloc(&mut fbuilder, size_of_struct(packed_fields, true));
+            let ptr = fbuilder.ins().stack_addr(size_t, struct_stack_slot, 0);
 
-                out_ptr
-            };
+            // `MemFlags::trusted()` would tell Cranelift that the access is aligned, which
+            // isn't true for `b`. On targets with strict alignment requirements, Cranelift may
+            // then use instructions which fault on misaligned addresses.
+            //
+            // With the default flags, Cranelift assumes the access may be misaligned.
+            let flags = cl::MemFlags::new();
 
-            // let incremented_small_struct = inc_small_struct(small_struct);
-            let incremented_small_struct: Vec<cl::Value> = {
-                let fref = module.declare_func_in_func(inc_small_funcid, &mut fbuilder.func);
+            for (i, n) in [1, 2].into_iter().enumerate() {
+                let offset = offset_of_field(i, packed_fields, true);
+                let value = fbuilder.ins().iconst(packed_fields[i].as_scalar(), n);
+                fbuilder.ins().store(flags, value, ptr, offset);
+            }
 
-                let call = fbuilder.ins().call(fref, &small_struct);
+            // Read the misaligned `i32`
+            let b_offset = offset_of_field(1, packed_fields, true);
+            fbuilder.ins().load(types::I32, flags, ptr, b_offset);
+        }
 
-                fbuilder.inst_results(call).to_vec()
-            };
+        // let _ = inc_large_struct(large_struct);
+        let _incremented_large_struct: cl::Value = {
+            let fref = module.declare_func_in_func(inc_large_funcid, fbuilder.func);
 
-            // Calculate the sum of all fields in the small struct
-            //
-            // let small_sum = incremented_small_struct.a + incremented_small_struct.b;
-            let small_sum = {
-                let init = fbuilder.ins().iconst(types::I32, 0);
+            let out_ptr = {
+                let out_stack_slot =
+                    stack_alloc(&mut fbuilder, size_of_struct(large_struct_fields, false));
 
-                incremented_small_struct
-                    .into_iter()
-                    .fold(init, |sum, v| fbuilder.ins().iadd(sum, v))
+                fbuilder.ins().stack_addr(size_t, out_stack_slot, 0)
             };
 
-            // Return the sum of all fields in the small struct
-            //
-            // return small_sum;
-            fbuilder.ins().return_(&[small_sum]);
+            fbuilder.ins().call(fref, &[large_struct, out_ptr]);
 
-            fbuilder.finalize();
+            out_ptr
+        };
 
-            println!("fn main:\n{}", &ctx.func);
+        // let incremented_small_struct = inc_small_struct(small_struct);
+        let incremented_small_struct: Vec<cl::Value> = {
+            let fref = module.declare_func_in_func(inc_small_funcid, fbuilder.func);
 
-            module.define_function(main_func_id, ctx).unwrap();
-        }
+            let call = fbuilder.ins().call(fref, &small_struct);
 
-        // fn inc_large_struct(large: LargeStruct) -> LargeStruct {
-        //   return LargeStruct {
-        //     a: large.a + 1,
-        //     b: large.b + 1,
-        //     c: large.c + 1,
-        //     d: large.d + 1,
-        //   };
-        // }
-        //
-        // // -- Although the way we represent it in Cranelift looks like -- //
+            fbuilder.inst_results(call).to_vec()
+        };
+
+        // Calculate the sum of all fields in the small struct
         //
-        // fn inc_large_struct(large: &LargeStruct, out: &LargeStruct) {
-        //   (*out+0) = *(large+0) + 1;
-        //   (*out+4) = *(large+4) + 1;
-        //   (*out+8) = *(large+8) + 1;
-        //   (*out+12) = *(large+12) + 1;
-        // }
-        {
-            let (mut fbuilder, entry) =
-                function_builder_from_declaration(module, &mut ctx.func, fctx, inc_large_funcid);
+        // let small_sum = incremented_small_struct.a + incremented_small_struct.b;
+        let small_sum = {
+            let init = fbuilder.ins().iconst(types::I32, 0);
 
-            // By using `trusted`, we're asserting to Cranelift that the field is aligned and the
-            // pointer is valid.
-            let flags = cl::MemFlags::trusted();
+            incremented_small_struct
+                .into_iter()
+                .fold(init, |sum, v| fbuilder.ins().iadd(sum, v))
+        };
 
-            let param = fbuilder.block_params(entry)[0];
-            let out_pointer = fbuilder.block_params(entry)[1];
+        // Return the sum of all fields in the small struct
+        //
+        // return small_sum;
+        fbuilder.ins().return_(&[small_sum]);
 
-            for (i, &ty) in large_struct_fields.iter().enumerate() {
-                let offset = offset_of_field(i, large_struct_fields);
+        fbuilder.finalize();
 
-                // Access the field
-                let v = fbuilder.ins().load(ty, flags, param, offset);
-                // Increment it
-                let v = fbuilder.ins().iadd_imm(v, 1);
+        print_clif("fn main", &ctx.func);
 
-                // Write it to the second struct pointer
-                fbuilder.ins().store(flags, v, out_pointer, offset);
-            }
+        define_function(module, main_func_id, ctx).unwrap();
+    }
+
+    // fn inc_large_struct(large: LargeStruct) -> LargeStruct {
+    //   return LargeStruct {
+    //     a: large.a + 1,
+    //     b: large.b + 1,
+    //     c: large.c + 1,
+    //     d: large.d + 1,
+    //   };
+    // }
+    //
+    // // -- Although the way we represent it in Cranelift looks like -- //
+    //
+    // fn inc_large_struct(large: &LargeStruct, out: &LargeStruct) {
+    //   (*out+0) = *(large+0) + 1;
+    //   (*out+4) = *(large+4) + 1;
+    //   (*out+8) = *(large+8) + 1;
+    //   (*out+12) = *(large+12) + 1;
+    // }
+    {
+        let (mut fbuilder, entry) =
+            function_builder_from_declaration(module, &mut ctx.func, fctx, inc_large_funcid);
+
+        // By using `trusted`, we're asserting to Cranelift that the field is aligned and the
+        // pointer is valid.
+        let flags = cl::MemFlags::trusted();
+
+        let param = fbuilder.block_params(entry)[0];
+        let out_pointer = fbuilder.block_params(entry)[1];
+
+        for (i, field) in large_struct_fields.iter().enumerate() {
+            let ty = field.as_scalar();
+            let offset = offset_of_field(i, large_struct_fields, false);
+
+            // Access the field
+            let v = fbuilder.ins().load(ty, flags, param, offset);
+            // Increment it
+            let v = fbuilder.ins().iadd_imm(v, 1);
+
+            // Write it to the second struct pointer
+            fbuilder.ins().store(flags, v, out_pointer, offset);
+        }
 
-            // We don't return any values as we're using an out pointer instead
-            fbuilder.ins().return_(&[]);
-            fbuilder.finalize();
+        // We don't return any values as we're using an out pointer instead
+        fbuilder.ins().return_(&[]);
+        fbuilder.finalize();
 
-            println!("fn inc_large_struct:\n{}", &ctx.func);
+        print_clif("fn inc_large_struct", &ctx.func);
 
-            module.define_function(inc_large_funcid, ctx).unwrap();
-        }
+        define_function(module, inc_large_funcid, ctx).unwrap();
+    }
 
-        // fn inc_small_struct(small: SmallStruct) -> SmallStruct {
-        //   return SmallStruct {
-        //     a: small.a + 1,
-        //     b: small.b + 1,
-        //   };
-        // }
-        {
-            let (mut fbuilder, entry) =
-                function_builder_from_declaration(module, &mut ctx.func, fctx, inc_small_funcid);
+    // fn inc_small_struct(small: SmallStruct) -> SmallStruct {
+    //   return SmallStruct {
+    //     a: small.a + 1,
+    //     b: small.b + 1,
+    //   };
+    // }
+    {
+        let (mut fbuilder, entry) =
+            function_builder_from_declaration(module, &mut ctx.func, fctx, inc_small_funcid);
 
-            let a = {
-                let small_a = fbuilder.block_params(entry)[0];
-                fbuilder.ins().iadd_imm(small_a, 1)
-            };
+        let a = {
+            let small_a = fbuilder.block_params(entry)[0];
+            fbuilder.ins().iadd_imm(small_a, 1)
+        };
 
-            let b = {
-                let small_b = fbuilder.block_params(entry)[1];
-                fbuilder.ins().iadd_imm(small_b, 1)
-            };
+        let b = {
+            let small_b = fbuilder.block_params(entry)[1];
+            fbuilder.ins().iadd_imm(small_b, 1)
+        };
 
-            fbuilder.ins().return_(&[a, b]);
-            fbuilder.finalize();
+        fbuilder.ins().return_(&[a, b]);
+        fbuilder.finalize();
 
-            println!("fn inc_small_struct:\n{}", &ctx.func);
+        print_clif("fn inc_small_struct", &ctx.func);
 
-            module.define_function(inc_small_funcid, ctx).unwrap();
-        }
-    });
+        define_function(module, inc_small_funcid, ctx).unwrap();
+    }
 }
 
-fn declare_increment_large(module: &mut ObjectModule, large_struct_fields: &[cl::Type]) -> FuncId {
+fn declare_increment_large(module: &mut impl Module, large_struct_fields: &[Field]) -> FuncId {
     let size_t = module.isa().pointer_type();
-    let struct_size = size_of_struct(large_struct_fields);
+    let struct_size = size_of_struct(large_struct_fields, false);
 
     let sig = cl::Signature {
         params: vec![
@@ -261,21 +451,19 @@ fn declare_increment_large(module: &mut ObjectModule, large_struct_fields: &[cl:
         .unwrap()
 }
 
-fn declare_increment_small(module: &mut ObjectModule, small_struct_fields: &[cl::Type]) -> FuncId {
+fn declare_increment_small(module: &mut impl Module, small_struct_fields: &[Field]) -> FuncId {
     let sig = cl::Signature {
         // Since it's only two scalar values, it's more efficient to pass the fields
         // individually in registers.
         params: small_struct_fields
             .iter()
-            .copied()
-            .map(cl::AbiParam::new)
+            .map(|field| field.abi_param())
             .collect(),
 
         // Since it's only two scalar values, it'll fit in the return registers
         returns: small_struct_fields
             .iter()
-            .copied()
-            .map(cl::AbiParam::new)
+            .map(|field| field.abi_param())
             .collect(),
 
         call_conv: CallConv::Fast,
@@ -286,6 +474,77 @@ fn declare_increment_small(module: &mut ObjectModule, small_struct_fields: &[cl:
         .unwrap()
 }
 
+// The computed layout of a struct
+//
+// `offsets` is indexed by the position of the field in the struct declaration, even when the
+// fields have been reordered in memory.
+struct Layout {
+    size: u32,
+    offsets: Vec<i32>,
+}
+
+// Compute the layout of a struct, either keeping the fields in declaration order like C does, or
+// reordering them to minimize padding.
+//
+// A packed struct has no padding to minimize, so its fields are never reordered.
+fn layout_of_struct(fields: &[Field], repr_c: bool, packed: bool) -> Layout {
+    // The order in which the fields will be placed in memory
+    let mut order = (0..fields.len()).collect::<Vec<_>>();
+
+    // Placing the most aligned fields first means every field after them is already aligned, so
+    // padding is only needed at the end of the struct.
+    //
+    // The sort is stable, so fields with the same alignment keep their declared order.
+    if !repr_c && !packed {
+        order.sort_by_key(|&i| std::cmp::Reverse(alignment_of_field(fields[i])));
+    }
+
+    let reordered = order.iter().map(|&i| fields[i]).collect::<Vec<_>>();
+
+    // Map the offsets back to the declared index of each field
+    let mut offsets = vec![0; fields.len()];
+    for (position, &i) in order.iter().enumerate() {
+        offsets[i] = offset_of_field(position, &reordered, packed);
+    }
+
+    Layout {
+        size: size_of_struct(&reordered, packed),
+        offsets,
+    }
+}
+
+// A field is either a scalar, or a nested struct with fields of its own
+#[derive(Clone, Copy)]
+enum Field<'a> {
+    Scalar(cl::Type),
+    Struct(&'a [Field<'a>]),
+}
+
+impl Field<'_> {
+    // Scalar fields can be loaded and stored directly, while nested structs have to be accessed
+    // one field at a time.
+    fn as_scalar(self) -> cl::Type {
+        match self {
+            Field::Scalar(ty) => ty,
+            Field::Struct(_) => panic!("nested structs aren't a single value"),
+        }
+    }
+
+    // Fields passed as separate parameters are ordinary integers at that point. The ones narrower
+    // than 32 bits don't fill a register, so they're marked to be extended to the full register by
+    // whichever side of the call the ABI requires. The integer fields in this example are all
+    // signed, such as `flag: i8`, so they're sign extended.
+    fn abi_param(self) -> cl::AbiParam {
+        let ty = self.as_scalar();
+
+        if ty.is_int() && ty.bits() < 32 {
+            cl::AbiParam::new(ty).sext()
+        } else {
+            cl::AbiParam::new(ty)
+        }
+    }
+}
+
 fn stack_alloc(fbuilder: &mut cl::FunctionBuilder<'_>, size: u32) -> StackSlot {
     fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
         cl::StackSlotKind::ExplicitSlot,
@@ -294,54 +553,87 @@ fn stack_alloc(fbuilder: &mut cl::FunctionBuilder<'_>, size: u32) -> StackSlot {
     ))
 }
 
-fn size_of_struct(fields: &[cl::Type]) -> u32 {
+// A packed struct treats every field as having an alignment of 1, so no padding is ever added.
+fn size_of_struct(fields: &[Field], packed: bool) -> u32 {
     let mut size = 0;
 
-    // Go through all fields and increment size by each fields size and padding
+    // Go through all fields and increment size by each fields padding and size
     for &field in fields {
-        size += field.bytes();
-
         // Add padding to ensure the field is aligned
-        let align = alignment_of_scalar_type(field);
+        let align = if packed { 1 } else { alignment_of_field(field) };
         let padding = (align - size % align) % align;
         size += padding;
+
+        size += size_of_field(field);
     }
 
     // Add padding to the end of the struct to make the struct itself aligned
-    let self_align = alignment_of_struct(fields);
+    let self_align = alignment_of_struct(fields, packed);
     let end_padding = (self_align - size % self_align) % self_align;
     size += end_padding;
 
     size
 }
 
+// This also holds for vector types such as `I32X4`, which are 16 bytes and 16-byte aligned
 fn alignment_of_scalar_type(of: cl::Type) -> u32 {
     of.bytes()
 }
 
-fn alignment_of_struct(fields: &[cl::Type]) -> u32 {
-    let mut alignment = 0;
+fn size_of_field(field: Field) -> u32 {
+    match field {
+        Field::Scalar(ty) => ty.bytes(),
+        // A nested struct takes up as much space as it would on its own, including its end padding
+        Field::Struct(fields) => size_of_struct(fields, false),
+    }
+}
+
+fn alignment_of_field(field: Field) -> u32 {
+    match field {
+        Field::Scalar(ty) => alignment_of_scalar_type(ty),
+        Field::Struct(fields) => alignment_of_struct(fields, false),
+    }
+}
+
+fn alignment_of_struct(fields: &[Field], packed: bool) -> u32 {
+    // A packed struct can be placed at any address
+    if packed {
+        return 1;
+    }
+
+    // Even an empty struct needs an alignment of at least 1
+    let mut alignment = 1;
 
-    // Since we don't have nested structs, the alignment of a struct is simply its largest field.
+    // The alignment of a struct is the alignment of its most aligned field. For nested structs
+    // this recurses, so the alignment ends up being that of the largest scalar anywhere inside it.
     for &field in fields {
-        let field_alignment = alignment_of_scalar_type(field);
+        let field_alignment = alignment_of_field(field);
         alignment = alignment.max(field_alignment);
     }
 
     alignment
 }
 
-fn offset_of_field(field: usize, fields: &[cl::Type]) -> i32 {
+fn offset_of_field(field: usize, fields: &[Field], packed: bool) -> i32 {
     let mut offset = 0;
 
-    // Go through all fields prior to this one and increment offset by their size and padding
-    for &prior in fields.iter().take(field) {
-        offset += prior.bytes() as i32;
-
+    // Go through all fields up to and including this one, and increment offset by the padding
+    // needed to align each field, and by the size of all prior fields.
+    for (i, &current) in fields.iter().enumerate() {
         // Add padding to ensure the field is aligned
-        let align = alignment_of_scalar_type(prior) as i32;
+        let align = if packed {
+            1
+        } else {
+            alignment_of_field(current) as i32
+        };
         let padding = (align - offset % align) % align;
         offset += padding;
+
+        if i == field {
+            break;
+        }
+
+        offset += size_of_field(current) as i32;
     }
 
     offset
@@ -0,0 +1,281 @@
+//! This example shows how to lower `match` expressions over enum tags using `cranelift_frontend::Switch`.
+//!
+//! A jump table indexed by the tag lets us branch with a single `br_table`. That works well when the
+//! tags are `0, 1, 2, ..`, but a jump table needs one entry for every possible tag between zero and
+//! the largest tag. Many languages allow explicit discriminants which don't have to be contiguous.
+//!
+//! ```
+//! enum Packet {
+//!   Pending = 0,
+//!   Data(i32, i32, i32) = 1,
+//!   Failed(i32) = 1000,
+//! }
+//! ```
+//!
+//! Using `br_table` for this enum would require a jump table with 1001 entries.
+//!
+//! `Switch` takes care of this for us. We only tell it which tag goes to which block, and it will
+//! split the tags into contiguous ranges. Dense ranges become jump tables while sparse ones become
+//! comparisons, with a binary search over the ranges if there are many of them.
+//!
+//! We'll lower the same `match` twice, once with dense tags and once with sparse tags, so that the
+//! emitted CLIF can be compared.
+//!
+//! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example switch-matching -- -o switch-matching.o`
+//! `$ clang switch-matching.o -o switch-matching`
+//! `$ ./switch-matching; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example switch-matching -- --jit`
+
+use cranelift::frontend::Switch;
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilder, FunctionBuilderContext, InstBuilder, codegen::Context};
+use cranelift_examples::{
+    Codegen, declare_function_from_types, declare_main, for_any_module, skip_boilerplate,
+    trap_unreachable,
+};
+use cranelift_module::{Linkage, Module};
+
+const TAG_TYPE: cl::Type = cl::types::I32;
+
+// The tags of each `Packet` variant
+#[derive(Clone, Copy)]
+struct PacketTags {
+    pending: i64,
+    data: i64,
+    failed: i64,
+}
+
+// enum Packet {
+//   Pending,
+//   Data(i32, i32, i32),
+//   Failed(i32),
+// }
+const DENSE_TAGS: PacketTags = PacketTags {
+    pending: 0,
+    data: 1,
+    failed: 2,
+};
+
+// enum Packet {
+//   Pending = 0,
+//   Data(i32, i32, i32) = 1,
+//   Failed(i32) = 1000,
+// }
+const SPARSE_TAGS: PacketTags = PacketTags {
+    pending: 0,
+    data: 1,
+    failed: 1000,
+};
+
+fn main() {
+    skip_boilerplate(b"switch-matching", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // As in the `tagged-union-layouts` example, a `Packet` is passed as a tag and a payload.
+    //
+    // fn match_dense(p: Packet) -> i32;
+    // fn match_sparse(p: Packet) -> i32;
+    let [match_dense_func_id, match_sparse_func_id] = ["match_dense", "match_sparse"].map(|name| {
+        declare_function_from_types(
+            codegen.module,
+            name,
+            Linkage::Local,
+            &[TAG_TYPE, size_t],
+            &[cl::types::I32],
+            None,
+        )
+    });
+
+    // fn main() -> i32 {
+    //   let t = match_dense(Packet::Data(10, 20, 30));
+    //   let u = match_sparse(Packet::Failed(100));
+    //   return t + u;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let t = match_dense(Packet::Data(10, 20, 30));
+            let t = {
+                let fields = [10, 20, 30].map(|n| fbuilder.ins().iconst(cl::types::I32, n));
+                let payload = stack_alloc_payload(size_t, fbuilder, &fields);
+                let tag = fbuilder.ins().iconst(TAG_TYPE, DENSE_TAGS.data);
+
+                let fref = module.declare_func_in_func(match_dense_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[tag, payload]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            // let u = match_sparse(Packet::Failed(100));
+            let u = {
+                let code = fbuilder.ins().iconst(size_t, 100);
+                let tag = fbuilder.ins().iconst(TAG_TYPE, SPARSE_TAGS.failed);
+
+                let fref = module.declare_func_in_func(match_sparse_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[tag, code]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            // return t + u;
+            let sum = fbuilder.ins().iadd(t, u);
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+
+    // With the tags `0, 1, 2`, `Switch` sees a single contiguous range and emits a `br_table`.
+    // This ends up being the same as building the jump table by hand.
+    //
+    // block0(v0: i32, v1: i64):
+    //     br_table v0, block4, [block1, block2, block3]
+    codegen
+        .define(match_dense_func_id, |_, fbuilder, block| {
+            let [tag, payload] = [0, 1].map(|i| fbuilder.block_params(block)[i]);
+            lower_packet_match(fbuilder, DENSE_TAGS, tag, payload);
+        })
+        .unwrap();
+
+    // With the tags `0, 1, 1000`, `Switch` sees two ranges: `0..=1` and `1000..=1000`.
+    //
+    // The single-entry range becomes a comparison, while the dense range still gets a jump table.
+    // Notice that the jump table only has two entries, instead of the 1001 entries we'd have
+    // needed with a hand-written `br_table`.
+    //
+    // block0(v0: i32, v1: i64):
+    //     v2 = icmp_imm eq v0, 1000
+    //     brif v2, block3, block5
+    //
+    // block5:
+    //     br_table v0, block4, [block1, block2]
+    codegen
+        .define(match_sparse_func_id, |_, fbuilder, block| {
+            let [tag, payload] = [0, 1].map(|i| fbuilder.block_params(block)[i]);
+            lower_packet_match(fbuilder, SPARSE_TAGS, tag, payload);
+        })
+        .unwrap();
+}
+
+// match p {
+//   Packet::Pending => return 10,
+//   Packet::Data(x, y, z) => return x + y + z,
+//   Packet::Failed(code) => return code,
+// }
+fn lower_packet_match(
+    fbuilder: &mut FunctionBuilder<'_>,
+    tags: PacketTags,
+    tag: cl::Value,
+    payload: cl::Value,
+) {
+    let size_t = fbuilder.func.dfg.value_type(payload);
+
+    // Declare the blocks for each branch
+    let [pending, data, failed] = [(); 3].map(|_| fbuilder.create_block());
+
+    // Declare the block for the default branch
+    let trap = fbuilder.create_block();
+
+    // Instead of creating a jump table, we only need to tell the switch which tag goes to which block
+    let mut switch = Switch::new();
+    switch.set_entry(tags.pending as u128, pending);
+    switch.set_entry(tags.data as u128, data);
+    switch.set_entry(tags.failed as u128, failed);
+
+    // Set the current block's terminator to the switch. Any tag that doesn't have an entry will
+    // jump to the default block.
+    switch.emit(fbuilder, tag, trap);
+
+    // The switch is the only predecessor of our branch blocks, so they can be sealed right away
+    fbuilder.seal_block(pending);
+    fbuilder.seal_block(data);
+    fbuilder.seal_block(failed);
+    fbuilder.seal_block(trap);
+
+    // Packet::Pending => return 10,
+    {
+        fbuilder.switch_to_block(pending);
+
+        let ten = fbuilder.ins().iconst(cl::types::I32, 10);
+
+        fbuilder.ins().return_(&[ten]);
+    }
+
+    // Packet::Data(x, y, z) => return x + y + z,
+    {
+        fbuilder.switch_to_block(data);
+
+        // The payload is a pointer to the variant parameters
+        let [x, y, z] = [0, 4, 8].map(|offset| {
+            fbuilder
+                .ins()
+                .load(cl::types::I32, cl::MemFlags::new(), payload, offset)
+        });
+
+        let sum = fbuilder.ins().iadd(x, y);
+        let sum = fbuilder.ins().iadd(sum, z);
+
+        fbuilder.ins().return_(&[sum]);
+    }
+
+    // Packet::Failed(code) => return code,
+    {
+        fbuilder.switch_to_block(failed);
+
+        // The payload is the variant parameter inlined into `size_t`
+        let code = if size_t == cl::types::I32 {
+            payload
+        } else {
+            fbuilder.ins().ireduce(cl::types::I32, payload)
+        };
+
+        fbuilder.ins().return_(&[code]);
+    }
+
+    // Trap the default block
+    //
+    // _ => unreachable!(),
+    {
+        fbuilder.switch_to_block(trap);
+        trap_unreachable(fbuilder);
+    }
+}
+
+// Larger enum variants will store their data behind a pointer.
+//
+// See the `tagged-union-layouts` example for more details.
+fn stack_alloc_payload(
+    size_t: cl::Type,
+    fbuilder: &mut FunctionBuilder<'_>,
+    params: &[cl::Value],
+) -> cl::Value {
+    let size = params.len() as u32 * cl::types::I32.bytes();
+
+    let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+        cl::StackSlotKind::ExplicitSlot,
+        size,
+        0,
+    ));
+
+    let mut offset = 0;
+    for &v in params {
+        fbuilder.ins().stack_store(v, slot, offset);
+        offset += cl::types::I32.bytes() as i32;
+    }
+
+    fbuilder.ins().stack_addr(size_t, slot, 0)
+}
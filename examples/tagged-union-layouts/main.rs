@@ -11,170 +11,729 @@
 //!
 //! If the payload of a variant is small enough, instead of storing it indirectly through a
 //! pointer, the pointer can be treated as an inlined integer scalar and the value will be
-//! extended / truncated to the right size.
+//! extended / truncated to the right size. Multiple small integer parameters can be packed into
+//! the same scalar as well.
 //!
-//! In this example all tagged union types will have the size `TAG_TYPE.bytes() + size_t`
+//! The tag uses the smallest integer type which can fit all variants, so most enums only need a
+//! single byte for their tag.
+//!
+//! In this example all tagged union types will have the size `tag_type.bytes() + size_t`, except for
+//! enums such as `Option<&T>` which can use the niche optimization to elide the tag entirely.
+//!
+//! Variants may be given explicit discriminants, such as `Data(..) = 10`, which are then stored as
+//! their tag. Variants without one continue counting from the variant before them, so `Closed`
+//! gets the tag `1`. Since the tags are no longer contiguous, `match_packet` branches on them with
+//! `Switch` rather than with a jump table indexed by the tag.
+//!
+//! The `match` in `match_packet` is an expression whose result is used afterwards, rather than
+//! each arm returning on its own. `match_expr` lowers it by having every arm jump to a shared merge
+//! block, passing its value as a block parameter. The arm for `Pending | Closed` covers both
+//! variants, with the tags of both leading to the same block.
+//!
+//! Arms can also have guards, such as `Packet::Data(x, _, _) if x > 0`. When a guard fails, the
+//! next arm is tried, so `classify` is lowered by `match_arms` as a chain of checks rather than a
+//! single `Switch`.
 //!
 //! To link against system libraries and produce a binary on Linux or MacOS, you can use `gcc` or `clang`
 //!
 //! `$ cargo run --example tagged-union-layouts -- -o tagged-union-layouts.o`
 //! `$ clang tagged-union-layouts.o -o tagged-union-layouts`
 //! `$ ./tagged-union-layouts; echo $?`
+//!
+//! `tests/tagged_union_layouts.rs` runs `main` in Cranelift's interpreter, which checks that it
+//! returns `(10 + 20 + 30 + 1) + 10` without having to link and run it. It runs `match_packet` with
+//! tags which don't belong to any variant as well, to check that they end up in the trap block.
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example tagged-union-layouts -- --jit`
 
-use cranelift::codegen::ir::BlockCall;
+use cranelift::frontend::Switch;
 use cranelift::prelude as cl;
-use cranelift::prelude::{FunctionBuilder, InstBuilder, JumpTableData, types};
-use cranelift_examples::{declare_main, function_builder_from_declaration, skip_boilerplate};
-use cranelift_module::Module;
-use cranelift_object::ObjectModule;
+use cranelift::prelude::{
+    FunctionBuilder, FunctionBuilderContext, InstBuilder, IntCC, codegen::Context, types,
+};
+use cranelift_examples::{
+    InterpretedFunctions, declare_function_from_types, declare_main, define_maybe_interpreted,
+    for_any_module, function_builder_from_declaration, print_clif, skip_boilerplate,
+    trap_unreachable,
+};
+use cranelift_module::{Linkage, Module};
 use std::cmp::Ordering;
 
-const TAG_TYPE: cl::Type = cl::types::I32;
-
 // enum Packet {
-//   Pending,
-//   Data(I32, I32, I32),
+//   Pending = 0,
+//   Closed,
+//   Data(i32, i32, i32) = 10,
 //   Failed(i32),
+//   Short(i8, i16),
+//   Meta(Point) = 255,
+// }
+//
+// struct Point {
+//   x: i32,
+//   y: i32,
+// }
+//
+// The variants in the order they're declared in
+pub(crate) const PACKET_PENDING: usize = 0;
+pub(crate) const PACKET_CLOSED: usize = 1;
+pub(crate) const PACKET_DATA: usize = 2;
+pub(crate) const PACKET_FAILED: usize = 3;
+pub(crate) const PACKET_SHORT: usize = 4;
+pub(crate) const PACKET_META: usize = 5;
+
+// The explicit discriminant of each variant, if it was given one. The tags of the other variants
+// are assigned by `discriminants`.
+pub(crate) const PACKET_DISCRIMINANTS: [Option<i64>; 6] =
+    [Some(0), None, Some(10), None, None, Some(255)];
+
+// The parameters of each variant, used by `tests/tagged_union_layouts.rs` to check that `Packet`
+// can't use the niche optimization. `Meta` is listed with the fields of its `Point`.
+#[allow(dead_code)]
+pub(crate) const PACKET_VARIANTS: [&[Param]; 6] = [
+    &[],
+    &[],
+    &[
+        Param::Scalar(types::I32),
+        Param::Scalar(types::I32),
+        Param::Scalar(types::I32),
+    ],
+    &[Param::Scalar(types::I32)],
+    &[Param::Scalar(types::I8), Param::Scalar(types::I16)],
+    &[Param::Scalar(types::I32), Param::Scalar(types::I32)],
+];
+
+const POINT_FIELDS: [cl::Type; 2] = [cl::types::I32, cl::types::I32];
+
+// enum Opt {
+//   None,
+//   Some(&i32),
 // }
-const TAG_PACKET_PENDING: i64 = 0;
-const TAG_PACKET_DATA: i64 = 1;
-const TAG_PACKET_FAILED: i64 = 2;
+//
+// Using the tag+payload layout, `Opt` would be `tag_type(&[0, 1]).bytes() + size_t` bytes large. That's 9
+// bytes on 64-bit targets, or 16 bytes once padded to the alignment of the pointer.
+//
+// However; since a reference can never be null, the all-zero bit pattern of the payload is never
+// used by `Opt::Some`. We can use that "niche" to represent `Opt::None` instead, making `Opt` the
+// same 8 bytes as the pointer itself.
+const TAG_OPT_NONE: i64 = 0;
+const TAG_OPT_SOME: i64 = 1;
+const OPT_VARIANTS: [&[Param]; 2] = [&[], &[Param::NonNull]];
 
 fn main() {
-    skip_boilerplate(b"tagged-union-layouts", |ctx, fctx, module, _args| {
-        let size_t = module.isa().pointer_type();
+    skip_boilerplate(b"tagged-union-layouts", for_any_module!(define_functions));
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    define_packet_functions(ctx, fctx, module, None);
+}
+
+// Define `main` along with the functions it calls.
+//
+// If `interpreted` is given, every function is kept for the interpreter as well, which
+// `tests/tagged_union_layouts.rs` uses to run them.
+pub(crate) fn define_packet_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    interpreted: Option<&InterpretedFunctions>,
+) {
+    let size_t = module.isa().pointer_type();
+
+    let main_func_id = declare_main(module);
+
+    // Variants without an explicit discriminant continue counting from the variant before them
+    let packet_tags = discriminants(&PACKET_DISCRIMINANTS);
+
+    // Since the largest tag is 255, a single byte is still enough to store the tag
+    let packet_tag_type = tag_type(&packet_tags);
+
+    // fn match_packet(packet: Packet) -> i32;
+    //
+    // `Packet` is passed as its tag followed by its payload. None of the payloads has an invalid
+    // value which could stand in for the other variants, so `Packet` has to keep its tag.
+    let match_packet_func_id = declare_function_from_types(
+        module,
+        "match_packet",
+        Linkage::Local,
+        &[packet_tag_type, size_t],
+        &[cl::types::I32],
+        None,
+    );
+
+    // fn classify(packet: Packet) -> i32;
+    let classify_func_id = declare_function_from_types(
+        module,
+        "classify",
+        Linkage::Local,
+        &[packet_tag_type, size_t],
+        &[cl::types::I32],
+        None,
+    );
 
-        let main_func_id = declare_main(module);
+    // fn unwrap_or(opt: Opt, default: i32) -> i32;
+    //
+    // Since `Opt` uses the niche layout, it's passed as a single pointer instead of a tag and payload.
+    let unwrap_or_func_id = declare_function_from_types(
+        module,
+        "unwrap_or",
+        Linkage::Local,
+        &[size_t, cl::types::I32],
+        &[cl::types::I32],
+        None,
+    );
+    let opt_layout = niche_layout(&OPT_VARIANTS).expect("Opt should be eligible for a niche");
+
+    // fn main() -> i32 {
+    //   let packet_data = Packet::Data(10, 20, 30);
+    //   let packet_pending = Packet::Pending;
+    //   let packet_failed = Packet::Failed(100);
+    //   let packet_short = Packet::Short(1, 2);
+    //   let packet_meta = Packet::Meta(Point { x: 3, y: 4 });
+    //
+    //   let x = 5;
+    //   let opt_some = Opt::Some(&x);
+    //   let opt_none = Opt::None;
+    //   unwrap_or(opt_some, 0);
+    //
+    //   let packet_negative = Packet::Data(-1, 2, 3);
+    //
+    //   return match_packet(packet_data) + classify(packet_data) + classify(packet_negative);
+    // }
+    {
+        let (mut fbuilder, _) =
+            function_builder_from_declaration(module, &mut ctx.func, fctx, main_func_id);
+
+        // let packet_data = Packet::Data(10, 20, 30)
+        let packet_data = {
+            let x = fbuilder.ins().iconst(cl::types::I32, 10);
+            let y = fbuilder.ins().iconst(cl::types::I32, 20);
+            let z = fbuilder.ins().iconst(cl::types::I32, 30);
 
-        // fn main() -> i32 {
-        //   let packet_data = Packet::Data(1, 2, 3);
-        //   let packet_pending = Packet::Pending;
-        //   let packet_failed = Packet::Failed(100);
+            construct_tagged_union(
+                module,
+                &mut fbuilder,
+                packet_tag_type,
+                packet_tags[PACKET_DATA],
+                &[x, y, z],
+            )
+        };
+
+        // let packet_pending = Packet::Pending
         //
-        //   let matched = packet_data;
+        // Even though this variant doesn't have a payload, all values of type `Packet`
+        // still needs to have the same size. Therefore, we still create a zeroed inlined payload.
+        let _packet_pending = construct_tagged_union(
+            module,
+            &mut fbuilder,
+            packet_tag_type,
+            packet_tags[PACKET_PENDING],
+            &[],
+        );
+
+        // let packet_failed = Packet::Failed(100)
         //
-        //   match matched {
-        //     Packet::Pending => return 10,
-        //     Packet::Data(x, y, z) => return x + y + z,
-        //     Packet::Failed(code) => return code,
-        //   }
-        // }
-        {
-            let (mut fbuilder, _) =
-                function_builder_from_declaration(module, &mut ctx.func, fctx, main_func_id);
+        // Since the variant parameter is small enough, it does not need a stack pointer.
+        let _packet_failed = {
+            let hundred = fbuilder.ins().iconst(cl::types::I32, 100);
+            construct_tagged_union(
+                module,
+                &mut fbuilder,
+                packet_tag_type,
+                packet_tags[PACKET_FAILED],
+                &[hundred],
+            )
+        };
 
-            // let packet_data = Packet::Data(10, 20, 30)
-            let packet_data = {
-                let one = fbuilder.ins().iconst(cl::types::I32, 10);
-                let two = fbuilder.ins().iconst(cl::types::I32, 20);
-                let three = fbuilder.ins().iconst(cl::types::I32, 30);
+        // let packet_short = Packet::Short(1, 2)
+        //
+        // The variant parameters together fit inside a `size_t`, so they're packed into the
+        // payload instead of being stack allocated.
+        let _packet_short = {
+            let a = fbuilder.ins().iconst(cl::types::I8, 1);
+            let b = fbuilder.ins().iconst(cl::types::I16, 2);
+            construct_tagged_union(
+                module,
+                &mut fbuilder,
+                packet_tag_type,
+                packet_tags[PACKET_SHORT],
+                &[a, b],
+            )
+        };
 
-                construct_tagged_union(module, &mut fbuilder, TAG_PACKET_DATA, &[one, two, three])
+        // let packet_meta = Packet::Meta(Point { x: 3, y: 4 })
+        //
+        // The payload is a pointer to the struct, laid out with the same alignment rules as in the
+        // `struct-layouts` example.
+        let _packet_meta = {
+            let point = {
+                let x = fbuilder.ins().iconst(cl::types::I32, 3);
+                let y = fbuilder.ins().iconst(cl::types::I32, 4);
+                [x, y]
             };
 
-            // let packet_pending = Packet::Pending
-            //
-            // Even though this variant doesn't have a payload, all values of type `Packet`
-            // still needs to have the same size. Therefore, we still create a zeroed inlined payload.
-            let _packet_pending =
-                construct_tagged_union(module, &mut fbuilder, TAG_PACKET_PENDING, &[]);
+            construct_tagged_union_with_struct(
+                module,
+                &mut fbuilder,
+                packet_tag_type,
+                packet_tags[PACKET_META],
+                &point,
+            )
+        };
 
-            // let packet_failed = Packet::Failed(100)
-            //
-            // Since the variant parameter is small enough, it does not need a stack pointer.
-            let _packet_failed = {
-                let hundred = fbuilder.ins().iconst(cl::types::I32, 100);
-                construct_tagged_union(module, &mut fbuilder, TAG_PACKET_FAILED, &[hundred])
+        // let x = 5;
+        // let opt_some = Opt::Some(&x);
+        let opt_some = {
+            let x = {
+                let five = fbuilder.ins().iconst(cl::types::I32, 5);
+                stack_alloc_payload(module, &mut fbuilder, &[five])
             };
 
-            // match matched {
-            //   Packet::Pending => return 10,
-            //   Packet::Data(x, y, z) => return x + y + z,
-            //   Packet::Failed(code) => return code,
-            // }
-            {
-                // Which of the constructed variants we're matching against
-                let (tag, payload) = packet_data;
-
-                // Declare all the blocks for the jump table branches
-                let branches = [TAG_PACKET_PENDING, TAG_PACKET_DATA, TAG_PACKET_FAILED].map(|_| {
-                    let block = fbuilder.create_block();
-                    BlockCall::new(block, [], &mut fbuilder.func.dfg.value_lists)
-                });
-
-                // Declare the block for the default branch
-                let trap = {
-                    let block = fbuilder.create_block();
-                    BlockCall::new(block, [], &mut fbuilder.func.dfg.value_lists)
-                };
-
-                // Create the table
-                let table = {
-                    let table_data = JumpTableData::new(trap, &branches);
-                    fbuilder.func.create_jump_table(table_data)
-                };
-
-                // Set main's block terminator to the jump table
-                fbuilder.ins().br_table(tag, table);
-
-                // Packet::Pending => return 10,
-                {
-                    switch_to_branch_block(&mut fbuilder, branches[TAG_PACKET_PENDING as usize]);
-
-                    let ten = fbuilder.ins().iconst(types::I32, 10);
-
-                    fbuilder.ins().return_(&[ten]);
-                }
+            construct_niche_union(size_t, &mut fbuilder, &opt_layout, TAG_OPT_SOME, &[x])
+        };
+
+        // let opt_none = Opt::None;
+        let _opt_none =
+            construct_niche_union(size_t, &mut fbuilder, &opt_layout, TAG_OPT_NONE, &[]);
+
+        // unwrap_or(opt_some, 0);
+        let _unwrapped = {
+            let zero = fbuilder.ins().iconst(cl::types::I32, 0);
+            let fref = module.declare_func_in_func(unwrap_or_func_id, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &[opt_some, zero]);
+            fbuilder.inst_results(call)[0]
+        };
+
+        // let packet_negative = Packet::Data(-1, 2, 3);
+        let packet_negative = {
+            let x = fbuilder.ins().iconst(cl::types::I32, -1);
+            let y = fbuilder.ins().iconst(cl::types::I32, 2);
+            let z = fbuilder.ins().iconst(cl::types::I32, 3);
+
+            construct_tagged_union(
+                module,
+                &mut fbuilder,
+                packet_tag_type,
+                packet_tags[PACKET_DATA],
+                &[x, y, z],
+            )
+        };
+
+        // return match_packet(packet_data) + classify(packet_data) + classify(packet_negative);
+        {
+            let match_packet = module.declare_func_in_func(match_packet_func_id, fbuilder.func);
+            let classify = module.declare_func_in_func(classify_func_id, fbuilder.func);
+
+            let mut call = |fref, (tag, payload)| {
+                let call = fbuilder.ins().call(fref, &[tag, payload]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            let matched = call(match_packet, packet_data);
+            let positive = call(classify, packet_data);
+            let negative = call(classify, packet_negative);
+
+            let sum = fbuilder.ins().iadd(matched, positive);
+            let sum = fbuilder.ins().iadd(sum, negative);
+
+            fbuilder.ins().return_(&[sum]);
+        }
+
+        fbuilder.finalize();
+
+        print_clif("fn main", &ctx.func);
+
+        define_maybe_interpreted(module, main_func_id, ctx, interpreted).unwrap();
+        ctx.clear();
+    }
 
-                // Packet::Data(x, y, z) => return x + y + z,
-                {
-                    switch_to_branch_block(&mut fbuilder, branches[TAG_PACKET_DATA as usize]);
+    // fn unwrap_or(opt: Opt, default: i32) -> i32 {
+    //   match opt {
+    //     Opt::Some(x) => return *x,
+    //     Opt::None => return default,
+    //   }
+    // }
+    {
+        let (mut fbuilder, entry) =
+            function_builder_from_declaration(module, &mut ctx.func, fctx, unwrap_or_func_id);
 
+        let opt = fbuilder.block_params(entry)[0];
+        let default = fbuilder.block_params(entry)[1];
+
+        let some_block = fbuilder.create_block();
+        let none_block = fbuilder.create_block();
+
+        // Instead of reading a tag, we compare the pointer against zero.
+        //
+        // `brif` jumps to the first block if the value is non-zero.
+        fbuilder.ins().brif(opt, some_block, &[], none_block, &[]);
+
+        // Opt::Some(x) => return *x,
+        {
+            fbuilder.seal_block(some_block);
+            fbuilder.switch_to_block(some_block);
+
+            // The entire value *is* the payload, so no conversion is needed
+            let x = fbuilder
+                .ins()
+                .load(cl::types::I32, cl::MemFlags::new(), opt, 0);
+
+            fbuilder.ins().return_(&[x]);
+        }
+
+        // Opt::None => return default,
+        {
+            fbuilder.seal_block(none_block);
+            fbuilder.switch_to_block(none_block);
+
+            fbuilder.ins().return_(&[default]);
+        }
+
+        fbuilder.finalize();
+
+        print_clif("fn unwrap_or", &ctx.func);
+
+        define_maybe_interpreted(module, unwrap_or_func_id, ctx, interpreted).unwrap();
+        ctx.clear();
+    }
+
+    // fn match_packet(packet: Packet) -> i32 {
+    //   let n = match packet {
+    //     Packet::Pending | Packet::Closed => 10,
+    //     Packet::Data(x, y, z) => x + y + z,
+    //     Packet::Failed(code) => code,
+    //     Packet::Short(a, b) => a + b,
+    //     Packet::Meta(Point { x, y }) => x + y,
+    //   };
+    //   return n + 1;
+    // }
+    {
+        let (mut fbuilder, entry) =
+            function_builder_from_declaration(module, &mut ctx.func, fctx, match_packet_func_id);
+
+        let tag = fbuilder.block_params(entry)[0];
+        let payload = fbuilder.block_params(entry)[1];
+
+        // The variants covered by each arm, in the order the arms are written in
+        let arms: [&[usize]; 5] = [
+            &[PACKET_PENDING, PACKET_CLOSED],
+            &[PACKET_DATA],
+            &[PACKET_FAILED],
+            &[PACKET_SHORT],
+            &[PACKET_META],
+        ];
+
+        // let n = match packet { ... };
+        let n = match_expr(
+            &mut fbuilder,
+            tag,
+            &packet_tags,
+            &arms,
+            cl::types::I32,
+            |fbuilder, variants| match variants {
+                // Packet::Pending | Packet::Closed => 10,
+                //
+                // The arm is reached with either tag, so it can't read the payload as if it
+                // belonged to one of them. Neither variant has a payload to bind here anyway.
+                [PACKET_PENDING, PACKET_CLOSED] => fbuilder.ins().iconst(types::I32, 10),
+
+                // Packet::Data(x, y, z) => x + y + z,
+                [PACKET_DATA] => {
                     let params = [cl::types::I32, cl::types::I32, cl::types::I32];
-                    let [x, y, z] = read_payload(size_t, &mut fbuilder, payload, params);
+                    let [x, y, z] = read_payload(size_t, fbuilder, payload, params);
 
                     let sum = fbuilder.ins().iadd(x, y);
-                    let sum = fbuilder.ins().iadd(sum, z);
+                    fbuilder.ins().iadd(sum, z)
+                }
 
-                    fbuilder.ins().return_(&[sum]);
+                // Packet::Failed(code) => code,
+                [PACKET_FAILED] => {
+                    let [code] = read_payload(size_t, fbuilder, payload, [cl::types::I32]);
+                    code
                 }
 
-                // Packet::Failed(code) => return code,
-                {
-                    switch_to_branch_block(&mut fbuilder, branches[TAG_PACKET_FAILED as usize]);
+                // Packet::Short(a, b) => a + b,
+                [PACKET_SHORT] => {
+                    let params = [cl::types::I8, cl::types::I16];
+                    let [a, b] = read_payload(size_t, fbuilder, payload, params);
 
-                    let [code] = read_payload(size_t, &mut fbuilder, payload, [cl::types::I32]);
+                    let a = fbuilder.ins().sextend(cl::types::I32, a);
+                    let b = fbuilder.ins().sextend(cl::types::I32, b);
+                    fbuilder.ins().iadd(a, b)
+                }
 
-                    fbuilder.ins().return_(&[code]);
+                // Packet::Meta(Point { x, y }) => x + y,
+                [PACKET_META] => {
+                    let [x, y] = read_struct_payload(fbuilder, payload, POINT_FIELDS);
+                    fbuilder.ins().iadd(x, y)
                 }
 
-                // Trap the default block
-                //
-                // _ => unreachable!(),
-                {
-                    switch_to_branch_block(&mut fbuilder, trap);
+                _ => unreachable!(),
+            },
+        );
 
-                    const TRAP_UNREACHABLE: u8 = 100;
+        // return n + 1;
+        //
+        // We're now in the merge block, where `n` is the block parameter each arm jumped with
+        let result = fbuilder.ins().iadd_imm(n, 1);
+        fbuilder.ins().return_(&[result]);
 
-                    fbuilder
-                        .ins()
-                        .trap(cl::TrapCode::user(TRAP_UNREACHABLE).unwrap());
-                }
-            }
+        fbuilder.finalize();
+
+        print_clif("fn match_packet", &ctx.func);
 
-            fbuilder.finalize();
+        define_maybe_interpreted(module, match_packet_func_id, ctx, interpreted).unwrap();
+        ctx.clear();
+    }
 
-            println!("fn main:\n{}", &ctx.func);
+    // fn classify(packet: Packet) -> i32 {
+    //   match packet {
+    //     Packet::Data(x, _, _) if x > 0 => x,
+    //     Packet::Failed(code) => code,
+    //     _ => 0,
+    //   }
+    // }
+    {
+        let (mut fbuilder, entry) =
+            function_builder_from_declaration(module, &mut ctx.func, fctx, classify_func_id);
 
-            module.define_function(main_func_id, ctx).unwrap();
+        let tag = fbuilder.block_params(entry)[0];
+        let payload = fbuilder.block_params(entry)[1];
+
+        let data_params = [cl::types::I32, cl::types::I32, cl::types::I32];
+
+        let arms = vec![
+            // Packet::Data(x, _, _) if x > 0 => x,
+            MatchArm {
+                variant: Some(PACKET_DATA),
+                guard: Some(Box::new(|fbuilder| {
+                    let [x, _, _] = read_payload(size_t, fbuilder, payload, data_params);
+                    fbuilder.ins().icmp_imm(IntCC::SignedGreaterThan, x, 0)
+                })),
+                body: Box::new(|fbuilder| {
+                    let [x, _, _] = read_payload(size_t, fbuilder, payload, data_params);
+                    x
+                }),
+            },
+            // Packet::Failed(code) => code,
+            MatchArm {
+                variant: Some(PACKET_FAILED),
+                guard: None,
+                body: Box::new(|fbuilder| {
+                    let [code] = read_payload(size_t, fbuilder, payload, [cl::types::I32]);
+                    code
+                }),
+            },
+            // _ => 0,
+            MatchArm {
+                variant: None,
+                guard: None,
+                body: Box::new(|fbuilder| fbuilder.ins().iconst(cl::types::I32, 0)),
+            },
+        ];
+
+        let result = match_arms(&mut fbuilder, tag, &packet_tags, cl::types::I32, arms);
+        fbuilder.ins().return_(&[result]);
+
+        fbuilder.finalize();
+
+        print_clif("fn classify", &ctx.func);
+
+        define_maybe_interpreted(module, classify_func_id, ctx, interpreted).unwrap();
+        ctx.clear();
+    }
+}
+
+// Lower a `match` on `tag` which produces a value of type `ty`, such as `let n = match p { .. };`
+//
+// `arms` lists the variants covered by each arm, and every variant must be covered by exactly one
+// of them. An arm with several variants is an or-pattern such as `Pending | Closed`.
+//
+// `arm` is called once for each arm with the builder positioned in that arm's block, and returns
+// the value of that arm. Instead of returning from the function, each arm jumps to a merge block
+// and passes its value along as a block parameter. The builder is left in the merge block, so that
+// the code after the `match` can use the result.
+//
+// The block of an or-pattern is reached from several tags, so `arm` can't read the payload as any
+// one of those variants. This is why an or-pattern may only bind what all of its alternatives
+// have in common, which a type checker would make sure of before lowering.
+//
+// Any tag which isn't in `tags` goes to a block which traps with `trap_unreachable`.
+//
+// The `lowering-structs` example does the same in `FuncLower::match_on`, for enums stored in memory.
+pub(crate) fn match_expr(
+    fbuilder: &mut FunctionBuilder<'_>,
+    tag: cl::Value,
+    tags: &[i64],
+    arms: &[&[usize]],
+    ty: cl::Type,
+    mut arm: impl FnMut(&mut FunctionBuilder<'_>, &[usize]) -> cl::Value,
+) -> cl::Value {
+    let mut covered = arms
+        .iter()
+        .flat_map(|variants| variants.iter().copied())
+        .collect::<Vec<_>>();
+    covered.sort();
+    assert!(
+        covered.into_iter().eq(0..tags.len()),
+        "every variant must be covered by exactly one arm"
+    );
+
+    // One block for each arm, in the order they're written in
+    let branches = arms
+        .iter()
+        .map(|_| fbuilder.create_block())
+        .collect::<Vec<_>>();
+
+    // The block for any tag which doesn't belong to a variant
+    let trap = fbuilder.create_block();
+
+    // Where every arm ends up, with the value of the arm as its parameter
+    let merge = fbuilder.create_block();
+    let result = fbuilder.append_block_param(merge, ty);
+
+    // With the tags `0, 1, 10, 11, 12, 255`, a jump table indexed by the tag would need 256
+    // entries. `Switch` instead splits the tags into the ranges `0..=1`, `10..=12` and `255`, and
+    // only uses jump tables for the ranges with more than one tag. See the `switch-matching`
+    // example for more on how it does this.
+    //
+    // The tags of an or-pattern all get an entry pointing at the block of the same arm.
+    //
+    // Any tag without an entry, such as `2` or `254`, goes to the trap block.
+    {
+        let mut switch = Switch::new();
+        for (&variants, &block) in arms.iter().zip(&branches) {
+            for &variant in variants {
+                switch.set_entry(tags[variant] as u128, block);
+            }
         }
-    });
+        switch.emit(fbuilder, tag, trap);
+    }
+
+    // The switch is the only predecessor of our branch blocks, so they can be sealed right away
+    for &block in &branches {
+        fbuilder.seal_block(block);
+    }
+    fbuilder.seal_block(trap);
+
+    for (&variants, &block) in arms.iter().zip(&branches) {
+        fbuilder.switch_to_block(block);
+
+        let v = arm(fbuilder, variants);
+        fbuilder.ins().jump(merge, &[v.into()]);
+    }
+
+    // Trap the default block
+    //
+    // _ => unreachable!(),
+    {
+        fbuilder.switch_to_block(trap);
+        trap_unreachable(fbuilder);
+    }
+
+    // Every arm has jumped to the merge block by now
+    fbuilder.seal_block(merge);
+    fbuilder.switch_to_block(merge);
+
+    result
+}
+
+// One arm of a `match`, such as `Packet::Data(x, _, _) if x > 0 => x`
+//
+// The payload isn't bound to anything by the arm itself. Instead the guard and the body read
+// whichever parts of it they use, the same way as the arms given to `match_expr`.
+struct MatchArm<'a> {
+    // The variant the arm matches, or `None` for `_`
+    variant: Option<usize>,
+    // Checked once the variant has matched, the arm is only taken if it produces a non-zero value
+    guard: Option<Box<ArmFn<'a>>>,
+    // Produces the value of the arm
+    body: Box<ArmFn<'a>>,
 }
 
-fn switch_to_branch_block(fbuilder: &mut FunctionBuilder<'_>, call: BlockCall) {
-    let block = call.block(&fbuilder.func.dfg.value_lists);
-    fbuilder.seal_block(block);
-    fbuilder.switch_to_block(block);
+type ArmFn<'a> = dyn FnMut(&mut FunctionBuilder<'_>) -> cl::Value + 'a;
+
+// Lower a `match` whose arms are tried one after another in the order they're written in
+//
+// A guard can only be checked once the variant has matched, and if it fails, the next arm has to
+// be tried even if it's for the same variant. So unlike `match_expr`, this can't jump straight to
+// the arm with a `Switch`. Instead each arm checks its variant and guard, and falls through to the
+// next arm if either of them fails:
+//
+//   arm0:  brif tag == 10, guard0, arm1
+//   guard0:  brif x > 0, body0, arm1
+//   body0:  jump merge(x)
+//   arm1:  brif tag == 11, body1, arm2
+//   body1:  jump merge(code)
+//   arm2:  jump merge(0)
+//
+// Each arm jumps to the merge block with its value the same way as in `match_expr`, and the
+// builder is left in the merge block. If the last arm falls through, no arm matched, and we trap
+// with `trap_unreachable`.
+fn match_arms(
+    fbuilder: &mut FunctionBuilder<'_>,
+    tag: cl::Value,
+    tags: &[i64],
+    ty: cl::Type,
+    arms: Vec<MatchArm<'_>>,
+) -> cl::Value {
+    let merge = fbuilder.create_block();
+    let result = fbuilder.append_block_param(merge, ty);
+
+    // The first arm is checked in the block we're already in
+    for mut arm in arms {
+        // Where we continue if this arm doesn't match
+        let next = fbuilder.create_block();
+
+        // if tag == tags[variant] { .. } else { next }
+        //
+        // `_` matches any variant, so there's nothing to check
+        if let Some(variant) = arm.variant {
+            let matched = fbuilder.create_block();
+
+            let is_variant = fbuilder.ins().icmp_imm(IntCC::Equal, tag, tags[variant]);
+            fbuilder.ins().brif(is_variant, matched, &[], next, &[]);
+
+            fbuilder.seal_block(matched);
+            fbuilder.switch_to_block(matched);
+        }
+
+        // if guard { .. } else { next }
+        if let Some(guard) = &mut arm.guard {
+            let body = fbuilder.create_block();
+
+            let cond = guard(fbuilder);
+            fbuilder.ins().brif(cond, body, &[], next, &[]);
+
+            fbuilder.seal_block(body);
+            fbuilder.switch_to_block(body);
+        }
+
+        let v = (arm.body)(fbuilder);
+        fbuilder.ins().jump(merge, &[v.into()]);
+
+        // Only the variant check and the guard of this arm can fall through to the next one
+        fbuilder.seal_block(next);
+        fbuilder.switch_to_block(next);
+    }
+
+    // _ => unreachable!(),
+    //
+    // Never reached if the last arm is `_` without a guard, in which case nothing jumps here
+    trap_unreachable(fbuilder);
+
+    fbuilder.seal_block(merge);
+    fbuilder.switch_to_block(merge);
+
+    result
 }
 
 // Convert the payload to the requested type.
@@ -198,6 +757,16 @@ fn read_payload<const N: usize>(
         // Use the payload as-is
         PayloadKind::Inline => param_types.map(|_| payload),
 
+        // Shift each parameter down to the bottom of the payload and then reduce it to its own size
+        PayloadKind::InlineConcat(_) => {
+            let mut shift = 0;
+            param_types.map(|ty| {
+                let v = fbuilder.ins().ushr_imm(payload, shift);
+                shift += ty.bits() as i64;
+                fbuilder.ins().ireduce(ty, v)
+            })
+        }
+
         // Use zero as the payload so that this payload-less variant still has the same size
         PayloadKind::Zero => param_types.map(|_| fbuilder.ins().iconst(size_t, 0)),
 
@@ -215,9 +784,10 @@ fn read_payload<const N: usize>(
     }
 }
 
-fn construct_tagged_union(
-    module: &ObjectModule,
+pub(crate) fn construct_tagged_union(
+    module: &impl Module,
     fbuilder: &mut FunctionBuilder<'_>,
+    tag_type: cl::Type,
     tag: i64,
     params: &[cl::Value],
 ) -> (cl::Value, cl::Value) {
@@ -231,18 +801,120 @@ fn construct_tagged_union(
     let payload = match payload_kind(size_t, &param_types) {
         PayloadKind::InlineCasted(_) => fbuilder.ins().sextend(size_t, params[0]),
         PayloadKind::Inline => params[0],
+        PayloadKind::InlineConcat(types) => {
+            // Place each parameter after the bits of the previous one
+            //
+            // `Packet::Short(1, 2)` becomes `0x0000_0000_0000_0201` on 64-bit targets
+            let mut payload = fbuilder.ins().iconst(size_t, 0);
+            let mut shift = 0;
+            for (&param, ty) in params.iter().zip(types) {
+                // Zero-extend so that the sign bits don't overwrite the parameters after it
+                let param = fbuilder.ins().uextend(size_t, param);
+                let param = fbuilder.ins().ishl_imm(param, shift);
+                payload = fbuilder.ins().bor(payload, param);
+                shift += ty.bits() as i64;
+            }
+            payload
+        }
         PayloadKind::Zero => fbuilder.ins().iconst(size_t, 0),
         PayloadKind::StackPointer => stack_alloc_payload(module, fbuilder, params),
     };
 
-    let tag = fbuilder.ins().iconst(TAG_TYPE, tag);
+    let tag = fbuilder.ins().iconst(tag_type, tag);
 
     (tag, payload)
 }
 
+// The parameters of an enum variant, used to decide whether the enum has a niche
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Param {
+    Scalar(cl::Type),
+    // A pointer which can never be zero, such as a reference
+    NonNull,
+}
+
+// An enum where the tag is elided and instead encoded in an otherwise invalid value of the payload.
+pub(crate) struct NicheLayout {
+    // The tag of the variant which holds the payload
+    payload_variant: i64,
+}
+
+// Check whether an enum can use the niche optimization.
+//
+// This is the case for enums with exactly two variants where one variant has no parameters and the
+// other has a single non-null pointer, such as `Option<&T>`. The null pointer then represents the
+// variant without parameters.
+//
+// Rust applies this more generally, such as using the invalid values `2..=255` of a `bool` payload.
+pub(crate) fn niche_layout(variants: &[&[Param]]) -> Option<NicheLayout> {
+    match variants {
+        [[], [Param::NonNull]] => Some(NicheLayout { payload_variant: 1 }),
+        [[Param::NonNull], []] => Some(NicheLayout { payload_variant: 0 }),
+        _ => None,
+    }
+}
+
+// Construct an enum which uses the niche layout.
+//
+// Unlike `construct_tagged_union`, this returns a single value since there's no tag.
+fn construct_niche_union(
+    size_t: cl::Type,
+    fbuilder: &mut FunctionBuilder<'_>,
+    layout: &NicheLayout,
+    tag: i64,
+    params: &[cl::Value],
+) -> cl::Value {
+    if tag == layout.payload_variant {
+        // The pointer is used as-is
+        params[0]
+    } else {
+        // The otherwise invalid null pointer represents the variant without parameters
+        fbuilder.ins().iconst(size_t, 0)
+    }
+}
+
+// The smallest integer type which can represent the tag of every variant
+//
+// Keep in mind that `tag_type(&[0, 1]).bytes() + size_t` still gets padded to the alignment of
+// `size_t` if the enum is stored in memory. The savings only show up if something else can fit in
+// the padding.
+pub(crate) fn tag_type(tags: &[i64]) -> cl::Type {
+    // Our discriminants can't be negative, so only the largest one matters
+    let largest_tag = tags.iter().copied().max().unwrap_or(0) as u64;
+
+    if largest_tag <= u8::MAX as u64 {
+        cl::types::I8
+    } else if largest_tag <= u16::MAX as u64 {
+        cl::types::I16
+    } else if largest_tag <= u32::MAX as u64 {
+        cl::types::I32
+    } else {
+        cl::types::I64
+    }
+}
+
+// The tag of each variant, given the explicit discriminants of the variants which have one
+//
+// Same as in Rust and C, a variant without an explicit discriminant gets the tag of the variant
+// before it plus one, and the first variant starts at zero. Two variants ending up with the same
+// tag is an error, since `match` couldn't tell them apart.
+pub(crate) fn discriminants<const N: usize>(explicit: &[Option<i64>; N]) -> [i64; N] {
+    let mut tags: Vec<i64> = Vec::with_capacity(N);
+
+    for &discriminant in explicit {
+        let tag = discriminant.unwrap_or_else(|| tags.last().map_or(0, |prev| prev + 1));
+        assert!(tag >= 0, "negative discriminants aren't supported");
+        assert!(!tags.contains(&tag), "discriminant {tag} is used twice");
+        tags.push(tag);
+    }
+
+    tags.try_into().unwrap()
+}
+
 enum PayloadKind {
     InlineCasted(cl::Type),
     Inline,
+    InlineConcat(Vec<cl::Type>),
     Zero,
     StackPointer,
 }
@@ -265,17 +937,126 @@ fn payload_kind(size_t: cl::Type, params: &[cl::Type]) -> PayloadKind {
         // zeroed payload.
         [] => PayloadKind::Zero,
 
-        // Stack allocate larger payloads to store them behind a pointer.
+        // Multiple integers which together fit within the bytes of size_t can be packed into the
+        // payload instead of being stack allocated.
         //
-        // One possible optimization is to still inline the payload if it's multiple scalars that
-        // fit within size_t by using `iconcat` and `isplit`.
+        // Cranelift has `iconcat` and `isplit` for this, but they only work on two halves of the
+        // same type. Shifting each parameter into place lets us pack any mix of integer types.
+        _ if params.iter().all(|p| p.is_int())
+            && params.iter().map(|p| p.bytes()).sum::<u32>() <= size_t.bytes() =>
+        {
+            PayloadKind::InlineConcat(params.to_vec())
+        }
+
+        // Stack allocate larger payloads to store them behind a pointer.
         _ => PayloadKind::StackPointer,
     }
 }
 
+// Construct a variant whose parameter is a struct, such as `Packet::Meta(Point)`.
+//
+// Since a struct is usually larger than a scalar, and its fields may need to be accessed through a
+// pointer anyway, the payload is always a pointer to the struct.
+fn construct_tagged_union_with_struct(
+    module: &impl Module,
+    fbuilder: &mut FunctionBuilder<'_>,
+    tag_type: cl::Type,
+    tag: i64,
+    fields: &[cl::Value],
+) -> (cl::Value, cl::Value) {
+    let size_t = module.isa().pointer_type();
+
+    let field_types = fields
+        .iter()
+        .map(|&v| type_of_value(fbuilder, v))
+        .collect::<Vec<_>>();
+
+    let payload = {
+        // Unlike `stack_alloc_payload`, we lay out the fields with padding so that the struct has
+        // the same layout it would have anywhere else.
+        let slot = fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+            cl::StackSlotKind::ExplicitSlot,
+            size_of_struct(&field_types),
+            alignment_of_struct(&field_types).trailing_zeros() as u8,
+        ));
+
+        for (i, &v) in fields.iter().enumerate() {
+            let offset = offset_of_field(i, &field_types);
+            fbuilder.ins().stack_store(v, slot, offset);
+        }
+
+        fbuilder.ins().stack_addr(size_t, slot, 0)
+    };
+
+    let tag = fbuilder.ins().iconst(tag_type, tag);
+
+    (tag, payload)
+}
+
+// Read the fields of a struct payload written by `construct_tagged_union_with_struct`.
+//
+// The payload will be treated as a pointer to the struct.
+fn read_struct_payload<const N: usize>(
+    fbuilder: &mut FunctionBuilder<'_>,
+    payload: cl::Value,
+    field_types: [cl::Type; N],
+) -> [cl::Value; N] {
+    let mut i = 0;
+    field_types.map(|ty| {
+        let offset = offset_of_field(i, &field_types);
+        i += 1;
+        fbuilder
+            .ins()
+            .load(ty, cl::MemFlags::new(), payload, offset)
+    })
+}
+
+// The same layout rules as in the `struct-layouts` example.
+//
+// Fields are aligned to their own size, and the struct is aligned to its largest field.
+fn size_of_struct(fields: &[cl::Type]) -> u32 {
+    let mut size = 0;
+
+    for &field in fields {
+        // Add padding to ensure the field is aligned
+        let align = field.bytes();
+        size += (align - size % align) % align;
+
+        size += field.bytes();
+    }
+
+    // Add padding to the end of the struct to make the struct itself aligned
+    let self_align = alignment_of_struct(fields);
+    size += (self_align - size % self_align) % self_align;
+
+    size
+}
+
+fn alignment_of_struct(fields: &[cl::Type]) -> u32 {
+    fields.iter().map(|field| field.bytes()).max().unwrap_or(1)
+}
+
+fn offset_of_field(field: usize, fields: &[cl::Type]) -> i32 {
+    let mut offset = 0;
+
+    for (i, &ty) in fields.iter().enumerate() {
+        // Add padding to ensure the field is aligned
+        let align = ty.bytes();
+        offset += (align - offset % align) % align;
+
+        if i == field {
+            return offset as i32;
+        }
+
+        offset += ty.bytes();
+    }
+
+    panic!("field not found");
+}
+
 // Larger enum variants will store their data behind a pointer.
 fn stack_alloc_payload(
-    module: &ObjectModule,
+    module: &impl Module,
     fbuilder: &mut FunctionBuilder<'_>,
     params: &[cl::Value],
 ) -> cl::Value {
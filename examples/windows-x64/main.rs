@@ -0,0 +1,272 @@
+//! This example shows how to target Windows on x86-64, and how its C calling convention passes
+//! structs differently from Linux.
+//!
+//! ```
+//! struct Small { a: i32, b: i32 }          // 8 bytes
+//! struct Large { a: i64, b: i64, c: i64 }  // 24 bytes
+//!
+//! extern "C" fn sum_small(s: Small) -> i32 {
+//!   return s.a + s.b;
+//! }
+//!
+//! extern "C" fn sum_large(l: Large) -> i64 {
+//!   return l.a + l.b + l.c;
+//! }
+//!
+//! fn main() -> i32 {
+//!   let s = Small { a: 1, b: 2 };
+//!   let l = Large { a: 3, b: 4, c: 5 };
+//!   return sum_small(s) + sum_large(l) as i32;
+//! }
+//! ```
+//!
+//! The examples default to `x86_64-unknown-linux`. Passing `-t x86_64-pc-windows-msvc` makes
+//! Cranelift emit a COFF object (`.obj`) instead of ELF, and `isa.default_call_conv()` becomes
+//! `CallConv::WindowsFastcall` instead of `CallConv::SystemV`. So as long as the signatures of
+//! functions called by or exported to C use the default call conv, the registers are taken care
+//! of for us. That's what `declare_main` does.
+//!
+//! The entrypoint is still `main`. Same as with libc on Linux, the C runtime on Windows (`libcmt`
+//! or `msvcrt`) defines the real entrypoint `mainCRTStartup`, which calls our `main` and exits
+//! the process with the returned exit code.
+//!
+//! What the calling convention doesn't take care of is structs, since Cranelift has no struct
+//! types. It's up to us to pass them the way C would.
+//!
+//! * Windows passes structs of 1, 2, 4 or 8 bytes as if they were an integer of that size.
+//!   Any other struct is copied by the caller into memory it owns, and a pointer to the copy is
+//!   passed instead.
+//! * SystemV also passes structs of up to 8 bytes of integers in a single register, but larger
+//!   structs are more involved. Structs larger than 16 bytes are copied onto the stack as part
+//!   of the arguments, which Cranelift does for us with `ArgumentPurpose::StructArgument`.
+//!
+//! `StructArgument` always copies onto the stack regardless of the calling convention, so it
+//! can't be used for `WindowsFastcall`. See `abi_param_for_struct` for how we pick between them.
+//!
+//! Both of these are the rules for x86-64, so the example exits with an error for any other
+//! architecture. See `lowering-structs` for how structs are passed on AArch64.
+//!
+//! The main function returns `(1 + 2) + (3 + 4 + 5)`, so the exit code will be `15`.
+//!
+//! To link against the C runtime and produce a binary on Windows, you can use `link.exe` from a
+//! Visual Studio developer prompt, or `lld-link` which accepts the same flags on any platform.
+//!
+//! `$ cargo run --example windows-x64 -- -t x86_64-pc-windows-msvc -o windows-x64.obj`
+//! `$ lld-link windows-x64.obj /out:windows-x64.exe /subsystem:console /defaultlib:libcmt`
+//! `$ .\windows-x64.exe; echo $LastExitCode`
+//!
+//! Without `-t` the same example targets Linux, where you can use `gcc` or `clang`
+//!
+//! `$ cargo run --example windows-x64 -- -o windows-x64.o`
+//! `$ clang windows-x64.o -o windows-x64`
+//! `$ ./windows-x64; echo $?`
+//!
+//! Or run it in-process without going through an object file
+//!
+//! `$ cargo run --example windows-x64 -- --jit`
+
+use cranelift::codegen::ir::{ArgumentPurpose, StackSlot};
+use cranelift::codegen::isa::CallConv;
+use cranelift::prelude as cl;
+use cranelift::prelude::{FunctionBuilderContext, InstBuilder, MemFlags, codegen::Context};
+use cranelift_examples::{Codegen, declare_main, for_any_module, skip_boilerplate};
+use cranelift_module::{Linkage, Module};
+use target_lexicon::Architecture;
+
+fn main() {
+    skip_boilerplate(b"windows-x64", for_any_module!(define_functions));
+}
+
+// struct Small { a: i32, b: i32 }
+const SMALL_SIZE: u32 = 8;
+
+// struct Large { a: i64, b: i64, c: i64 }
+const LARGE_SIZE: u32 = 24;
+
+// How a struct of `size` bytes is passed to a C function with this calling convention
+//
+// The parameter is a pointer to the struct for both `StructArgument` and Windows' hidden pointer,
+// the difference is who makes the copy. With `StructArgument` Cranelift copies the struct onto
+// the stack, while for Windows we have to make the copy ourselves before the call.
+fn abi_param_for_struct(call_conv: CallConv, size: u32, size_t: cl::Type) -> cl::AbiParam {
+    match size {
+        // Passed in a single integer register in both calling conventions
+        1 | 2 | 4 | 8 => cl::AbiParam::new(cl::Type::int_with_byte_size(size as u16).unwrap()),
+
+        _ if call_conv == CallConv::WindowsFastcall => cl::AbiParam::new(size_t),
+
+        // Only correct for structs larger than 16 bytes. Smaller structs are split into
+        // registers, which isn't needed for this example.
+        _ => {
+            assert!(size > 16);
+            cl::AbiParam::special(size_t, ArgumentPurpose::StructArgument(size))
+        }
+    }
+}
+
+// Allocate a stack slot for a struct, aligned to 8 bytes since both structs have that alignment
+fn struct_slot(fbuilder: &mut cl::FunctionBuilder<'_>, size: u32) -> StackSlot {
+    fbuilder.create_sized_stack_slot(cl::StackSlotData::new(
+        cl::StackSlotKind::ExplicitSlot,
+        size,
+        3,
+    ))
+}
+
+// The functions are defined generically over the `Module` so that they can be both emitted into an
+// object file and JIT compiled.
+fn define_functions<M: Module>(
+    ctx: &mut Context,
+    fctx: &mut FunctionBuilderContext,
+    module: &mut M,
+    _args: clap::ArgMatches,
+) {
+    // Checked before anything is declared, since Cranelift would otherwise panic on the
+    // `StructArgument` parameters for targets which don't support them
+    let triple = module.isa().triple();
+    if triple.architecture != Architecture::X86_64 {
+        eprintln!("windows-x64 only supports x86_64 targets, but {triple} was given");
+        std::process::exit(1);
+    }
+
+    let int = cl::types::I32;
+    let i64 = cl::types::I64;
+    let size_t = module.isa().pointer_type();
+
+    // The calling convention C uses on the target, either `SystemV` or `WindowsFastcall`
+    let call_conv = module.isa().default_call_conv();
+    println!(" passing structs with the {call_conv} calling convention ");
+
+    let mut codegen = Codegen::new(ctx, fctx, module);
+
+    let main_func_id = declare_main(codegen.module);
+
+    // extern "C" fn sum_small(s: Small) -> i32;
+    let sum_small_func_id = {
+        let mut sig = cl::Signature::new(call_conv);
+        sig.params
+            .push(abi_param_for_struct(call_conv, SMALL_SIZE, size_t));
+        sig.returns.push(cl::AbiParam::new(int));
+
+        codegen
+            .module
+            .declare_function("sum_small", Linkage::Export, &sig)
+            .unwrap()
+    };
+
+    // extern "C" fn sum_large(l: Large) -> i64;
+    let sum_large_func_id = {
+        let mut sig = cl::Signature::new(call_conv);
+        sig.params
+            .push(abi_param_for_struct(call_conv, LARGE_SIZE, size_t));
+        sig.returns.push(cl::AbiParam::new(i64));
+
+        codegen
+            .module
+            .declare_function("sum_large", Linkage::Export, &sig)
+            .unwrap()
+    };
+
+    // fn main() -> i32 {
+    //   let s = Small { a: 1, b: 2 };
+    //   let l = Large { a: 3, b: 4, c: 5 };
+    //   return sum_small(s) + sum_large(l) as i32;
+    // }
+    codegen
+        .define(main_func_id, |module, fbuilder, _| {
+            // let s = Small { a: 1, b: 2 };
+            let s = {
+                let slot = struct_slot(fbuilder, SMALL_SIZE);
+                let a = fbuilder.ins().iconst(int, 1);
+                let b = fbuilder.ins().iconst(int, 2);
+                fbuilder.ins().stack_store(a, slot, 0);
+                fbuilder.ins().stack_store(b, slot, 4);
+                slot
+            };
+
+            // let l = Large { a: 3, b: 4, c: 5 };
+            let l = {
+                let slot = struct_slot(fbuilder, LARGE_SIZE);
+                for (i, n) in [3, 4, 5].into_iter().enumerate() {
+                    let n = fbuilder.ins().iconst(i64, n);
+                    fbuilder.ins().stack_store(n, slot, i as i32 * 8);
+                }
+                slot
+            };
+
+            // sum_small(s)
+            let small_sum = {
+                // Both `a` and `b` are passed together as a single 8-byte integer
+                let s = fbuilder.ins().stack_load(i64, s, 0);
+
+                let fref = module.declare_func_in_func(sum_small_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[s]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            // sum_large(l)
+            let large_sum = {
+                let l = if call_conv == CallConv::WindowsFastcall {
+                    // The callee is allowed to modify the struct behind the pointer, so we pass a
+                    // pointer to a copy rather than to `l` itself.
+                    let copy = struct_slot(fbuilder, LARGE_SIZE);
+                    for offset in (0..LARGE_SIZE as i32).step_by(8) {
+                        let field = fbuilder.ins().stack_load(i64, l, offset);
+                        fbuilder.ins().stack_store(field, copy, offset);
+                    }
+                    fbuilder.ins().stack_addr(size_t, copy, 0)
+                } else {
+                    // `StructArgument` copies the struct behind this pointer for us
+                    fbuilder.ins().stack_addr(size_t, l, 0)
+                };
+
+                let fref = module.declare_func_in_func(sum_large_func_id, fbuilder.func);
+                let call = fbuilder.ins().call(fref, &[l]);
+                fbuilder.inst_results(call)[0]
+            };
+
+            let large_sum = fbuilder.ins().ireduce(int, large_sum);
+            let sum = fbuilder.ins().iadd(small_sum, large_sum);
+
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+
+    // extern "C" fn sum_small(s: Small) -> i32 {
+    //   return s.a + s.b;
+    // }
+    codegen
+        .define(sum_small_func_id, |_, fbuilder, entry| {
+            let s = fbuilder.block_params(entry)[0];
+
+            // Put the integer back into memory so that the fields can be read at their offsets
+            let slot = struct_slot(fbuilder, SMALL_SIZE);
+            fbuilder.ins().stack_store(s, slot, 0);
+
+            let a = fbuilder.ins().stack_load(int, slot, 0);
+            let b = fbuilder.ins().stack_load(int, slot, 4);
+            let sum = fbuilder.ins().iadd(a, b);
+
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+
+    // extern "C" fn sum_large(l: Large) -> i64 {
+    //   return l.a + l.b + l.c;
+    // }
+    codegen
+        .define(sum_large_func_id, |_, fbuilder, entry| {
+            // Whether it's Windows' hidden pointer or `StructArgument`, the parameter is a pointer
+            // to a copy of the struct which this function is free to use.
+            let l = fbuilder.block_params(entry)[0];
+
+            let [a, b, c] =
+                [0, 8, 16].map(|offset| fbuilder.ins().load(i64, MemFlags::trusted(), l, offset));
+
+            let sum = fbuilder.ins().iadd(a, b);
+            let sum = fbuilder.ins().iadd(sum, c);
+
+            fbuilder.ins().return_(&[sum]);
+        })
+        .unwrap();
+}
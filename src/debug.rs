@@ -0,0 +1,281 @@
+//! Minimal DWARF debug info for the functions in an object file, enabled with `--debug`.
+//!
+//! Only a compilation unit with a `DW_TAG_subprogram` for each defined function is emitted. There's
+//! no line table or variable info, but it's enough for debuggers to name the frames in a backtrace.
+
+use cranelift::codegen::ir::Endianness;
+use cranelift::codegen::isa::TargetIsa;
+use cranelift_object::ObjectProduct;
+use cranelift_object::object::write::{Object, Relocation, SymbolId, SymbolSection};
+use cranelift_object::object::{
+    BinaryFormat, RelocationEncoding, RelocationFlags, RelocationKind, SectionKind,
+};
+use gimli::write::{
+    Address, AttributeValue, DwarfUnit, EndianVec, Range, RangeList, Sections, Writer,
+};
+use gimli::{Encoding, Format, RunTimeEndian};
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+/// Attach debug info describing every defined function to the object.
+///
+/// Only ELF objects are supported, for other formats the object is left as-is.
+pub fn emit_debug_info(
+    product: &mut ObjectProduct,
+    isa: &dyn TargetIsa,
+    unit_name: &[u8],
+) -> gimli::write::Result<()> {
+    if product.object.format() != BinaryFormat::Elf {
+        println!(" `--debug` is only supported for ELF objects, skipping debug info ");
+        return Ok(());
+    }
+
+    let encoding = Encoding {
+        format: Format::Dwarf32,
+        version: 4,
+        address_size: isa.pointer_bytes(),
+    };
+
+    let mut dwarf = DwarfUnit::new(encoding);
+
+    // The addresses of our functions aren't known until the object is linked. Instead, gimli
+    // refers to them by an index into `symbols`, which we turn into relocations when writing.
+    let mut symbols: Vec<SymbolId> = vec![];
+    let mut ranges = vec![];
+
+    let functions = product
+        .functions
+        .values()
+        .filter_map(|f| match f {
+            Some((symbol, true)) => Some(*symbol),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    for symbol_id in functions {
+        let symbol = product.object.symbol(symbol_id);
+
+        // Only symbols which ended up in a section have an address
+        if !matches!(symbol.section, SymbolSection::Section(_)) {
+            continue;
+        }
+
+        let name = symbol.name.clone();
+        let size = symbol.size;
+
+        let low_pc = Address::Symbol {
+            symbol: symbols.len(),
+            addend: 0,
+        };
+        symbols.push(symbol_id);
+
+        let root = dwarf.unit.root();
+        let id = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        let entry = dwarf.unit.get_mut(id);
+
+        let name = dwarf.strings.add(name);
+        entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+        entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(low_pc));
+
+        // Since DWARF 4, the high PC may be given as an offset from the low PC
+        entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(size));
+
+        ranges.push(Range::StartLength {
+            begin: low_pc,
+            length: size,
+        });
+    }
+
+    // The compilation unit covers all of the functions, which is how a debugger finds which unit
+    // an address belongs to.
+    {
+        let ranges = dwarf.unit.ranges.add(RangeList(ranges));
+
+        let root = dwarf.unit.root();
+        let entry = dwarf.unit.get_mut(root);
+
+        entry.set(
+            gimli::DW_AT_name,
+            AttributeValue::String(unit_name.to_vec()),
+        );
+        entry.set(
+            gimli::DW_AT_producer,
+            AttributeValue::String(b"cranelift-examples".to_vec()),
+        );
+        entry.set(
+            gimli::DW_AT_low_pc,
+            AttributeValue::Address(Address::Constant(0)),
+        );
+        entry.set(gimli::DW_AT_ranges, AttributeValue::RangeListRef(ranges));
+    }
+
+    let endian = match isa.endianness() {
+        Endianness::Little => RunTimeEndian::Little,
+        Endianness::Big => RunTimeEndian::Big,
+    };
+
+    let mut sections = Sections::new(RelocWriter::new(endian));
+    dwarf.write(&mut sections)?;
+
+    write_sections(&mut product.object, &sections, &symbols);
+
+    Ok(())
+}
+
+// Add the DWARF sections to the object, along with the relocations recorded while writing them
+fn write_sections(
+    object: &mut Object<'static>,
+    sections: &Sections<RelocWriter>,
+    symbols: &[SymbolId],
+) {
+    let mut writers = vec![];
+    sections
+        .for_each(|id, writer| {
+            writers.push((id, writer));
+            Ok::<_, Infallible>(())
+        })
+        .unwrap();
+
+    // All sections need to exist before we can relocate against them
+    let mut section_ids = HashMap::new();
+    for (id, writer) in &writers {
+        if writer.data.slice().is_empty() {
+            continue;
+        }
+
+        let section = object.add_section(vec![], id.name().as_bytes().to_vec(), SectionKind::Debug);
+        object.set_section_data(section, writer.data.slice().to_vec(), 1);
+        section_ids.insert(*id, section);
+    }
+
+    for (id, writer) in &writers {
+        let Some(&section) = section_ids.get(id) else {
+            continue;
+        };
+
+        for reloc in &writer.relocs {
+            let symbol = match reloc.target {
+                RelocTarget::Symbol(index) => symbols[index],
+                RelocTarget::Section(target) => object.section_symbol(section_ids[&target]),
+            };
+
+            let relocation = Relocation {
+                offset: reloc.offset as u64,
+                symbol,
+                addend: reloc.addend,
+                flags: RelocationFlags::Generic {
+                    kind: RelocationKind::Absolute,
+                    encoding: RelocationEncoding::Generic,
+                    size: reloc.size * 8,
+                },
+            };
+
+            object
+                .add_relocation(section, relocation)
+                .expect("debug info relocations are supported by ELF");
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum RelocTarget {
+    // An index into the symbols given to gimli as `Address::Symbol`
+    Symbol(usize),
+    // The start of another DWARF section, such as `.debug_str` for strings
+    Section(gimli::SectionId),
+}
+
+#[derive(Clone)]
+struct Reloc {
+    offset: usize,
+    size: u8,
+    target: RelocTarget,
+    addend: i64,
+}
+
+// A `gimli::write::Writer` which records relocations instead of failing on addresses it doesn't know
+//
+// Once linked, the DWARF sections of several objects are concatenated. So other than addresses,
+// offsets into other DWARF sections also need to be relocated.
+#[derive(Clone)]
+struct RelocWriter {
+    data: EndianVec<RunTimeEndian>,
+    relocs: Vec<Reloc>,
+}
+
+impl RelocWriter {
+    fn new(endian: RunTimeEndian) -> Self {
+        Self {
+            data: EndianVec::new(endian),
+            relocs: vec![],
+        }
+    }
+}
+
+impl Writer for RelocWriter {
+    type Endian = RunTimeEndian;
+
+    fn endian(&self) -> Self::Endian {
+        self.data.endian()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write(bytes)
+    }
+
+    fn write_at(&mut self, offset: usize, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write_at(offset, bytes)
+    }
+
+    fn write_address(&mut self, address: Address, size: u8) -> gimli::write::Result<()> {
+        match address {
+            Address::Constant(val) => self.write_udata(val, size),
+            Address::Symbol { symbol, addend } => {
+                self.relocs.push(Reloc {
+                    offset: self.len(),
+                    size,
+                    target: RelocTarget::Symbol(symbol),
+                    addend,
+                });
+
+                // The value is filled in by the linker
+                self.write_udata(0, size)
+            }
+        }
+    }
+
+    fn write_offset(
+        &mut self,
+        val: usize,
+        section: gimli::SectionId,
+        size: u8,
+    ) -> gimli::write::Result<()> {
+        self.relocs.push(Reloc {
+            offset: self.len(),
+            size,
+            target: RelocTarget::Section(section),
+            addend: val as i64,
+        });
+        self.write_udata(0, size)
+    }
+
+    fn write_offset_at(
+        &mut self,
+        offset: usize,
+        val: usize,
+        section: gimli::SectionId,
+        size: u8,
+    ) -> gimli::write::Result<()> {
+        self.relocs.push(Reloc {
+            offset,
+            size,
+            target: RelocTarget::Section(section),
+            addend: val as i64,
+        });
+        self.write_udata_at(offset, 0, size)
+    }
+}
@@ -1,21 +1,231 @@
 use clap::{arg, command};
 use cranelift::{
-    codegen::ir::Function,
-    prelude::{self as cl, Configurable, FunctionBuilder},
+    codegen::{
+        control::ControlPlane,
+        data_value::DataValue,
+        ir::{Function, UserFuncName},
+        isa::{CallConv, OwnedTargetIsa, TargetIsa},
+        print_errors::pretty_verifier_error,
+        settings::OptLevel,
+    },
+    prelude::{self as cl, Configurable, FunctionBuilder, InstBuilder},
+};
+use cranelift_interpreter::{
+    environment::FunctionStore,
+    interpreter::{Interpreter, InterpreterState},
+    step::{ControlFlow, CraneliftTrap},
+};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{
+    DataDescription, DataId, FuncId, FuncOrDataId, Linkage, Module, ModuleError, ModuleResult,
+};
+use cranelift_object::{
+    ObjectBuilder, ObjectModule, ObjectProduct,
+    object::write::{Symbol, SymbolScope},
+};
+use target_lexicon::Triple;
+mod debug;
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
 };
-use cranelift_module::{FuncId, Linkage, Module};
-use cranelift_object::{ObjectBuilder, ObjectModule};
-use std::{fs::File, io::Write};
+
+/// The target used when no `--target-triple` is given
+pub const DEFAULT_TARGET_TRIPLE: &str = "x86_64-unknown-linux";
 
 pub fn parse_arguments() -> clap::ArgMatches {
     command!()
         .arg(arg!(-t --"target-triple" <TRIPLE> "Target triple arch-vendor-platform"))
         .arg(arg!(-o --"output" <FILE> "Path for output object file"))
+        .arg(arg!(--"jit" "Run the example in-process instead of emitting an object file"))
+        .arg(arg!(--"verify" "Run the cranelift verifier on each function before it's defined"))
+        .arg(arg!(--"debug" "Emit DWARF debug info naming each function in the object file"))
+        .arg(arg!(-S --"emit-asm" "Print the machine code of each function after it's defined"))
+        .arg(arg!(--"debug-prints" "Print values at runtime with printf, in examples which support it"))
+        .arg(
+            arg!(--"emit-clif" <FILE> "Write the IR of every function to a file instead of stdout"),
+        )
+        .arg(
+            arg!(-O --"opt-level" <LEVEL> "Optimization level used by cranelift")
+                // Restricting the values here lets clap report invalid levels, instead of
+                // cranelift panicking when the setting is applied.
+                .value_parser(["none", "speed", "speed_and_size"])
+                .default_value("none"),
+        )
         .get_matches()
 }
 
+/// Everything that can go wrong while setting up or finalizing cranelift in [`try_skip_boilerplate`]
+#[derive(Debug)]
+pub enum BoilerplateError {
+    /// The target triple was not recognized, or support for its architecture wasn't compiled in
+    UnknownTriple(cl::isa::LookupError),
+    /// The ISA rejected the flags it was finalized with
+    IsaFinish(cl::codegen::CodegenError),
+    /// Declaring or defining something in the module failed
+    Module(Box<ModuleError>),
+    /// Writing the finished object file into memory failed
+    Emit(cranelift_object::object::write::Error),
+    /// Generating the DWARF debug info for `--debug` failed
+    DebugInfo(gimli::write::Error),
+    /// `--jit` was passed to an example which can only emit object files
+    JitUnsupported,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for BoilerplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BoilerplateError::UnknownTriple(err) => write!(f, "unknown target triple: {err}"),
+            BoilerplateError::IsaFinish(err) => write!(f, "could not finalize the ISA: {err}"),
+            BoilerplateError::Module(err) => write!(f, "module error: {err}"),
+            BoilerplateError::Emit(err) => write!(f, "could not emit object file: {err}"),
+            BoilerplateError::DebugInfo(err) => write!(f, "could not generate debug info: {err}"),
+            BoilerplateError::JitUnsupported => {
+                write!(
+                    f,
+                    "`--jit` is not supported, this example can only emit object files"
+                )
+            }
+            BoilerplateError::Io(err) => write!(f, "could not write object file: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for BoilerplateError {}
+
+impl From<ModuleError> for BoilerplateError {
+    fn from(err: ModuleError) -> Self {
+        BoilerplateError::Module(Box::new(err))
+    }
+}
+
+impl From<std::io::Error> for BoilerplateError {
+    fn from(err: std::io::Error) -> Self {
+        BoilerplateError::Io(err)
+    }
+}
+
+/// The functions of an example, defined generically over the `Module` so that they can be both
+/// emitted into an object file and JIT compiled.
+///
+/// Closures can't be generic, so this trait stands in for a callback which is. Examples write a
+/// `fn define_functions<M: Module>(..)` and turn it into a `DefineFunctions` with [`for_any_module`].
+pub trait DefineFunctions {
+    fn define<M: Module>(
+        self,
+        ctx: &mut cl::codegen::Context,
+        fctx: &mut cl::FunctionBuilderContext,
+        module: &mut M,
+        args: clap::ArgMatches,
+    ) -> Result<(), BoilerplateError>;
+}
+
+/// Turn a function which is generic over the `Module`, such as
+/// `fn define_functions<M: Module>(ctx, fctx, module: &mut M, args)`, into a [`DefineFunctions`]
+/// which can be given to [`skip_boilerplate`].
+#[macro_export]
+macro_rules! for_any_module {
+    ($f:path) => {{
+        struct Callback;
+
+        impl $crate::DefineFunctions for Callback {
+            fn define<M: ::cranelift_module::Module>(
+                self,
+                ctx: &mut ::cranelift::codegen::Context,
+                fctx: &mut ::cranelift::prelude::FunctionBuilderContext,
+                module: &mut M,
+                args: ::clap::ArgMatches,
+            ) -> ::std::result::Result<(), $crate::BoilerplateError> {
+                $f(ctx, fctx, module, args);
+                Ok(())
+            }
+        }
+
+        Callback
+    }};
+}
+
 /// Performs initialization and finalization of cranelift similarly to the instructions provided in [output-a-binary](examples/output-a-binary/main.rs)
-pub fn skip_boilerplate(
+///
+/// With `--jit` the functions are instead compiled into memory with a [`JITModule`]. Once they've
+/// been defined, `main` is looked up and invoked in-process. This skips having to link the object
+/// file, but it can only target the host machine.
+///
+/// Panics if any step fails, see [`try_skip_boilerplate`] for a version which returns the error instead.
+pub fn skip_boilerplate(unit_name: &[u8], f: impl DefineFunctions) {
+    try_skip_boilerplate(unit_name, f).unwrap_or_else(boilerplate_failed)
+}
+
+/// Same as [`skip_boilerplate`] but errors are returned instead of causing panics.
+///
+/// The callback may also return an error, which will then be propagated as-is.
+pub fn try_skip_boilerplate(
+    unit_name: &[u8],
+    f: impl DefineFunctions,
+) -> Result<(), BoilerplateError> {
+    try_skip_boilerplate_with(unit_name, |_, _| {}, f)
+}
+
+/// Same as [`skip_boilerplate`] but lets `configure` change the cranelift settings before they're
+/// used to create the ISA. It's given the target triple, since some settings depend on it.
+///
+/// `configure` runs after the defaults (`opt_level` and `is_pic`) have been set, so it may
+/// also override those. It isn't used with `--jit`, where the settings are picked for the host.
+///
+/// Some settings which affect the emitted object file:
+///
+/// * `is_pic` decides whether calls and data accesses go through the GOT/PLT, and thus which
+///   relocations the linker will see. Most linkers produce position independent executables by
+///   default, which requires this to be enabled.
+/// * `tls_model` decides which relocations are used for thread-local data.
+/// * `use_colocated_libcalls` allows calls to libcalls such as `memcpy` to use shorter
+///   relocations, which requires them to be linked close to the code calling them.
+/// * `enable_probestack` makes large stack frames call a probestack function. That function
+///   then needs to exist when linking.
+/// * `preserve_frame_pointers` keeps frame pointers around so that debuggers and profilers can
+///   walk the stack.
+/// * `unwind_info` generates unwind information for each function. Note that `ObjectModule`
+///   will not write it to an `.eh_frame` section on its own.
+pub fn skip_boilerplate_with(
+    unit_name: &[u8],
+    configure: impl FnOnce(&mut cl::settings::Builder, &Triple),
+    f: impl DefineFunctions,
+) {
+    try_skip_boilerplate_with(unit_name, configure, f).unwrap_or_else(boilerplate_failed)
+}
+
+/// Same as [`skip_boilerplate_with`] but errors are returned instead of causing panics.
+pub fn try_skip_boilerplate_with(
+    unit_name: &[u8],
+    configure: impl FnOnce(&mut cl::settings::Builder, &Triple),
+    f: impl DefineFunctions,
+) -> Result<(), BoilerplateError> {
+    let args = parse_arguments();
+
+    if args.get_flag("jit") {
+        return boilerplate_jit(args, f);
+    }
+
+    boilerplate_object(unit_name, args, configure, |ctx, fctx, module, args| {
+        f.define(ctx, fctx, module, args).map(|()| vec![])
+    })
+}
+
+/// Same as [`skip_boilerplate`] but for examples which can only emit an object file, such as
+/// ones that inspect the object file or whose `main` can't be called in-process.
+///
+/// `f` is given the `ObjectModule` itself, and `--jit` is rejected with
+/// [`BoilerplateError::JitUnsupported`].
+pub fn skip_boilerplate_object(
     unit_name: &[u8],
     f: impl FnOnce(
         &mut cl::codegen::Context,
@@ -24,47 +234,261 @@ pub fn skip_boilerplate(
         clap::ArgMatches,
     ),
 ) {
-    let args = parse_arguments();
+    try_skip_boilerplate_object(unit_name, |ctx, fctx, module, args| {
+        f(ctx, fctx, module, args);
+        Ok(())
+    })
+    .unwrap_or_else(boilerplate_failed)
+}
 
-    let isa = {
-        let mut builder = cl::settings::builder();
+/// Same as [`skip_boilerplate_object`] but errors are returned instead of causing panics.
+pub fn try_skip_boilerplate_object(
+    unit_name: &[u8],
+    f: impl FnOnce(
+        &mut cl::codegen::Context,
+        &mut cl::FunctionBuilderContext,
+        &mut ObjectModule,
+        clap::ArgMatches,
+    ) -> Result<(), BoilerplateError>,
+) -> Result<(), BoilerplateError> {
+    boilerplate_object(
+        unit_name,
+        parse_arguments(),
+        |_, _| {},
+        |ctx, fctx, module, args| f(ctx, fctx, module, args).map(|()| vec![]),
+    )
+}
 
-        builder.set("opt_level", "none").unwrap();
-        builder.enable("is_pic").unwrap();
+/// Same as [`skip_boilerplate`] but `f` returns the aliases created with [`declare_alias`], which
+/// are added to the object file once the module has been finished.
+pub fn skip_boilerplate_with_aliases(
+    unit_name: &[u8],
+    f: impl FnOnce(
+        &mut cl::codegen::Context,
+        &mut cl::FunctionBuilderContext,
+        &mut ObjectModule,
+        clap::ArgMatches,
+    ) -> Vec<Alias>,
+) {
+    try_skip_boilerplate_with_aliases(unit_name, |ctx, fctx, module, args| {
+        Ok(f(ctx, fctx, module, args))
+    })
+    .unwrap_or_else(boilerplate_failed)
+}
 
-        let flags = cl::settings::Flags::new(builder);
+/// Same as [`skip_boilerplate_with_aliases`] but errors are returned instead of causing panics.
+pub fn try_skip_boilerplate_with_aliases(
+    unit_name: &[u8],
+    f: impl FnOnce(
+        &mut cl::codegen::Context,
+        &mut cl::FunctionBuilderContext,
+        &mut ObjectModule,
+        clap::ArgMatches,
+    ) -> Result<Vec<Alias>, BoilerplateError>,
+) -> Result<(), BoilerplateError> {
+    boilerplate_object(unit_name, parse_arguments(), |_, _| {}, f)
+}
 
-        let triple = args
-            .get_one::<&str>("target-triple")
-            .unwrap_or(&"x86_64-unknown-linux");
+// Shared by the functions emitting a single object file, where `f` returns the aliases to add
+fn boilerplate_object(
+    unit_name: &[u8],
+    args: clap::ArgMatches,
+    configure: impl FnOnce(&mut cl::settings::Builder, &Triple),
+    f: impl FnOnce(
+        &mut cl::codegen::Context,
+        &mut cl::FunctionBuilderContext,
+        &mut ObjectModule,
+        clap::ArgMatches,
+    ) -> Result<Vec<Alias>, BoilerplateError>,
+) -> Result<(), BoilerplateError> {
+    if args.get_flag("jit") {
+        return Err(BoilerplateError::JitUnsupported);
+    }
 
-        cl::isa::lookup_by_name(triple)
-            .unwrap()
-            .finish(flags)
-            .unwrap()
-    };
+    let clif_path = apply_arguments(&args);
+    let isa = isa_from_arguments(&args, configure)?;
 
     let mut module = {
         let libcall_names = cranelift_module::default_libcall_names();
-        let builder = ObjectBuilder::new(isa.clone(), unit_name, libcall_names).unwrap();
+        let builder = ObjectBuilder::new(isa.clone(), unit_name, libcall_names)?;
         ObjectModule::new(builder)
     };
 
     let path: Option<String> = args.get_one("output").cloned();
+    let debug = args.get_flag("debug");
+
+    let mut ctx = cl::codegen::Context::new();
+    let mut fctx = cl::FunctionBuilderContext::new();
+
+    let aliases = f(&mut ctx, &mut fctx, &mut module, args)?;
+
+    if let Some(path) = clif_path {
+        write_clif(&path)?;
+    }
+
+    emit_object(module, &aliases, &*isa, unit_name, path.as_deref(), debug)
+}
+
+/// Same as [`skip_boilerplate`] but creates one module for each unit name, which are emitted as
+/// separate object files.
+///
+/// This is how separate compilation works in C, where each `.c` file becomes its own `.o` file.
+/// A function exported by one unit can be declared with `Linkage::Import` in another, and calls to
+/// it are resolved once the object files are linked together.
+///
+/// The modules are given to `f` in the same order as `unit_names`. With `-o <DIR>`, each unit is
+/// written to `<DIR>/<unit name>.o`. Since the units have to be linked, `--jit` is rejected with
+/// [`BoilerplateError::JitUnsupported`].
+pub fn skip_boilerplate_units(
+    unit_names: &[&[u8]],
+    f: impl FnOnce(
+        &mut cl::codegen::Context,
+        &mut cl::FunctionBuilderContext,
+        &mut [ObjectModule],
+        clap::ArgMatches,
+    ),
+) {
+    try_skip_boilerplate_units(unit_names, |ctx, fctx, modules, args| {
+        f(ctx, fctx, modules, args);
+        Ok(())
+    })
+    .unwrap_or_else(boilerplate_failed)
+}
+
+/// Same as [`skip_boilerplate_units`] but errors are returned instead of causing panics.
+pub fn try_skip_boilerplate_units(
+    unit_names: &[&[u8]],
+    f: impl FnOnce(
+        &mut cl::codegen::Context,
+        &mut cl::FunctionBuilderContext,
+        &mut [ObjectModule],
+        clap::ArgMatches,
+    ) -> Result<(), BoilerplateError>,
+) -> Result<(), BoilerplateError> {
+    let args = parse_arguments();
+    if args.get_flag("jit") {
+        return Err(BoilerplateError::JitUnsupported);
+    }
+
+    let clif_path = apply_arguments(&args);
+
+    // Every unit is compiled for the same target, so they can share the ISA
+    let isa = isa_from_arguments(&args, |_, _| {})?;
+
+    let mut modules = unit_names
+        .iter()
+        .map(|unit_name| {
+            let libcall_names = cranelift_module::default_libcall_names();
+            let builder = ObjectBuilder::new(isa.clone(), *unit_name, libcall_names)?;
+            Ok(ObjectModule::new(builder))
+        })
+        .collect::<Result<Vec<_>, BoilerplateError>>()?;
+
+    let dir: Option<String> = args.get_one("output").cloned();
+    let debug = args.get_flag("debug");
 
     let mut ctx = cl::codegen::Context::new();
     let mut fctx = cl::FunctionBuilderContext::new();
 
-    f(&mut ctx, &mut fctx, &mut module, args);
+    f(&mut ctx, &mut fctx, &mut modules, args)?;
+
+    if let Some(path) = clif_path {
+        write_clif(&path)?;
+    }
+
+    if let Some(dir) = &dir {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    for (module, unit_name) in modules.into_iter().zip(unit_names) {
+        let path = dir.as_ref().map(|dir| {
+            let file_name = format!("{}.o", String::from_utf8_lossy(unit_name));
+            std::path::Path::new(dir)
+                .join(file_name)
+                .to_string_lossy()
+                .into_owned()
+        });
+
+        emit_object(module, &[], &*isa, unit_name, path.as_deref(), debug)?;
+    }
+
+    Ok(())
+}
+
+// Panic with the message of the error rather than its `Debug` representation
+fn boilerplate_failed(err: BoilerplateError) {
+    panic!("cranelift boilerplate failed: {err}")
+}
+
+// Enable the options which are read while defining functions, and return the path for
+// `--emit-clif` if one was given
+fn apply_arguments(args: &clap::ArgMatches) -> Option<String> {
+    VERIFY.store(args.get_flag("verify"), Ordering::Relaxed);
+    EMIT_ASM.store(args.get_flag("emit-asm"), Ordering::Relaxed);
+
+    let clif_path: Option<String> = args.get_one("emit-clif").cloned();
+    if clif_path.is_some() {
+        *CLIF.lock().unwrap() = Some(String::new());
+    }
+
+    clif_path
+}
+
+// Create the ISA for the target given with `--target-triple`, with the settings shared by every
+// example that emits an object file.
+fn isa_from_arguments(
+    args: &clap::ArgMatches,
+    configure: impl FnOnce(&mut cl::settings::Builder, &Triple),
+) -> Result<OwnedTargetIsa, BoilerplateError> {
+    // Clap stores the flag as an owned `String`, so it has to be looked up as such.
+    let triple = args
+        .get_one::<String>("target-triple")
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_TARGET_TRIPLE.to_string());
+
+    println!(" targeting {triple} ");
+
+    let mut builder = cl::settings::builder();
+
+    // Defaults to "none" so disassembly will more directly correlate to our Cranelift usage
+    let opt_level = args.get_one::<String>("opt-level").unwrap();
+    builder.set("opt_level", opt_level).unwrap();
+    builder.enable("is_pic").unwrap();
+
+    let isa_builder = cl::isa::lookup_by_name(&triple).map_err(BoilerplateError::UnknownTriple)?;
+    configure(&mut builder, isa_builder.triple());
+
+    let flags = cl::settings::Flags::new(builder);
+
+    isa_builder
+        .finish(flags)
+        .map_err(BoilerplateError::IsaFinish)
+}
+
+// Finish the module and write it to `path` as an object file, if one was given
+fn emit_object(
+    module: ObjectModule,
+    aliases: &[Alias],
+    isa: &dyn TargetIsa,
+    unit_name: &[u8],
+    path: Option<&str>,
+    debug: bool,
+) -> Result<(), BoilerplateError> {
+    let mut product = module.finish();
+
+    define_aliases(&mut product, aliases);
 
-    let product = module.finish();
+    if debug {
+        debug::emit_debug_info(&mut product, isa, unit_name)
+            .map_err(BoilerplateError::DebugInfo)?;
+    }
 
     match path {
         Some(path) => {
-            let bytes = product.emit().unwrap();
+            let bytes = product.emit().map_err(BoilerplateError::Emit)?;
 
-            let mut f = File::create(&path).unwrap();
-            f.write_all(&bytes).unwrap();
+            let mut f = File::create(path)?;
+            f.write_all(&bytes)?;
 
             println!(" wrote output to {} ", path);
         }
@@ -72,10 +496,397 @@ pub fn skip_boilerplate(
             println!(" no `-o` path specified ");
         }
     }
+
+    Ok(())
+}
+
+// Compile the functions into memory with a `JITModule` and run `main`, see `skip_boilerplate`
+fn boilerplate_jit(
+    args: clap::ArgMatches,
+    f: impl DefineFunctions,
+) -> Result<(), BoilerplateError> {
+    let clif_path = apply_arguments(&args);
+
+    if args.contains_id("target-triple") {
+        println!(" ignoring `-t`, the JIT always targets the host ");
+    }
+
+    let mut module = {
+        let opt_level = args.get_one::<String>("opt-level").unwrap();
+        let libcall_names = cranelift_module::default_libcall_names();
+
+        // `JITBuilder` looks up the ISA of the host machine for us.
+        //
+        // It also disables `is_pic`, since the functions will be called directly from memory
+        // rather than being relocated by a linker.
+        let builder = JITBuilder::with_flags(&[("opt_level", opt_level)], libcall_names)?;
+        JITModule::new(builder)
+    };
+
+    let mut ctx = cl::codegen::Context::new();
+    let mut fctx = cl::FunctionBuilderContext::new();
+
+    f.define(&mut ctx, &mut fctx, &mut module, args)?;
+
+    if let Some(path) = clif_path {
+        write_clif(&path)?;
+    }
+
+    // Perform the relocations and make the memory of the defined functions executable
+    module.finalize_definitions()?;
+
+    let main = match module.get_name("main") {
+        Some(FuncOrDataId::Func(func_id)) => module.get_finalized_function(func_id),
+        _ => panic!("no `main` function was declared"),
+    };
+
+    // SAFETY: `declare_main` gives main the `fn() -> i32` signature using the default call
+    // convention of the host, which is what `extern "C"` refers to.
+    let main = unsafe { std::mem::transmute::<*const u8, extern "C" fn() -> i32>(main) };
+
+    let exit_code = main();
+    println!(" main returned {exit_code} ");
+
+    // SAFETY: `main` has returned and none of the JIT compiled functions are used past this point.
+    unsafe { module.free_memory() };
+
+    Ok(())
+}
+
+/// A second symbol for the code of a function, see [`declare_alias`].
+#[must_use = "aliases are only added if they're returned to `skip_boilerplate_with_aliases`"]
+pub struct Alias {
+    name: String,
+    target: FuncId,
+}
+
+/// Make the code of `target` reachable under a second exported symbol, such as `__memcpy` for
+/// `memcpy`.
+///
+/// Modules have no notion of aliases, so the alias has to be returned to
+/// [`skip_boilerplate_with_aliases`], which adds the symbol to the object file after finishing the
+/// module. The alias is an ordinary symbol which points at the same section and offset as `target`,
+/// the same as what `__attribute__((alias))` produces in C. Calls to either name end up at the
+/// same code, without a forwarding stub in-between.
+///
+/// `target` must be defined by the time the module is finished.
+pub fn declare_alias(module: &ObjectModule, alias_name: &str, target: FuncId) -> Alias {
+    // Catch aliases of functions from another module early, rather than when finishing
+    let _ = module.declarations().get_function_decl(target);
+
+    Alias {
+        name: alias_name.to_string(),
+        target,
+    }
+}
+
+fn define_aliases(product: &mut ObjectProduct, aliases: &[Alias]) {
+    for Alias { name, target } in aliases {
+        let (symbol_id, defined) = product.functions[*target].expect("alias target is undeclared");
+        assert!(defined, "alias target of {name} is never defined");
+
+        let target = product.object.symbol(symbol_id);
+        let alias = Symbol {
+            name: name.clone().into_bytes(),
+            value: target.value,
+            size: target.size,
+            kind: target.kind,
+            scope: SymbolScope::Dynamic,
+            weak: false,
+            section: target.section,
+            flags: target.flags,
+        };
+
+        product.object.add_symbol(alias);
+    }
+}
+
+// Whether `--verify` was passed, see `define_function`
+static VERIFY: AtomicBool = AtomicBool::new(false);
+
+// Whether `--emit-asm` was passed, see `define_function`
+static EMIT_ASM: AtomicBool = AtomicBool::new(false);
+
+// The IR collected for `--emit-clif`, or `None` if it's printed to stdout instead
+static CLIF: Mutex<Option<String>> = Mutex::new(None);
+
+/// Print the IR of a function, or collect it for the file given with `--emit-clif`.
+///
+/// `header` is printed above the function to tell it apart from the others, such as
+/// `fn main (optimized)`.
+pub fn print_clif(header: &str, func: &Function) {
+    match CLIF.lock().unwrap().as_mut() {
+        // `Function` prints as the same text format that `clif-util` and the filetests read.
+        // The header is written as a comment of that format.
+        Some(clif) => {
+            use std::fmt::Write;
+            writeln!(clif, "; {header}\n{func}").unwrap();
+        }
+        None => println!("{header}:\n{func}"),
+    }
+}
+
+fn write_clif(path: &str) -> std::io::Result<()> {
+    let clif = CLIF.lock().unwrap().take().unwrap_or_default();
+    std::fs::write(path, clif)?;
+
+    println!(" wrote IR to {path} ");
+
+    Ok(())
+}
+
+/// Defines the function in the module, verifying it first if `--verify` was passed.
+///
+/// `Module::define_function` would eventually reject malformed IR as well, but the resulting error
+/// doesn't say much about what's wrong or where.
+///
+/// If `--emit-asm` was passed, the machine code is printed once the function has been compiled.
+// Returns the same result as `Module::define_function` so that it can be used as a drop-in replacement
+#[allow(clippy::result_large_err)]
+pub fn define_function(
+    module: &mut impl Module,
+    func_id: FuncId,
+    ctx: &mut cl::codegen::Context,
+) -> ModuleResult<()> {
+    let name = module
+        .declarations()
+        .get_function_decl(func_id)
+        .name
+        .clone();
+    let name = name.as_deref().unwrap_or("<anonymous>");
+
+    if VERIFY.load(Ordering::Relaxed) {
+        verify_named(name, &ctx.func, module.isa());
+    }
+
+    // The disassembly is produced by the backend while compiling, from the same instructions that
+    // are encoded into machine code. That's cheaper than decoding the bytes again afterwards, and
+    // doesn't need an external disassembler such as capstone.
+    let emit_asm = EMIT_ASM.load(Ordering::Relaxed);
+    ctx.set_disasm(emit_asm);
+
+    module.define_function(func_id, ctx)?;
+
+    // The module leaves the compiled code in the context after defining the function
+    if emit_asm {
+        let compiled = ctx.compiled_code().unwrap();
+        let asm = compiled.vcode.as_deref().unwrap_or_default();
+        println!("fn {name} (asm):\n{asm}");
+    }
+
+    Ok(())
+}
+
+/// Keeps the IR of functions so that they can be run in Cranelift's interpreter, without compiling
+/// them to machine code.
+///
+/// Since the IR is interpreted directly, the result doesn't depend on the host or the target, which
+/// makes it useful for checking that an example computes what it's supposed to. The tests in
+/// `tests/` use it this way.
+///
+/// Compiling a function legalizes its IR in place, so the IR has to be kept from before
+/// [`define_function`] is called. Only the functions given to [`InterpretedFunctions::define`], or
+/// to a [`Codegen`] with `interpreted` set, are kept. That includes every function called by the
+/// ones which are run.
+///
+/// The interpreter only knows about the functions kept here, so calling an imported function such
+/// as `malloc` fails. It also only models the stack, so memory has to come from stack slots.
+/// Functions are looked up by their `FuncId`, so a single `InterpretedFunctions` must only be used
+/// with one module.
+#[derive(Default)]
+pub struct InterpretedFunctions {
+    // Behind a `RefCell` so that functions can be added while the owner is borrowed elsewhere, such
+    // as by a `Codegen` defining the function which in turn defines another
+    functions: RefCell<Vec<Function>>,
+}
+
+impl InterpretedFunctions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep a copy of `func` to be run as `func_id`.
+    ///
+    /// The copy is named after its `FuncId` the same way calls to it are, so that the interpreter
+    /// can find it.
+    pub fn add(&self, func_id: FuncId, func: &Function) {
+        let mut func = func.clone();
+        func.name = UserFuncName::user(0, func_id.as_u32());
+        self.functions.borrow_mut().push(func);
+    }
+
+    /// Same as [`define_function`], but keeps the IR of the function first.
+    #[allow(clippy::result_large_err)]
+    pub fn define(
+        &self,
+        module: &mut impl Module,
+        func_id: FuncId,
+        ctx: &mut cl::codegen::Context,
+    ) -> ModuleResult<()> {
+        self.add(func_id, &ctx.func);
+        define_function(module, func_id, ctx)
+    }
+
+    /// Run a function in Cranelift's interpreter.
+    ///
+    /// Panics if the function traps or can't be interpreted, see [`InterpretedFunctions::try_run`]
+    /// for a version which returns the trap instead.
+    pub fn run(&self, func: FuncId, args: &[DataValue]) -> Vec<DataValue> {
+        self.try_run(func, args).unwrap_or_else(|trap| {
+            panic!("{func} trapped in the interpreter: {trap}");
+        })
+    }
+
+    /// Same as [`InterpretedFunctions::run`] but a trap is returned instead of causing a panic,
+    /// which is useful for checking that a function traps when it's supposed to.
+    ///
+    /// Still panics if the function can't be interpreted.
+    pub fn try_run(
+        &self,
+        func: FuncId,
+        args: &[DataValue],
+    ) -> Result<Vec<DataValue>, CraneliftTrap> {
+        let name = UserFuncName::user(0, func.as_u32()).to_string();
+        self.interpret(None, &name, args)
+    }
+
+    /// Run `func` in Cranelift's interpreter, with the kept functions available for it to call.
+    ///
+    /// `func` doesn't need to be declared in the module, which makes it useful for calling the
+    /// kept functions with arguments that have to be built in IR, such as pointers to stack slots.
+    pub fn run_function(&self, func: &Function, args: &[DataValue]) -> Vec<DataValue> {
+        let name = func.name.to_string();
+        self.interpret(Some(func), &name, args)
+            .unwrap_or_else(|trap| panic!("{name} trapped in the interpreter: {trap}"))
+    }
+
+    fn interpret(
+        &self,
+        extra: Option<&Function>,
+        name: &str,
+        args: &[DataValue],
+    ) -> Result<Vec<DataValue>, CraneliftTrap> {
+        let defined = self.functions.borrow();
+
+        let mut functions = FunctionStore::default();
+        for f in defined.iter().chain(extra) {
+            functions.add(f.name.to_string(), f);
+        }
+
+        let state = InterpreterState::default().with_function_store(functions);
+        let mut interpreter = Interpreter::new(state);
+
+        match interpreter.call_by_name(name, args) {
+            Ok(ControlFlow::Return(results)) => Ok(results.into_vec()),
+            Ok(ControlFlow::Trap(trap)) => Err(trap),
+            Ok(_) => unreachable!("a call always ends with a return or a trap"),
+            Err(err) => panic!("failed to interpret {name}: {err}"),
+        }
+    }
+}
+
+/// Same as [`InterpretedFunctions::define`] if `interpreted` is given, otherwise the same as
+/// [`define_function`].
+///
+/// This lets an example define its functions the same way whether or not a test is going to run
+/// them in the interpreter afterwards.
+#[allow(clippy::result_large_err)]
+pub fn define_maybe_interpreted(
+    module: &mut impl Module,
+    func_id: FuncId,
+    ctx: &mut cl::codegen::Context,
+    interpreted: Option<&InterpretedFunctions>,
+) -> ModuleResult<()> {
+    match interpreted {
+        Some(interpreted) => interpreted.define(module, func_id, ctx),
+        None => define_function(module, func_id, ctx),
+    }
+}
+
+/// Runs the cranelift verifier on the function and panics with an annotated listing if it's malformed.
+///
+/// Catches mistakes such as forgetting to seal a block or leaving a block without a terminator.
+pub fn verify_or_panic(func: &Function, isa: &dyn TargetIsa) {
+    verify_named(&func.name.to_string(), func, isa)
+}
+
+fn verify_named(name: &str, func: &Function, isa: &dyn TargetIsa) {
+    if let Err(errors) = cl::codegen::verify_function(func, isa) {
+        let listing = pretty_verifier_error(func, None, errors);
+        panic!("verifier error in fn {name}:\n{listing}");
+    }
+}
+
+/// Bundles the contexts that are needed to define functions, so they don't have to be threaded
+/// through separately.
+///
+/// This is an alternative to using [`function_builder_from_declaration`] and
+/// [`define_function`] manually.
+pub struct Codegen<'a, M: Module = ObjectModule> {
+    pub ctx: &'a mut cl::codegen::Context,
+    pub fctx: &'a mut cl::FunctionBuilderContext,
+    pub module: &'a mut M,
+    /// Where to keep the IR of the defined functions, if they're going to be interpreted
+    pub interpreted: Option<&'a InterpretedFunctions>,
+}
+
+impl<'a, M: Module> Codegen<'a, M> {
+    pub fn new(
+        ctx: &'a mut cl::codegen::Context,
+        fctx: &'a mut cl::FunctionBuilderContext,
+        module: &'a mut M,
+    ) -> Self {
+        Self {
+            ctx,
+            fctx,
+            module,
+            interpreted: None,
+        }
+    }
+
+    /// Define the contents of a previously declared function.
+    ///
+    /// The `FunctionBuilder` given to `build` will have the signature from the declaration and
+    /// will already be switched to the entry block. Once `build` returns, the function is
+    /// finalized, printed, and defined in the module.
+    ///
+    /// If `--opt-level` isn't `none`, the function is also printed again after being optimized.
+    #[allow(clippy::result_large_err)]
+    pub fn define(
+        &mut self,
+        id: FuncId,
+        build: impl FnOnce(&mut M, &mut FunctionBuilder<'_>, cl::Block),
+    ) -> ModuleResult<()> {
+        self.ctx.clear();
+
+        let (mut fbuilder, entry) =
+            function_builder_from_declaration(self.module, &mut self.ctx.func, self.fctx, id);
+
+        build(self.module, &mut fbuilder, entry);
+
+        fbuilder.finalize();
+
+        let name = &self.module.declarations().get_function_decl(id).name;
+        let name = name.as_deref().unwrap_or("<anonymous>");
+        print_clif(&format!("fn {name}"), &self.ctx.func);
+
+        // The IR we built is what the optimizer starts from, which is rarely what ends up being
+        // compiled. `define_function` would optimize it for us, but doing it here first lets us
+        // print the result. Compiling then runs the same passes again, which have nothing left to do.
+        if self.module.isa().flags().opt_level() != OptLevel::None {
+            self.ctx
+                .optimize(self.module.isa(), &mut ControlPlane::default())
+                .map_err(ModuleError::Compilation)?;
+
+            print_clif(&format!("fn {name} (optimized)"), &self.ctx.func);
+        }
+
+        define_maybe_interpreted(self.module, id, self.ctx, self.interpreted)
+    }
 }
 
 pub fn function_builder_from_declaration<'a>(
-    module: &mut ObjectModule,
+    module: &mut impl Module,
     func: &'a mut Function,
     fctx: &'a mut cl::FunctionBuilderContext,
     func_id: FuncId,
@@ -88,7 +899,7 @@ pub fn function_builder_from_declaration<'a>(
     (fbuilder, entry)
 }
 
-pub fn signature_from_decl(module: &ObjectModule, func: FuncId) -> cl::Signature {
+pub fn signature_from_decl(module: &impl Module, func: FuncId) -> cl::Signature {
     module
         .declarations()
         .get_function_decl(func)
@@ -96,7 +907,49 @@ pub fn signature_from_decl(module: &ObjectModule, func: FuncId) -> cl::Signature
         .clone()
 }
 
-// Define a block with the same parameter and return types as the function
+/// Caches the signatures of declared functions, keyed by their `FuncId`.
+///
+/// [`signature_from_decl`] clones the signature out of the module's declarations every time it's
+/// called. The cache only does so the first time a function is looked up, and hands out the same
+/// copy afterwards. That saves a clone for every lookup where the signature is only inspected, such
+/// as when checking the arguments of each call to a function.
+///
+/// `FuncId`s are only unique within a module, so a `SigCache` must only be used with one module.
+#[derive(Default, Debug)]
+pub struct SigCache {
+    sigs: HashMap<FuncId, cl::Signature>,
+}
+
+impl SigCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&mut self, module: &impl Module, func: FuncId) -> &cl::Signature {
+        self.sigs
+            .entry(func)
+            .or_insert_with(|| signature_from_decl(module, func))
+    }
+}
+
+/// Define a block with the same parameter and return types as the function.
+///
+/// The block is sealed right away. Sealing tells `FunctionBuilder` that every predecessor of a
+/// block is known, which it needs before it can resolve the variables used within it. The rules
+/// for when a block may be sealed are:
+///
+/// * A block must only be sealed once every branch to it has been added. Branching to a block
+///   after sealing it panics in debug builds, and silently produces wrong IR in release builds.
+/// * Every block must be sealed before `FunctionBuilder::finalize`, which is also only checked in
+///   debug builds. `seal_all_blocks` seals the remaining blocks for frontends which can't keep
+///   track of when each block is complete.
+/// * Sealing early is preferred, since variables used in sealed blocks can be resolved right away.
+///
+/// The entry block is the one exception which can always be sealed immediately, since it never
+/// has any predecessors. The verifier rejects branches to the entry block, its parameters being
+/// the function's parameters. A loop which starts at the top of the function therefore needs a
+/// separate header block, which the entry block jumps to and which is sealed once the back-edge
+/// from the end of the loop has been added. See the `loops` example.
 pub fn create_entry_block(fbuilder: &mut cl::FunctionBuilder<'_>) -> cl::Block {
     let block = fbuilder.create_block();
     fbuilder.seal_block(block);
@@ -104,11 +957,142 @@ pub fn create_entry_block(fbuilder: &mut cl::FunctionBuilder<'_>) -> cl::Block {
     block
 }
 
+/// The user trap code of [`trap_unreachable`].
+///
+/// Cranelift reserves the lowest codes for its own traps such as `INTEGER_OVERFLOW`, and the rest
+/// are free for us to use. The examples use codes from 100 upwards for their own traps, so that a
+/// trap code printed by a crash handler or the interpreter can be traced back to its cause.
+pub const TRAP_UNREACHABLE: u8 = 100;
+
+/// Terminate the current block with a trap using [`TRAP_UNREACHABLE`], such as for the default
+/// block of a `Switch` or `br_table` which none of the values we match on lead to.
+///
+/// Every block needs a terminator, even the ones which can never be reached. Trapping instead of
+/// jumping somewhere arbitrary means that a tag read from corrupted memory crashes right away
+/// rather than continuing with a wrong variant.
+///
+/// Cranelift has a few instructions which sound alike:
+///
+/// * `trap` stops execution with the given trap code, and is a terminator. Nothing after it runs,
+///   so Cranelift won't let us add more instructions to the block.
+/// * `trapz` and `trapnz` trap only if a condition is zero or non-zero, and otherwise continue
+///   with the next instruction. They're not terminators.
+/// * `debugtrap` is a breakpoint, such as `int3` on x86-64. A debugger can continue past it, so
+///   it's not a terminator either and the block still needs one afterwards.
+/// * `resumable_trap` used to exist for traps which a runtime could continue from, but it has been
+///   removed from Cranelift.
+///
+/// How a trap looks to the program depends on the target. On Linux it's usually `SIGILL` from a
+/// `ud2` or `udf` instruction, and the trap code is only kept in the trap metadata of the
+/// compiled code.
+pub fn trap_unreachable(fbuilder: &mut cl::FunctionBuilder<'_>) -> cl::codegen::ir::Inst {
+    fbuilder
+        .ins()
+        .trap(cl::TrapCode::user(TRAP_UNREACHABLE).unwrap())
+}
+
+/// Declare a function with a signature made from the given parameter and return types.
+///
+/// Uses the default calling convention of the target if `call_conv` is `None`.
+pub fn declare_function_from_types(
+    module: &mut impl Module,
+    name: &str,
+    linkage: Linkage,
+    params: &[cl::Type],
+    returns: &[cl::Type],
+    call_conv: Option<CallConv>,
+) -> FuncId {
+    let call_conv = call_conv.unwrap_or_else(|| module.isa().default_call_conv());
+
+    let sig = cl::Signature {
+        params: params.iter().copied().map(cl::AbiParam::new).collect(),
+        returns: returns.iter().copied().map(cl::AbiParam::new).collect(),
+        call_conv,
+    };
+
+    module.declare_function(name, linkage, &sig).unwrap()
+}
+
+/// Declare and define an exported wrapper around `target` which can be called from C.
+///
+/// Internal functions are best declared with `CallConv::Fast`, which leaves Cranelift free to pass
+/// arguments however it likes. C code calling them, or being given a pointer to them, expects the
+/// default calling convention of the target instead, such as SystemV on Linux. The trampoline takes
+/// the same parameters using the default calling convention and forwards them to `target`, whose
+/// results it then returns.
+///
+/// The trampoline is named after `target` with a `_c` suffix, so `compare` is wrapped by
+/// `compare_c`. Extension attributes such as `uext` are kept from the signature of `target`.
+pub fn generate_c_trampoline<M: Module>(module: &mut M, target: FuncId) -> FuncId {
+    let (name, sig) = {
+        let decl = module.declarations().get_function_decl(target);
+        let name = decl.name.as_deref().expect("trampoline target has no name");
+
+        let mut sig = decl.signature.clone();
+        sig.call_conv = module.isa().default_call_conv();
+
+        (format!("{name}_c"), sig)
+    };
+
+    let func_id = module
+        .declare_function(&name, Linkage::Export, &sig)
+        .unwrap();
+
+    let mut ctx = cl::codegen::Context::new();
+    let mut fctx = cl::FunctionBuilderContext::new();
+
+    Codegen::new(&mut ctx, &mut fctx, module)
+        .define(func_id, |module, fbuilder, entry| {
+            // Since the parameter types are the same, only the calling convention changes between
+            // the block parameters we receive and the arguments we call with.
+            let args = fbuilder.block_params(entry).to_vec();
+
+            let fref = module.declare_func_in_func(target, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &args);
+            let results = fbuilder.inst_results(call).to_vec();
+
+            fbuilder.ins().return_(&results);
+        })
+        .unwrap();
+
+    func_id
+}
+
 // fn main();
-pub fn declare_main(module: &mut ObjectModule) -> FuncId {
+//
+// On MacOS the C entrypoint symbol is `_main`, since Mach-O prefixes every C symbol with an
+// underscore. `cranelift-object` adds the prefix for us when emitting a Mach-O object, so we
+// declare `main` regardless of the target. Declaring `_main` ourselves would end up as `__main`.
+//
+// The same goes for every other symbol, such as `puts` becoming `_puts`.
+pub fn declare_main(module: &mut impl Module) -> FuncId {
+    let call_conv = module.isa().default_call_conv();
+    let mut sig = cl::Signature::new(call_conv);
+
+    // Add the exit code return type
+    sig.returns.push(cl::AbiParam::new(cl::types::I32));
+
+    module
+        .declare_function("main", Linkage::Export, &sig)
+        .unwrap()
+}
+
+// fn main(argc: i32, argv: *const *const u8) -> i32;
+//
+// The signature C programs use to receive their command-line arguments. `argc` is a C `int`, and
+// `argv` points to `argc` pointers to nul-terminated strings, followed by a null pointer. The first
+// string is usually the name the program was started with.
+//
+// See `declare_main` for why this is declared as `main` on every target.
+pub fn declare_main_with_args(module: &mut impl Module) -> FuncId {
     let call_conv = module.isa().default_call_conv();
+    let size_t = module.isa().pointer_type();
     let mut sig = cl::Signature::new(call_conv);
 
+    // argc: i32, argv: *const *const u8
+    sig.params.push(cl::AbiParam::new(cl::types::I32));
+    sig.params.push(cl::AbiParam::new(size_t));
+
     // Add the exit code return type
     sig.returns.push(cl::AbiParam::new(cl::types::I32));
 
@@ -116,3 +1100,72 @@ pub fn declare_main(module: &mut ObjectModule) -> FuncId {
         .declare_function("main", Linkage::Export, &sig)
         .unwrap()
 }
+
+/// Load `argv[index]`, the pointer to the nul-terminated string of a command-line argument.
+///
+/// `index` may be any integer type, such as the `i32` of `argc`. It isn't checked against `argc`.
+pub fn load_argv(
+    module: &impl Module,
+    fbuilder: &mut FunctionBuilder<'_>,
+    argv: cl::Value,
+    index: cl::Value,
+) -> cl::Value {
+    let size_t = module.isa().pointer_type();
+
+    // The index has to be the same width as the pointer before it can be added to it
+    let index = match fbuilder.func.dfg.value_type(index) {
+        ty if ty.bits() < size_t.bits() => fbuilder.ins().uextend(size_t, index),
+        ty if ty.bits() > size_t.bits() => fbuilder.ins().ireduce(size_t, index),
+        _ => index,
+    };
+
+    // &argv[index]
+    let offset = fbuilder.ins().imul_imm(index, size_t.bytes() as i64);
+    let addr = fbuilder.ins().iadd(argv, offset);
+
+    // `argv` is never null and its elements are aligned
+    let flags = cl::MemFlags::trusted();
+    fbuilder.ins().load(size_t, flags, addr, 0)
+}
+
+/// Declare a read-only string constant and embed its bytes in the object file.
+///
+/// C functions such as `puts` expect strings to end with a NUL byte, which we can append
+/// for them with `nul_terminated`.
+pub fn declare_data_string(
+    module: &mut impl Module,
+    name: &str,
+    bytes: &[u8],
+    nul_terminated: bool,
+) -> DataId {
+    // Since `writable` is false, the data will be put into a read-only section.
+    let data_id = module
+        .declare_data(name, Linkage::Local, false, false)
+        .unwrap();
+
+    let mut contents = bytes.to_vec();
+    if nul_terminated {
+        contents.push(0);
+    }
+
+    let mut desc = DataDescription::new();
+    desc.define(contents.into_boxed_slice());
+
+    module.define_data(data_id, &desc).unwrap();
+
+    data_id
+}
+
+/// Get the address of a declared data object as a pointer from within a function.
+pub fn data_addr_in_func(
+    module: &mut impl Module,
+    data_id: DataId,
+    fbuilder: &mut FunctionBuilder<'_>,
+) -> cl::Value {
+    // Similarly to functions, the global DataId first needs to be mapped to a GlobalValue local
+    // to the current function.
+    let gv = module.declare_data_in_func(data_id, fbuilder.func);
+
+    let size_t = module.isa().pointer_type();
+    fbuilder.ins().global_value(size_t, gv)
+}
@@ -0,0 +1,149 @@
+//! Checks how the `closures` example lays out captures, and runs the closures it builds in
+//! Cranelift's interpreter.
+
+use cranelift::codegen::data_value::DataValue;
+use cranelift::codegen::ir::{Function, UserFuncName};
+use cranelift::prelude::{self as cl, FunctionBuilder, FunctionBuilderContext, InstBuilder};
+use cranelift_examples::{Codegen, InterpretedFunctions};
+use cranelift_module::{Module, default_libcall_names};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+#[allow(dead_code)]
+#[path = "../examples/closures/main.rs"]
+mod closures;
+
+use closures::{
+    Capture, CaptureStorage, CaptureType, construct_closure, declare_f2_real_function,
+    offset_of_field, stack_alloc_captures, stack_alloc_point,
+};
+
+fn object_module() -> ObjectModule {
+    let isa = cl::isa::lookup_by_name("x86_64-unknown-linux")
+        .unwrap()
+        .finish(cl::settings::Flags::new(cl::settings::builder()))
+        .unwrap();
+
+    let builder = ObjectBuilder::new(isa, "closures", default_libcall_names()).unwrap();
+    ObjectModule::new(builder)
+}
+
+// Check that the closures add up to what `main` returns.
+//
+// `main` itself can't be interpreted, since `make_adder` calls `malloc` which the interpreter
+// doesn't have the IR for. Instead, the functions the closures point to are called from a
+// function which only exists in the interpreter, with the same captures as `main` gives them.
+// The captures are written to a stack slot the same way `main` writes them, so the forwarding
+// functions have to read them back from the right offsets.
+//
+// fn check_closures() -> int {
+//   let x = 3;
+//   return f0.func(&(1), x)
+//        + f1.func(&(1, 2), x)
+//        + add10.func(&(10), x)
+//        + f0_again.func(&(2), x)
+//        + f2.func(null, x)
+//        + f3.func(&(1 as i8, 4 as i64), x)
+//        + f4.func(&(Point { x: 5, y: 6 }), x);
+// }
+#[test]
+fn closures_add_up_to_main() {
+    let mut module = object_module();
+    let mut ctx = cl::codegen::Context::new();
+    let mut fctx = FunctionBuilderContext::new();
+
+    let interpreted = InterpretedFunctions::new();
+
+    let closure_funcs = {
+        let mut codegen = Codegen::new(&mut ctx, &mut fctx, &mut module);
+        codegen.interpreted = Some(&interpreted);
+        closures::define_closures(&mut codegen)
+    };
+
+    let mut sig = module.make_signature();
+    sig.returns.push(cl::AbiParam::new(cl::types::I32));
+    let mut func = Function::with_name_signature(UserFuncName::testcase("check_closures"), sig);
+
+    let mut fbuilder = FunctionBuilder::new(&mut func, &mut fctx);
+    let block = fbuilder.create_block();
+    fbuilder.switch_to_block(block);
+    fbuilder.seal_block(block);
+
+    let [one, two, ten, x] = [1, 2, 10, 3].map(|n| fbuilder.ins().iconst(cl::types::I32, n));
+    let small = fbuilder.ins().iconst(cl::types::I8, 1);
+    let big = fbuilder.ins().iconst(cl::types::I64, 4);
+    let point = stack_alloc_point(&mut module, &mut fbuilder, [5, 6]);
+
+    let closures = [
+        (closure_funcs.f0, vec![Capture::Scalar(one)]),
+        (
+            closure_funcs.f1,
+            vec![Capture::Scalar(one), Capture::Scalar(two)],
+        ),
+        (closure_funcs.add10, vec![Capture::Scalar(ten)]),
+        (closure_funcs.f0_again, vec![Capture::Scalar(two)]),
+        (closure_funcs.f2, vec![]),
+        (
+            closure_funcs.f3,
+            vec![Capture::Scalar(small), Capture::Scalar(big)],
+        ),
+        (closure_funcs.f4, vec![point]),
+    ];
+
+    let mut sum = fbuilder.ins().iconst(cl::types::I32, 0);
+    for (func_id, captures) in closures {
+        let func_id = func_id.expect("closure should have been constructed");
+
+        // Non-capturing closures are given a null data pointer, just like in `construct_closure`
+        let data = if captures.is_empty() {
+            let size_t = module.isa().pointer_type();
+            fbuilder.ins().iconst(size_t, 0)
+        } else {
+            stack_alloc_captures(&module, &mut fbuilder, &captures)
+        };
+
+        let fref = module.declare_func_in_func(func_id, fbuilder.func);
+        let call = fbuilder.ins().call(fref, &[data, x]);
+        let n = fbuilder.inst_results(call)[0];
+        sum = fbuilder.ins().iadd(sum, n);
+    }
+
+    fbuilder.ins().return_(&[sum]);
+    fbuilder.finalize();
+
+    assert_eq!(interpreted.run_function(&func, &[]), [DataValue::I32(52)]);
+}
+
+// A closure without captures has nothing to allocate, so it shouldn't create a stack slot
+#[test]
+fn no_captures_no_stack_slot() {
+    let mut module = object_module();
+    let f2 = declare_f2_real_function(&mut module);
+
+    let sig = module.make_signature();
+    let mut func = Function::with_name_signature(UserFuncName::testcase("construct_f2"), sig);
+
+    let mut fctx = FunctionBuilderContext::new();
+    let mut fbuilder = FunctionBuilder::new(&mut func, &mut fctx);
+    let block = fbuilder.create_block();
+    fbuilder.switch_to_block(block);
+    fbuilder.seal_block(block);
+
+    construct_closure(
+        &mut module,
+        &mut fbuilder,
+        None,
+        f2,
+        &[],
+        CaptureStorage::Stack,
+    );
+
+    assert!(fbuilder.func.sized_stack_slots.is_empty());
+}
+
+// Captures of different sizes need padding between them. Without it, `big` would be stored right
+// after `small` at the misaligned offset 1.
+#[test]
+fn captures_are_aligned() {
+    let captys = [cl::types::I8, cl::types::I64].map(CaptureType::Scalar);
+    assert_eq!(offset_of_field(1, &captys), 8);
+}
@@ -0,0 +1,93 @@
+//! Compares the IR of `main` in some of the examples against the fixtures in `tests/golden`.
+//!
+//! Each example is run with `--emit-clif`, and the `main` function is cut out of the written file.
+//! Any difference fails the test, which catches changes to the IR made by accident while
+//! refactoring the helpers in `lib.rs`.
+//!
+//! When the IR is meant to change, the fixtures can be regenerated with
+//!
+//! `$ BLESS=1 cargo test --test golden_clif`
+//!
+//! The examples are run for the default target, so the IR is the same on every host.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, fs};
+
+#[test]
+fn closures() {
+    check_golden("closures");
+}
+
+#[test]
+fn struct_layouts() {
+    check_golden("struct-layouts");
+}
+
+#[test]
+fn tagged_union_layouts() {
+    check_golden("tagged-union-layouts");
+}
+
+fn check_golden(example: &str) {
+    let actual = main_clif(example);
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{example}.clif"));
+
+    if env::var_os("BLESS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "could not read {}: {err}\nrun with BLESS=1 to create it",
+            path.display()
+        )
+    });
+
+    assert_eq!(
+        actual,
+        expected,
+        "the IR of `main` in {example} doesn't match {}\nrun with BLESS=1 if the change is intended",
+        path.display()
+    );
+}
+
+// Run the example and return the IR of its `main` function
+fn main_clif(example: &str) -> String {
+    let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(example);
+    fs::create_dir_all(&out_dir).unwrap();
+
+    let object = out_dir.join(format!("{example}.o"));
+    let clif = out_dir.join(format!("{example}.clif"));
+
+    let output = Command::new(env!("CARGO"))
+        .args(["run", "--quiet", "--example", example, "--"])
+        .arg("-o")
+        .arg(&object)
+        .arg("--emit-clif")
+        .arg(&clif)
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{example} failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let clif = fs::read_to_string(&clif).unwrap();
+
+    // Every function is preceded by a `; fn <name>` header, see `print_clif`
+    let start = clif
+        .find("; fn main\n")
+        .unwrap_or_else(|| panic!("{example} didn't emit the IR of `main`"));
+    let main = &clif[start..];
+    let end = main[1..].find("\n; fn ").map_or(main.len(), |i| i + 2);
+
+    main[..end].to_string()
+}
@@ -0,0 +1,205 @@
+//! Checks the struct layouts of the `lowering-structs` example, and runs the functions it defines
+//! which only touch stack memory in Cranelift's interpreter.
+
+use cranelift::codegen::data_value::DataValue;
+use cranelift::prelude::{self as cl, FunctionBuilderContext};
+use cranelift_examples::InterpretedFunctions;
+use cranelift_module::{FuncId, FuncOrDataId, Module, default_libcall_names};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+#[allow(dead_code)]
+#[path = "../examples/lowering-structs/main.rs"]
+mod lowering_structs;
+
+use lowering_structs::types::{Abi, Chunk, Convention, LookupTable, StructPassingMode, Type};
+
+// Both targets the example knows the C ABI of are 64-bit
+const PTR_SIZE: u32 = 8;
+
+// Define every function of the example, keeping the ones which can be interpreted
+fn interpreted_program() -> (ObjectModule, InterpretedFunctions) {
+    let isa = cl::isa::lookup_by_name("x86_64-unknown-linux")
+        .unwrap()
+        .finish(cl::settings::Flags::new(cl::settings::builder()))
+        .unwrap();
+
+    let builder = ObjectBuilder::new(isa, "lowering-structs", default_libcall_names()).unwrap();
+    let mut module = ObjectModule::new(builder);
+
+    let mut ctx = cl::codegen::Context::new();
+    let mut fctx = FunctionBuilderContext::new();
+    let interpreted = InterpretedFunctions::new();
+
+    lowering_structs::define_program(&mut ctx, &mut fctx, &mut module, Some(&interpreted), false);
+
+    (module, interpreted)
+}
+
+fn func_id_of(module: &impl Module, name: &str) -> FuncId {
+    match module.get_name(name) {
+        Some(FuncOrDataId::Func(id)) => id,
+        _ => panic!("function {name} has not been declared"),
+    }
+}
+
+// The fields survive the trip through the array, the enum payload and the `match`
+#[test]
+fn frame_roundtrip() {
+    let (module, interpreted) = interpreted_program();
+    let frame_roundtrip = func_id_of(&module, "frame_roundtrip");
+
+    let int = |n| DataValue::I32(n);
+    let result = interpreted.run(frame_roundtrip, &[int(1), int(2), int(3)]);
+    assert_eq!(result, [int(123 + 4)]);
+
+    let result = interpreted.run(frame_roundtrip, &[int(-5), int(0), int(9)]);
+    assert_eq!(result, [int(-500 + 9 + 4)]);
+}
+
+// The position is read from before the player was moved any further
+#[test]
+fn position_after_move() {
+    let (module, interpreted) = interpreted_program();
+    let position_after_move = func_id_of(&module, "position_after_move");
+
+    let int = |n| DataValue::I32(n);
+    let result = interpreted.run(position_after_move, &[int(3), int(4)]);
+    assert_eq!(result, [int(3 + 4)]);
+}
+
+fn chunks(types: &[(i32, cl::Type)]) -> StructPassingMode {
+    let chunks = types
+        .iter()
+        .map(|&(offset, type_)| Chunk { offset, type_ })
+        .collect();
+    StructPassingMode::ByChunks(chunks)
+}
+
+// The same struct can be passed differently depending on the target, so the classification is
+// checked for both ABIs.
+
+// An HFA of four floats is passed in four float registers on AArch64
+#[test]
+fn aarch64_passes_hfas_in_float_registers() {
+    use cl::types::F32;
+
+    let aarch64 = LookupTable::hardcoded(PTR_SIZE, Abi::Aarch64);
+
+    assert_eq!(
+        aarch64.struct_passing_mode(Type::Struct("Quad"), Convention::C),
+        chunks(&[(0, F32), (4, F32), (8, F32), (12, F32)]),
+    );
+}
+
+// The expected chunks for x86-64 are the parameter types clang gives these structs in LLVM IR,
+// which can be seen with `clang -S -emit-llvm -O1` on a function taking the struct.
+#[test]
+fn x86_64_passes_eightbytes_like_clang() {
+    use cl::types::{F32, F64, I64};
+
+    let x86_64 = LookupTable::hardcoded(PTR_SIZE, Abi::X86_64);
+
+    // clang: `<2 x float>, <2 x float>`, two floats share each SSE register on x86-64
+    assert_eq!(
+        x86_64.struct_passing_mode(Type::Struct("Quad"), Convention::C),
+        chunks(&[(0, F64), (8, F64)]),
+    );
+
+    // clang: `<2 x float>, float`
+    assert_eq!(
+        x86_64.struct_passing_mode(Type::Struct("Vec3"), Convention::C),
+        chunks(&[(0, F64), (8, F32)]),
+    );
+
+    // clang: `i64, double`, the first eightbyte mixes an integer with a float so it's INTEGER
+    assert_eq!(
+        x86_64.struct_passing_mode(Type::Struct("Sample"), Convention::C),
+        chunks(&[(0, I64), (8, F64)]),
+    );
+
+    // clang: `i64`, both integers share a single register
+    assert_eq!(
+        x86_64.struct_passing_mode(Type::Struct("Point"), Convention::C),
+        chunks(&[(0, I64)]),
+    );
+
+    // clang: `i64, i64`, arrays inside structs are classified by their elements like fields are
+    assert_eq!(
+        x86_64.struct_passing_mode(Type::Struct("Board"), Convention::C),
+        chunks(&[(0, I64), (8, I64)]),
+    );
+}
+
+// Mixing integers and booleans means it's not an HFA, so its 16 bytes are loaded as integers.
+// On x86-64 both eightbytes are INTEGER for the same reason.
+#[test]
+fn mixed_structs_are_passed_as_integers() {
+    use cl::types::I64;
+
+    for abi in [Abi::X86_64, Abi::Aarch64] {
+        let types = LookupTable::hardcoded(PTR_SIZE, abi);
+
+        assert_eq!(
+            types.struct_passing_mode(Type::Struct("Player"), Convention::C),
+            chunks(&[(0, I64), (8, I64)]),
+            "{abi:?}",
+        );
+    }
+}
+
+// Too large to be passed in registers on either target
+#[test]
+fn large_structs_are_passed_by_pointer() {
+    for abi in [Abi::X86_64, Abi::Aarch64] {
+        let types = LookupTable::hardcoded(PTR_SIZE, abi);
+
+        assert_eq!(
+            types.struct_passing_mode(Type::Struct("Grid"), Convention::C),
+            StructPassingMode::ByPointer,
+            "{abi:?}",
+        );
+    }
+}
+
+// Our own functions aren't affected by the target's C ABI
+#[test]
+fn internal_structs_are_passed_as_scalars() {
+    for abi in [Abi::X86_64, Abi::Aarch64] {
+        let types = LookupTable::hardcoded(PTR_SIZE, abi);
+
+        assert_eq!(
+            types.struct_passing_mode(Type::Struct("Vec2"), Convention::Internal),
+            StructPassingMode::ByScalars,
+            "{abi:?}",
+        );
+    }
+}
+
+// Booleans are narrower than a register, so they have to be marked for zero extension wherever they
+// cross a function boundary. Other parameters are left alone.
+#[test]
+fn booleans_are_zero_extended() {
+    use cranelift::codegen::ir::ArgumentExtension::{None, Uext};
+
+    let extensions = |params: &[cl::AbiParam]| {
+        params
+            .iter()
+            .map(|param| (param.value_type, param.extension))
+            .collect::<Vec<_>>()
+    };
+
+    for abi in [Abi::X86_64, Abi::Aarch64] {
+        let types = LookupTable::hardcoded(PTR_SIZE, abi);
+        let call_conv = cl::isa::CallConv::SystemV;
+
+        // fn is_alive(p: Box<Player>) -> bool;
+        let sig = types.create_signature(call_conv, "is_alive");
+        assert_eq!(extensions(&sig.params), [(types.size_t(), None)]);
+        assert_eq!(extensions(&sig.returns), [(cl::types::I8, Uext)]);
+
+        // fn spawn(id: int) -> Box<Player>;
+        let sig = types.create_signature(call_conv, "spawn");
+        assert_eq!(extensions(&sig.params), [(cl::types::I32, None)]);
+        assert_eq!(extensions(&sig.returns), [(types.size_t(), None)]);
+    }
+}
@@ -0,0 +1,105 @@
+//! Defines a large number of functions through `function_builder_from_declaration`, the way a
+//! compiler lowering a whole program would.
+//!
+//! Each function body gets its own copy of its signature, since a `Function` owns its signature.
+//! This checks that those copies match the declarations, and that a `SigCache` reuses the
+//! signatures of the functions which are called over and over.
+
+use cranelift::codegen::Context;
+use cranelift::prelude::{self as cl, FunctionBuilderContext, InstBuilder};
+use cranelift_examples::{SigCache, function_builder_from_declaration};
+use cranelift_module::{Linkage, Module, default_libcall_names};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+const FUNCTIONS: usize = 2000;
+
+// The first few functions are called by all the others
+const CALLEES: usize = 8;
+
+// fn f{i}(a0: i64, a1: i64, ...) -> i64 {
+//   return a0 + a1 + ... + i;
+// }
+//
+// Or for every function after the first `CALLEES`
+//
+// fn f{i}(a0: i64, a1: i64, ...) -> i64 {
+//   return f{i % CALLEES}(a0, a1, ...) + i;
+// }
+#[test]
+fn define_many_functions() {
+    let isa = cl::isa::lookup_by_name("x86_64-unknown-linux")
+        .unwrap()
+        .finish(cl::settings::Flags::new(cl::settings::builder()))
+        .unwrap();
+
+    let builder = ObjectBuilder::new(isa, "many-functions", default_libcall_names()).unwrap();
+    let mut module = ObjectModule::new(builder);
+
+    let ids = (0..FUNCTIONS)
+        .map(|i| {
+            let mut sig = module.make_signature();
+            for _ in 0..i % CALLEES {
+                sig.params.push(cl::AbiParam::new(cl::types::I64));
+            }
+            sig.returns.push(cl::AbiParam::new(cl::types::I64));
+
+            module
+                .declare_function(&format!("f{i}"), Linkage::Export, &sig)
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    let mut ctx = Context::new();
+    let mut fctx = FunctionBuilderContext::new();
+    let mut sigs = SigCache::new();
+
+    for (i, &id) in ids.iter().enumerate() {
+        ctx.clear();
+
+        let (mut fbuilder, entry) =
+            function_builder_from_declaration(&mut module, &mut ctx.func, &mut fctx, id);
+
+        let params = fbuilder.block_params(entry).to_vec();
+
+        let mut sum = fbuilder.ins().iconst(cl::types::I64, i as i64);
+        if i < CALLEES {
+            for p in params {
+                sum = fbuilder.ins().iadd(sum, p);
+            }
+        } else {
+            let callee = ids[i % CALLEES];
+
+            let callee_sig = sigs.get(&module, callee);
+            assert_eq!(callee_sig.params.len(), params.len(), "arguments of f{i}");
+
+            let fref = module.declare_func_in_func(callee, fbuilder.func);
+            let call = fbuilder.ins().call(fref, &params);
+            let returned = fbuilder.inst_results(call)[0];
+            sum = fbuilder.ins().iadd(sum, returned);
+        }
+        fbuilder.ins().return_(&[sum]);
+        fbuilder.finalize();
+
+        let declared = &module.declarations().get_function_decl(id).signature;
+        assert_eq!(&ctx.func.signature, declared, "signature of f{i}");
+
+        module.define_function(id, &mut ctx).unwrap();
+    }
+
+    // Looking up a callee again hands out the copy made the first time, rather than a new clone
+    for &callee in &ids[..CALLEES] {
+        let first: *const cl::Signature = sigs.get(&module, callee);
+        let second: *const cl::Signature = sigs.get(&module, callee);
+        assert_eq!(first, second);
+
+        let declared = &module.declarations().get_function_decl(callee).signature;
+        assert_eq!(sigs.get(&module, callee), declared);
+    }
+
+    let product = module.finish();
+    let defined = ids
+        .iter()
+        .filter(|&&id| product.functions[id].is_some_and(|(_, defined)| defined))
+        .count();
+    assert_eq!(defined, FUNCTIONS);
+}
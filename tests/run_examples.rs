@@ -0,0 +1,286 @@
+//! Compiles each example to an object file, links it into an executable, runs it, and checks the
+//! exit code. This is the same workflow the examples describe in their documentation.
+//!
+//! The examples emit objects for `x86_64-unknown-linux` by default, so the tests are skipped on
+//! other hosts. They're also skipped if neither `clang` nor `cc` is available to link with.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// The exit code of every example which emits a single object file
+const EXIT_CODES: &[(&str, i32)] = &[
+    ("aliases", 10),
+    ("atomics", 13),
+    ("bitops", 8),
+    ("c-trampoline", 19),
+    ("call-libc", 0),
+    ("casts", 68),
+    ("checked-arith", 21),
+    ("closure-array", 11),
+    ("closures", 52),
+    ("constant-folding", 20),
+    ("conversions", 9),
+    ("fn-ptr-field", 42),
+    ("gc-roots", 4),
+    ("generators", 36),
+    ("generics", 11),
+    ("globals", 2),
+    ("heap", 10),
+    ("if-else", 5),
+    ("inlining", 35),
+    ("linkage", 10),
+    ("loops", 42),
+    ("lowering-structs", 3),
+    ("macos", 0),
+    ("multi-return", 10),
+    ("mutual-recursion", 1),
+    ("pic-calls", 10),
+    ("printf", 0),
+    ("recursion", 120),
+    ("recursive-enums", 6),
+    ("select", 7),
+    ("setjmp-longjmp", 12),
+    ("short-circuit", 41),
+    ("simd", 7),
+    ("struct-layouts", 5),
+    ("switch-matching", 160),
+    ("tagged-union-layouts", 71),
+    ("windows-x64", 15),
+];
+
+#[test]
+fn exit_codes() {
+    let Some(linker) = find_linker() else {
+        return;
+    };
+
+    let mut failures = vec![];
+
+    for &(example, expected) in EXIT_CODES {
+        let dir = out_dir(example);
+        let object = dir.join(format!("{example}.o"));
+
+        compile(example, &dir, Some(&object));
+        let exe = link(linker, &dir, example, &[&object]);
+
+        let code = run(&exe, &[]);
+        if code != expected {
+            failures.push(format!("{example} exited with {code}, expected {expected}"));
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+// `output-a-binary` doesn't use the helpers from `lib.rs`, and always writes its object file to
+// the current directory
+#[test]
+fn output_a_binary() {
+    let Some(linker) = find_linker() else {
+        return;
+    };
+
+    let dir = out_dir("output-a-binary");
+    compile("output-a-binary", &dir, None);
+
+    let exe = link(
+        linker,
+        &dir,
+        "output-a-binary",
+        &[&dir.join("output-a-binary.o")],
+    );
+    assert_eq!(run(&exe, &[]), 2);
+}
+
+// `separate-compilation` writes one object file per unit into the directory given with `-o`
+#[test]
+fn separate_compilation() {
+    let Some(linker) = find_linker() else {
+        return;
+    };
+
+    let dir = out_dir("separate-compilation");
+    compile("separate-compilation", &dir, Some(&dir));
+
+    let objects = [dir.join("math.o"), dir.join("main.o")];
+    let exe = link(
+        linker,
+        &dir,
+        "separate-compilation",
+        &[&objects[0], &objects[1]],
+    );
+    assert_eq!(run(&exe, &[]), 10);
+}
+
+// `command-line-args` returns `argc`, which includes the program name
+#[test]
+fn command_line_args() {
+    let Some(linker) = find_linker() else {
+        return;
+    };
+
+    let dir = out_dir("command-line-args");
+    let object = dir.join("command-line-args.o");
+
+    compile("command-line-args", &dir, Some(&object));
+    let exe = link(linker, &dir, "command-line-args", &[&object]);
+
+    assert_eq!(run(&exe, &[]), 1);
+    assert_eq!(run(&exe, &["foo", "bar"]), 3);
+}
+
+// A non-default `-t` has to reach the ISA, and not just the printed message. The object can't be
+// run on this host, so check the machine in its ELF header instead.
+#[test]
+fn target_triple() {
+    let dir = out_dir("target-triple");
+    let object = dir.join("if-else.o");
+
+    let stdout = compile_with_args(
+        "if-else",
+        &dir,
+        Some(&object),
+        &["-t", "aarch64-unknown-linux"],
+    );
+    assert!(
+        stdout.contains(" targeting aarch64-unknown-linux "),
+        "unexpected output:\n{stdout}"
+    );
+
+    // `e_machine` is at offset 18 of the ELF header, and `EM_AARCH64` is 183
+    let bytes = fs::read(&object).unwrap();
+    assert_eq!(&bytes[..4], b"\x7fELF");
+    assert_eq!(u16::from_le_bytes([bytes[18], bytes[19]]), 183);
+}
+
+// `--jit` has to run `main` in-process rather than quietly writing an object file, and examples
+// which can only emit object files have to reject it.
+#[test]
+fn jit() {
+    let dir = out_dir("jit");
+
+    let stdout = compile_with_args("if-else", &dir, None, &["--jit"]);
+    assert!(
+        stdout.contains(" main returned 5 "),
+        "unexpected output:\n{stdout}"
+    );
+
+    let out = example_command("separate-compilation", &dir, &["--jit"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(!out.status.success());
+    assert!(
+        stderr.contains("`--jit` is not supported"),
+        "unexpected output:\n{stderr}"
+    );
+}
+
+// `windows-x64` only knows how x86-64 passes structs, so other targets have to be rejected with an
+// error rather than a panic from inside Cranelift.
+#[test]
+fn windows_x64_rejects_other_architectures() {
+    let dir = out_dir("windows-x64-aarch64");
+
+    let out = example_command("windows-x64", &dir, &["-t", "aarch64-unknown-linux"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert_eq!(out.status.code(), Some(1), "unexpected output:\n{stderr}");
+    assert!(
+        stderr.contains("only supports x86_64 targets"),
+        "unexpected output:\n{stderr}"
+    );
+}
+
+// The first linker which can be run, or `None` if the tests should be skipped
+fn find_linker() -> Option<&'static str> {
+    if !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+        eprintln!("skipping, the examples target x86_64 Linux by default");
+        return None;
+    }
+
+    let linker = ["clang", "cc"].into_iter().find(|linker| {
+        Command::new(linker)
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+    });
+
+    if linker.is_none() {
+        eprintln!("skipping, neither clang nor cc was found");
+    }
+
+    linker
+}
+
+fn out_dir(example: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+        .join("run-examples")
+        .join(example);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Run the example from within `dir`, passing `output` to `-o` if given
+fn compile(example: &str, dir: &Path, output: Option<&Path>) {
+    compile_with_args(example, dir, output, &[]);
+}
+
+// Same as `compile`, with extra arguments for the example. Returns what the example printed.
+fn compile_with_args(example: &str, dir: &Path, output: Option<&Path>, args: &[&str]) -> String {
+    let mut cmd = example_command(example, dir, args);
+    if let Some(output) = output {
+        cmd.arg("-o").arg(output);
+    }
+
+    let out = cmd.output().unwrap();
+
+    assert!(
+        out.status.success(),
+        "{example} failed to compile:\n{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+// `cargo run` for the example from within `dir`
+fn example_command(example: &str, dir: &Path, args: &[&str]) -> Command {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+
+    let mut cmd = Command::new(env!("CARGO"));
+    cmd.args(["run", "--quiet", "--manifest-path"])
+        .arg(manifest)
+        .args(["--example", example, "--"])
+        .args(args)
+        .current_dir(dir);
+    cmd
+}
+
+fn link(linker: &str, dir: &Path, example: &str, objects: &[&Path]) -> PathBuf {
+    let exe = dir.join(example);
+
+    let out = Command::new(linker)
+        .args(objects)
+        .arg("-o")
+        .arg(&exe)
+        .output()
+        .unwrap();
+
+    assert!(
+        out.status.success(),
+        "{example} failed to link:\n{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    exe
+}
+
+fn run(exe: &Path, args: &[&str]) -> i32 {
+    let status = Command::new(exe).args(args).output().unwrap().status;
+    status
+        .code()
+        .unwrap_or_else(|| panic!("{} was killed by a signal", exe.display()))
+}
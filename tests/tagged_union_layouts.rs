@@ -0,0 +1,251 @@
+//! Checks the layouts picked by the `tagged-union-layouts` example, and runs the functions it
+//! defines in Cranelift's interpreter.
+//!
+//! Interpreting them doesn't depend on the target, so it'd work the same when cross compiling.
+//! Since the payload and `Opt::Some(&x)` both point into stack slots, the interpreter can follow
+//! them.
+
+use cranelift::codegen::data_value::DataValue;
+use cranelift::codegen::ir::{Function, UserFuncName};
+use cranelift::prelude::{self as cl, FunctionBuilder, FunctionBuilderContext, InstBuilder};
+use cranelift_examples::{InterpretedFunctions, TRAP_UNREACHABLE};
+use cranelift_interpreter::step::CraneliftTrap;
+use cranelift_module::{FuncId, FuncOrDataId, Module, default_libcall_names};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+#[allow(dead_code)]
+#[path = "../examples/tagged-union-layouts/main.rs"]
+mod tagged_union_layouts;
+
+use tagged_union_layouts::{
+    PACKET_CLOSED, PACKET_DATA, PACKET_DISCRIMINANTS, PACKET_FAILED, PACKET_META, PACKET_PENDING,
+    PACKET_SHORT, PACKET_VARIANTS, construct_tagged_union, discriminants, match_expr, niche_layout,
+    tag_type,
+};
+
+fn object_module() -> ObjectModule {
+    let isa = cl::isa::lookup_by_name("x86_64-unknown-linux")
+        .unwrap()
+        .finish(cl::settings::Flags::new(cl::settings::builder()))
+        .unwrap();
+
+    let builder = ObjectBuilder::new(isa, "tagged-union-layouts", default_libcall_names()).unwrap();
+    ObjectModule::new(builder)
+}
+
+// Define every function of the example, keeping them all for the interpreter
+fn interpreted_packet_functions() -> (ObjectModule, InterpretedFunctions) {
+    let mut module = object_module();
+
+    let mut ctx = cl::codegen::Context::new();
+    let mut fctx = FunctionBuilderContext::new();
+    let interpreted = InterpretedFunctions::new();
+
+    tagged_union_layouts::define_packet_functions(
+        &mut ctx,
+        &mut fctx,
+        &mut module,
+        Some(&interpreted),
+    );
+
+    (module, interpreted)
+}
+
+fn func_id_of(module: &impl Module, name: &str) -> FuncId {
+    match module.get_name(name) {
+        Some(FuncOrDataId::Func(id)) => id,
+        _ => panic!("function {name} has not been declared"),
+    }
+}
+
+// Variants without an explicit discriminant continue counting from the variant before them
+#[test]
+fn discriminants_continue_counting() {
+    let packet_tags = discriminants(&PACKET_DISCRIMINANTS);
+    assert_eq!(packet_tags, [0, 1, 10, 11, 12, 255]);
+}
+
+// Since the largest tag of `Packet` is 255, a single byte is still enough to store the tag
+#[test]
+fn tag_type_fits_the_largest_tag() {
+    let packet_tags = discriminants(&PACKET_DISCRIMINANTS);
+    assert_eq!(tag_type(&packet_tags), cl::types::I8);
+
+    // One more and it no longer fits
+    assert_eq!(tag_type(&[0, 256]), cl::types::I16);
+}
+
+// None of the payloads of `Packet` has an invalid value which could stand in for the other
+// variants, so `Packet` has to keep its tag
+#[test]
+fn packet_has_no_niche() {
+    assert!(niche_layout(&PACKET_VARIANTS).is_none());
+}
+
+// The parameters of `Packet::Short(1, 2)` together fit inside a `size_t`, so they're packed into
+// the payload instead of being stack allocated
+#[test]
+fn short_payload_is_packed() {
+    let module = object_module();
+
+    let packet_tags = discriminants(&PACKET_DISCRIMINANTS);
+    let packet_tag_type = tag_type(&packet_tags);
+
+    let sig = module.make_signature();
+    let mut func = Function::with_name_signature(UserFuncName::testcase("packet_short"), sig);
+
+    let mut fctx = FunctionBuilderContext::new();
+    let mut fbuilder = FunctionBuilder::new(&mut func, &mut fctx);
+    let entry = fbuilder.create_block();
+    fbuilder.switch_to_block(entry);
+    fbuilder.seal_block(entry);
+
+    let a = fbuilder.ins().iconst(cl::types::I8, 1);
+    let b = fbuilder.ins().iconst(cl::types::I16, 2);
+    construct_tagged_union(
+        &module,
+        &mut fbuilder,
+        packet_tag_type,
+        packet_tags[PACKET_SHORT],
+        &[a, b],
+    );
+
+    assert!(fbuilder.func.sized_stack_slots.is_empty());
+}
+
+// The blocks each jump table of the function leads to, not counting its default block
+fn jump_table_targets(func: &Function) -> Vec<Vec<cl::Block>> {
+    func.stencil
+        .dfg
+        .jump_tables
+        .values()
+        .map(|table| {
+            table
+                .as_slice()
+                .iter()
+                .map(|call| call.block(&func.dfg.value_lists))
+                .collect()
+        })
+        .collect()
+}
+
+// Since the tags of `Pending` and `Closed` are next to each other, `Switch` puts them in a jump
+// table of their own. Both of its entries lead to the block of the same arm, rather than to a copy
+// of the arm for each variant.
+#[test]
+fn or_pattern_shares_its_arm() {
+    let packet_tags = discriminants(&PACKET_DISCRIMINANTS);
+    let packet_tag_type = tag_type(&packet_tags);
+
+    let mut sig = cl::Signature::new(cl::isa::CallConv::SystemV);
+    sig.params.push(cl::AbiParam::new(packet_tag_type));
+    sig.returns.push(cl::AbiParam::new(cl::types::I32));
+    let mut func = Function::with_name_signature(UserFuncName::testcase("match_tag"), sig);
+
+    let mut fctx = FunctionBuilderContext::new();
+    let mut fbuilder = FunctionBuilder::new(&mut func, &mut fctx);
+    let entry = fbuilder.create_block();
+    fbuilder.append_block_params_for_function_params(entry);
+    fbuilder.switch_to_block(entry);
+    fbuilder.seal_block(entry);
+    let tag = fbuilder.block_params(entry)[0];
+
+    // The same arms as in `match_packet`
+    let arms: [&[usize]; 5] = [
+        &[PACKET_PENDING, PACKET_CLOSED],
+        &[PACKET_DATA],
+        &[PACKET_FAILED],
+        &[PACKET_SHORT],
+        &[PACKET_META],
+    ];
+
+    let n = match_expr(
+        &mut fbuilder,
+        tag,
+        &packet_tags,
+        &arms,
+        cl::types::I32,
+        |fbuilder, _| fbuilder.ins().iconst(cl::types::I32, 0),
+    );
+    fbuilder.ins().return_(&[n]);
+    fbuilder.finalize();
+
+    let tables = jump_table_targets(&func);
+    assert_eq!(
+        tables.len(),
+        2,
+        "expected jump tables for 0..=1 and 10..=12"
+    );
+
+    // `Switch` doesn't promise an order for its tables, so look them up by size
+    let or_pattern = tables.iter().find(|t| t.len() == 2).unwrap();
+    assert_eq!(or_pattern[0], or_pattern[1]);
+
+    // Every variant of `10..=12` has an arm of its own
+    let data_to_short = tables.iter().find(|t| t.len() == 3).unwrap();
+    assert!(data_to_short[0] != data_to_short[1] && data_to_short[1] != data_to_short[2]);
+    assert!(!data_to_short.contains(&or_pattern[0]));
+}
+
+// `main` takes the `Packet::Data(x, y, z)` branch of `match_packet` and returns `x + y + z + 1`.
+// `classify` then adds `x` for `packet_data`, while the guard fails for `packet_negative` which
+// falls through to `_`.
+#[test]
+fn main_matches_data() {
+    let (module, interpreted) = interpreted_packet_functions();
+
+    let results = interpreted.run(func_id_of(&module, "main"), &[]);
+    assert_eq!(results, [DataValue::I32((10 + 20 + 30 + 1) + 10)]);
+}
+
+// Variants with an inline payload can be passed to `classify` directly
+#[test]
+fn classify_inline_payloads() {
+    let (module, interpreted) = interpreted_packet_functions();
+
+    let size_t = module.isa().pointer_type();
+    let packet_tags = discriminants(&PACKET_DISCRIMINANTS);
+    let packet_tag_type = tag_type(&packet_tags);
+
+    let classify = |tag: i64, payload: i64| {
+        let tag = DataValue::from_integer(tag.into(), packet_tag_type).unwrap();
+        let payload = DataValue::from_integer(payload.into(), size_t).unwrap();
+        interpreted.run(func_id_of(&module, "classify"), &[tag, payload])
+    };
+
+    // Packet::Failed(100) matches the second arm
+    assert_eq!(
+        classify(packet_tags[PACKET_FAILED], 100),
+        [DataValue::I32(100)]
+    );
+
+    // Packet::Pending, as well as any other tag, is caught by `_`
+    assert_eq!(
+        classify(packet_tags[PACKET_PENDING], 0),
+        [DataValue::I32(0)]
+    );
+    assert_eq!(classify(2, 0), [DataValue::I32(0)]);
+}
+
+// A tag which doesn't belong to any variant, such as one read from corrupted memory, has to end up
+// in the trap block rather than in whichever variant happens to be closest.
+#[test]
+fn match_packet_traps_on_unknown_tags() {
+    let (module, interpreted) = interpreted_packet_functions();
+
+    let size_t = module.isa().pointer_type();
+    let packet_tag_type = tag_type(&discriminants(&PACKET_DISCRIMINANTS));
+
+    for tag in [2, 9, 13, 254] {
+        let tag = DataValue::from_integer(tag, packet_tag_type).unwrap();
+        let payload = DataValue::from_integer(0, size_t).unwrap();
+
+        let result = interpreted.try_run(func_id_of(&module, "match_packet"), &[tag, payload]);
+        assert_eq!(
+            result,
+            Err(CraneliftTrap::User(
+                cl::TrapCode::user(TRAP_UNREACHABLE).unwrap()
+            ))
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! Checks that `trap_unreachable` terminates the block it's emitted in, so that it can be used as
+//! the only instruction of a default block.
+
+use cranelift::codegen::data_value::DataValue;
+use cranelift::codegen::ir::{Function, UserFuncName};
+use cranelift::codegen::{settings, verify_function};
+use cranelift::prelude::{
+    self as cl, FunctionBuilder, FunctionBuilderContext, InstBuilder, TrapCode,
+};
+use cranelift_examples::{TRAP_UNREACHABLE, create_entry_block, trap_unreachable};
+use cranelift_interpreter::environment::FunctionStore;
+use cranelift_interpreter::interpreter::{Interpreter, InterpreterState};
+use cranelift_interpreter::step::{ControlFlow, CraneliftTrap};
+
+// fn pick(n: i32) -> i32 {
+//   if n != 0 { return n } else { unreachable!() }
+// }
+fn pick() -> (Function, cl::Block, cl::codegen::ir::Inst) {
+    let mut sig = cl::Signature::new(cl::isa::CallConv::SystemV);
+    sig.params.push(cl::AbiParam::new(cl::types::I32));
+    sig.returns.push(cl::AbiParam::new(cl::types::I32));
+
+    let mut func = Function::with_name_signature(UserFuncName::user(0, 0), sig);
+    let mut fctx = FunctionBuilderContext::new();
+    let mut fbuilder = FunctionBuilder::new(&mut func, &mut fctx);
+
+    let entry = create_entry_block(&mut fbuilder);
+    let ret = fbuilder.create_block();
+    let trap = fbuilder.create_block();
+
+    fbuilder.switch_to_block(entry);
+    let n = fbuilder.block_params(entry)[0];
+    fbuilder.ins().brif(n, ret, &[], trap, &[]);
+    fbuilder.seal_block(ret);
+    fbuilder.seal_block(trap);
+
+    fbuilder.switch_to_block(ret);
+    fbuilder.ins().return_(&[n]);
+
+    fbuilder.switch_to_block(trap);
+    let inst = trap_unreachable(&mut fbuilder);
+
+    fbuilder.finalize();
+
+    (func, trap, inst)
+}
+
+#[test]
+fn terminates_the_block() {
+    let (func, block, inst) = pick();
+
+    assert!(func.dfg.insts[inst].opcode().is_terminator());
+    assert_eq!(func.layout.last_inst(block), Some(inst));
+
+    // The trap is the only instruction needed for the block to be valid
+    assert_eq!(func.layout.first_inst(block), Some(inst));
+
+    let flags = settings::Flags::new(settings::builder());
+    verify_function(&func, &flags).unwrap();
+}
+
+#[test]
+fn traps_with_the_unreachable_code() {
+    let (func, _, _) = pick();
+
+    let mut functions = FunctionStore::default();
+    functions.add(func.name.to_string(), &func);
+    let state = InterpreterState::default().with_function_store(functions);
+    let mut interpreter = Interpreter::new(state);
+
+    let mut call = |n| {
+        interpreter
+            .call_by_name(&func.name.to_string(), &[DataValue::I32(n)])
+            .unwrap()
+    };
+
+    assert!(matches!(call(5), ControlFlow::Return(results) if results[..] == [DataValue::I32(5)]));
+
+    let expected = CraneliftTrap::User(TrapCode::user(TRAP_UNREACHABLE).unwrap());
+    assert!(matches!(call(0), ControlFlow::Trap(trap) if trap == expected));
+}